@@ -0,0 +1,262 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Benchmarks of a whole device. Like the integration tests they create tunnel interfaces, so
+// they need the privileges to do that.
+#![feature(test)]
+extern crate test;
+
+#[cfg(test)]
+mod tests {
+    use boringtun::crypto::x25519::*;
+    use boringtun::device::uapi_client::UapiClient;
+    use boringtun::device::*;
+    use boringtun::noise::{Tunn, TunnResult};
+    use hex::encode;
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use test::{black_box, Bencher};
+
+    static NEXT_IFACE_IDX: AtomicUsize = AtomicUsize::new(200); // Clear of the utun 100+ of the integration tests
+
+    // A device with a private key and count peers without endpoints, and the keys of the peers
+    fn device_with_peers(count: usize, lean_peers: bool) -> (DeviceHandle, Vec<X25519PublicKey>) {
+        let name = format!("utun{}", NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed));
+        let device = DeviceHandle::new(
+            &name,
+            DeviceConfig {
+                n_threads: 2,
+                api_max_request_size: 1 << 24,
+                lean_peers,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let keys: Vec<_> = (0..count)
+            .map(|_| X25519SecretKey::new().public_key())
+            .collect();
+        let mut config = format!(
+            "private_key={}\n",
+            encode(X25519SecretKey::new().as_bytes())
+        );
+        for key in &keys {
+            config.push_str(&format!("public_key={}\n", encode(key.as_bytes())));
+        }
+        UapiClient::connect_interface(&name)
+            .unwrap()
+            .set(&config)
+            .unwrap();
+
+        (device, keys)
+    }
+
+    #[bench]
+    fn bench_peer_stats_50k_peers(b: &mut Bencher) {
+        let (device, keys) = device_with_peers(50_000, false);
+        b.iter(|| {
+            black_box(
+                keys.iter()
+                    .map(|key| device.peer_stats(key).unwrap())
+                    .collect::<Vec<_>>(),
+            )
+        });
+    }
+
+    #[bench]
+    fn bench_all_stats_50k_peers(b: &mut Bencher) {
+        let (device, _) = device_with_peers(50_000, false);
+        b.iter(|| black_box(device.all_stats()));
+    }
+
+    // The bytes in use on the heap
+    #[cfg(target_os = "linux")]
+    fn heap_in_use() -> usize {
+        let info = unsafe { libc::mallinfo() };
+        info.uordblks as u32 as usize + info.hblkhd as u32 as usize
+    }
+
+    // A device with 100k idle peers, and the heap it takes per peer, without the keys kept for
+    // the benchmark
+    #[cfg(target_os = "linux")]
+    fn idle_peers_memory(lean_peers: bool) -> (DeviceHandle, usize) {
+        let before = heap_in_use();
+        let (device, keys) = device_with_peers(100_000, lean_peers);
+        let used = heap_in_use() - before - keys.capacity() * std::mem::size_of_val(&keys[0]);
+        (device, used / keys.len())
+    }
+
+    // Compare the memory of idle peers with and without lean_peers, keeping both devices up so
+    // the teardown of one does not skew the other. The time is that of the stats of lean peers.
+    #[bench]
+    #[cfg(target_os = "linux")]
+    fn bench_idle_peers_memory_100k(b: &mut Bencher) {
+        let (_device, default) = idle_peers_memory(false);
+        let (lean, lean_bytes) = idle_peers_memory(true);
+        println!(
+            "bytes per idle peer: {} by default, {} with lean_peers",
+            default, lean_bytes
+        );
+        assert!(lean_bytes < default);
+        b.iter(|| black_box(lean.all_stats()));
+    }
+
+    const BURST: usize = 64; // Datagrams sent into the tunnel per iteration
+
+    // A device with a single peer, emulated with Tunn, and a route to it through the tunnel
+    #[cfg(target_os = "linux")]
+    struct Tunnel {
+        _device: DeviceHandle,
+        peer: Box<Tunn>,
+        peer_sock: UdpSocket,
+        sender: UdpSocket,
+        target: SocketAddr,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Tunnel {
+        fn new(tx_batch_linger: Duration, tun_read_buffers: usize) -> Tunnel {
+            let idx = NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed);
+            let name = format!("utun{}", idx);
+            // Use 198.51.100.0/24, clear of the 192.0.2.0/24 of the integration tests
+            let addr = Ipv4Addr::new(198, 51, 100, (idx % 128 * 2) as u8);
+            let peer_ip = Ipv4Addr::new(198, 51, 100, (idx % 128 * 2 + 1) as u8);
+
+            let device = DeviceHandle::new(
+                &name,
+                DeviceConfig {
+                    n_threads: 1,
+                    use_connected_socket: false,
+                    use_multi_queue: false,
+                    tx_batch_linger,
+                    tun_read_buffers,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let private_key = X25519SecretKey::new();
+            let public_key = Arc::new(private_key.public_key());
+            let peer_key = Arc::new(X25519SecretKey::new());
+            let peer_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+            peer_sock
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            UapiClient::connect_interface(&name)
+                .unwrap()
+                .set(&format!(
+                    "private_key={}\npublic_key={}\nendpoint={}\nallowed_ip={}/32",
+                    encode(private_key.as_bytes()),
+                    encode(peer_key.public_key().as_bytes()),
+                    peer_sock.local_addr().unwrap(),
+                    peer_ip
+                ))
+                .unwrap();
+
+            for args in &[
+                vec!["address", "add", &addr.to_string(), "dev", &name],
+                vec!["link", "set", "mtu", "1400", "up", "dev", &name],
+                vec!["route", "add", &format!("{}/32", peer_ip), "dev", &name],
+            ] {
+                Command::new("ip")
+                    .args(args)
+                    .status()
+                    .expect("failed to configure the tunnel");
+            }
+
+            let mut tunnel = Tunnel {
+                _device: device,
+                peer: Tunn::new(peer_key, public_key, None, None, 0, None).unwrap(),
+                peer_sock,
+                sender: UdpSocket::bind("0.0.0.0:0").unwrap(),
+                target: SocketAddr::new(peer_ip.into(), 9999),
+            };
+
+            // The first datagram is queued during the handshake
+            tunnel.sender.send_to(b"session", tunnel.target).unwrap();
+            assert_eq!(tunnel.receive(1), 1, "No session with the peer");
+            tunnel
+        }
+
+        // Receive up to count datagrams at the peer, answering the handshake messages of the
+        // device, returns how many arrived before the read timed out
+        fn receive(&mut self, count: usize) -> usize {
+            let mut buf = [0u8; 2048];
+            let mut dst = [0u8; 2048];
+            let mut received = 0;
+            while received < count {
+                let (n, addr) = match self.peer_sock.recv_from(&mut buf) {
+                    Ok(res) => res,
+                    Err(_) => break,
+                };
+                match self.peer.decapsulate(Some(addr.ip()), &buf[..n], &mut dst) {
+                    TunnResult::WriteToNetwork(packet) => {
+                        self.peer_sock.send_to(packet, addr).unwrap();
+                        while let TunnResult::WriteToNetwork(packet) =
+                            self.peer.decapsulate(None, &[], &mut dst)
+                        {
+                            self.peer_sock.send_to(packet, addr).unwrap();
+                        }
+                    }
+                    TunnResult::WriteToTunnelV4(..) => received += 1,
+                    _ => {}
+                }
+            }
+            received
+        }
+    }
+
+    // Send bursts of datagrams through the tunnel, with the device batching what it sends
+    #[cfg(target_os = "linux")]
+    fn bench_tx_batch(b: &mut Bencher, tx_batch_linger: Duration, tun_read_buffers: usize) {
+        let mut tunnel = Tunnel::new(tx_batch_linger, tun_read_buffers);
+        let payload = [0u8; 1000];
+        b.bytes = (BURST * payload.len()) as u64;
+        b.iter(|| {
+            for _ in 0..BURST {
+                tunnel.sender.send_to(&payload, tunnel.target).unwrap();
+            }
+            black_box(tunnel.receive(BURST))
+        });
+    }
+
+    #[bench]
+    #[cfg(target_os = "linux")]
+    fn bench_tx_batch_linger_0us(b: &mut Bencher) {
+        bench_tx_batch(b, Duration::ZERO, 1);
+    }
+
+    #[bench]
+    #[cfg(target_os = "linux")]
+    fn bench_tx_batch_linger_50us(b: &mut Bencher) {
+        bench_tx_batch(b, Duration::from_micros(50), 1);
+    }
+
+    #[bench]
+    #[cfg(target_os = "linux")]
+    fn bench_tx_batch_linger_200us(b: &mut Bencher) {
+        bench_tx_batch(b, Duration::from_micros(200), 1);
+    }
+
+    // The packets of a burst per second is 1e9 * BURST divided by the ns per iteration
+    #[bench]
+    #[cfg(target_os = "linux")]
+    fn bench_tun_read_buffers_1(b: &mut Bencher) {
+        bench_tx_batch(b, Duration::ZERO, 1);
+    }
+
+    #[bench]
+    #[cfg(target_os = "linux")]
+    fn bench_tun_read_buffers_8(b: &mut Bencher) {
+        bench_tx_batch(b, Duration::ZERO, 8);
+    }
+
+    #[bench]
+    #[cfg(target_os = "linux")]
+    fn bench_tun_read_buffers_32(b: &mut Bencher) {
+        bench_tx_batch(b, Duration::ZERO, 32);
+    }
+}
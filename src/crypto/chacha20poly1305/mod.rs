@@ -18,6 +18,13 @@ pub struct ChaCha20Poly1305 {
     key: [u32; 8],
 }
 
+impl Drop for ChaCha20Poly1305 {
+    fn drop(&mut self) {
+        // Force zero out of the memory on Drop
+        unsafe { std::ptr::write_volatile(&mut self.key, [0u32; 8]) }
+    }
+}
+
 impl ChaCha20Poly1305 {
     /// Returns a new instance of the ChaCha20-Poly1305 AEAD with the key `key`, which must be 256
     /// bits.
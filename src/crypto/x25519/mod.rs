@@ -7,8 +7,9 @@ mod tests;
 
 use crate::noise::errors::WireGuardError;
 use crate::noise::make_array;
-use base64::decode;
+use std::fmt;
 use std::ops::Add;
+use std::ops::Deref;
 use std::ops::Mul;
 use std::ops::Sub;
 use std::str::FromStr;
@@ -47,6 +48,24 @@ pub mod SystemRandom {
     }
 }
 
+/// A source of randomness for key generation.
+/// `OsRng` is the only implementation suitable for real traffic. Any other implementation exists
+/// to make protocol tests reproducible and is insecure by definition.
+pub trait Rng: Send + Sync {
+    /// Fill dest with random bytes
+    fn fill(&self, dest: &mut [u8]);
+}
+
+/// The OS rng, used by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsRng;
+
+impl Rng for OsRng {
+    fn fill(&self, dest: &mut [u8]) {
+        SystemRandom::new().fill(dest).unwrap();
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 /// A secret X25519 key.
@@ -58,9 +77,13 @@ pub struct X25519SecretKey {
 impl X25519SecretKey {
     /// Generate a new secret key using the OS rng.
     pub fn new() -> Self {
-        let rng = SystemRandom::new();
+        X25519SecretKey::new_from_rng(&OsRng)
+    }
+
+    /// Generate a new secret key using the provided rng.
+    pub fn new_from_rng(rng: &dyn Rng) -> Self {
         let mut private_key = [0u8; 32];
-        rng.fill(&mut private_key[..]).unwrap();
+        rng.fill(&mut private_key[..]);
         X25519SecretKey {
             internal: private_key,
         }
@@ -87,6 +110,30 @@ impl X25519SecretKey {
     pub fn as_bytes(&self) -> &[u8] {
         &self.internal[..]
     }
+
+    /// Parse a key in the base64 encoding of `wg genkey`: 44 characters, with padding.
+    pub fn from_base64(s: &str) -> Result<Self, &'static str> {
+        Ok(X25519SecretKey {
+            internal: decode_base64_key(s)?,
+        })
+    }
+
+    /// Parse a key in the hex encoding of the configuration protocol: 64 hex digits.
+    pub fn from_hex(s: &str) -> Result<Self, &'static str> {
+        Ok(X25519SecretKey {
+            internal: decode_hex_key(s)?,
+        })
+    }
+
+    /// The base64 encoding of the key, as `wg genkey` prints it.
+    pub fn to_base64(&self) -> SecretString {
+        SecretString(base64::encode(self.internal))
+    }
+
+    /// The lowercase hex encoding of the key, as the configuration protocol uses it.
+    pub fn to_hex(&self) -> SecretString {
+        SecretString(hex::encode(self.internal))
+    }
 }
 
 impl FromStr for X25519SecretKey {
@@ -94,32 +141,61 @@ impl FromStr for X25519SecretKey {
 
     /// Can parse a secret key from a hex or base64 encoded string.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut key = X25519SecretKey {
-            internal: [0u8; 32],
-        };
-
         match s.len() {
-            64 => {
-                // Try to parse as hex
-                for i in 0..32 {
-                    key.internal[i] = u8::from_str_radix(&s[i * 2..=i * 2 + 1], 16)
-                        .map_err(|_| "Illegal character in key")?;
-                }
-            }
-            43 | 44 => {
-                // Try to parse as base64
-                if let Ok(decoded_key) = decode(s) {
-                    if decoded_key.len() == key.internal.len() {
-                        key.internal[..].copy_from_slice(&decoded_key);
-                    } else {
-                        return Err("Illegal character in key");
-                    }
-                }
-            }
-            _ => return Err("Illegal key size"),
+            64 => X25519SecretKey::from_hex(s),
+            44 => X25519SecretKey::from_base64(s),
+            _ => Err("Illegal key size"),
         }
+    }
+}
+
+// Decode a key the way wg does: exactly one padding character, and no bits set beyond the 32
+// bytes, so every key has exactly one encoding
+fn decode_base64_key(s: &str) -> Result<[u8; 32], &'static str> {
+    if s.len() != 44 || !s.ends_with('=') {
+        return Err("Illegal key size");
+    }
+    let mut decoded = base64::decode(s).map_err(|_| "Illegal character in key")?;
+    let key = match decoded.len() {
+        32 => Ok(make_array(&decoded)),
+        _ => Err("Illegal key size"),
+    };
+    unsafe { std::ptr::write_bytes(decoded.as_mut_ptr(), 0, decoded.len()) };
+    key
+}
+
+fn decode_hex_key(s: &str) -> Result<[u8; 32], &'static str> {
+    if s.len() != 64 {
+        return Err("Illegal key size");
+    }
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(s, &mut key).map_err(|_| "Illegal character in key")?;
+    Ok(key)
+}
+
+/// The encoding of a secret key, zeroed out when dropped.
+pub struct SecretString(String);
+
+impl Deref for SecretString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
 
-        Ok(key)
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretString(..)")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        // Force zero out of the memory on Drop
+        for b in unsafe { self.0.as_bytes_mut() } {
+            unsafe { std::ptr::write_volatile(b, 0) }
+        }
     }
 }
 
@@ -147,6 +223,30 @@ impl X25519PublicKey {
     pub fn as_bytes(&self) -> &[u8] {
         &self.internal[..]
     }
+
+    /// Parse a key in the base64 encoding of `wg pubkey`: 44 characters, with padding.
+    pub fn from_base64(s: &str) -> Result<Self, &'static str> {
+        Ok(X25519PublicKey {
+            internal: decode_base64_key(s)?,
+        })
+    }
+
+    /// Parse a key in the hex encoding of the configuration protocol: 64 hex digits.
+    pub fn from_hex(s: &str) -> Result<Self, &'static str> {
+        Ok(X25519PublicKey {
+            internal: decode_hex_key(s)?,
+        })
+    }
+
+    /// The base64 encoding of the key, as `wg pubkey` prints it.
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.internal)
+    }
+
+    /// The lowercase hex encoding of the key, as the configuration protocol uses it.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.internal)
+    }
 }
 
 /// Will panic if the slice.len() != 32.
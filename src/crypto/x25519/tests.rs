@@ -3,7 +3,9 @@
 
 #[cfg(test)]
 mod tests {
-    use super::super::{mod_final_25519, mod_inv_25519, x25519_shared_key, Felem};
+    use super::super::{
+        mod_final_25519, mod_inv_25519, x25519_shared_key, Felem, X25519PublicKey, X25519SecretKey,
+    };
 
     #[allow(dead_code)]
     struct X25519ArithTest {
@@ -60,6 +62,80 @@ mod tests {
         assert_eq!(mod_final_25519(max + one).0, zero.0);
     }
 
+    #[test]
+    fn x25519_key_encoding() {
+        // A key from RFC 7748 section 6.1 clamped as by `wg genkey`, with its `wg pubkey`
+        let private_b64 = "cAdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LGo=";
+        let private_hex = "70076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c6a";
+        let public_b64 = "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=";
+        let public_hex = "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a";
+
+        let private = X25519SecretKey::from_base64(private_b64).unwrap();
+        assert_eq!(&*private.to_base64(), private_b64);
+        assert_eq!(&*private.to_hex(), private_hex);
+        assert_eq!(
+            X25519SecretKey::from_hex(private_hex).unwrap().as_bytes(),
+            private.as_bytes()
+        );
+
+        let public = private.public_key();
+        assert_eq!(public.to_base64(), public_b64);
+        assert_eq!(public.to_hex(), public_hex);
+        assert_eq!(X25519PublicKey::from_base64(public_b64).unwrap(), public);
+        assert_eq!(X25519PublicKey::from_hex(public_hex).unwrap(), public);
+        assert_eq!(public_b64.parse::<X25519PublicKey>().unwrap(), public);
+        assert_eq!(public_hex.parse::<X25519PublicKey>().unwrap(), public);
+        assert_eq!(
+            X25519PublicKey::from_hex(&public_hex.to_uppercase()).unwrap(),
+            public
+        );
+
+        for malformed in &[
+            "",
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo", // No padding
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo==", // Too long
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmp=", // Bits beyond the key
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTm==", // 31 bytes
+            "hSDwCYkwp1R0i33ctD73Wg2_Og0mOBr066SpjqqbTmo=", // URL safe alphabet
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066Spjqqb Tmo",
+        ] {
+            assert!(
+                X25519PublicKey::from_base64(malformed).is_err(),
+                "{}",
+                malformed
+            );
+            assert!(
+                X25519SecretKey::from_base64(malformed).is_err(),
+                "{}",
+                malformed
+            );
+            assert!(
+                malformed.parse::<X25519PublicKey>().is_err(),
+                "{}",
+                malformed
+            );
+        }
+        for malformed in &[
+            &public_hex[..62],
+            "+520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a",
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6g",
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4éa",
+        ] {
+            assert!(
+                X25519PublicKey::from_hex(malformed).is_err(),
+                "{}",
+                malformed
+            );
+            assert!(
+                malformed.parse::<X25519PublicKey>().is_err(),
+                "{}",
+                malformed
+            );
+        }
+        assert!(X25519PublicKey::from_hex(public_b64).is_err());
+        assert!(X25519PublicKey::from_base64(public_hex).is_err());
+    }
+
     #[test]
     fn x25519_test_vectors() {
         let base: [u8; 32] = [
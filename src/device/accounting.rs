@@ -0,0 +1,209 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Per-peer counters of inner packets by transport protocol, kept when detailed accounting is on
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const IPV4_MIN_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMPV6: u8 = 58;
+
+// IPv6 extension headers that may precede the transport header
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AUTH: u8 = 51;
+const IPV6_DEST_OPTS: u8 = 60;
+
+// No legitimate packet has this many extension headers
+const MAX_EXTENSION_HEADERS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InnerProtocol {
+    Tcp,
+    Udp,
+    /// ICMP or ICMPv6
+    Icmp,
+    /// Any other protocol, and packets whose headers could not be parsed
+    Other,
+}
+
+impl InnerProtocol {
+    /// The transport protocol of an IPv4 or IPv6 packet, skipping IPv6 extension headers
+    pub fn of(packet: &[u8]) -> InnerProtocol {
+        match packet.first().map(|b| b >> 4) {
+            Some(4) if packet.len() >= IPV4_MIN_HEADER_SIZE => {
+                InnerProtocol::from_number(packet[9])
+            }
+            Some(6) if packet.len() >= IPV6_HEADER_SIZE => ipv6_protocol(packet),
+            _ => InnerProtocol::Other,
+        }
+    }
+
+    fn from_number(protocol: u8) -> InnerProtocol {
+        match protocol {
+            IPPROTO_TCP => InnerProtocol::Tcp,
+            IPPROTO_UDP => InnerProtocol::Udp,
+            IPPROTO_ICMP | IPPROTO_ICMPV6 => InnerProtocol::Icmp,
+            _ => InnerProtocol::Other,
+        }
+    }
+}
+
+fn ipv6_protocol(packet: &[u8]) -> InnerProtocol {
+    let mut next_header = packet[6];
+    let mut offset = IPV6_HEADER_SIZE;
+
+    for _ in 0..MAX_EXTENSION_HEADERS {
+        let header_len = match next_header {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTS => packet
+                .get(offset + 1)
+                .map(|&len| (usize::from(len) + 1) * 8),
+            IPV6_FRAGMENT => Some(8),
+            IPV6_AUTH => packet
+                .get(offset + 1)
+                .map(|&len| (usize::from(len) + 2) * 4),
+            protocol => return InnerProtocol::from_number(protocol),
+        };
+
+        match (packet.get(offset), header_len) {
+            (Some(&next), Some(len)) if offset + len <= packet.len() => {
+                next_header = next;
+                offset += len;
+            }
+            _ => return InnerProtocol::Other,
+        }
+    }
+
+    InnerProtocol::Other
+}
+
+/// Packets and bytes of one protocol
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolCount {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Inner packets in one direction, by transport protocol
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolStats {
+    pub tcp: ProtocolCount,
+    pub udp: ProtocolCount,
+    pub icmp: ProtocolCount,
+    pub other: ProtocolCount,
+}
+
+#[derive(Debug, Default)]
+pub struct ProtocolCounters {
+    packets: [AtomicU64; 4],
+    bytes: [AtomicU64; 4],
+}
+
+impl ProtocolCounters {
+    pub fn count(&self, packet: &[u8]) {
+        let i = InnerProtocol::of(packet) as usize;
+        self.packets[i].fetch_add(1, Ordering::Relaxed);
+        self.bytes[i].fetch_add(packet.len() as u64, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ProtocolStats {
+        let get = |protocol: InnerProtocol| ProtocolCount {
+            packets: self.packets[protocol as usize].load(Ordering::Relaxed),
+            bytes: self.bytes[protocol as usize].load(Ordering::Relaxed),
+        };
+        ProtocolStats {
+            tcp: get(InnerProtocol::Tcp),
+            udp: get(InnerProtocol::Udp),
+            icmp: get(InnerProtocol::Icmp),
+            other: get(InnerProtocol::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv6_packet(next_header: u8, extensions: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; IPV6_HEADER_SIZE];
+        packet[0] = 0x60;
+        packet[6] = next_header;
+        packet.extend_from_slice(extensions);
+        packet
+    }
+
+    #[test]
+    fn test_ipv4_protocol() {
+        let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, IPPROTO_TCP, 0, 0];
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        assert_eq!(InnerProtocol::of(&packet), InnerProtocol::Tcp);
+        packet[9] = IPPROTO_ICMP;
+        assert_eq!(InnerProtocol::of(&packet), InnerProtocol::Icmp);
+        packet[9] = 47; // GRE
+        assert_eq!(InnerProtocol::of(&packet), InnerProtocol::Other);
+    }
+
+    #[test]
+    fn test_ipv6_extension_headers() {
+        assert_eq!(
+            InnerProtocol::of(&ipv6_packet(IPPROTO_UDP, &[])),
+            InnerProtocol::Udp
+        );
+
+        // Hop-by-hop options of 8 bytes, then a fragment header, then ICMPv6
+        let mut extensions = vec![IPV6_FRAGMENT, 0, 0, 0, 0, 0, 0, 0];
+        extensions.extend_from_slice(&[IPPROTO_ICMPV6, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            InnerProtocol::of(&ipv6_packet(IPV6_HOP_BY_HOP, &extensions)),
+            InnerProtocol::Icmp
+        );
+    }
+
+    #[test]
+    fn test_malformed_packets() {
+        // A truncated extension header, and a header claiming to be longer than the packet
+        assert_eq!(
+            InnerProtocol::of(&ipv6_packet(IPV6_ROUTING, &[])),
+            InnerProtocol::Other
+        );
+        assert_eq!(
+            InnerProtocol::of(&ipv6_packet(IPV6_DEST_OPTS, &[IPPROTO_TCP, 255])),
+            InnerProtocol::Other
+        );
+
+        // A loop of extension headers ends
+        let looping = [IPV6_DEST_OPTS, 0, 0, 0, 0, 0, 0, 0].repeat(16);
+        assert_eq!(
+            InnerProtocol::of(&ipv6_packet(IPV6_DEST_OPTS, &looping)),
+            InnerProtocol::Other
+        );
+
+        assert_eq!(InnerProtocol::of(&[]), InnerProtocol::Other);
+        assert_eq!(InnerProtocol::of(&[0x45, 0, 0]), InnerProtocol::Other);
+    }
+
+    #[test]
+    fn test_protocol_counters() {
+        let counters = ProtocolCounters::default();
+        counters.count(&ipv6_packet(IPPROTO_TCP, &[]));
+        counters.count(&ipv6_packet(IPPROTO_TCP, &[0; 20]));
+        counters.count(&[0x45]);
+
+        let stats = counters.stats();
+        assert_eq!(
+            stats.tcp,
+            ProtocolCount {
+                packets: 2,
+                bytes: 100
+            }
+        );
+        assert_eq!(stats.udp, ProtocolCount::default());
+        assert_eq!(stats.other.packets, 1);
+    }
+}
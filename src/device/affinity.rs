@@ -0,0 +1,127 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Pinning of worker threads to CPUs, so the packets of a flow steered to a CPU by the NIC, RPS
+//! and the reuseport sockets are also encrypted there. Only Linux supports pinning, elsewhere it
+//! does nothing.
+
+use super::Error;
+
+/// The number of CPU ids a CPU set holds, ids from here on can not be pinned to
+#[cfg(target_os = "linux")]
+pub const MAX_CPUS: usize = std::mem::size_of::<libc::cpu_set_t>() * 8;
+#[cfg(not(target_os = "linux"))]
+pub const MAX_CPUS: usize = 1024;
+
+/// Parse a list of CPU ids in the format of `/sys/devices/system/cpu/online`: ids and ranges of
+/// ids separated by commas, such as `0-3,8`. Ids must be below `MAX_CPUS`.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = vec![];
+    for part in list.trim().split(',').filter(|part| !part.is_empty()) {
+        let invalid = || format!("Invalid CPU list {}", list);
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (part, part),
+        };
+        let first = first.parse::<usize>().map_err(|_| invalid())?;
+        let last = last.parse::<usize>().map_err(|_| invalid())?;
+        if last < first || last >= MAX_CPUS {
+            return Err(invalid());
+        }
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
+
+/// The CPUs that are online
+pub fn online_cpus() -> Vec<usize> {
+    if let Ok(cpus) = std::fs::read_to_string("/sys/devices/system/cpu/online")
+        .map_err(|e| e.to_string())
+        .and_then(|list| parse_cpu_list(&list))
+    {
+        return cpus;
+    }
+    match unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } {
+        n if n > 0 => (0..n as usize).collect(),
+        _ => vec![0],
+    }
+}
+
+/// Check that every CPU of cpus is online
+pub fn validate(cpus: &[usize]) -> Result<(), Error> {
+    let online = online_cpus();
+    match cpus.iter().find(|cpu| !online.contains(cpu)) {
+        Some(cpu) => Err(Error::InvalidConfig(format!("CPU {} is not online", cpu))),
+        None => Ok(()),
+    }
+}
+
+/// Restrict the calling thread to cpu
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> Result<(), Error> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    if cpu >= MAX_CPUS {
+        return Err(Error::Affinity(format!("CPU {} is out of range", cpu)));
+    }
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    match unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } {
+        -1 => Err(Error::Affinity(std::io::Error::last_os_error().to_string())),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpu: usize) -> Result<(), Error> {
+    Ok(())
+}
+
+/// The CPUs the calling thread may run on, None where it can not be told
+#[cfg(target_os = "linux")]
+pub fn current_thread_affinity() -> Option<Vec<usize>> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    match unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) } {
+        -1 => None,
+        _ => Some(
+            (0..MAX_CPUS)
+                .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current_thread_affinity() -> Option<Vec<usize>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8\n").unwrap(), [0, 1, 2, 3, 8]);
+        assert_eq!(parse_cpu_list("5").unwrap(), [5]);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+        assert!(parse_cpu_list(&format!("{}", MAX_CPUS)).is_err());
+        assert!(parse_cpu_list("0-18446744073709551615").is_err());
+
+        let online = online_cpus();
+        assert!(validate(&online).is_ok());
+        assert!(validate(&[online.iter().max().unwrap() + 1]).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_pin_current_thread() {
+        let cpu = *current_thread_affinity().unwrap().last().unwrap();
+        let pinned = std::thread::spawn(move || {
+            pin_current_thread(cpu).unwrap();
+            current_thread_affinity().unwrap()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(pinned, [cpu]);
+    }
+}
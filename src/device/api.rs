@@ -3,17 +3,33 @@
 
 use super::dev_lock::LockReadGuard;
 use super::drop_privileges::*;
-use super::{make_array, AllowedIP, Device, Error, SocketAddr, X25519PublicKey, X25519SecretKey};
+use super::ecmp::MAX_WEIGHT;
+use super::peer::is_valid_metadata;
+use super::resolve::is_valid_host_endpoint;
+use super::trace::MAX_TRACE_EVENTS;
+use super::{
+    make_array, AllowedIP, Device, Error, IpAddr, SocketAddr, X25519PublicKey, X25519SecretKey,
+};
 use crate::device::{Action, Sock, Tun};
 use hex::encode as encode_hex;
 use libc::*;
+use slog::{error, warn};
+use std::collections::HashMap;
 use std::fs::{create_dir, remove_file};
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+#[cfg(feature = "tcp-api")]
+use std::net::{TcpListener, TcpStream};
 use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::FromRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
-const SOCK_DIR: &str = "/var/run/wireguard/";
+pub(super) const SOCK_DIR: &str = "/var/run/wireguard/";
+/// The shortest token the TCP transport accepts, any local user can try tokens against it
+#[cfg(feature = "tcp-api")]
+pub const MIN_API_TOKEN_LEN: usize = 16;
 
 fn create_sock_dir() {
     let _ = create_dir(SOCK_DIR); // Create the directory if it does not exist
@@ -32,20 +48,294 @@ fn create_sock_dir() {
     }
 }
 
+/// The transport the UAPI is served on
+#[derive(Debug, Clone, Default)]
+pub enum ApiSocket {
+    /// A filesystem unix socket with a known path: /var/run/wireguard/{tun_name}.sock
+    #[default]
+    Path,
+    /// A Linux abstract namespace unix socket, `@name`, for when the filesystem is read-only
+    #[cfg(target_os = "linux")]
+    Abstract(String),
+    /// A TCP socket on the loopback interface. Each connection must first present the
+    /// preshared token as a `token={token}` line, before the regular get=1/set=1 command. The
+    /// token must be at least `MIN_API_TOKEN_LEN` bytes long.
+    #[cfg(feature = "tcp-api")]
+    Tcp { port: u16, token: String },
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> Result<UnixListener, Error> {
+    let name = name.trim_start_matches('@').as_bytes();
+
+    let mut addr: sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = AF_UNIX as _;
+    // The leading zero byte of sun_path places the name in the abstract namespace
+    if name.is_empty() || name.len() >= addr.sun_path.len() {
+        return Err(Error::ApiSocket(std::io::ErrorKind::InvalidInput.into()));
+    }
+    for (dst, src) in addr.sun_path[1..].iter_mut().zip(name) {
+        *dst = *src as _;
+    }
+    let addr_len = std::mem::size_of::<sa_family_t>() + 1 + name.len();
+
+    let fd = match unsafe { socket(AF_UNIX, SOCK_STREAM | SOCK_CLOEXEC, 0) } {
+        -1 => return Err(Error::ApiSocket(std::io::Error::last_os_error())),
+        fd => fd,
+    };
+
+    if unsafe { bind(fd, &addr as *const sockaddr_un as _, addr_len as _) } == -1
+        || unsafe { listen(fd, 128) } == -1
+    {
+        let err = std::io::Error::last_os_error();
+        unsafe { close(fd) };
+        return Err(Error::ApiSocket(err));
+    }
+
+    Ok(unsafe { UnixListener::from_raw_fd(fd) })
+}
+
+// Bind a unix socket to path with the given file mode. The umask is set for the duration of the
+// bind, so the socket is never accessible more widely, not even briefly. The socket is then given
+// to owner, if set.
+fn bind_path(path: &str, mode: u32, owner: Option<(uid_t, gid_t)>) -> Result<UnixListener, Error> {
+    let old_mask = unsafe { umask((!mode & 0o777) as mode_t) };
+    let api_listener = UnixListener::bind(path);
+    unsafe { umask(old_mask) };
+    let api_listener = api_listener.map_err(Error::ApiSocket)?;
+
+    if let Some((uid, gid)) = owner {
+        let c_path = std::ffi::CString::new(path).unwrap();
+        if unsafe { chown(c_path.as_ptr(), uid, gid) } == -1 {
+            return Err(Error::ApiSocket(std::io::Error::last_os_error()));
+        }
+    }
+
+    Ok(api_listener)
+}
+
+// The uid and gid of the process at the other end of a UAPI connection
+#[cfg(target_os = "linux")]
+fn peer_credentials(conn: &UnixStream) -> std::io::Result<(uid_t, gid_t)> {
+    let mut cred = ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<ucred>() as socklen_t;
+    match unsafe {
+        getsockopt(
+            conn.as_raw_fd(),
+            SOL_SOCKET,
+            SO_PEERCRED,
+            &mut cred as *mut ucred as *mut c_void,
+            &mut len,
+        )
+    } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok((cred.uid, cred.gid)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_credentials(conn: &UnixStream) -> std::io::Result<(uid_t, gid_t)> {
+    let (mut uid, mut gid) = (0, 0);
+    match unsafe { getpeereid(conn.as_raw_fd(), &mut uid, &mut gid) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok((uid, gid)),
+    }
+}
+
+// Whether a client with these credentials may use the UAPI: any client when no uid and no gid
+// are allowed, otherwise one whose uid or gid is allowed
+fn credentials_allowed((uid, gid): (uid_t, gid_t), uids: &[uid_t], gids: &[gid_t]) -> bool {
+    (uids.is_empty() && gids.is_empty()) || uids.contains(&uid) || gids.contains(&gid)
+}
+
+// Check the credentials of a UAPI client against DeviceConfig::api_allowed_uids and gids, before
+// it can send a command
+fn client_allowed<T: Tun, S: Sock>(conn: &UnixStream, d: &Device<T, S>) -> bool {
+    let (uids, gids) = (&d.config.api_allowed_uids, &d.config.api_allowed_gids);
+    if uids.is_empty() && gids.is_empty() {
+        return true;
+    }
+    match peer_credentials(conn) {
+        Ok(cred) if credentials_allowed(cred, uids, gids) => true,
+        Ok((uid, gid)) => {
+            warn!(d.config.logger, "Refused a UAPI connection"; "uid" => uid, "gid" => gid);
+            false
+        }
+        Err(e) => {
+            warn!(
+                d.config.logger,
+                "Refused a UAPI connection, no credentials: {}", e
+            );
+            false
+        }
+    }
+}
+
+// Compare the presented token with the expected one, in constant time
+#[cfg(feature = "tcp-api")]
+fn token_matches(presented: &str, expected: &str) -> bool {
+    let (presented, expected) = (presented.as_bytes(), expected.as_bytes());
+    if presented.len() != expected.len() {
+        return false;
+    }
+    presented
+        .iter()
+        .zip(expected)
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+// A stream connection to the UAPI, with a timeout for reads
+trait ApiConn {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl ApiConn for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(feature = "tcp-api")]
+impl ApiConn for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+// Reads a request from a UAPI connection. Reads fail with TimedOut once the client sent nothing
+// for the idle timeout, or the whole request took longer than the request timeout, so a client
+// that stalls can not hold on to the worker thread serving it.
+struct ApiReader<'a, C> {
+    conn: &'a C,
+    idle: Duration,
+    deadline: Instant,
+}
+
+impl<'a, C: ApiConn> ApiReader<'a, C>
+where
+    &'a C: Read,
+{
+    fn new<T: Tun, S: Sock>(conn: &'a C, d: &Device<T, S>) -> ApiReader<'a, C> {
+        ApiReader {
+            conn,
+            idle: d.config.api_idle_timeout,
+            deadline: Instant::now() + d.config.api_request_timeout,
+        }
+    }
+}
+
+impl<'a, C: ApiConn> Read for ApiReader<'a, C>
+where
+    &'a C: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::ZERO {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.conn.set_read_timeout(Some(remaining.min(self.idle)))?;
+        let mut conn = self.conn;
+        match conn.read(buf) {
+            // The socket reports an expired read timeout as EAGAIN
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                Err(std::io::ErrorKind::TimedOut.into())
+            }
+            res => res,
+        }
+    }
+}
+
+// The errno to answer a failed read of the request with
+fn read_errno(err: &std::io::Error) -> i32 {
+    match err.kind() {
+        std::io::ErrorKind::TimedOut => ETIMEDOUT,
+        _ => EIO,
+    }
+}
+
+// Serve a single UAPI request: the command line, followed by the get or set exchange
+fn api_exec<R: BufRead, W: Write, T: Tun, S: Sock>(
+    reader: &mut R,
+    writer: &mut W,
+    d: &mut LockReadGuard<Device<T, S>>,
+) {
+    let mut cmd = String::new();
+    let max_size = d.config.api_max_request_size;
+    let status = match reader.by_ref().take(max_size).read_line(&mut cmd) {
+        Ok(_) => {
+            cmd.pop(); // pop the new line character
+            match cmd.as_ref() {
+                // Only two commands are legal according to the protocol, get=1 and set=1.
+                "get=1" => api_get(writer, d),
+                "set=1" => api_set(reader, d),
+                "get_trace=1" => api_get_trace(writer, d),
+                _ => EIO,
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => ETIMEDOUT,
+        Err(_) => return,
+    };
+    // The protocol requires to return an error code as the response, or zero on success
+    writeln!(writer, "errno={}\n", status).ok();
+}
+
 impl<T: Tun, S: Sock> Device<T, S> {
-    /// Register the api handler for this Device. The api handler receives stream connections on a Unix socket
-    /// with a known path: /var/run/wireguard/{tun_name}.sock.
+    /// Register the api handler for this Device. By default the api handler receives stream
+    /// connections on a Unix socket with a known path: /var/run/wireguard/{tun_name}.sock.
+    /// The transport can be changed with DeviceConfig::api_socket.
     pub fn register_api_handler(&mut self) -> Result<(), Error> {
-        let path = format!("{}/{}.sock", SOCK_DIR, self.iface.name()?);
+        match self.config.api_socket.clone() {
+            ApiSocket::Path => {
+                let path = format!("{}/{}.sock", SOCK_DIR, self.iface_name()?);
 
-        create_sock_dir();
+                create_sock_dir();
 
-        let _ = remove_file(&path); // Attempt to remove the socket if already exists
+                let _ = remove_file(&path); // Attempt to remove the socket if already exists
 
-        let api_listener = UnixListener::bind(&path).map_err(Error::ApiSocket)?; // Bind a new socket to the path
+                // Bind a new socket to the path
+                let api_listener = bind_path(
+                    &path,
+                    self.config.api_socket_mode,
+                    self.config.api_socket_owner,
+                )?;
 
-        self.cleanup_paths.push(path.clone());
+                self.cleanup_paths.push(path.clone());
 
+                self.register_unix_api_listener(api_listener)?;
+                self.register_monitor(Some(path))?;
+            }
+            #[cfg(target_os = "linux")]
+            ApiSocket::Abstract(name) => {
+                self.register_unix_api_listener(bind_abstract(&name)?)?;
+                // There is no file to watch for an abstract socket
+                self.register_monitor(None)?;
+            }
+            #[cfg(feature = "tcp-api")]
+            ApiSocket::Tcp { port, token } => {
+                if token.len() < MIN_API_TOKEN_LEN {
+                    return Err(Error::InvalidConfig(format!(
+                        "The API token must be at least {} bytes long",
+                        MIN_API_TOKEN_LEN
+                    )));
+                }
+                let api_listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, port))
+                    .map_err(Error::ApiSocket)?;
+
+                self.register_tcp_api_listener(api_listener, token)?;
+                self.register_monitor(None)?;
+            }
+        }
+
+        self.register_api_signal_handlers()
+    }
+
+    fn register_unix_api_listener(&mut self, api_listener: UnixListener) -> Result<(), Error> {
+        self.api_fd = Some(api_listener.as_raw_fd());
         self.queue.new_event(
             api_listener.as_raw_fd(),
             Box::new(move |d, _| {
@@ -55,29 +345,70 @@ impl<T: Tun, S: Sock> Device<T, S> {
                     _ => return Action::Continue,
                 };
 
-                let mut reader = BufReader::new(&api_conn);
-                let mut writer = BufWriter::new(&api_conn);
-                let mut cmd = String::new();
-                if reader.read_line(&mut cmd).is_ok() {
-                    cmd.pop(); // pop the new line character
-                    let status = match cmd.as_ref() {
-                        // Only two commands are legal according to the protocol, get=1 and set=1.
-                        "get=1" => api_get(&mut writer, d),
-                        "set=1" => api_set(&mut reader, d),
-                        _ => EIO,
-                    };
-                    // The protocol requires to return an error code as the response, or zero on success
-                    writeln!(writer, "errno={}\n", status).ok();
+                if !client_allowed(&api_conn, d) {
+                    writeln!(&api_conn, "errno={}\n", EACCES).ok();
+                    return Action::Continue;
                 }
+
+                let mut reader = BufReader::new(ApiReader::new(&api_conn, d));
+                let mut writer = BufWriter::new(&api_conn);
+                api_exec(&mut reader, &mut writer, d);
                 Action::Continue // Indicates the worker thread should continue as normal
             }),
         )?;
 
-        self.register_monitor(path)?;
-        self.register_api_signal_handlers()
+        Ok(())
+    }
+
+    #[cfg(feature = "tcp-api")]
+    fn register_tcp_api_listener(
+        &mut self,
+        api_listener: TcpListener,
+        token: String,
+    ) -> Result<(), Error> {
+        self.api_fd = Some(api_listener.as_raw_fd());
+        self.queue.new_event(
+            api_listener.as_raw_fd(),
+            Box::new(move |d, _| {
+                // This is the closure that listens on the api tcp socket
+                let (api_conn, _) = match api_listener.accept() {
+                    Ok(conn) => conn,
+                    _ => return Action::Continue,
+                };
+
+                let mut reader = BufReader::new(ApiReader::new(&api_conn, d));
+                let mut writer = BufWriter::new(&api_conn);
+
+                // The first line must carry the preshared token, otherwise the connection is dropped
+                let mut auth = String::new();
+                let max_size = d.config.api_max_request_size;
+                if let Err(e) = reader.by_ref().take(max_size).read_line(&mut auth) {
+                    if e.kind() == std::io::ErrorKind::TimedOut {
+                        writeln!(writer, "errno={}\n", ETIMEDOUT).ok();
+                    }
+                    return Action::Continue;
+                }
+                match auth
+                    .trim_end_matches('\n')
+                    .splitn(2, '=')
+                    .collect::<Vec<_>>()[..]
+                {
+                    ["token", presented] if token_matches(presented, &token) => {}
+                    _ => {
+                        writeln!(writer, "errno={}\n", EACCES).ok();
+                        return Action::Continue;
+                    }
+                }
+
+                api_exec(&mut reader, &mut writer, d);
+                Action::Continue
+            }),
+        )?;
+
+        Ok(())
     }
 
-    fn register_monitor(&self, path: String) -> Result<(), Error> {
+    fn register_monitor(&self, path: Option<String>) -> Result<(), Error> {
         self.queue.new_periodic_event(
             Box::new(move |d, _| {
                 // This is not a very nice hack to detect if the control socket was removed
@@ -87,14 +418,15 @@ impl<T: Tun, S: Sock> Device<T, S> {
                 // deletion, and kqueue EVFILT_VNODE can be used for the same purpose, but that
                 // will require introducing new events, for no measurable benefit.
                 // TODO: Could this be an issue if we restart the service too quickly?
-                let path = std::path::Path::new(&path);
-                if !path.exists() {
-                    d.trigger_exit();
-                    return Action::Exit;
+                if let Some(path) = &path {
+                    if !std::path::Path::new(path).exists() {
+                        d.trigger_exit();
+                        return Action::Exit;
+                    }
                 }
 
                 // Periodically read the mtu of the interface in case it changes
-                if let Ok(mtu) = d.iface.mtu() {
+                if let Some(Ok(mtu)) = d.iface.as_ref().map(|iface| iface.mtu()) {
                     d.mtu.store(mtu, Ordering::Relaxed);
                 }
 
@@ -106,6 +438,122 @@ impl<T: Tun, S: Sock> Device<T, S> {
         Ok(())
     }
 
+    /// Check a configuration in the format of a set command, without applying any of it. The
+    /// set=1 line is optional, and the configuration ends at the first empty line. Besides the
+    /// checks of a set command, the changes are checked against the current peers of the device.
+    pub fn validate_config(&self, cfg: &str) -> Result<(), ConfigError> {
+        self.check_config(cfg, false)
+    }
+
+    /// Like `validate_config`, but also rejects an allowed IP that overlaps one of another peer,
+    /// which a set command accepts with the longest prefix taking precedence
+    pub fn validate_config_strict(&self, cfg: &str) -> Result<(), ConfigError> {
+        self.check_config(cfg, true)
+    }
+
+    fn check_config(&self, cfg: &str, strict: bool) -> Result<(), ConfigError> {
+        let mut lines: Vec<String> = cfg.lines().map(str::to_owned).collect();
+        let offset = match lines.first() {
+            Some(first) if first == "set=1" => {
+                lines.remove(0);
+                1
+            }
+            _ => 0,
+        };
+        if let Some(end) = lines.iter().position(|line| line.is_empty()) {
+            lines.truncate(end);
+        }
+
+        let settings = parse_set_block(&lines).map_err(|e| ConfigError {
+            line: e.line + offset,
+            ..e
+        })?;
+        let error = |line: usize, key: &str, kind| ConfigError {
+            line: line + offset + 1,
+            key: key.to_owned(),
+            kind,
+        };
+
+        // The allowed IPs of every peer the device would have, as the settings are applied
+        let mut peers: HashMap<String, Vec<AllowedIP>> = self
+            .peers
+            .iter()
+            .map(|(key, peer)| {
+                let ips = peer
+                    .allowed_ips()
+                    .map(|(_, addr, cidr)| AllowedIP {
+                        addr,
+                        cidr: cidr as u8,
+                    })
+                    .collect();
+                (key.to_hex(), ips)
+            })
+            .collect();
+        let mut has_key = self.key_pair.is_some();
+
+        for setting in settings {
+            let peer = match setting {
+                Setting::PrivateKey(_) => {
+                    has_key = true;
+                    continue;
+                }
+                Setting::ReplacePeers => {
+                    peers.clear();
+                    continue;
+                }
+                Setting::Peer(peer) => peer,
+                _ => continue,
+            };
+
+            let key = peer.pub_key.to_hex();
+            if peer.remove {
+                peers.remove(&key);
+                continue;
+            }
+            if peers.contains_key(&key) {
+                if peer.only_sets_runtime_options() {
+                    continue;
+                }
+                return Err(error(peer.line, "public_key", ConfigErrorKind::PeerExists));
+            }
+            if !has_key {
+                return Err(error(
+                    peer.line,
+                    "public_key",
+                    ConfigErrorKind::MissingPrivateKey,
+                ));
+            }
+            if self.config.max_peers > 0 && peers.len() >= self.config.max_peers {
+                return Err(error(
+                    peer.line,
+                    "public_key",
+                    ConfigErrorKind::TooManyPeers,
+                ));
+            }
+
+            if strict {
+                for (ip, &line) in peer.allowed_ips.iter().zip(&peer.allowed_ip_lines) {
+                    for (other, ips) in &peers {
+                        if let Some(overlap) = ips.iter().find(|other_ip| overlaps(ip, other_ip)) {
+                            return Err(error(
+                                line,
+                                "allowed_ip",
+                                ConfigErrorKind::OverlappingAllowedIp {
+                                    peer: other.clone(),
+                                    allowed_ip: format!("{}/{}", overlap.addr, overlap.cidr),
+                                },
+                            ));
+                        }
+                    }
+                }
+            }
+
+            peers.insert(key, peer.allowed_ips);
+        }
+
+        Ok(())
+    }
+
     fn register_api_signal_handlers(&self) -> Result<(), Error> {
         self.queue
             .new_signal_event(SIGINT, Box::new(move |_, _| Action::Exit))?;
@@ -118,10 +566,10 @@ impl<T: Tun, S: Sock> Device<T, S> {
 }
 
 #[allow(unused_must_use)]
-fn api_get<T: Tun, S: Sock>(writer: &mut BufWriter<&UnixStream>, d: &Device<T, S>) -> i32 {
+fn api_get<W: Write, T: Tun, S: Sock>(writer: &mut W, d: &Device<T, S>) -> i32 {
     // get command requires an empty line, but there is no reason to be religious about it
     if let Some(ref k) = d.key_pair {
-        writeln!(writer, "private_key={}", encode_hex(k.0.as_bytes()));
+        writeln!(writer, "private_key={}", &*k.0.to_hex());
     }
 
     if d.listen_port != 0 {
@@ -132,8 +580,63 @@ fn api_get<T: Tun, S: Sock>(writer: &mut BufWriter<&UnixStream>, d: &Device<T, S
         writeln!(writer, "fwmark={}", fwmark);
     }
 
-    for (k, p) in d.peers.iter() {
-        writeln!(writer, "public_key={}", encode_hex(k.as_bytes()));
+    if let Some(rate) = d.pacing_rate {
+        writeln!(writer, "pacing_rate={}", rate);
+    }
+
+    if let Some(prio) = d.priority {
+        writeln!(writer, "priority={}", prio);
+    }
+
+    if let Some(ttl) = d.ttl {
+        writeln!(writer, "ttl={}", ttl);
+    }
+
+    if d.detailed_accounting {
+        writeln!(writer, "accounting=detailed");
+    }
+
+    if let Some(name) = d.mirror_tun() {
+        writeln!(writer, "mirror_tun={}", name);
+        writeln!(writer, "mirror_drops={}", d.mirror_drops());
+    }
+
+    if d.validate_inner {
+        writeln!(writer, "validate_inner=strict");
+        writeln!(writer, "invalid_inner_drops={}", d.invalid_inner_drops());
+    }
+
+    if let Some(df) = d.dont_fragment {
+        writeln!(writer, "df={}", if df { "on" } else { "off" });
+    }
+
+    if d.trace_buffer != 0 {
+        writeln!(writer, "trace_buffer={}", d.trace_buffer);
+    }
+
+    if d.prewarm {
+        writeln!(writer, "prewarm=on");
+    }
+
+    if d.freebind {
+        writeln!(writer, "freebind=on");
+    }
+
+    if d.config.drop_unknown_indices {
+        writeln!(writer, "unknown_index_drops={}", d.unknown_index_drops());
+    }
+
+    let rate_limit = d.rate_limit_stats();
+    if rate_limit.cookie_replies > 0 {
+        writeln!(writer, "cookie_replies={}", rate_limit.cookie_replies);
+    }
+    if rate_limit.invalid_mac > 0 {
+        writeln!(writer, "invalid_mac_drops={}", rate_limit.invalid_mac);
+    }
+
+    for (k, p) in d.peers_by_id() {
+        writeln!(writer, "public_key={}", k.to_hex());
+        writeln!(writer, "peer_id={}", p.peer_id());
 
         if let Some(ref key) = p.preshared_key() {
             writeln!(writer, "preshared_key={}", encode_hex(key));
@@ -143,10 +646,46 @@ fn api_get<T: Tun, S: Sock>(writer: &mut BufWriter<&UnixStream>, d: &Device<T, S
             writeln!(writer, "persistent_keepalive_interval={}", keepalive);
         }
 
+        if p.route_metric() != 0 {
+            writeln!(writer, "route_metric={}", p.route_metric());
+        }
+
+        if let Some(addr) = p.bind_addr() {
+            writeln!(writer, "peer_bind_addr={}", addr);
+        }
+
+        if p.tunnel.is_responder_only() {
+            writeln!(writer, "responder_only=true");
+        }
+
+        if let Some(timeout) = p.tunnel.idle_timeout() {
+            writeln!(writer, "idle_timeout={}", timeout.as_secs());
+        }
+
+        if !p.is_enabled() {
+            writeln!(writer, "enabled=false");
+        }
+
         if let Some(ref addr) = p.endpoint().addr {
             writeln!(writer, "endpoint={}", addr);
         }
 
+        if let Some(host) = p.endpoint_host() {
+            writeln!(writer, "endpoint_host={}", host);
+        }
+
+        if let Some(addr) = p.backup_endpoint() {
+            writeln!(writer, "peer_backup_endpoint={}", addr);
+        }
+
+        if let Some(metadata) = p.metadata() {
+            writeln!(writer, "peer_metadata={}", metadata);
+        }
+
+        for (addr, weight) in p.ecmp_endpoints() {
+            writeln!(writer, "ecmp_endpoint={}/{}", addr, weight);
+        }
+
         for (_, ip, cidr) in p.allowed_ips() {
             writeln!(writer, "allowed_ip={}/{}", ip, cidr);
         }
@@ -160,67 +699,602 @@ fn api_get<T: Tun, S: Sock>(writer: &mut BufWriter<&UnixStream>, d: &Device<T, S
 
         writeln!(writer, "rx_bytes={}", rx_bytes);
         writeln!(writer, "tx_bytes={}", tx_bytes);
+
+        if p.tunnel.rekeys() > 0 {
+            writeln!(writer, "rekeys={}", p.tunnel.rekeys());
+        }
+
+        let latency = p.tunnel.handshake_latency();
+        if latency.count() > 0 {
+            writeln!(writer, "handshake_latency_count={}", latency.count());
+            writeln!(
+                writer,
+                "handshake_latency_sum_us={}",
+                latency.sum().as_micros()
+            );
+            for (bound, count) in latency.buckets() {
+                match bound {
+                    Some(bound) => writeln!(
+                        writer,
+                        "handshake_latency_bucket={}/{}",
+                        bound.as_millis(),
+                        count
+                    ),
+                    None => writeln!(writer, "handshake_latency_bucket=inf/{}", count),
+                };
+            }
+        }
+
+        if p.no_endpoint_drops() > 0 {
+            writeln!(writer, "no_endpoint_drops={}", p.no_endpoint_drops());
+        }
+
+        if p.liveness().is_enabled() {
+            writeln!(writer, "liveness_probe=on");
+        }
+
+        if let Some(reachable) = p.liveness().reachable() {
+            writeln!(writer, "reachable={}", reachable);
+        }
+    }
+    0
+}
+
+#[allow(unused_must_use)]
+fn api_get_trace<W: Write, T: Tun, S: Sock>(writer: &mut W, d: &Device<T, S>) -> i32 {
+    for (k, p) in d.peers_by_id() {
+        writeln!(writer, "public_key={}", k.to_hex());
+        for event in p.trace() {
+            writeln!(
+                writer,
+                "trace={}.{:06},{},{},{},{}",
+                event.time.as_secs(),
+                event.time.subsec_micros(),
+                event.direction.as_str(),
+                event.kind.as_str(),
+                event.len,
+                event.outcome.as_str()
+            );
+        }
     }
     0
 }
 
-fn api_set<'a, T: Tun, S: Sock>(
-    reader: &mut BufReader<&UnixStream>,
+/// The first problem found in a configuration by `Device::validate_config`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// The line of the offending key, starting from 1
+    pub line: usize,
+    pub key: String,
+    pub kind: ConfigErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigErrorKind {
+    /// The line is not a key=value pair
+    Malformed,
+    /// The key is unknown, or its value is invalid
+    InvalidValue,
+    /// Peers are added, but the device has no private key
+    MissingPrivateKey,
+    /// The peer already exists, or is defined twice, and peers can not be modified
+    PeerExists,
+    /// Adding the peer would exceed the `max_peers` of the device
+    TooManyPeers,
+    /// Strict mode only: the allowed IP overlaps one of another peer
+    OverlappingAllowedIp { peer: String, allowed_ip: String },
+}
+
+impl ConfigError {
+    // The errno a set command answers with for this error
+    fn errno(&self) -> i32 {
+        match self.kind {
+            ConfigErrorKind::Malformed => EPROTO,
+            ConfigErrorKind::TooManyPeers => ENOSPC,
+            _ => EINVAL,
+        }
+    }
+}
+
+// A single validated change requested by a set command
+enum Setting {
+    PrivateKey(X25519SecretKey),
+    ListenPort(u16),
+    Fwmark(u32),
+    PacingRate(u64),
+    DontFragment(bool),
+    Priority(u32),
+    Ttl(u32),
+    DetailedAccounting(bool),
+    ValidateInner(bool),
+    MirrorTun(Option<String>),
+    TraceBuffer(usize),
+    Prewarm(bool),
+    Freebind(bool),
+    Address(AllowedIP),
+    ReplacePeers,
+    Peer(Box<PeerUpdate>),
+}
+
+// The accumulated changes for a single peer section
+struct PeerUpdate {
+    pub_key: X25519PublicKey,
+    line: usize,                  // The index of the public_key line in the block
+    allowed_ip_lines: Vec<usize>, // The index of the line of each allowed_ip
+    remove: bool,
+    replace_ips: bool,
+    endpoint: Option<SocketAddr>,
+    endpoint_host: Option<String>, // The endpoint by name, resolved once the peer is added
+    keepalive: Option<u16>,
+    preshared_key: Option<[u8; 32]>,
+    allowed_ips: Vec<AllowedIP>,
+    route_metric: u32,
+    bind_addr: Option<IpAddr>,
+    responder_only: bool,
+    enabled: Option<bool>,
+    idle_timeout: Option<u64>, // 0 disables the idle timeout
+    ecmp_endpoints: Vec<(SocketAddr, u32)>,
+    backup_endpoint: Option<SocketAddr>,
+    metadata: Option<String>, // Empty removes the metadata
+    liveness_probe: Option<bool>,
+}
+
+impl Setting {
+    // Settings of the device that fail when the host refuses them, such as a port in use
+    fn may_fail(&self) -> bool {
+        matches!(
+            self,
+            Setting::ListenPort(_)
+                | Setting::Fwmark(_)
+                | Setting::PacingRate(_)
+                | Setting::DontFragment(_)
+                | Setting::Priority(_)
+                | Setting::Ttl(_)
+                | Setting::MirrorTun(_)
+                | Setting::Address(_)
+        )
+    }
+}
+
+// Would the peers a block adds take the device beyond max_peers, once those it removes are gone
+fn exceeds_peer_limit<T: Tun, S: Sock>(device: &Device<T, S>, settings: &[Setting]) -> bool {
+    let max = device.config.max_peers;
+    if max == 0 {
+        return false;
+    }
+    let mut keys: std::collections::HashSet<&[u8]> =
+        device.peers.keys().map(|key| key.as_bytes()).collect();
+    for setting in settings {
+        match setting {
+            Setting::ReplacePeers => keys.clear(),
+            Setting::Peer(peer) if peer.remove => {
+                keys.remove(peer.pub_key.as_bytes());
+            }
+            Setting::Peer(peer) => {
+                if !keys.contains(peer.pub_key.as_bytes()) && keys.len() >= max {
+                    return true;
+                }
+                keys.insert(peer.pub_key.as_bytes());
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+impl PeerUpdate {
+    fn new(pub_key: X25519PublicKey, line: usize) -> PeerUpdate {
+        PeerUpdate {
+            pub_key,
+            line,
+            allowed_ip_lines: vec![],
+            remove: false,
+            replace_ips: false,
+            endpoint: None,
+            endpoint_host: None,
+            keepalive: None,
+            preshared_key: None,
+            allowed_ips: vec![],
+            route_metric: 0,
+            bind_addr: None,
+            responder_only: false,
+            enabled: None,
+            idle_timeout: None,
+            ecmp_endpoints: vec![],
+            backup_endpoint: None,
+            metadata: None,
+            liveness_probe: None,
+        }
+    }
+
+    // Only pauses or resumes the peer or changes its idle timeout, which leaves an existing peer
+    // untouched otherwise
+    fn only_sets_runtime_options(&self) -> bool {
+        (self.enabled.is_some()
+            || self.idle_timeout.is_some()
+            || self.metadata.is_some()
+            || self.liveness_probe.is_some())
+            && !self.remove
+            && !self.replace_ips
+            && self.endpoint.is_none()
+            && self.endpoint_host.is_none()
+            && self.keepalive.is_none()
+            && self.preshared_key.is_none()
+            && self.allowed_ips.is_empty()
+            && self.route_metric == 0
+            && self.bind_addr.is_none()
+            && !self.responder_only
+            && self.ecmp_endpoints.is_empty()
+            && self.backup_endpoint.is_none()
+    }
+}
+
+// Two prefixes overlap when they agree on the bits of the shorter one
+fn overlaps(a: &AllowedIP, b: &AllowedIP) -> bool {
+    let cidr = u32::from(a.cidr.min(b.cidr));
+    match (a.addr, b.addr) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let mask = u32::MAX
+                .checked_shl(32u32.saturating_sub(cidr))
+                .unwrap_or(0);
+            (u32::from(a) ^ u32::from(b)) & mask == 0
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let mask = u128::MAX
+                .checked_shl(128u32.saturating_sub(cidr))
+                .unwrap_or(0);
+            (u128::from(a) ^ u128::from(b)) & mask == 0
+        }
+        _ => false,
+    }
+}
+
+// Read the lines of a set command block, up to the terminating empty line or EOF. The block is
+// limited to max_size bytes, so a client can't exhaust our memory.
+fn read_set_block<R: BufRead>(reader: &mut R, max_size: u64) -> Result<Vec<String>, i32> {
+    let mut lines = vec![];
+    let mut budget = max_size;
+
+    loop {
+        let mut cmd = String::new();
+        let n = match reader.by_ref().take(budget).read_line(&mut cmd) {
+            Ok(0) => return Ok(lines), // EOF
+            Ok(n) => n,
+            Err(e) => return Err(read_errno(&e)),
+        };
+        budget -= n as u64;
+
+        if cmd.ends_with('\n') {
+            cmd.pop(); // remove newline
+        } else if budget == 0 {
+            // The block did not fit in the buffer
+            return Err(E2BIG);
+        }
+
+        if cmd.is_empty() {
+            return Ok(lines); // Done
+        }
+
+        lines.push(cmd);
+    }
+}
+
+// Check a set command block as the device would, for a client about to send it
+pub(super) fn check_set_block(lines: &[String]) -> Result<(), ConfigError> {
+    parse_set_block(lines).map(|_| ())
+}
+
+// Validate every line of a set command block, without applying any of them
+fn parse_set_block(lines: &[String]) -> Result<Vec<Setting>, ConfigError> {
+    let mut settings = vec![];
+    let mut peer: Option<PeerUpdate> = None;
+
+    for (n, cmd) in lines.iter().enumerate() {
+        parse_set_line(n, cmd, &mut settings, &mut peer).map_err(|errno| ConfigError {
+            line: n + 1,
+            key: cmd.split('=').next().unwrap_or_default().to_owned(),
+            kind: match errno {
+                EPROTO => ConfigErrorKind::Malformed,
+                _ => ConfigErrorKind::InvalidValue,
+            },
+        })?;
+    }
+
+    if let Some(peer) = peer {
+        settings.push(Setting::Peer(Box::new(peer)));
+    }
+
+    Ok(settings)
+}
+
+// Parse line n of a set command block, into a new setting or the current peer section
+fn parse_set_line(
+    n: usize,
+    cmd: &str,
+    settings: &mut Vec<Setting>,
+    peer: &mut Option<PeerUpdate>,
+) -> Result<(), i32> {
+    let parsed_cmd: Vec<&str> = cmd.splitn(2, '=').collect();
+    if parsed_cmd.len() != 2 {
+        return Err(EPROTO);
+    }
+
+    let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
+
+    if key == "public_key" {
+        // Indicates a new peer section. Commit changes for current peer, and continue to next peer
+        let key = X25519PublicKey::from_hex(val).map_err(|_| EINVAL)?;
+        if let Some(peer) = peer.replace(PeerUpdate::new(key, n)) {
+            settings.push(Setting::Peer(Box::new(peer)));
+        }
+        return Ok(());
+    }
+
+    match peer {
+        None => settings.push(match key {
+            "private_key" => {
+                Setting::PrivateKey(X25519SecretKey::from_hex(val).map_err(|_| EINVAL)?)
+            }
+            "listen_port" => Setting::ListenPort(val.parse().map_err(|_| EINVAL)?),
+            "fwmark" => Setting::Fwmark(val.parse().map_err(|_| EINVAL)?),
+            "pacing_rate" => Setting::PacingRate(val.parse().map_err(|_| EINVAL)?),
+            "priority" => Setting::Priority(val.parse().map_err(|_| EINVAL)?),
+            "ttl" => match val.parse::<u32>() {
+                Ok(ttl) if (1..=255).contains(&ttl) => Setting::Ttl(ttl),
+                _ => return Err(EINVAL),
+            },
+            "accounting" => match val {
+                "detailed" => Setting::DetailedAccounting(true),
+                "basic" => Setting::DetailedAccounting(false),
+                _ => return Err(EINVAL),
+            },
+            "validate_inner" => match val {
+                "strict" => Setting::ValidateInner(true),
+                "off" => Setting::ValidateInner(false),
+                _ => return Err(EINVAL),
+            },
+            "mirror_tun" => match val {
+                "" => Setting::MirrorTun(None),
+                name if name.len() < IFNAMSIZ => Setting::MirrorTun(Some(name.to_owned())),
+                _ => return Err(EINVAL),
+            },
+            "trace_buffer" => match val.parse::<usize>() {
+                Ok(n) if n <= MAX_TRACE_EVENTS => Setting::TraceBuffer(n),
+                _ => return Err(EINVAL),
+            },
+            "prewarm" => match val {
+                "on" => Setting::Prewarm(true),
+                "off" => Setting::Prewarm(false),
+                _ => return Err(EINVAL),
+            },
+            "freebind" => match val {
+                "on" => Setting::Freebind(true),
+                "off" => Setting::Freebind(false),
+                _ => return Err(EINVAL),
+            },
+            "df" => match val {
+                "on" => Setting::DontFragment(true),
+                "off" => Setting::DontFragment(false),
+                _ => return Err(EINVAL),
+            },
+            "address" => Setting::Address(val.parse().map_err(|_| EINVAL)?),
+            "replace_peers" => match val.parse::<bool>() {
+                Ok(true) => Setting::ReplacePeers,
+                Ok(false) => return Ok(()),
+                Err(_) => return Err(EINVAL),
+            },
+            _ => return Err(EINVAL),
+        }),
+        Some(ref mut peer) => match key {
+            "remove" => peer.remove = val.parse().map_err(|_| EINVAL)?,
+            "preshared_key" => match X25519PublicKey::from_hex(val) {
+                Ok(key) => peer.preshared_key = Some(make_array(key.as_bytes())),
+                Err(_) => return Err(EINVAL),
+            },
+            "endpoint" => match val.parse() {
+                Ok(addr) => peer.endpoint = Some(addr),
+                Err(_) if is_valid_host_endpoint(val) => peer.endpoint_host = Some(val.to_owned()),
+                Err(_) => return Err(EINVAL),
+            },
+            "endpoint_host" if is_valid_host_endpoint(val) => {
+                peer.endpoint_host = Some(val.to_owned())
+            }
+            "ecmp_endpoint" => peer.ecmp_endpoints.push(parse_weighted_endpoint(val)?),
+            "peer_backup_endpoint" => peer.backup_endpoint = Some(val.parse().map_err(|_| EINVAL)?),
+            "peer_metadata" if is_valid_metadata(val) => peer.metadata = Some(val.to_owned()),
+            "persistent_keepalive_interval" => {
+                peer.keepalive = Some(val.parse().map_err(|_| EINVAL)?)
+            }
+            "replace_allowed_ips" => peer.replace_ips = val.parse().map_err(|_| EINVAL)?,
+            "allowed_ip" => {
+                peer.allowed_ips.push(val.parse().map_err(|_| EINVAL)?);
+                peer.allowed_ip_lines.push(n);
+            }
+            "route_metric" => peer.route_metric = val.parse().map_err(|_| EINVAL)?,
+            "peer_bind_addr" => peer.bind_addr = Some(val.parse().map_err(|_| EINVAL)?),
+            "responder_only" => peer.responder_only = val.parse().map_err(|_| EINVAL)?,
+            "enabled" => peer.enabled = Some(val.parse().map_err(|_| EINVAL)?),
+            "idle_timeout" => peer.idle_timeout = Some(val.parse().map_err(|_| EINVAL)?),
+            "liveness_probe" => match val {
+                "on" => peer.liveness_probe = Some(true),
+                "off" => peer.liveness_probe = Some(false),
+                _ => return Err(EINVAL),
+            },
+            "protocol_version" => match val.parse::<u32>() {
+                Ok(1) => {} // Only version 1 is legal
+                _ => return Err(EINVAL),
+            },
+            _ => return Err(EINVAL),
+        },
+    }
+
+    Ok(())
+}
+
+// Parse an endpoint with an optional weight, as in 192.0.2.1:51820/3
+fn parse_weighted_endpoint(val: &str) -> Result<(SocketAddr, u32), i32> {
+    let (addr, weight) = match val.rsplit_once('/') {
+        Some((addr, weight)) => (addr, weight.parse().map_err(|_| EINVAL)?),
+        None => (val, 1),
+    };
+    match addr.parse() {
+        Ok(addr) if (1..=MAX_WEIGHT).contains(&weight) => Ok((addr, weight)),
+        _ => Err(EINVAL),
+    }
+}
+
+fn api_set<R: BufRead, T: Tun, S: Sock>(
+    reader: &mut R,
     d: &mut LockReadGuard<Device<T, S>>,
 ) -> i32 {
+    // The whole block is validated before the device is touched, so a malformed line
+    // never leaves the device half configured. Of a valid block, the settings the host may
+    // refuse are applied first, in the order of the block, and the peer limit is checked: when
+    // one fails the private key, the peers and the other settings are left as they were, only
+    // the settings that may fail applied before it are kept.
+    let max_size = d.config.api_max_request_size;
+    let settings = match read_set_block(reader, max_size)
+        .and_then(|lines| parse_set_block(&lines).map_err(|e| e.errno()))
+    {
+        Ok(settings) => settings,
+        Err(errno) => return errno,
+    };
+
     d.try_writeable(
         |device| device.trigger_yield(),
         |device| {
             device.cancel_yield();
 
-            let mut cmd = String::new();
+            let (mut ordered, rest): (Vec<_>, Vec<_>) =
+                settings.into_iter().partition(Setting::may_fail);
+            if exceeds_peer_limit(device, &rest) {
+                return ENOSPC;
+            }
+            ordered.extend(rest);
 
-            while let Ok(_) = reader.read_line(&mut cmd) {
-                cmd.pop(); // remove newline if any
-                if cmd.is_empty() {
-                    return 0; // Done
-                }
-                {
-                    let parsed_cmd: Vec<&str> = cmd.split('=').collect();
-                    if parsed_cmd.len() != 2 {
-                        return EPROTO;
+            for setting in ordered {
+                match setting {
+                    Setting::PrivateKey(key) => device.set_key(key),
+                    Setting::ListenPort(port) => {
+                        if device.open_listen_socket(port).is_err() {
+                            return EADDRINUSE;
+                        }
                     }
-
-                    let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
-
-                    match key {
-                        "private_key" => match val.parse::<X25519SecretKey>() {
-                            Ok(key) => device.set_key(key),
-                            Err(_) => return EINVAL,
-                        },
-                        "listen_port" => match val.parse::<u16>() {
-                            Ok(port) => match device.open_listen_socket(port) {
-                                Ok(()) => {}
-                                Err(_) => return EADDRINUSE,
-                            },
-                            Err(_) => return EINVAL,
-                        },
-                        "fwmark" => match val.parse::<u32>() {
-                            Ok(mark) => match device.set_fwmark(mark) {
-                                Ok(()) => {}
-                                Err(_) => return EADDRINUSE,
-                            },
-                            Err(_) => return EINVAL,
-                        },
-                        "replace_peers" => match val.parse::<bool>() {
-                            Ok(true) => device.clear_peers(),
-                            Ok(false) => {}
-                            Err(_) => return EINVAL,
-                        },
-                        "public_key" => match val.parse::<X25519PublicKey>() {
-                            // Indicates a new peer section
-                            Ok(key) => return api_set_peer(reader, device, key),
-                            Err(_) => return EINVAL,
-                        },
-                        _ => return EINVAL,
+                    Setting::Fwmark(mark) => {
+                        if device.set_fwmark(mark).is_err() {
+                            return EADDRINUSE;
+                        }
+                    }
+                    Setting::PacingRate(rate) => {
+                        if let Err(e) = device.set_pacing_rate(rate) {
+                            error!(device.config.logger, "Failed to set pacing rate: {:?}", e);
+                            return ENOTSUP;
+                        }
+                    }
+                    Setting::DetailedAccounting(detailed) => device.detailed_accounting = detailed,
+                    Setting::ValidateInner(strict) => device.validate_inner = strict,
+                    Setting::MirrorTun(name) => {
+                        if let Err(e) = device.set_mirror_tun(name.as_deref()) {
+                            error!(device.config.logger, "Failed to open the mirror: {:?}", e);
+                            return ENODEV;
+                        }
+                    }
+                    Setting::TraceBuffer(capacity) => device.set_trace_buffer(capacity),
+                    Setting::Prewarm(prewarm) => device.prewarm = prewarm,
+                    Setting::Freebind(freebind) => device.freebind = freebind,
+                    Setting::Priority(prio) => {
+                        if let Err(e) = device.set_priority(prio) {
+                            error!(device.config.logger, "Failed to set priority: {:?}", e);
+                            return EPERM;
+                        }
+                    }
+                    Setting::Ttl(ttl) => {
+                        if let Err(e) = device.set_ttl(ttl) {
+                            error!(device.config.logger, "Failed to set the TTL: {:?}", e);
+                            return ENOTSUP;
+                        }
+                    }
+                    Setting::DontFragment(df) => {
+                        if let Err(e) = device.set_dont_fragment(df) {
+                            error!(device.config.logger, "Failed to set the DF bit: {:?}", e);
+                            return ENOTSUP;
+                        }
+                    }
+                    Setting::Address(addr) => {
+                        if let Err(e) = device.set_address(addr.addr, addr.cidr) {
+                            error!(device.config.logger, "Failed to set address: {:?}", e);
+                            return EPERM;
+                        }
+                    }
+                    Setting::ReplacePeers => device.clear_peers(),
+                    Setting::Peer(peer) => {
+                        let key = X25519PublicKey::from(peer.pub_key.as_bytes());
+                        let enabled = peer.enabled.filter(|_| !peer.remove);
+                        let idle_timeout = peer.idle_timeout.filter(|_| !peer.remove);
+                        let metadata = peer.metadata.clone().filter(|_| !peer.remove);
+                        let liveness_probe = peer.liveness_probe.filter(|_| !peer.remove);
+                        let endpoint_host = peer.endpoint_host.clone().filter(|_| !peer.remove);
+                        if !(peer.only_sets_runtime_options() && device.peers.contains_key(&key)) {
+                            if let Err(Error::TooManyPeers) = device.update_peer(
+                                peer.pub_key,
+                                peer.remove,
+                                peer.replace_ips,
+                                peer.endpoint,
+                                peer.allowed_ips,
+                                peer.keepalive,
+                                peer.preshared_key,
+                                peer.route_metric,
+                                peer.bind_addr,
+                                peer.responder_only,
+                            ) {
+                                return ENOSPC;
+                            }
+                        }
+                        if endpoint_host.is_some()
+                            && device.set_peer_endpoint_host(&key, endpoint_host).is_err()
+                        {
+                            return ENOENT;
+                        }
+                        if let Some(enabled) = enabled {
+                            if device.set_peer_enabled(&key, enabled).is_err() {
+                                return ENOENT;
+                            }
+                        }
+                        if let Some(metadata) = metadata {
+                            let metadata = Some(metadata).filter(|m| !m.is_empty());
+                            if device.set_peer_metadata(&key, metadata).is_err() {
+                                return ENOENT;
+                            }
+                        }
+                        if let Some(on) = liveness_probe {
+                            if device.set_peer_liveness_probe(&key, on).is_err() {
+                                return ENOENT;
+                            }
+                        }
+                        if let Some(secs) = idle_timeout {
+                            let timeout = Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+                            if device.set_peer_idle_timeout(&key, timeout).is_err() {
+                                return ENOENT;
+                            }
+                        }
+                        if !peer.remove
+                            && !peer.ecmp_endpoints.is_empty()
+                            && device
+                                .set_peer_ecmp_endpoints(&key, peer.ecmp_endpoints)
+                                .is_err()
+                        {
+                            return ENOENT;
+                        }
+                        if !peer.remove
+                            && peer.backup_endpoint.is_some()
+                            && device
+                                .set_peer_backup_endpoint(&key, peer.backup_endpoint)
+                                .is_err()
+                        {
+                            return ENOENT;
+                        }
                     }
                 }
-                cmd.clear();
             }
 
             0
@@ -229,93 +1303,22 @@ fn api_set<'a, T: Tun, S: Sock>(
     .unwrap_or(EIO)
 }
 
-fn api_set_peer<T: Tun, S: Sock>(
-    reader: &mut BufReader<&UnixStream>,
-    d: &mut Device<T, S>,
-    pub_key: X25519PublicKey,
-) -> i32 {
-    let mut cmd = String::new();
-
-    let mut remove = false;
-    let mut replace_ips = false;
-    let mut endpoint = None;
-    let mut keepalive = None;
-    let mut preshared_key = None;
-    let mut allowed_ips: Vec<AllowedIP> = vec![];
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    while let Ok(_) = reader.read_line(&mut cmd) {
-        cmd.pop(); // remove newline if any
-        if cmd.is_empty() {
-            d.update_peer(
-                pub_key,
-                remove,
-                replace_ips,
-                endpoint,
-                allowed_ips,
-                keepalive,
-                preshared_key,
-            );
-            return 0; // Done
-        }
-        {
-            let parsed_cmd: Vec<&str> = cmd.splitn(2, '=').collect();
-            if parsed_cmd.len() != 2 {
-                return EPROTO;
-            }
+    #[test]
+    fn test_credentials_allowed() {
+        assert!(credentials_allowed((1000, 1000), &[], &[]));
+        assert!(credentials_allowed((1000, 100), &[0, 1000], &[]));
+        assert!(credentials_allowed((1000, 100), &[0], &[100]));
+        assert!(!credentials_allowed((1001, 1001), &[0, 1000], &[]));
+        assert!(!credentials_allowed((1001, 1001), &[0], &[100]));
 
-            let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
-
-            match key {
-                "remove" => match val.parse::<bool>() {
-                    Ok(true) => remove = true,
-                    Ok(false) => remove = false,
-                    Err(_) => return EINVAL,
-                },
-                "preshared_key" => match val.parse::<X25519PublicKey>() {
-                    Ok(key) => preshared_key = Some(make_array(key.as_bytes())),
-                    Err(_) => return EINVAL,
-                },
-                "endpoint" => match val.parse::<SocketAddr>() {
-                    Ok(addr) => endpoint = Some(addr),
-                    Err(_) => return EINVAL,
-                },
-                "persistent_keepalive_interval" => match val.parse::<u16>() {
-                    Ok(interval) => keepalive = Some(interval),
-                    Err(_) => return EINVAL,
-                },
-                "replace_allowed_ips" => match val.parse::<bool>() {
-                    Ok(true) => replace_ips = true,
-                    Ok(false) => replace_ips = false,
-                    Err(_) => return EINVAL,
-                },
-                "allowed_ip" => match val.parse::<AllowedIP>() {
-                    Ok(ip) => allowed_ips.push(ip),
-                    Err(_) => return EINVAL,
-                },
-                "public_key" => {
-                    // Indicates a new peer section. Commit changes for current peer, and continue to next peer
-                    d.update_peer(
-                        pub_key,
-                        remove,
-                        replace_ips,
-                        endpoint,
-                        allowed_ips,
-                        keepalive,
-                        preshared_key,
-                    );
-                    match val.parse::<X25519PublicKey>() {
-                        Ok(key) => return api_set_peer::<T, S>(reader, d, key),
-                        Err(_) => return EINVAL,
-                    }
-                }
-                "protocol_version" => match val.parse::<u32>() {
-                    Ok(1) => {} // Only version 1 is legal
-                    _ => return EINVAL,
-                },
-                _ => return EINVAL,
-            }
-        }
-        cmd.clear();
+        // The credentials of the client are those of the process at the other end
+        let (client, server) = UnixStream::pair().unwrap();
+        let cred = unsafe { (getuid(), getgid()) };
+        assert_eq!(peer_credentials(&server).unwrap(), cred);
+        assert_eq!(peer_credentials(&client).unwrap(), cred);
     }
-    0
 }
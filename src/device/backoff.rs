@@ -0,0 +1,103 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Jittered exponential backoff, so an operation that keeps failing is not retried in a busy loop
+
+use std::time::{Duration, Instant};
+
+use crate::crypto::x25519::{OsRng, Rng};
+
+#[derive(Debug)]
+pub struct Backoff {
+    base: Duration,
+    ceiling: Duration,
+    failures: u32, // Consecutive failures since the last success
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, ceiling: Duration) -> Backoff {
+        Backoff {
+            base,
+            ceiling,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    /// Can the operation be attempted again
+    pub fn is_ready(&self, now: Instant) -> bool {
+        match self.retry_at {
+            Some(retry_at) => now >= retry_at,
+            None => true,
+        }
+    }
+
+    /// Record a failed attempt, the next one is allowed after the returned interval
+    pub fn failed(&mut self, now: Instant) -> Duration {
+        let mut random = [0u8; 4];
+        OsRng.fill(&mut random);
+
+        self.failures = self.failures.saturating_add(1);
+        let interval = self.interval(u32::from_le_bytes(random));
+        self.retry_at = Some(now + interval);
+        interval
+    }
+
+    /// Forget previous failures after the operation succeeded
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    // The wait after the current number of failures. The interval doubles with every failure up
+    // to the ceiling, and a random half of it is jittered so peers that failed together do not
+    // retry together.
+    fn interval(&self, random: u32) -> Duration {
+        let doublings = self.failures.saturating_sub(1).min(31);
+        let interval = self
+            .base
+            .checked_mul(1 << doublings)
+            .map_or(self.ceiling, |interval| interval.min(self.ceiling));
+        let half = interval / 2;
+        half + half.mul_f64(f64::from(random) / f64::from(u32::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_interval() {
+        let base = Duration::from_millis(100);
+        let ceiling = Duration::from_secs(2);
+        let mut backoff = Backoff::new(base, ceiling);
+
+        let now = Instant::now();
+        assert!(backoff.is_ready(now));
+
+        let mut previous_max = Duration::from_secs(0);
+        for failures in 1..=10u32 {
+            let interval = backoff.failed(now);
+            assert!(!backoff.is_ready(now));
+            assert!(backoff.is_ready(now + interval));
+
+            // Each interval is jittered within the upper half of the doubled interval
+            let max = (base * 2u32.pow(failures - 1)).min(ceiling);
+            assert!(interval >= max / 2 && interval <= max);
+            assert_eq!(backoff.interval(0), max / 2);
+            assert_eq!(backoff.interval(u32::MAX), max);
+            assert!(max >= previous_max);
+            previous_max = max;
+        }
+
+        // Capped at the ceiling
+        assert_eq!(previous_max, ceiling);
+
+        backoff.reset();
+        assert!(backoff.is_ready(now));
+        backoff.failed(now);
+        assert_eq!(backoff.interval(u32::MAX), base);
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Classic BPF socket filters, so the kernel drops datagrams that can not be WireGuard messages
+//! before they are queued to the socket
+
+// Instruction classes and fields of classic BPF, from linux/filter.h and linux/bpf_common.h
+const BPF_LD: u16 = 0x00;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_W: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_LEN: u16 = 0x80;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_K: u16 = 0x00;
+
+// Filters on UDP sockets see the packet from the UDP header on
+const UDP_HEADER_SIZE: u32 = 8;
+// The smallest message is a data message without payload, a keepalive
+const MIN_MESSAGE_SIZE: u32 = 32;
+// Message types are little endian, a big endian load of the type field must fall in this range
+const MIN_MESSAGE_TYPE: u32 = 1 << 24;
+const MAX_MESSAGE_TYPE: u32 = 4 << 24;
+
+const ACCEPT: u32 = u32::MAX;
+const DROP: u32 = 0;
+
+/// A single instruction, laid out as struct sock_filter
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BpfInstruction {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+// Laid out as struct sock_fprog
+#[repr(C)]
+pub(crate) struct SockFprog {
+    pub(crate) len: u16,
+    pub(crate) filter: *const BpfInstruction,
+}
+
+/// A classic BPF program, attached to a socket with `UDPSocket::attach_bpf_filter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BpfProgram {
+    instructions: Vec<BpfInstruction>,
+}
+
+fn stmt(code: u16, k: u32) -> BpfInstruction {
+    BpfInstruction {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> BpfInstruction {
+    BpfInstruction { code, jt, jf, k }
+}
+
+impl BpfProgram {
+    pub fn new(instructions: Vec<BpfInstruction>) -> BpfProgram {
+        BpfProgram { instructions }
+    }
+
+    /// Drop datagrams with less than min_len bytes of payload
+    pub fn min_length(min_len: u32) -> BpfProgram {
+        BpfProgram::new(vec![
+            stmt(BPF_LD | BPF_W | BPF_LEN, 0),
+            jump(BPF_JMP | BPF_JGE | BPF_K, UDP_HEADER_SIZE + min_len, 0, 1),
+            stmt(BPF_RET | BPF_K, ACCEPT),
+            stmt(BPF_RET | BPF_K, DROP),
+        ])
+    }
+
+    /// Drop datagrams shorter than the smallest WireGuard message, or with a message type other
+    /// than initiation, response, cookie reply and data
+    pub fn wireguard() -> BpfProgram {
+        BpfProgram::new(vec![
+            stmt(BPF_LD | BPF_W | BPF_LEN, 0),
+            jump(
+                BPF_JMP | BPF_JGE | BPF_K,
+                UDP_HEADER_SIZE + MIN_MESSAGE_SIZE,
+                0,
+                4,
+            ),
+            stmt(BPF_LD | BPF_W | BPF_ABS, UDP_HEADER_SIZE),
+            jump(BPF_JMP | BPF_JGE | BPF_K, MIN_MESSAGE_TYPE, 0, 2),
+            jump(BPF_JMP | BPF_JGT | BPF_K, MAX_MESSAGE_TYPE, 1, 0),
+            stmt(BPF_RET | BPF_K, ACCEPT),
+            stmt(BPF_RET | BPF_K, DROP),
+        ])
+    }
+
+    pub fn instructions(&self) -> &[BpfInstruction] {
+        &self.instructions
+    }
+
+    pub(crate) fn as_fprog(&self) -> SockFprog {
+        SockFprog {
+            len: self.instructions.len() as u16,
+            filter: self.instructions.as_ptr(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::udp::UDPSocket;
+    use crate::device::Sock;
+    use std::net::SocketAddr;
+
+    fn receiver(prog: &BpfProgram) -> (UDPSocket, SocketAddr) {
+        let sock = UDPSocket::new()
+            .and_then(|s| s.set_non_blocking())
+            .and_then(|s| s.bind(0))
+            .unwrap();
+        sock.attach_bpf_filter(prog).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], sock.port().unwrap()));
+        (sock, addr)
+    }
+
+    // The sizes of the datagrams the socket receives, after the sender is done
+    fn received(sock: &UDPSocket) -> Vec<usize> {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut buf = [0u8; 256];
+        let mut sizes = vec![];
+        while let Ok((_, packet)) = sock.recvfrom(&mut buf) {
+            sizes.push(packet.len());
+        }
+        sizes
+    }
+
+    #[test]
+    fn test_min_length_filter() {
+        let (sock, addr) = receiver(&BpfProgram::min_length(32));
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for len in &[1, 31, 32, 100] {
+            sender.send_to(&vec![4u8; *len], addr).unwrap();
+        }
+        assert_eq!(received(&sock), [32, 100]);
+    }
+
+    #[test]
+    fn test_wireguard_filter() {
+        let (sock, addr) = receiver(&BpfProgram::wireguard());
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let message = |message_type: u8, len: usize| {
+            let mut message = vec![0u8; len];
+            message[0] = message_type;
+            message
+        };
+        for packet in &[
+            message(1, 148),
+            message(4, 16), // Too short
+            message(0, 64), // Unknown type
+            message(5, 64),
+            message(4, 32),
+            message(2, 92),
+        ] {
+            sender.send_to(packet, addr).unwrap();
+        }
+        assert_eq!(received(&sock), [148, 32, 92]);
+    }
+}
@@ -0,0 +1,640 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A transport that intercepts WireGuard datagrams off the wire with an AF_PACKET socket instead
+//! of binding a UDP port, for transparent gateways that sit inline and do not own the address
+//! peers send to.
+//!
+//! Untagged Ethernet frames carrying UDP datagrams to the listen port are read from a TPACKET_V3
+//! ring. Replies are written as whole frames, from the address and hardware address the peer sent
+//! to, back to the hardware address its datagram came from, so only endpoints the socket heard
+//! from can be sent to. The kernel still sees the captured frames, it should not deliver or
+//! forward them itself. UDP checksums of captured datagrams are not checked, WireGuard
+//! authenticates every message anyway.
+//!
+//! The interface is set with `DeviceConfig::capture_interface`.
+
+use super::{errno, errno_str, DeviceConfig, Error};
+use crate::device::Sock;
+use libc::*;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// From linux/if_packet.h, missing from libc
+const PACKET_RX_RING: c_int = 5;
+const PACKET_VERSION: c_int = 10;
+const PACKET_IGNORE_OUTGOING: c_int = 23;
+const TPACKET_V3: c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const PACKET_OUTGOING: u8 = 4;
+
+const BLOCK_SIZE: usize = 1 << 18;
+const BLOCK_COUNT: usize = 8;
+const FRAME_SIZE: usize = 2048;
+const BLOCK_TIMEOUT_MS: u32 = 1; // A block that is not full is handed over after this long
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const UDP: u8 = 17;
+const DEFAULT_TTL: u32 = 64;
+const MAX_ROUTES: usize = 4096; // The number of endpoints replies can be sent to
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: c_uint,
+    tp_block_nr: c_uint,
+    tp_frame_size: c_uint,
+    tp_frame_nr: c_uint,
+    tp_retire_blk_tov: c_uint,
+    tp_sizeof_priv: c_uint,
+    tp_feature_req_word: c_uint,
+}
+
+// The start of struct tpacket_block_desc, with the start of struct tpacket_hdr_v1
+#[repr(C)]
+struct BlockDesc {
+    version: u32,
+    offset_to_priv: u32,
+    block_status: AtomicU32,
+    num_pkts: u32,
+    offset_to_first_pkt: u32,
+}
+
+// The start of struct tpacket3_hdr
+#[repr(C)]
+struct Tpacket3Hdr {
+    tp_next_offset: u32,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_snaplen: u32,
+    tp_len: u32,
+    tp_status: u32,
+    tp_mac: u16,
+    tp_net: u16,
+}
+
+// The size of struct tpacket3_hdr rounded up to TPACKET_ALIGNMENT, the sockaddr_ll of a packet
+// follows at this offset
+const TPACKET3_HDR_LEN: usize = 48;
+
+/// A UDP datagram found in an Ethernet frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub src_mac: [u8; 6],
+    pub dst_mac: [u8; 6],
+    /// Where the payload is in the frame
+    pub payload: std::ops::Range<usize>,
+}
+
+/// Parse an untagged Ethernet frame that carries a UDP datagram over IPv4 or IPv6. Fragments
+/// and IPv6 extension headers are not supported.
+pub fn parse_frame(frame: &[u8]) -> Option<Datagram> {
+    if frame.len() < ETHERNET_HEADER_SIZE {
+        return None;
+    }
+    let mut dst_mac = [0u8; 6];
+    let mut src_mac = [0u8; 6];
+    dst_mac.copy_from_slice(&frame[0..6]);
+    src_mac.copy_from_slice(&frame[6..12]);
+    let ip = &frame[ETHERNET_HEADER_SIZE..];
+
+    let (src_ip, dst_ip, udp_start, ip_end) = match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_IPV4 if ip.len() >= IPV4_HEADER_SIZE && ip[0] >> 4 == 4 => {
+            let header_len = usize::from(ip[0] & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes([ip[2], ip[3]]));
+            let fragment = u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff;
+            if ip[9] != UDP || fragment != 0 || header_len < IPV4_HEADER_SIZE {
+                return None;
+            }
+            let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+            let dst = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+            (IpAddr::V4(src), IpAddr::V4(dst), header_len, total_len)
+        }
+        ETHERTYPE_IPV6 if ip.len() >= IPV6_HEADER_SIZE && ip[0] >> 4 == 6 => {
+            if ip[6] != UDP {
+                return None;
+            }
+            let payload_len = usize::from(u16::from_be_bytes([ip[4], ip[5]]));
+            let mut src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            src.copy_from_slice(&ip[8..24]);
+            dst.copy_from_slice(&ip[24..40]);
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                IPV6_HEADER_SIZE,
+                IPV6_HEADER_SIZE + payload_len,
+            )
+        }
+        _ => return None,
+    };
+
+    // Frames may be padded beyond the IP packet
+    let ip = ip.get(..ip_end)?;
+    let udp = ip.get(udp_start..udp_start + UDP_HEADER_SIZE)?;
+    let udp_len = usize::from(u16::from_be_bytes([udp[4], udp[5]]));
+    if udp_len < UDP_HEADER_SIZE || udp_start + udp_len > ip.len() {
+        return None;
+    }
+    let payload_start = ETHERNET_HEADER_SIZE + udp_start + UDP_HEADER_SIZE;
+    Some(Datagram {
+        src: SocketAddr::new(src_ip, u16::from_be_bytes([udp[0], udp[1]])),
+        dst: SocketAddr::new(dst_ip, u16::from_be_bytes([udp[2], udp[3]])),
+        src_mac,
+        dst_mac,
+        payload: payload_start..payload_start + udp_len - UDP_HEADER_SIZE,
+    })
+}
+
+// The one's complement sum of data, as 16 bit big endian words
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Build the Ethernet frame of a UDP datagram. src and dst must be of the same address family.
+pub fn build_frame(
+    frame: &mut Vec<u8>,
+    src_mac: [u8; 6],
+    dst_mac: [u8; 6],
+    src: SocketAddr,
+    dst: SocketAddr,
+    ttl: u8,
+    payload: &[u8],
+) {
+    let udp_len = UDP_HEADER_SIZE + payload.len();
+    frame.clear();
+    frame.extend_from_slice(&dst_mac);
+    frame.extend_from_slice(&src_mac);
+
+    // The UDP checksum covers a pseudo header of the addresses, the protocol and the length
+    let mut sum = u32::from(UDP) + udp_len as u32;
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+            let mut header = [0u8; IPV4_HEADER_SIZE];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((IPV4_HEADER_SIZE + udp_len) as u16).to_be_bytes());
+            header[6] = 0x40; // Don't fragment
+            header[8] = ttl;
+            header[9] = UDP;
+            header[12..16].copy_from_slice(&src_ip.octets());
+            header[16..20].copy_from_slice(&dst_ip.octets());
+            let header_sum = checksum_fold(checksum_add(0, &header));
+            header[10..12].copy_from_slice(&header_sum.to_be_bytes());
+            frame.extend_from_slice(&header);
+            sum = checksum_add(sum, &header[12..20]);
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+            let mut header = [0u8; IPV6_HEADER_SIZE];
+            header[0] = 0x60;
+            header[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+            header[6] = UDP;
+            header[7] = ttl;
+            header[8..24].copy_from_slice(&to_v6(src_ip).octets());
+            header[24..40].copy_from_slice(&to_v6(dst_ip).octets());
+            frame.extend_from_slice(&header);
+            sum = checksum_add(sum, &header[8..40]);
+        }
+    }
+
+    let mut header = [0u8; UDP_HEADER_SIZE];
+    header[0..2].copy_from_slice(&src.port().to_be_bytes());
+    header[2..4].copy_from_slice(&dst.port().to_be_bytes());
+    header[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    sum = checksum_add(checksum_add(sum, &header), payload);
+    // A checksum that computes to zero is sent as all ones, zero means none
+    let udp_sum = match checksum_fold(sum) {
+        0 => 0xffff,
+        udp_sum => udp_sum,
+    };
+    header[6..8].copy_from_slice(&udp_sum.to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(payload);
+}
+
+// How to reach an endpoint the socket heard from
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    local: SocketAddr, // The address the endpoint sent to, replies come from it
+    local_mac: [u8; 6],
+    peer_mac: [u8; 6],
+}
+
+// The position of the reader in the ring
+#[derive(Debug)]
+struct Cursor {
+    block: usize,   // The block being read, or the next one to wait for
+    remaining: u32, // Packets of the block not read yet, 0 if the block is not ours
+    offset: usize,  // The offset of the next packet in the block
+}
+
+/// Captures the WireGuard datagrams of one address family arriving on an interface, and sends
+/// the replies to their sources on the same interface
+#[derive(Debug)]
+pub struct CaptureSocket {
+    fd: RawFd,
+    protocol: u16, // The ethertype captured
+    ring: *mut u8,
+    cursor: Mutex<Cursor>,
+    port: u16, // The destination port of the datagrams that are captured, 0 captures none
+    routes: RwLock<HashMap<SocketAddr, Route>>,
+    ttl: AtomicU32,
+}
+
+// The ring is only accessed by the reader holding the cursor lock
+unsafe impl Send for CaptureSocket {}
+unsafe impl Sync for CaptureSocket {}
+
+impl CaptureSocket {
+    fn open(config: &DeviceConfig, protocol: u16) -> Result<CaptureSocket, Error> {
+        let name = match &config.capture_interface {
+            Some(name) => CString::new(name.as_str())
+                .map_err(|_| Error::Socket("Invalid interface name".to_owned()))?,
+            None => return Err(Error::Socket("No capture interface set".to_owned())),
+        };
+        let ifindex = match unsafe { if_nametoindex(name.as_ptr()) } {
+            0 => return Err(Error::Socket(errno_str())),
+            ifindex => ifindex as c_int,
+        };
+
+        let fd = match unsafe { socket(AF_PACKET, SOCK_RAW, c_int::from(protocol.to_be())) } {
+            -1 => return Err(Error::Socket(errno_str())),
+            fd => fd,
+        };
+        let mut sock = CaptureSocket {
+            fd,
+            protocol,
+            ring: std::ptr::null_mut(),
+            cursor: Mutex::new(Cursor {
+                block: 0,
+                remaining: 0,
+                offset: 0,
+            }),
+            port: 0,
+            routes: Default::default(),
+            ttl: AtomicU32::new(DEFAULT_TTL),
+        };
+
+        sock.set_packet_option(PACKET_VERSION, &TPACKET_V3)?;
+        let req = TpacketReq3 {
+            tp_block_size: BLOCK_SIZE as _,
+            tp_block_nr: BLOCK_COUNT as _,
+            tp_frame_size: FRAME_SIZE as _,
+            tp_frame_nr: (BLOCK_SIZE / FRAME_SIZE * BLOCK_COUNT) as _,
+            tp_retire_blk_tov: BLOCK_TIMEOUT_MS,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        sock.set_packet_option(PACKET_RX_RING, &req)?;
+        // Only supported since Linux 4.20, outgoing frames are also skipped when reading
+        let _ = sock.set_packet_option(PACKET_IGNORE_OUTGOING, &1 as &c_int);
+
+        sock.ring = match unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                BLOCK_SIZE * BLOCK_COUNT,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                fd,
+                0,
+            )
+        } {
+            MAP_FAILED => return Err(Error::Socket(errno_str())),
+            ring => ring as *mut u8,
+        };
+
+        let mut addr: sockaddr_ll = unsafe { std::mem::zeroed() };
+        addr.sll_family = AF_PACKET as _;
+        addr.sll_protocol = protocol.to_be();
+        addr.sll_ifindex = ifindex;
+        match unsafe {
+            bind(
+                fd,
+                &addr as *const sockaddr_ll as *const sockaddr,
+                std::mem::size_of::<sockaddr_ll>() as _,
+            )
+        } {
+            -1 => Err(Error::Bind(errno_str())),
+            _ => Ok(sock),
+        }
+    }
+
+    fn set_packet_option<V>(&self, option: c_int, value: &V) -> Result<(), Error> {
+        match unsafe {
+            setsockopt(
+                self.fd,
+                SOL_PACKET,
+                option,
+                value as *const V as *const c_void,
+                std::mem::size_of::<V>() as _,
+            )
+        } {
+            -1 => Err(Error::SetSockOpt(errno_str())),
+            _ => Ok(()),
+        }
+    }
+
+    fn block(&self, index: usize) -> *mut u8 {
+        unsafe { self.ring.add(index * BLOCK_SIZE) }
+    }
+
+    // Read the next frame of the ring, and pass it to f while the cursor is held. Returns None
+    // once no block is ready.
+    fn next_frame<R, F: FnMut(&[u8]) -> Option<R>>(&self, mut f: F) -> Option<R> {
+        let mut cursor = self.cursor.lock();
+        loop {
+            let block = self.block(cursor.block);
+            let desc = unsafe { &*(block as *const BlockDesc) };
+            if cursor.remaining == 0 {
+                if desc.block_status.load(Ordering::Acquire) & TP_STATUS_USER == 0 {
+                    return None;
+                }
+                cursor.remaining = desc.num_pkts;
+                cursor.offset = desc.offset_to_first_pkt as usize;
+                if cursor.remaining == 0 {
+                    self.release_block(&mut cursor);
+                    continue;
+                }
+            }
+
+            let packet = unsafe { block.add(cursor.offset) };
+            let hdr = unsafe { &*(packet as *const Tpacket3Hdr) };
+            let pkttype =
+                unsafe { (*(packet.add(TPACKET3_HDR_LEN) as *const sockaddr_ll)).sll_pkttype };
+            let frame = unsafe {
+                std::slice::from_raw_parts(
+                    packet.add(usize::from(hdr.tp_mac)),
+                    hdr.tp_snaplen as usize,
+                )
+            };
+            let result = Some(frame)
+                .filter(|_| pkttype != PACKET_OUTGOING)
+                .and_then(&mut f);
+
+            cursor.offset += hdr.tp_next_offset as usize;
+            cursor.remaining -= 1;
+            if cursor.remaining == 0 {
+                self.release_block(&mut cursor);
+            }
+            if result.is_some() {
+                return result;
+            }
+        }
+    }
+
+    // Hand the block of the cursor back to the kernel, and move to the next one
+    fn release_block(&self, cursor: &mut Cursor) {
+        let desc = unsafe { &*(self.block(cursor.block) as *const BlockDesc) };
+        desc.block_status.store(TP_STATUS_KERNEL, Ordering::Release);
+        cursor.block = (cursor.block + 1) % BLOCK_COUNT;
+        cursor.remaining = 0;
+    }
+
+    fn learn_route(&self, datagram: &Datagram) {
+        let route = Route {
+            local: datagram.dst,
+            local_mac: datagram.dst_mac,
+            peer_mac: datagram.src_mac,
+        };
+        if let Some(known) = self.routes.read().get(&datagram.src) {
+            if known.local == route.local && known.peer_mac == route.peer_mac {
+                return;
+            }
+        }
+        let mut routes = self.routes.write();
+        if routes.len() >= MAX_ROUTES && !routes.contains_key(&datagram.src) {
+            if let Some(old) = routes.keys().next().copied() {
+                routes.remove(&old);
+            }
+        }
+        routes.insert(datagram.src, route);
+    }
+}
+
+impl Drop for CaptureSocket {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ring.is_null() {
+                munmap(self.ring as *mut c_void, BLOCK_SIZE * BLOCK_COUNT);
+            }
+            close(self.fd);
+        }
+    }
+}
+
+impl AsRawFd for CaptureSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Sock for CaptureSocket {
+    // A socket can only be opened on the interface of a device
+    fn new() -> Result<CaptureSocket, Error> {
+        Err(Error::Socket("No capture interface set".to_owned()))
+    }
+
+    fn new6() -> Result<CaptureSocket, Error> {
+        Err(Error::Socket("No capture interface set".to_owned()))
+    }
+
+    fn new_with_config(config: &DeviceConfig) -> Result<CaptureSocket, Error> {
+        CaptureSocket::open(config, ETHERTYPE_IPV4)
+    }
+
+    fn new6_with_config(config: &DeviceConfig) -> Result<CaptureSocket, Error> {
+        CaptureSocket::open(config, ETHERTYPE_IPV6)
+    }
+
+    // Nothing is bound, datagrams to the port are captured
+    fn bind(mut self, port: u16) -> Result<CaptureSocket, Error> {
+        self.port = port;
+        Ok(self)
+    }
+
+    fn connect(self, _dst: &SocketAddr) -> Result<CaptureSocket, Error> {
+        Err(Error::Connect(
+            "Capture sockets can not be connected".to_owned(),
+        ))
+    }
+
+    fn set_non_blocking(self) -> Result<CaptureSocket, Error> {
+        match unsafe { fcntl(self.fd, F_GETFL) } {
+            -1 => Err(Error::FCntl(errno_str())),
+            flags => match unsafe { fcntl(self.fd, F_SETFL, flags | O_NONBLOCK) } {
+                -1 => Err(Error::FCntl(errno_str())),
+                _ => Ok(self),
+            },
+        }
+    }
+
+    fn set_reuse(self) -> Result<CaptureSocket, Error> {
+        Ok(self)
+    }
+
+    fn set_fwmark(&self, mark: u32) -> Result<(), Error> {
+        match unsafe {
+            setsockopt(
+                self.fd,
+                SOL_SOCKET,
+                SO_MARK,
+                &mark as *const u32 as *const c_void,
+                std::mem::size_of_val(&mark) as _,
+            )
+        } {
+            -1 => Err(Error::SetSockOpt(errno_str())),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_ttl(&self, ttl: u32) -> Result<(), Error> {
+        if ttl == 0 || ttl > 255 {
+            return Err(Error::SetSockOpt("Invalid TTL".to_owned()));
+        }
+        self.ttl.store(ttl, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn ttl(&self) -> Result<u32, Error> {
+        Ok(self.ttl.load(Ordering::Relaxed))
+    }
+
+    fn port(&self) -> Result<u16, Error> {
+        Ok(self.port)
+    }
+
+    fn sendto(&self, buf: &[u8], dst: SocketAddr) -> usize {
+        let route = match self.routes.read().get(&dst) {
+            Some(route) => *route,
+            None => return 0,
+        };
+        let mut frame = Vec::with_capacity(ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + 8 + buf.len());
+        build_frame(
+            &mut frame,
+            route.local_mac,
+            route.peer_mac,
+            route.local,
+            dst,
+            self.ttl.load(Ordering::Relaxed) as u8,
+            buf,
+        );
+        match unsafe { send(self.fd, frame.as_ptr() as *const c_void, frame.len(), 0) } {
+            -1 => 0,
+            _ => buf.len(),
+        }
+    }
+
+    fn recvfrom<'a>(&self, buf: &'a mut [u8]) -> Result<(SocketAddr, &'a mut [u8]), Error> {
+        let port = self.port;
+        let protocol = self.protocol;
+        let found = self.next_frame(|frame| {
+            let datagram = parse_frame(frame)?;
+            let is_family = match datagram.dst {
+                SocketAddr::V4(_) => protocol == ETHERTYPE_IPV4,
+                SocketAddr::V6(_) => protocol == ETHERTYPE_IPV6,
+            };
+            if port == 0 || datagram.dst.port() != port || !is_family {
+                return None;
+            }
+            let payload = &frame[datagram.payload.clone()];
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+            Some((datagram, len))
+        });
+
+        match found {
+            Some((datagram, len)) => {
+                self.learn_route(&datagram);
+                Ok((datagram.src, &mut buf[..len]))
+            }
+            None => Err(Error::UDPRead(EWOULDBLOCK)),
+        }
+    }
+
+    fn write(&self, _buf: &[u8]) -> usize {
+        0
+    }
+
+    fn read<'a>(&self, _buf: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        Err(Error::UDPRead(errno()))
+    }
+
+    fn shutdown(&self) {
+        unsafe { shutdown(self.fd, SHUT_RDWR) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_and_parse_frame() {
+        let src_mac = [2, 0, 0, 0, 0, 1];
+        let dst_mac = [2, 0, 0, 0, 0, 2];
+        for (src, dst) in [
+            ("192.0.2.1:51820", "198.51.100.7:4500"),
+            ("[2001:db8::1]:51820", "[2001:db8::2]:4500"),
+        ]
+        .iter()
+        {
+            let src: SocketAddr = src.parse().unwrap();
+            let dst: SocketAddr = dst.parse().unwrap();
+            let mut frame = vec![];
+            build_frame(&mut frame, src_mac, dst_mac, src, dst, 64, b"wireguard");
+
+            // Padding beyond the IP packet is not part of the payload
+            frame.extend_from_slice(&[0; 4]);
+            let datagram = parse_frame(&frame).unwrap();
+            assert_eq!(datagram.src, src);
+            assert_eq!(datagram.dst, dst);
+            assert_eq!((datagram.src_mac, datagram.dst_mac), (src_mac, dst_mac));
+            assert_eq!(&frame[datagram.payload.clone()], b"wireguard");
+
+            // The checksums of the headers verify
+            let ip = &frame[ETHERNET_HEADER_SIZE..frame.len() - 4];
+            let (header_len, pseudo) = match src {
+                SocketAddr::V4(_) => {
+                    assert_eq!(checksum_fold(checksum_add(0, &ip[..IPV4_HEADER_SIZE])), 0);
+                    (IPV4_HEADER_SIZE, &ip[12..20])
+                }
+                SocketAddr::V6(_) => (IPV6_HEADER_SIZE, &ip[8..40]),
+            };
+            let udp = &ip[header_len..];
+            let sum = checksum_add(u32::from(UDP) + udp.len() as u32, pseudo);
+            assert_eq!(checksum_fold(checksum_add(sum, udp)), 0);
+
+            assert_eq!(parse_frame(&frame[..frame.len() - 10]), None);
+        }
+    }
+}
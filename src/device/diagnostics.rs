@@ -0,0 +1,141 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Diagnostic reports about packets received from the network that could not be decapsulated
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::noise::errors::WireGuardError;
+
+// The number of failures reported per second, further failures are only counted
+const MAX_REPORTS_PER_SEC: u64 = 10;
+
+/// Called with every reported decryption failure, see `DeviceConfig::on_decrypt_failure`
+pub type DecryptFailureCallback = Box<dyn Fn(DecryptFailure) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecryptFailureReason {
+    /// The receiver index matches neither a peer nor one of its sessions
+    UnknownReceiverIndex,
+    /// The counter was already seen, or is too old for the anti-replay window
+    ReplayRejected,
+    /// The authentication tag or mac1 did not verify, usually because of mismatched keys
+    AuthFailed,
+    /// The packet is truncated, has the wrong size or an unknown type
+    Malformed,
+    /// The packet was rejected because the device is under load
+    RateLimited,
+}
+
+impl DecryptFailureReason {
+    /// The reason for a decapsulation error, if it is caused by the received packet
+    pub fn from_error(err: &WireGuardError) -> Option<DecryptFailureReason> {
+        match err {
+            WireGuardError::WrongIndex | WireGuardError::NoCurrentSession => {
+                Some(DecryptFailureReason::UnknownReceiverIndex)
+            }
+            WireGuardError::InvalidCounter | WireGuardError::WrongTai64nTimestamp => {
+                Some(DecryptFailureReason::ReplayRejected)
+            }
+            WireGuardError::InvalidAeadTag | WireGuardError::InvalidMac => {
+                Some(DecryptFailureReason::AuthFailed)
+            }
+            WireGuardError::IncorrectPacketLength
+            | WireGuardError::InvalidPacket
+            | WireGuardError::WrongPacketType
+            | WireGuardError::InvalidTai64nTimestamp => Some(DecryptFailureReason::Malformed),
+            WireGuardError::UnderLoad => Some(DecryptFailureReason::RateLimited),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecryptFailure {
+    pub reason: DecryptFailureReason,
+    /// The address the packet was received from
+    pub src: SocketAddr,
+    /// The number of failures that were not reported since the previous report
+    pub suppressed: u64,
+}
+
+/// Limits reports to a fixed number per second, so a flood of bad packets can not be amplified
+/// into a flood of reports
+pub struct ReportLimiter {
+    start: Instant,
+    window: AtomicU64, // The second since start that count applies to
+    count: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl Default for ReportLimiter {
+    fn default() -> Self {
+        ReportLimiter {
+            start: Instant::now(),
+            window: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ReportLimiter {
+    /// Returns the number of suppressed failures if this one should be reported
+    pub fn allow(&self) -> Option<u64> {
+        let now = self.start.elapsed().as_secs();
+        let window = self.window.load(Ordering::Relaxed);
+        if window != now
+            && self
+                .window
+                .compare_exchange(window, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.count.store(0, Ordering::Relaxed);
+        }
+
+        if self.count.fetch_add(1, Ordering::Relaxed) < MAX_REPORTS_PER_SEC {
+            Some(self.suppressed.swap(0, Ordering::Relaxed))
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_limiter() {
+        let limiter = ReportLimiter::default();
+        for _ in 0..MAX_REPORTS_PER_SEC {
+            assert_eq!(limiter.allow(), Some(0));
+        }
+        assert_eq!(limiter.allow(), None);
+        assert_eq!(limiter.allow(), None);
+
+        // The next window reports how many failures were dropped
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        assert_eq!(limiter.allow(), Some(2));
+        assert_eq!(limiter.allow(), Some(0));
+    }
+
+    #[test]
+    fn test_failure_reason() {
+        assert_eq!(
+            DecryptFailureReason::from_error(&WireGuardError::InvalidAeadTag),
+            Some(DecryptFailureReason::AuthFailed)
+        );
+        assert_eq!(
+            DecryptFailureReason::from_error(&WireGuardError::InvalidCounter),
+            Some(DecryptFailureReason::ReplayRejected)
+        );
+        assert_eq!(
+            DecryptFailureReason::from_error(&WireGuardError::DestinationBufferTooSmall),
+            None
+        );
+    }
+}
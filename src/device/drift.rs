@@ -0,0 +1,399 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Drift detection: a digest of the configuration a device runs with, and the changes that bring
+//! it to a declared configuration. Both cover the public key of the device and, for every peer,
+//! its public key, endpoint and allowed IPs. Private and preshared keys are left out, so a
+//! digest can be logged and compared anywhere.
+
+use super::{Device, Sock, Tun};
+use crate::crypto::blake2s::Blake2s;
+use crate::crypto::x25519::{X25519PublicKey, X25519SecretKey};
+use crate::device::peer::AllowedIP;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+const LABEL_DIGEST: &[u8] = b"boringtun config digest v1";
+
+/// The part of the configuration of a device that drift detection covers, either declared or
+/// taken from a running device with `Device::running_config`
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    /// The public key of the device, a declared config without one matches any key
+    pub public_key: Option<X25519PublicKey>,
+    pub peers: Vec<PeerConfig>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PeerConfig {
+    pub public_key: X25519PublicKey,
+    /// The endpoint, a declared peer without one matches any endpoint, as the endpoint of a peer
+    /// that roams is learned from its packets
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<AllowedIP>,
+}
+
+impl PeerConfig {
+    pub fn new(public_key: X25519PublicKey) -> PeerConfig {
+        PeerConfig {
+            public_key,
+            endpoint: None,
+            allowed_ips: vec![],
+        }
+    }
+
+    // The allowed IPs as networks, sorted and without duplicates: 10.0.0.1/24 and 10.0.0.0/24
+    // are the same
+    fn canonical_ips(&self) -> Vec<(IpAddr, u8)> {
+        let mut ips: Vec<_> = self
+            .allowed_ips
+            .iter()
+            .map(|ip| (network(ip.addr, ip.cidr), ip.cidr))
+            .collect();
+        ips.sort_unstable();
+        ips.dedup();
+        ips
+    }
+}
+
+/// A canonical hash of a `Config`, the same for the same configuration whatever the order of its
+/// peers and allowed IPs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConfigDigest(pub [u8; 32]);
+
+impl fmt::Display for ConfigDigest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// A change that brings a running device to a declared configuration, as found by
+/// `Device::diff_against`
+#[derive(Debug, PartialEq)]
+pub enum ConfigChange {
+    /// The device runs with another private key than declared, or with none
+    ChangePublicKey {
+        running: Option<X25519PublicKey>,
+        declared: X25519PublicKey,
+    },
+    /// A declared peer is missing from the device
+    AddPeer(X25519PublicKey),
+    /// The device has a peer that is not declared
+    RemovePeer(X25519PublicKey),
+    /// A peer has another endpoint than declared, or none
+    ChangeEndpoint {
+        public_key: X25519PublicKey,
+        running: Option<SocketAddr>,
+        declared: SocketAddr,
+    },
+    /// A peer lacks some of its declared allowed IPs, or has some that are not declared
+    ChangeAllowedIps {
+        public_key: X25519PublicKey,
+        add: Vec<AllowedIP>,
+        remove: Vec<AllowedIP>,
+    },
+}
+
+impl Config {
+    /// The digest of the configuration, see `ConfigDigest`
+    pub fn digest(&self) -> ConfigDigest {
+        let mut hash = Blake2s::new_hash();
+        hash.hash(LABEL_DIGEST);
+        match &self.public_key {
+            Some(key) => hash.hash(&[1]).hash(key.as_bytes()),
+            None => hash.hash(&[0]),
+        };
+
+        let peers = self.by_key();
+        hash.hash(&(peers.len() as u64).to_le_bytes());
+        for peer in peers.values() {
+            hash.hash(peer.public_key.as_bytes());
+            match peer.endpoint {
+                Some(addr) => hash
+                    .hash(&[1])
+                    .hash(&ip_bytes(addr.ip()))
+                    .hash(&addr.port().to_le_bytes()),
+                None => hash.hash(&[0]),
+            };
+            let ips = peer.canonical_ips();
+            hash.hash(&(ips.len() as u64).to_le_bytes());
+            for (addr, cidr) in ips {
+                hash.hash(&ip_bytes(addr)).hash(&[cidr]);
+            }
+        }
+
+        ConfigDigest(hash.finalize())
+    }
+
+    /// The changes that turn this configuration into the declared one, in the order of the public
+    /// keys of the peers they apply to
+    pub fn diff(&self, declared: &Config) -> Vec<ConfigChange> {
+        let mut changes = vec![];
+        if let Some(key) = &declared.public_key {
+            if self.public_key.as_ref() != Some(key) {
+                changes.push(ConfigChange::ChangePublicKey {
+                    running: self.public_key.as_ref().map(copy_key),
+                    declared: copy_key(key),
+                });
+            }
+        }
+
+        let (running, declared) = (self.by_key(), declared.by_key());
+        let mut keys: Vec<_> = running.keys().chain(declared.keys()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        for key in keys {
+            let public_key = X25519PublicKey::from(&key[..]);
+            let (running, declared) = match (running.get(key), declared.get(key)) {
+                (Some(running), Some(declared)) => (running, declared),
+                (None, _) => {
+                    changes.push(ConfigChange::AddPeer(public_key));
+                    continue;
+                }
+                (_, None) => {
+                    changes.push(ConfigChange::RemovePeer(public_key));
+                    continue;
+                }
+            };
+
+            match declared.endpoint {
+                Some(addr) if running.endpoint != Some(addr) => {
+                    changes.push(ConfigChange::ChangeEndpoint {
+                        public_key: copy_key(&public_key),
+                        running: running.endpoint,
+                        declared: addr,
+                    })
+                }
+                _ => {}
+            }
+
+            let (running_ips, declared_ips) = (running.canonical_ips(), declared.canonical_ips());
+            let missing = |ips: &[(IpAddr, u8)], from: &[(IpAddr, u8)]| -> Vec<AllowedIP> {
+                ips.iter()
+                    .filter(|ip| !from.contains(ip))
+                    .map(|&(addr, cidr)| AllowedIP { addr, cidr })
+                    .collect()
+            };
+            let add = missing(&declared_ips, &running_ips);
+            let remove = missing(&running_ips, &declared_ips);
+            if !add.is_empty() || !remove.is_empty() {
+                changes.push(ConfigChange::ChangeAllowedIps {
+                    public_key,
+                    add,
+                    remove,
+                });
+            }
+        }
+        changes
+    }
+
+    // The peers by public key, a peer listed twice is the last one
+    fn by_key(&self) -> BTreeMap<[u8; 32], &PeerConfig> {
+        self.peers
+            .iter()
+            .map(|peer| (copy_bytes(&peer.public_key), peer))
+            .collect()
+    }
+}
+
+/// Parse a configuration from the lines of a UAPI set command, as in
+/// `private_key=...\npublic_key=...\nendpoint=...\nallowed_ip=...`. The keys drift detection does
+/// not cover are ignored, so a whole set command is accepted.
+impl FromStr for Config {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Config::default();
+        for line in s.lines().filter(|line| !line.is_empty()) {
+            let (key, val) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Malformed line {}", line))?;
+            let invalid = || format!("Invalid value of {}: {}", key, val);
+            if key == "public_key" {
+                let key = X25519PublicKey::from_hex(val).map_err(|_| invalid())?;
+                config.peers.push(PeerConfig::new(key));
+                continue;
+            }
+            match (key, config.peers.last_mut()) {
+                ("private_key", None) => {
+                    let key = X25519SecretKey::from_hex(val).map_err(|_| invalid())?;
+                    config.public_key = Some(key.public_key());
+                }
+                ("endpoint", Some(peer)) => {
+                    peer.endpoint = Some(val.parse().map_err(|_| invalid())?)
+                }
+                ("allowed_ip", Some(peer)) => {
+                    peer.allowed_ips.push(val.parse().map_err(|_| invalid())?)
+                }
+                ("replace_allowed_ips", Some(peer)) if val == "true" => peer.allowed_ips.clear(),
+                _ => {}
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl<T: Tun, S: Sock> Device<T, S> {
+    /// The configuration the device runs with, as far as drift detection covers it
+    pub fn running_config(&self) -> Config {
+        Config {
+            public_key: self.public_key(),
+            peers: self
+                .peers_by_id()
+                .into_iter()
+                .map(|(key, peer)| PeerConfig {
+                    public_key: copy_key(key),
+                    endpoint: peer.endpoint().addr,
+                    allowed_ips: peer
+                        .allowed_ips()
+                        .map(|(_, addr, cidr)| AllowedIP {
+                            addr,
+                            cidr: cidr as u8,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// A digest of the configuration the device runs with, equal to the digest of a declared
+    /// config with the same public keys, endpoints and allowed IPs
+    pub fn config_digest(&self) -> ConfigDigest {
+        self.running_config().digest()
+    }
+
+    /// The changes that bring the device to the declared configuration, empty when the device
+    /// runs with it
+    pub fn diff_against(&self, declared: &Config) -> Vec<ConfigChange> {
+        self.running_config().diff(declared)
+    }
+}
+
+fn copy_bytes(key: &X25519PublicKey) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(key.as_bytes());
+    bytes
+}
+
+fn copy_key(key: &X25519PublicKey) -> X25519PublicKey {
+    X25519PublicKey::from(key.as_bytes())
+}
+
+// An address as 16 bytes, IPv4 addresses mapped to IPv6 so the families are told apart
+fn ip_bytes(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped().octets(),
+        IpAddr::V6(addr) => addr.octets(),
+    }
+}
+
+// The network of an address, its bits beyond the prefix cleared
+fn network(addr: IpAddr, cidr: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX
+                .checked_shl(32u32.saturating_sub(cidr.into()))
+                .unwrap_or(0);
+            IpAddr::from((u32::from(addr) & mask).to_be_bytes())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX
+                .checked_shl(128u32.saturating_sub(cidr.into()))
+                .unwrap_or(0);
+            IpAddr::from((u128::from(addr) & mask).to_be_bytes())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_digest() {
+        let (a, b) = (X25519SecretKey::new(), X25519SecretKey::new());
+        let declared = format!(
+            "private_key={}\npublic_key={}\nendpoint=192.0.2.1:51820\nallowed_ip=10.0.0.1/24\n\
+             allowed_ip=fd00::/64\npublic_key={}\nallowed_ip=10.1.0.0/16\n",
+            &*X25519SecretKey::new().to_hex(),
+            a.public_key().to_hex(),
+            b.public_key().to_hex()
+        );
+        let declared: Config = declared.parse().unwrap();
+        assert_eq!(declared.peers.len(), 2);
+
+        // The same peers in another order, with their allowed IPs in another order and form
+        let mut reordered: Config = format!(
+            "public_key={}\nallowed_ip=10.1.0.0/16\npublic_key={}\nallowed_ip=fd00::1/64\n\
+             allowed_ip=10.0.0.0/24\nallowed_ip=10.0.0.0/24\nendpoint=192.0.2.1:51820\n",
+            b.public_key().to_hex(),
+            a.public_key().to_hex()
+        )
+        .parse()
+        .unwrap();
+        reordered.public_key = declared.public_key.as_ref().map(copy_key);
+        assert_eq!(reordered.digest(), declared.digest());
+        assert!(reordered.diff(&declared).is_empty());
+
+        reordered.peers[1].endpoint = Some("192.0.2.2:51820".parse().unwrap());
+        reordered.peers[0]
+            .allowed_ips
+            .push("10.2.0.0/16".parse().unwrap());
+        assert_ne!(reordered.digest(), declared.digest());
+        // The changes come in the order of the public keys of their peers
+        let mut expected = vec![
+            (
+                copy_bytes(&a.public_key()),
+                ConfigChange::ChangeEndpoint {
+                    public_key: a.public_key(),
+                    running: Some("192.0.2.2:51820".parse().unwrap()),
+                    declared: "192.0.2.1:51820".parse().unwrap(),
+                },
+            ),
+            (
+                copy_bytes(&b.public_key()),
+                ConfigChange::ChangeAllowedIps {
+                    public_key: b.public_key(),
+                    add: vec![],
+                    remove: vec!["10.2.0.0/16".parse().unwrap()],
+                },
+            ),
+        ];
+        expected.sort_by_key(|(key, _)| *key);
+        assert_eq!(
+            reordered.diff(&declared),
+            expected
+                .into_iter()
+                .map(|(_, change)| change)
+                .collect::<Vec<_>>()
+        );
+
+        // A declared peer without an endpoint matches any, not the other way around
+        let mut roaming: Config = format!("public_key={}\n", a.public_key().to_hex())
+            .parse()
+            .unwrap();
+        let running = Config {
+            public_key: None,
+            peers: vec![PeerConfig {
+                endpoint: Some("192.0.2.3:1".parse().unwrap()),
+                ..PeerConfig::new(a.public_key())
+            }],
+        };
+        assert!(running.diff(&roaming).is_empty());
+        assert_ne!(running.digest(), roaming.digest());
+        roaming.peers.clear();
+        assert_eq!(
+            running.diff(&roaming),
+            [ConfigChange::RemovePeer(a.public_key())]
+        );
+        assert_eq!(
+            roaming.diff(&running),
+            [ConfigChange::AddPeer(a.public_key())]
+        );
+
+        assert!("public_key=nope".parse::<Config>().is_err());
+        assert_eq!(ConfigDigest([0xab; 32]).to_string(), "ab".repeat(32));
+    }
+}
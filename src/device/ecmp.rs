@@ -0,0 +1,128 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Weighted selection among the equal cost endpoints of a peer. Each inner flow is hashed to one
+//! endpoint, so packets of a flow always take the same path and stay in order.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::net::SocketAddr;
+
+const IPV4_MIN_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+
+/// The largest weight of an endpoint
+pub const MAX_WEIGHT: u32 = 1 << 16;
+
+/// A hash of the flow of an inner packet: its addresses, protocol and, for TCP and UDP, ports.
+/// Fragments after the first carry no ports, so fragmented packets are hashed without them.
+pub fn flow_hash(packet: &[u8]) -> Option<u64> {
+    let (addrs, protocol, payload, fragmented) = match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= IPV4_MIN_HEADER_SIZE => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let flags_offset = u16::from_be_bytes([packet[6], packet[7]]);
+            (
+                &packet[12..20],
+                packet[9],
+                packet.get(header_len..)?,
+                flags_offset & 0x3fff != 0,
+            )
+        }
+        Some(6) if packet.len() >= IPV6_HEADER_SIZE => (
+            &packet[8..40],
+            packet[6],
+            &packet[IPV6_HEADER_SIZE..],
+            false,
+        ),
+        _ => return None,
+    };
+
+    let mut hasher = DefaultHasher::new();
+    hasher.write(addrs);
+    hasher.write_u8(protocol);
+    if (protocol == TCP || protocol == UDP) && !fragmented && payload.len() >= 4 {
+        hasher.write(&payload[..4]);
+    }
+    Some(hasher.finish())
+}
+
+/// The weighted endpoints of a peer
+#[derive(Debug, Default, Clone)]
+pub struct WeightedEndpoints {
+    endpoints: Vec<(SocketAddr, u32)>,
+    total: u64, // The sum of the weights
+}
+
+impl WeightedEndpoints {
+    pub fn new(endpoints: Vec<(SocketAddr, u32)>) -> WeightedEndpoints {
+        let total = endpoints.iter().map(|&(_, w)| u64::from(w)).sum();
+        WeightedEndpoints { endpoints, total }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    /// The endpoints with their weights, in the order they were configured
+    pub fn endpoints(&self) -> &[(SocketAddr, u32)] {
+        &self.endpoints
+    }
+
+    /// The endpoint of the flow with the given hash. Every endpoint gets a share of the flows
+    /// proportional to its weight.
+    pub fn pick(&self, hash: u64) -> Option<SocketAddr> {
+        if self.total == 0 {
+            return None;
+        }
+        let mut point = hash % self.total;
+        for &(addr, weight) in &self.endpoints {
+            if point < u64::from(weight) {
+                return Some(addr);
+            }
+            point -= u64::from(weight);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp_packet(src_port: u16) -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0, 0, 28, 0, 0, 0, 0, 64, UDP, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        packet.extend_from_slice(&src_port.to_be_bytes());
+        packet.extend_from_slice(&[0x1f, 0x90, 0, 8, 0, 0]);
+        packet
+    }
+
+    #[test]
+    fn test_weighted_endpoints() {
+        let a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let endpoints = WeightedEndpoints::new(vec![(a, 3), (b, 1)]);
+
+        let n_flows = 4000;
+        let to_a = (0..n_flows)
+            .map(|port| endpoints.pick(flow_hash(&udp_packet(port)).unwrap()))
+            .filter(|&addr| addr == Some(a))
+            .count();
+        // 3000 expected
+        assert!((2800..3200).contains(&to_a), "{}", to_a);
+
+        // A flow is pinned to one endpoint, whatever its payload
+        let mut packet = udp_packet(1234);
+        let first = endpoints.pick(flow_hash(&packet).unwrap());
+        for payload in 0..100 {
+            packet[27] = payload;
+            assert_eq!(endpoints.pick(flow_hash(&packet).unwrap()), first);
+        }
+
+        assert_eq!(WeightedEndpoints::default().pick(0), None);
+        assert_eq!(flow_hash(&[0x45; 8]), None);
+    }
+}
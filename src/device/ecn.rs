@@ -0,0 +1,108 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Propagation of explicit congestion notification between the inner and outer IP headers, as
+//! described by RFC 6040
+
+use super::offload::{checksum_add, checksum_fold};
+
+pub const ECN_NOT_ECT: u8 = 0b00;
+pub const ECN_ECT1: u8 = 0b01;
+pub const ECN_ECT0: u8 = 0b10;
+pub const ECN_CE: u8 = 0b11;
+
+const IPV4_MIN_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+
+/// The ECN codepoint of an IPv4 or IPv6 packet, to be copied to the outer header on encapsulation
+pub fn inner_ecn(packet: &[u8]) -> u8 {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= IPV4_MIN_HEADER_SIZE => packet[1] & 0b11,
+        Some(6) if packet.len() >= IPV6_HEADER_SIZE => (packet[1] >> 4) & 0b11,
+        _ => ECN_NOT_ECT,
+    }
+}
+
+/// Apply the ECN codepoint of the outer header to a decapsulated packet. Returns false if the
+/// packet must be dropped, because congestion was signalled for a packet that is not ECN capable.
+pub fn decapsulate(packet: &mut [u8], outer: u8) -> bool {
+    if outer != ECN_CE {
+        return true;
+    }
+
+    match inner_ecn(packet) {
+        ECN_NOT_ECT => false,
+        ECN_CE => true,
+        _ => {
+            set_ce(packet);
+            true
+        }
+    }
+}
+
+fn set_ce(packet: &mut [u8]) {
+    match packet[0] >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            if header_len < IPV4_MIN_HEADER_SIZE || header_len > packet.len() {
+                return;
+            }
+
+            packet[1] |= ECN_CE;
+            packet[10..12].copy_from_slice(&[0, 0]);
+            let csum = !checksum_fold(checksum_add(0, &packet[..header_len]));
+            packet[10..12].copy_from_slice(&csum.to_be_bytes());
+        }
+        6 => packet[1] |= ECN_CE << 4,
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_header(tos: u8) -> Vec<u8> {
+        let mut packet = vec![0x45, tos, 0, 20, 0, 0, 0x40, 0, 64, 17, 0, 0];
+        packet.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2]);
+        let csum = !checksum_fold(checksum_add(0, &packet));
+        packet[10..12].copy_from_slice(&csum.to_be_bytes());
+        packet
+    }
+
+    fn ipv6_header(tclass: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; IPV6_HEADER_SIZE];
+        packet[0] = 0x60 | (tclass >> 4);
+        packet[1] = tclass << 4;
+        packet
+    }
+
+    #[test]
+    fn test_inner_ecn() {
+        assert_eq!(inner_ecn(&ipv4_header(0xb8 | ECN_ECT0)), ECN_ECT0);
+        assert_eq!(inner_ecn(&ipv4_header(0xb8)), ECN_NOT_ECT);
+        assert_eq!(inner_ecn(&ipv6_header(0xb8 | ECN_ECT1)), ECN_ECT1);
+        assert_eq!(inner_ecn(&ipv6_header(ECN_CE)), ECN_CE);
+        assert_eq!(inner_ecn(&[0x45, ECN_CE]), ECN_NOT_ECT);
+    }
+
+    #[test]
+    fn test_decapsulate_ce() {
+        let mut packet = ipv4_header(0xb8 | ECN_ECT0);
+        assert!(decapsulate(&mut packet, ECN_CE));
+        assert_eq!(packet[1], 0xb8 | ECN_CE);
+        assert_eq!(checksum_fold(checksum_add(0, &packet)), 0xffff);
+
+        let mut packet = ipv6_header(ECN_ECT1);
+        assert!(decapsulate(&mut packet, ECN_CE));
+        assert_eq!(inner_ecn(&packet), ECN_CE);
+
+        // Without congestion the inner packet is not touched
+        let mut packet = ipv4_header(ECN_ECT0);
+        assert!(decapsulate(&mut packet, ECN_ECT0));
+        assert_eq!(packet, ipv4_header(ECN_ECT0));
+
+        // Congestion on a packet that is not ECN capable must be signalled by a drop
+        assert!(!decapsulate(&mut ipv4_header(0), ECN_CE));
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A check that the random number generator of the OS is ready before the device starts. Early in
+//! boot, or in a minimal container, the Linux pool may not be seeded yet, and ephemeral keys drawn
+//! from it then would be guessable. The device refuses to start instead. Handshakes do not check
+//! again: once seeded, the pool stays seeded.
+
+use super::Error;
+
+#[cfg(test)]
+thread_local! {
+    // Makes the check fail on this thread, so a test sees the device refuse to start
+    pub(crate) static FORCE_UNAVAILABLE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Check that the OS random number generator is available and seeded, without waiting for it
+pub fn check_os_rng() -> Result<(), Error> {
+    #[cfg(test)]
+    if FORCE_UNAVAILABLE.with(|force| force.get()) {
+        return probe(|_| Err(libc::EAGAIN));
+    }
+    probe(getrandom)
+}
+
+// Interpret a read of a byte from the OS generator, which fails with the errno of getrandom(2)
+fn probe<F: FnOnce(&mut [u8]) -> Result<(), i32>>(read: F) -> Result<(), Error> {
+    let mut byte = [0u8; 1];
+    match read(&mut byte) {
+        Ok(()) => Ok(()),
+        Err(libc::EAGAIN) => Err(Error::Entropy(
+            "The OS random number generator is not seeded yet".to_owned(),
+        )),
+        Err(errno) => Err(Error::Entropy(format!(
+            "The OS random number generator is unavailable: {}",
+            std::io::Error::from_raw_os_error(errno)
+        ))),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn getrandom(dst: &mut [u8]) -> Result<(), i32> {
+    loop {
+        match unsafe { libc::getrandom(dst.as_mut_ptr() as _, dst.len(), libc::GRND_NONBLOCK) } {
+            -1 => match super::errno() {
+                libc::EINTR => continue,
+                // Before Linux 3.17: /dev/random is readable once the pool is seeded
+                libc::ENOSYS => return random_ready(),
+                errno => return Err(errno),
+            },
+            _ => return Ok(()),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn random_ready() -> Result<(), i32> {
+    let random =
+        std::fs::File::open("/dev/random").map_err(|e| e.raw_os_error().unwrap_or(libc::ENOENT))?;
+    let mut pollfd = libc::pollfd {
+        fd: std::os::unix::io::AsRawFd::as_raw_fd(&random),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut pollfd, 1, 0) } {
+        -1 => Err(super::errno()),
+        0 => Err(libc::EAGAIN),
+        _ => Ok(()),
+    }
+}
+
+// The generators of the BSDs and macOS are seeded before any process runs
+#[cfg(not(target_os = "linux"))]
+fn getrandom(dst: &mut [u8]) -> Result<(), i32> {
+    match unsafe { libc::getentropy(dst.as_mut_ptr() as _, dst.len()) } {
+        -1 => Err(super::errno()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_os_rng() {
+        assert!(check_os_rng().is_ok());
+        assert!(matches!(
+            probe(|_| Err(libc::EAGAIN)),
+            Err(Error::Entropy(_))
+        ));
+        assert!(matches!(
+            probe(|_| Err(libc::EPERM)),
+            Err(Error::Entropy(_))
+        ));
+    }
+}
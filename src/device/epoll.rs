@@ -5,7 +5,7 @@ use super::{errno_str, Error};
 use libc::*;
 use parking_lot::Mutex;
 use std::ops::Deref;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr::null_mut;
 use std::time::Duration;
 
@@ -22,7 +22,16 @@ pub enum WaitResult<'a, H> {
 /// Implements a registry of pollable events
 pub struct EventPoll<H: Sized> {
     events: Mutex<Vec<Option<Box<Event<H>>>>>,
-    epoll: RawFd, // The OS epoll
+    epoll: RawFd,      // The OS epoll
+    batch_size: usize, // The maximal number of events returned by a single epoll_wait
+}
+
+/// Events returned by a single epoll_wait that were not yet handed out by EventPoll::wait
+/// Each thread waiting on the poll should own a batch
+pub struct EventBatch {
+    events: Vec<epoll_event>,
+    len: usize,  // The number of events returned by the last epoll_wait
+    next: usize, // The next event to hand out
 }
 
 /// A type that hold a reference to a triggered Event
@@ -39,12 +48,35 @@ pub struct EventRef {
     trigger: RawFd,
 }
 
+/// The kind of an event, as listed by EventPoll::registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// Triggered when the fd is readable
+    Fd,
+    Notifier,
+    Signal,
+    Timer,
+}
+
 struct Event<H> {
     event: epoll_event, // The epoll event description
     fd: RawFd,          // The associated fd
     handler: H,         // The associated data
-    notifier: bool,     // Is a notification event
-    needs_read: bool,   // This event needs to be read to be cleared
+    kind: EventKind,
+}
+
+impl<H> Event<H> {
+    // Timers and signals must be read to be cleared
+    fn needs_read(&self) -> bool {
+        matches!(self.kind, EventKind::Signal | EventKind::Timer)
+    }
+
+    // Read from the event to reset it
+    fn clear(&self) {
+        let mut buf: [std::mem::MaybeUninit<u8>; 256] =
+            unsafe { std::mem::MaybeUninit::uninit().assume_init() };
+        while unsafe { read(self.fd, buf.as_mut_ptr() as _, buf.len() as _) } != -1 {}
+    }
 }
 
 impl<H> Drop for EventPoll<H> {
@@ -56,6 +88,12 @@ impl<H> Drop for EventPoll<H> {
 impl<H: Sync + Send> EventPoll<H> {
     /// Create a new event registry
     pub fn new() -> Result<EventPoll<H>, Error> {
+        EventPoll::new_with_batch_size(1)
+    }
+
+    /// Create a new event registry, that retrieves up to batch_size triggered events from the OS
+    /// at once
+    pub fn new_with_batch_size(batch_size: usize) -> Result<EventPoll<H>, Error> {
         let epoll = match unsafe { epoll_create(1) } {
             -1 => return Err(Error::EventQueue(errno_str())),
             epoll => epoll,
@@ -64,9 +102,24 @@ impl<H: Sync + Send> EventPoll<H> {
         Ok(EventPoll {
             events: Mutex::new(vec![]),
             epoll,
+            batch_size: batch_size.max(1),
         })
     }
 
+    /// The maximal number of events retrieved from the OS at once
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Create an empty batch to use with wait
+    pub fn new_batch(&self) -> EventBatch {
+        EventBatch {
+            events: vec![epoll_event { events: 0, u64: 0 }; self.batch_size],
+            len: 0,
+            next: 0,
+        }
+    }
+
     /// Add and enable a new event with the factory.
     /// The event is triggered when a Read operation on the provided trigger becomes available
     /// If the trigger fd is closed, the event won't be triggered anymore, but it's data won't be
@@ -89,8 +142,7 @@ impl<H: Sync + Send> EventPoll<H> {
             },
             fd: trigger,
             handler,
-            notifier: false,
-            needs_read: false,
+            kind: EventKind::Fd,
         };
 
         self.register_event(ev)
@@ -110,8 +162,7 @@ impl<H: Sync + Send> EventPoll<H> {
             },
             fd: trigger,
             handler,
-            notifier: false,
-            needs_read: false,
+            kind: EventKind::Fd,
         };
 
         self.register_event(ev)
@@ -153,8 +204,7 @@ impl<H: Sync + Send> EventPoll<H> {
             },
             fd: tfd,
             handler,
-            notifier: false,
-            needs_read: true,
+            kind: EventKind::Timer,
         };
 
         self.register_event(ev)
@@ -182,8 +232,7 @@ impl<H: Sync + Send> EventPoll<H> {
             },
             fd: efd,
             handler,
-            notifier: true,
-            needs_read: false,
+            kind: EventKind::Notifier,
         };
 
         self.register_event(ev)
@@ -209,8 +258,7 @@ impl<H: Sync + Send> EventPoll<H> {
             },
             fd: sfd,
             handler,
-            notifier: false,
-            needs_read: true,
+            kind: EventKind::Signal,
         };
 
         self.register_event(ev)
@@ -220,13 +268,42 @@ impl<H: Sync + Send> EventPoll<H> {
     /// is triggered, a single caller thread gets the handler for that event.
     /// In case a notifier is triggered, all waiting threads will receive the same
     /// handler.
-    pub fn wait(&self) -> WaitResult<'_, H> {
-        let mut event = epoll_event { events: 0, u64: 0 };
-        match unsafe { epoll_wait(self.epoll, &mut event, 1, -1) } {
-            -1 => return WaitResult::Error(errno_str()),
-            1 => {}
-            _ => return WaitResult::Error("unexpected number of events returned".to_string()),
+    /// Events are retrieved from the OS into the batch, and handed out from it one at a time,
+    /// until the batch is exhausted.
+    pub fn wait(&self, batch: &mut EventBatch) -> WaitResult<'_, H> {
+        match self.next_event(batch, -1) {
+            Some(result) => result,
+            None => WaitResult::Error("unexpected number of events returned".to_string()),
         }
+    }
+
+    /// Like wait, but returns None instead of blocking when no event is triggered
+    pub fn poll(&self, batch: &mut EventBatch) -> Option<WaitResult<'_, H>> {
+        self.next_event(batch, 0)
+    }
+
+    // Hand out the next event of the batch, retrieving events from the OS if it is empty
+    fn next_event(&self, batch: &mut EventBatch, timeout: c_int) -> Option<WaitResult<'_, H>> {
+        if batch.next == batch.len {
+            let max_events = batch.events.len() as c_int;
+            match unsafe { epoll_wait(self.epoll, batch.events.as_mut_ptr(), max_events, timeout) }
+            {
+                -1 => return Some(WaitResult::Error(errno_str())),
+                0 if timeout == 0 => return None,
+                n if n > 0 && n <= max_events => {
+                    batch.len = n as usize;
+                    batch.next = 0;
+                }
+                _ => {
+                    return Some(WaitResult::Error(
+                        "unexpected number of events returned".to_string(),
+                    ))
+                }
+            }
+        }
+
+        let event = batch.events[batch.next];
+        batch.next += 1;
 
         let event_data = unsafe { (event.u64 as *mut Event<H>).as_mut().unwrap() };
 
@@ -238,10 +315,55 @@ impl<H: Sync + Send> EventPoll<H> {
 
         if event.events & EPOLLHUP as u32 != 0 {
             // End of file flag
-            WaitResult::EoF(guard)
+            Some(WaitResult::EoF(guard))
         } else {
-            WaitResult::Ok(guard)
+            Some(WaitResult::Ok(guard))
+        }
+    }
+
+    /// Re-enable the events in the batch that were not handed out yet, and empty it.
+    /// Must be called before giving up the device lock, since the events may be removed while
+    /// the lock is not held.
+    pub fn release_batch(&self, batch: &mut EventBatch) {
+        for event in &batch.events[batch.next..batch.len] {
+            let event_data = unsafe { (event.u64 as *mut Event<H>).as_mut().unwrap() };
+            // Dropping the guard re-enables the event
+            drop(EventGuard {
+                epoll: self.epoll,
+                event: event_data,
+                poll: self,
+            });
+        }
+        batch.len = 0;
+        batch.next = 0;
+    }
+
+    /// The fds of the registered events, with their kind
+    pub fn registered(&self) -> Vec<(RawFd, EventKind)> {
+        self.events
+            .lock()
+            .iter()
+            .flatten()
+            .map(|event| (event.fd, event.kind))
+            .collect()
+    }
+
+    /// Call f with the handler of the event registered for fd, for callers that poll the fds
+    /// themselves instead of waiting on the poll. Timers and signals are read to clear them
+    /// before f is called. Returns None if there is no event for fd.
+    /// Like for wait, events must not be removed while f runs.
+    pub fn with_handler<R>(&self, fd: RawFd, f: impl FnOnce(&H) -> R) -> Option<R> {
+        let event = {
+            // The lock is not held while f runs, the handler may register new events
+            let events = self.events.lock();
+            let event = events.get(fd as usize)?.as_ref()?; // A negative fd is out of range
+            event.as_ref() as *const Event<H>
+        };
+        let event = unsafe { &*event };
+        if event.needs_read() {
+            event.clear();
         }
+        Some(f(&event.handler))
     }
 
     // Register an event with this poll.
@@ -292,7 +414,7 @@ impl<H: Sync + Send> EventPoll<H> {
         let event_ref = &(*events)[notification_event.trigger as usize];
         let event_data = event_ref.as_ref().expect("Expected an event");
 
-        if !event_data.notifier {
+        if event_data.kind != EventKind::Notifier {
             panic!("Can only trigger a notification event");
         }
 
@@ -313,7 +435,7 @@ impl<H: Sync + Send> EventPoll<H> {
         let event_ref = &(*events)[notification_event.trigger as usize];
         let event_data = event_ref.as_ref().expect("Expected an event");
 
-        if !event_data.notifier {
+        if event_data.kind != EventKind::Notifier {
             panic!("Can only trigger a notification event");
         }
 
@@ -336,12 +458,27 @@ impl<H> EventPoll<H> {
     pub unsafe fn clear_event_by_fd(&self, index: RawFd) {
         let mut events = self.events.lock();
         assert!(index >= 0);
-        if events[index as usize].take().is_some() {
+        // The fd may be registered with another poll instead
+        if let Some(Some(_)) = events.get_mut(index as usize).map(Option::take) {
             epoll_ctl(self.epoll, EPOLL_CTL_DEL, index, null_mut());
         }
     }
 }
 
+impl<H> AsRawFd for EventPoll<H> {
+    // The fd is readable when an event of the poll is triggered, so a poll can wait on another
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll
+    }
+}
+
+impl EventBatch {
+    /// Whether every event retrieved into the batch was handed out
+    pub fn is_empty(&self) -> bool {
+        self.next == self.len
+    }
+}
+
 impl<'a, H> Deref for EventGuard<'a, H> {
     type Target = H;
     fn deref(&self) -> &H {
@@ -351,11 +488,9 @@ impl<'a, H> Deref for EventGuard<'a, H> {
 
 impl<'a, H> Drop for EventGuard<'a, H> {
     fn drop(&mut self) {
-        if self.event.needs_read {
+        if self.event.needs_read() {
             // Must read from the event to reset it before we enable it
-            let mut buf: [std::mem::MaybeUninit<u8>; 256] =
-                unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-            while unsafe { read(self.event.fd, buf.as_mut_ptr() as _, buf.len() as _) } != -1 {}
+            self.event.clear();
         }
 
         unsafe {
@@ -0,0 +1,157 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A stream of the changes of a device, for monitors that would otherwise poll the configuration
+//! socket. Every subscriber has a bounded queue, events for a subscriber that does not keep up
+//! are dropped and replaced by a single `Lagged` event.
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use super::{PeerId, PeerStats};
+use crate::crypto::x25519::X25519PublicKey;
+
+/// The number of events queued for a subscriber before events are dropped
+pub const SUBSCRIBER_QUEUE_SIZE: usize = 1024;
+
+/// A change of the device. The events of a peer carry the metadata it had when they were
+/// published, see `Device::set_peer_metadata`.
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    PeerAdded {
+        peer_id: PeerId,
+        public_key: Arc<X25519PublicKey>,
+    },
+    PeerRemoved {
+        peer_id: PeerId,
+        public_key: Arc<X25519PublicKey>,
+        metadata: Option<String>,
+    },
+    /// A new session with the peer became current
+    HandshakeCompleted {
+        peer_id: PeerId,
+        metadata: Option<String>,
+    },
+    /// The peer was heard from a new address
+    EndpointChanged {
+        peer_id: PeerId,
+        endpoint: SocketAddr,
+        metadata: Option<String>,
+    },
+    /// The counters of every peer, see `Device::all_stats`
+    Stats(Vec<(PeerId, PeerStats)>),
+    /// The number of events dropped because the subscriber did not keep up, sent before the next
+    /// event that fits the queue
+    Lagged(u64),
+}
+
+struct Subscriber {
+    sender: SyncSender<DeviceEvent>,
+    dropped: u64,
+}
+
+/// The subscribers of a device
+pub struct Subscribers {
+    capacity: usize,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl Default for Subscribers {
+    fn default() -> Self {
+        Subscribers::new(SUBSCRIBER_QUEUE_SIZE)
+    }
+}
+
+impl Subscribers {
+    pub fn new(capacity: usize) -> Subscribers {
+        Subscribers {
+            capacity: capacity.max(2),
+            subscribers: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (sender, receiver) = sync_channel(self.capacity);
+        self.subscribers
+            .lock()
+            .push(Subscriber { sender, dropped: 0 });
+        receiver
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.lock().is_empty()
+    }
+
+    /// Send event to every subscriber without blocking, subscribers that went away are dropped
+    pub fn publish(&self, event: DeviceEvent) {
+        self.subscribers.lock().retain_mut(|subscriber| {
+            if subscriber.dropped > 0 {
+                match subscriber
+                    .sender
+                    .try_send(DeviceEvent::Lagged(subscriber.dropped))
+                {
+                    Ok(()) => subscriber.dropped = 0,
+                    Err(TrySendError::Full(_)) => {
+                        subscriber.dropped += 1;
+                        return true;
+                    }
+                    Err(TrySendError::Disconnected(_)) => return false,
+                }
+            }
+            match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.dropped += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribers_drop_when_full() {
+        let subscribers = Subscribers::new(2);
+        let receiver = subscribers.subscribe();
+        for peer_id in 0..5 {
+            subscribers.publish(DeviceEvent::HandshakeCompleted {
+                peer_id,
+                metadata: None,
+            });
+        }
+
+        let ids = |receiver: &Receiver<DeviceEvent>| {
+            receiver
+                .try_iter()
+                .map(|event| match event {
+                    DeviceEvent::HandshakeCompleted { peer_id, .. } => peer_id as i64,
+                    DeviceEvent::Lagged(n) => -(n as i64),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&receiver), [0, 1]);
+
+        // The drops are reported once there is room again
+        subscribers.publish(DeviceEvent::HandshakeCompleted {
+            peer_id: 5,
+            metadata: None,
+        });
+        assert_eq!(ids(&receiver), [-3, 5]);
+
+        drop(receiver);
+        subscribers.publish(DeviceEvent::HandshakeCompleted {
+            peer_id: 6,
+            metadata: None,
+        });
+        assert!(subscribers.is_empty());
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Receive offload on the listen sockets. With `use_udp_gro` the kernel hands over a train of
+//! datagrams from the same source in a single buffer of up to 64KB, each but the last one as
+//! long as the segment size it reports. The buffer is taken apart in chunks of at most
+//! `gro_max_segments` datagrams, and the packets decapsulated from a chunk are written to the
+//! tunnel interface before the next chunk is started, so that a full buffer does not hold them
+//! back for the time it takes to decrypt all of it.
+
+use std::slice::ChunksMut;
+
+/// The most datagrams the kernel coalesces into one buffer, UDP_MAX_SEGMENTS on Linux
+pub const MAX_GRO_SEGMENTS: usize = 64;
+
+/// Split a coalesced buffer in chunks of at most max_segments datagrams of segment_size bytes,
+/// the last datagram may be shorter. A segment size of zero means the buffer is one datagram.
+pub fn chunks(
+    buf: &mut [u8],
+    segment_size: usize,
+    max_segments: usize,
+) -> impl Iterator<Item = ChunksMut<'_, u8>> {
+    let segment_size = match segment_size {
+        0 => buf.len().max(1),
+        n => n,
+    };
+    buf.chunks_mut(segment_size * max_segments.max(1))
+        .map(move |chunk| chunk.chunks_mut(segment_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gro_chunks() {
+        // A full buffer of 64 datagrams of 1000 bytes but the last, each filled with its index
+        let mut buf = vec![];
+        for i in 0..MAX_GRO_SEGMENTS {
+            let len = if i == MAX_GRO_SEGMENTS - 1 { 300 } else { 1000 };
+            buf.resize(buf.len() + len, i as u8);
+        }
+
+        let mut segments = vec![];
+        for chunk in chunks(&mut buf, 1000, 10) {
+            let chunk: Vec<_> = chunk.map(|segment| segment.to_vec()).collect();
+            assert!(!chunk.is_empty() && chunk.len() <= 10);
+            segments.extend(chunk);
+        }
+        assert_eq!(segments.len(), MAX_GRO_SEGMENTS);
+        for (i, segment) in segments.iter().enumerate() {
+            assert!(segment.iter().all(|&b| b == i as u8));
+            assert_eq!(
+                segment.len(),
+                if i == MAX_GRO_SEGMENTS - 1 { 300 } else { 1000 }
+            );
+        }
+        assert_eq!(chunks(&mut buf, 1000, 10).count(), 7);
+        assert_eq!(chunks(&mut buf, 1000, MAX_GRO_SEGMENTS).count(), 1);
+
+        // A datagram that was not coalesced is a single chunk of a single segment
+        let mut single = [1u8; 1420];
+        let mut all: Vec<_> = chunks(&mut single, 0, 10).collect();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].next().map(|s| s.len()), Some(1420));
+        assert!(all[0].next().is_none());
+        assert_eq!(chunks(&mut [], 0, 10).count(), 0);
+    }
+}
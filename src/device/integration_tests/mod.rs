@@ -41,6 +41,136 @@ mod tests {
         NEXT_PORT.fetch_add(1, Ordering::Relaxed) as u16
     }
 
+    /// A peer of a device emulated with Tunn, behind a socket on the loopback
+    struct LoopbackPeer {
+        tunn: Box<Tunn>,
+        sock: UDPSocket,
+        addr: SocketAddr, // The endpoint of the peer
+        ip: IpAddr,       // The address routed to the peer through the tunnel
+        public_key: X25519PublicKey,
+        initiations: std::cell::Cell<usize>, // The handshake initiations received from the device
+    }
+
+    impl LoopbackPeer {
+        /// Create a peer of the device with the given key, that is not added to it
+        fn new(device_key: &X25519SecretKey) -> LoopbackPeer {
+            let sock = UDPSocket::new()
+                .and_then(|s| s.set_non_blocking())
+                .and_then(|s| s.bind(0))
+                .unwrap();
+            let key = Arc::new(X25519SecretKey::new());
+            LoopbackPeer {
+                addr: SocketAddr::from(([127, 0, 0, 1], sock.port().unwrap())),
+                sock,
+                ip: next_ip(),
+                public_key: key.public_key(),
+                tunn: Tunn::new(key, Arc::new(device_key.public_key()), None, None, 0, None)
+                    .unwrap(),
+                initiations: Default::default(),
+            }
+        }
+
+        /// Send a UDP datagram into the tunnel, to the address of the peer
+        fn send_into_tunnel(&self, payload: &[u8]) {
+            UdpSocket::bind("0.0.0.0:0")
+                .unwrap()
+                .send_to(payload, SocketAddr::new(self.ip, 9999))
+                .unwrap();
+        }
+
+        /// A 40 byte TCP packet from the peer to an address that is not local, so the kernel
+        /// drops it without a reply
+        fn inner_packet(&self) -> Vec<u8> {
+            let mut packet = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, 6, 0, 0];
+            match self.ip {
+                IpAddr::V4(ip) => packet.extend_from_slice(&ip.octets()),
+                _ => unreachable!(),
+            }
+            packet.extend_from_slice(&[198, 51, 100, 1]);
+            packet.resize(40, 0);
+            packet
+        }
+
+        /// Encapsulate an inner packet and send it to the device
+        fn send_inner(&self, inner_packet: &[u8], device_addr: SocketAddr) {
+            let mut buf = [0u8; 2048];
+            match self.tunn.encapsulate(inner_packet, &mut buf) {
+                TunnResult::WriteToNetwork(packet) => self.sock.sendto(packet, device_addr),
+                _ => panic!("Expected a data packet"),
+            };
+        }
+
+        /// Answer the messages of the device at device_addr until a packet comes out of the
+        /// tunnel, for up to 5 seconds. Returns the address it came from and the inner packet.
+        fn pump_until_delivered(&self, device_addr: SocketAddr) -> Option<(SocketAddr, Vec<u8>)> {
+            self.pump_for(device_addr, std::time::Duration::from_secs(5))
+        }
+
+        /// Like pump_until_delivered, for up to timeout
+        fn pump_for(
+            &self,
+            device_addr: SocketAddr,
+            timeout: std::time::Duration,
+        ) -> Option<(SocketAddr, Vec<u8>)> {
+            let mut buf = [0u8; 2048];
+            let mut dst = [0u8; 2048];
+            let started = std::time::Instant::now();
+            while started.elapsed() < timeout {
+                let (from, packet) = match self.sock.recvfrom(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => {
+                        std::thread::sleep(std::time::Duration::from_millis(10));
+                        continue;
+                    }
+                };
+                if Tunn::is_handshake_init(packet) {
+                    self.initiations.set(self.initiations.get() + 1);
+                }
+                let mut results = self.tunn.decapsulate_iter(None, packet, &mut dst);
+                while let Some(result) = results.next_result() {
+                    match result {
+                        TunnResult::WriteToNetwork(packet) => {
+                            self.sock.sendto(packet, device_addr);
+                        }
+                        TunnResult::WriteToTunnelV4(packet, _) => {
+                            return Some((from, packet.to_vec()))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            None
+        }
+    }
+
+    /// Add a peer emulated with Tunn to the device, with its endpoint on the loopback and a route
+    /// to its address through the tunnel, which is started first
+    fn loopback_peer(wg: &mut WGHandle, device_key: &X25519SecretKey) -> LoopbackPeer {
+        let peer = LoopbackPeer::new(device_key);
+        assert_eq!(
+            wg.wg_set_peer(
+                &peer.public_key,
+                &peer.addr,
+                &[AllowedIp {
+                    ip: peer.ip,
+                    cidr: 32
+                }]
+            ),
+            "errno=0\n\n"
+        );
+        wg.start();
+        add_route(wg, peer.ip);
+        peer
+    }
+
+    /// Route the address of a peer through the tunnel of the device
+    fn add_route(wg: &WGHandle, ip: IpAddr) {
+        Command::new("ip")
+            .args(&["route", "add", &format!("{}/32", ip), "dev", &wg.name])
+            .status()
+            .expect("failed to add route");
+    }
+
     /// Represents an allowed IP and cidr for a peer
     struct AllowedIp {
         ip: IpAddr,
@@ -276,8 +406,7 @@ mod tests {
                     n_threads: 2,
                     logger,
                     use_connected_socket: true,
-                    #[cfg(target_os = "linux")]
-                    use_multi_queue: true,
+                    ..Default::default()
                 },
             )
         }
@@ -525,6 +654,7 @@ mod tests {
                 "private_key={}\n\
                  listen_port={}\n\
                  public_key={}\n\
+                 peer_id=1\n\
                  endpoint={}\n\
                  allowed_ip={}/{}\n\
                  allowed_ip={}/{}\n\
@@ -543,6 +673,812 @@ mod tests {
         );
     }
 
+    #[test]
+    /// Test that the public key of the device is derived from the private key it was given
+    fn test_wireguard_public_key() {
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert!(wg._device.device.read().public_key().is_none());
+
+        // The test vector from RFC 7748, section 6.1
+        let private_key = "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a"
+            .parse::<X25519SecretKey>()
+            .unwrap();
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let public_key = wg._device.device.read().public_key().unwrap();
+        assert_eq!(
+            encode(public_key.as_bytes()),
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Test that the pacing rate is applied to the listen sockets and reported back
+    fn test_wireguard_pacing_rate() {
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+        assert_eq!(wg.wg_set("pacing_rate=1250000"), "errno=0\n\n");
+        assert!(wg.wg_get().contains("pacing_rate=1250000\n"));
+        assert_eq!(wg.wg_set("pacing_rate=fast"), "errno=22\n\n");
+
+        let fd = wg._device.device.read().udp4.as_ref().unwrap().as_raw_fd();
+        let mut rate = 0u64;
+        let mut len = std::mem::size_of_val(&rate) as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_MAX_PACING_RATE,
+                &mut rate as *mut u64 as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(res, 0);
+        assert_eq!(rate, 1_250_000);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Test that the DF bit setting is applied to the listen sockets of both address families
+    fn test_wireguard_dont_fragment() {
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+        assert!(!wg.wg_get().contains("df="));
+
+        let mtu_discover = || {
+            let device = wg._device.device.read();
+            let get = |fd, level, option| {
+                let mut value: libc::c_int = -1;
+                let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+                let res = unsafe {
+                    libc::getsockopt(
+                        fd,
+                        level,
+                        option,
+                        &mut value as *mut libc::c_int as *mut libc::c_void,
+                        &mut len,
+                    )
+                };
+                assert_eq!(res, 0);
+                value
+            };
+            (
+                get(
+                    device.udp4.as_ref().unwrap().as_raw_fd(),
+                    libc::IPPROTO_IP,
+                    libc::IP_MTU_DISCOVER,
+                ),
+                get(
+                    device.udp6.as_ref().unwrap().as_raw_fd(),
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_MTU_DISCOVER,
+                ),
+            )
+        };
+
+        assert_eq!(wg.wg_set("df=off"), "errno=0\n\n");
+        assert!(wg.wg_get().contains("df=off\n"));
+        assert_eq!(
+            mtu_discover(),
+            (libc::IP_PMTUDISC_DONT, libc::IPV6_PMTUDISC_DONT)
+        );
+
+        assert_eq!(wg.wg_set("df=on"), "errno=0\n\n");
+        assert!(wg.wg_get().contains("df=on\n"));
+        assert_eq!(
+            mtu_discover(),
+            (libc::IP_PMTUDISC_DO, libc::IPV6_PMTUDISC_DO)
+        );
+
+        assert_eq!(wg.wg_set("df=maybe"), "errno=22\n\n");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Test that the socket priority is applied to the listen sockets and reported back
+    fn test_wireguard_priority() {
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+        assert_eq!(wg.wg_set("priority=5"), "errno=0\n\n");
+        assert!(wg.wg_get().contains("priority=5\n"));
+        assert_eq!(wg.wg_set("priority=-1"), "errno=22\n\n");
+
+        let device = wg._device.device.read();
+        for sock in &[device.udp4.as_ref().unwrap(), device.udp6.as_ref().unwrap()] {
+            let mut prio: libc::c_int = -1;
+            let mut len = std::mem::size_of_val(&prio) as libc::socklen_t;
+            let res = unsafe {
+                libc::getsockopt(
+                    sock.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_PRIORITY,
+                    &mut prio as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(res, 0);
+            assert_eq!(prio, 5);
+        }
+    }
+
+    #[test]
+    /// Test that the TTL is applied to the listen sockets of both families and reported back
+    fn test_wireguard_ttl() {
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+        assert!(!wg.wg_get().contains("ttl="));
+        assert_eq!(wg.wg_set("ttl=12"), "errno=0\n\n");
+        assert!(wg.wg_get().contains("ttl=12\n"));
+        assert_eq!(wg.wg_set("ttl=0"), "errno=22\n\n");
+        assert_eq!(wg.wg_set("ttl=256"), "errno=22\n\n");
+
+        let device = wg._device.device.read();
+        assert_eq!(device.udp4.as_ref().unwrap().ttl().unwrap(), 12);
+        assert_eq!(device.udp6.as_ref().unwrap().ttl().unwrap(), 12);
+    }
+
+    #[test]
+    /// Test that route lookups agree with the longest-prefix match of overlapping allowed IPs
+    fn test_wireguard_route_lookup() {
+        let wg = WGHandle::init("192.0.2.0".parse().unwrap(), "::2".parse().unwrap());
+        assert_eq!(wg.wg_set_key(&X25519SecretKey::new()), "errno=0\n\n");
+
+        let endpoint = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 0, 0, 1)), 50001);
+        let wide = X25519SecretKey::new().public_key();
+        let narrow = X25519SecretKey::new().public_key();
+
+        let allowed_ips = |addr: [u8; 4], cidr| {
+            vec![AllowedIp {
+                ip: IpAddr::V4(Ipv4Addr::from(addr)),
+                cidr,
+            }]
+        };
+
+        assert_eq!(
+            wg.wg_set_peer(&wide, &endpoint, &allowed_ips([10, 0, 0, 0], 8)),
+            "errno=0\n\n"
+        );
+        assert_eq!(
+            wg.wg_set_peer(&narrow, &endpoint, &allowed_ips([10, 1, 2, 0], 24)),
+            "errno=0\n\n"
+        );
+
+        let device = wg._device.device.read();
+        let lookup = |addr: [u8; 4]| device.route_lookup(IpAddr::V4(Ipv4Addr::from(addr)));
+
+        assert_eq!(lookup([10, 1, 2, 3]), Some(narrow));
+        assert_eq!(lookup([10, 1, 3, 3]), Some(wide));
+        assert_eq!(lookup([11, 1, 2, 3]), None);
+    }
+
+    #[test]
+    /// Test that peer ids are assigned in order, listed in order, and not reused after removal
+    fn test_wireguard_peer_id() {
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert_eq!(wg.wg_set_key(&X25519SecretKey::new()), "errno=0\n\n");
+
+        let keys: Vec<_> = (0..3)
+            .map(|_| X25519SecretKey::new().public_key())
+            .collect();
+        let add =
+            |key: &X25519PublicKey| wg.wg_set(&format!("public_key={}", encode(key.as_bytes())));
+        let peer_ids = || {
+            wg._device
+                .device
+                .read()
+                .peers()
+                .iter()
+                .map(|peer| (peer.peer_id, encode(peer.public_key.as_bytes())))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(add(&keys[0]), "errno=0\n\n");
+        assert_eq!(add(&keys[1]), "errno=0\n\n");
+        assert_eq!(
+            peer_ids(),
+            vec![
+                (1, encode(keys[0].as_bytes())),
+                (2, encode(keys[1].as_bytes()))
+            ]
+        );
+
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nremove=true",
+                encode(keys[1].as_bytes())
+            )),
+            "errno=0\n\n"
+        );
+        assert_eq!(add(&keys[2]), "errno=0\n\n");
+        assert_eq!(
+            peer_ids(),
+            vec![
+                (1, encode(keys[0].as_bytes())),
+                (3, encode(keys[2].as_bytes()))
+            ]
+        );
+
+        // The configuration lists peers in the same order
+        let config = wg.wg_get();
+        let first = config.find(&encode(keys[0].as_bytes())).unwrap();
+        let last = config.find(&encode(keys[2].as_bytes())).unwrap();
+        assert!(first < last);
+        assert!(config.contains("peer_id=3\n"));
+    }
+
+    #[test]
+    /// Test that the peer with the lower route metric wins a prefix shared with another peer,
+    /// regardless of the order the peers were added in, and that the other takes over on removal
+    fn test_wireguard_route_metric() {
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert_eq!(wg.wg_set_key(&X25519SecretKey::new()), "errno=0\n\n");
+
+        let primary = X25519SecretKey::new().public_key();
+        let backup = X25519SecretKey::new().public_key();
+        let set_peer = |key: &X25519PublicKey, metric: u32| {
+            wg.wg_set(&format!(
+                "public_key={}\nallowed_ip=10.0.0.0/8\nroute_metric={}",
+                encode(key.as_bytes()),
+                metric
+            ))
+        };
+        let lookup = || {
+            wg._device
+                .device
+                .read()
+                .route_lookup("10.1.2.3".parse().unwrap())
+        };
+
+        assert_eq!(set_peer(&primary, 10), "errno=0\n\n");
+        assert_eq!(set_peer(&backup, 20), "errno=0\n\n");
+        assert_eq!(lookup().as_ref(), Some(&primary));
+        assert!(wg.wg_get().contains("route_metric=20\n"));
+
+        // Adding the peers in the other order gives the same result
+        let remove = |key: &X25519PublicKey| {
+            wg.wg_set(&format!(
+                "public_key={}\nremove=true",
+                encode(key.as_bytes())
+            ))
+        };
+        assert_eq!(remove(&primary), "errno=0\n\n");
+        assert_eq!(lookup().as_ref(), Some(&backup));
+        assert_eq!(set_peer(&primary, 10), "errno=0\n\n");
+        assert_eq!(lookup().as_ref(), Some(&primary));
+
+        assert_eq!(
+            set_peer(&X25519SecretKey::new().public_key(), 30),
+            "errno=0\n\n"
+        );
+        assert_eq!(lookup().as_ref(), Some(&primary));
+    }
+
+    #[test]
+    /// Test that handshake initiations are only processed from allowed source networks
+    fn test_wireguard_handshake_source_allow() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 2,
+                handshake_source_allow: vec!["127.0.0.1/32".parse().unwrap()],
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let client_key = Arc::new(X25519SecretKey::new());
+        let client_endpoint = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 50001);
+        assert_eq!(
+            wg.wg_set_peer(&client_key.public_key(), &client_endpoint, &[]),
+            "errno=0\n\n"
+        );
+
+        let client = Tunn::new(client_key, Arc::clone(&public_key), None, None, 0, None).unwrap();
+
+        // Send a fresh handshake initiation from the given source and wait for a response
+        let handshake_from = |src: Ipv4Addr| {
+            let mut buf = [0u8; 256];
+            let init = match client.format_handshake_initiation(&mut buf, true) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("Failed to create a handshake initiation"),
+            };
+
+            let sock = UdpSocket::bind((src, 0)).unwrap();
+            sock.set_read_timeout(Some(std::time::Duration::from_secs(1)))
+                .unwrap();
+            sock.send_to(&init, ("127.0.0.1", port)).unwrap();
+            sock.recv(&mut buf).ok().map(|n| buf[..n].to_vec())
+        };
+
+        // 127.0.0.2 is on the loopback interface, but not in the allowed network
+        assert_eq!(handshake_from(Ipv4Addr::new(127, 0, 0, 2)), None);
+
+        let response = handshake_from(Ipv4Addr::new(127, 0, 0, 1)).expect("No handshake response");
+        assert_eq!(response[0], 2); // Handshake response message type
+    }
+
+    #[test]
+    /// Test that a set block with a malformed line is rejected as a whole
+    fn test_wireguard_set_transactional() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let wg = WGHandle::init("192.0.2.0".parse().unwrap(), "::2".parse().unwrap());
+
+        let response = wg.wg_set(&format!(
+            "listen_port={}\nprivate_key={}\nfwmark=not_a_number",
+            port,
+            encode(private_key.as_bytes())
+        ));
+        assert_ne!(response, "errno=0\n\n");
+        assert!(response.starts_with("errno="));
+
+        // None of the valid lines preceding the bad one were applied
+        let config = wg.wg_get();
+        assert!(!config.contains("private_key="));
+        assert!(!config.contains(&format!("listen_port={}\n", port)));
+
+        // An oversized block is refused without being applied either. The device stops reading
+        // early, so the write may fail partway through.
+        let mut socket =
+            UnixStream::connect(format!("/var/run/wireguard/{}.sock", wg.name)).unwrap();
+        let _ = write!(
+            socket,
+            "set=1\nlisten_port={}\n{}\n",
+            port,
+            "replace_peers=false\n".repeat(1 << 16)
+        );
+        let mut response = String::new();
+        socket.read_to_string(&mut response).unwrap();
+        assert_eq!(response, format!("errno={}\n\n", libc::E2BIG));
+        assert!(!wg.wg_get().contains(&format!("listen_port={}\n", port)));
+
+        // A port in use fails the block before the key that precedes it and the peers are applied
+        let taken = std::net::UdpSocket::bind(("0.0.0.0", 0)).unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let peer_key = X25519SecretKey::new().public_key();
+        let response = wg.wg_set(&format!(
+            "private_key={}\nlisten_port={}\npublic_key={}\nallowed_ip=192.0.2.1/32",
+            encode(private_key.as_bytes()),
+            taken_port,
+            encode(peer_key.as_bytes())
+        ));
+        assert_eq!(response, format!("errno={}\n\n", libc::EADDRINUSE));
+        let config = wg.wg_get();
+        assert!(!config.contains("private_key="));
+        assert!(!config.contains("public_key="));
+    }
+
+    #[test]
+    /// Test that UAPI clients that stall or trickle a request are cut off with ETIMEDOUT
+    fn test_wireguard_api_timeout() {
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                api_idle_timeout: std::time::Duration::from_millis(300),
+                api_request_timeout: std::time::Duration::from_secs(1),
+                ..Default::default()
+            },
+        );
+        let port = next_port();
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        let path = format!("/var/run/wireguard/{}.sock", wg.name);
+        let timed_out = format!("errno={}\n\n", libc::ETIMEDOUT);
+
+        // A client that stops in the middle of a line
+        let mut socket = UnixStream::connect(&path).unwrap();
+        let started = std::time::Instant::now();
+        write!(socket, "set=1\nlisten_po").unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).unwrap();
+        assert_eq!(response, timed_out);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(300));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+
+        // A client that never finishes the command line
+        let mut socket = UnixStream::connect(&path).unwrap();
+        write!(socket, "get").unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).unwrap();
+        assert_eq!(response, timed_out);
+
+        // A client that keeps sending a byte at a time is cut off by the request timeout
+        let socket = UnixStream::connect(&path).unwrap();
+        let mut writer = socket.try_clone().unwrap();
+        let started = std::time::Instant::now();
+        let trickle = std::thread::spawn(move || {
+            for b in b"set=1\nreplace_peers=false\nreplace_peers=false\n" {
+                if writer.write_all(&[*b]).is_err() {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+        });
+        let mut response = String::new();
+        (&socket).read_to_string(&mut response).unwrap();
+        assert_eq!(response, timed_out);
+        assert!(started.elapsed() >= std::time::Duration::from_secs(1));
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+        trickle.join().unwrap();
+
+        // None of it was applied, and the device still serves requests
+        assert!(wg.wg_get().contains(&format!("listen_port={}\n", port)));
+    }
+
+    /// Send count datagrams over a tunnel with the given TX batch linger and TUN read ahead, to a
+    /// peer emulated with Tunn, and check the peer receives them in order. Returns the device.
+    fn send_in_order(
+        tx_batch_linger: std::time::Duration,
+        tun_read_buffers: usize,
+        count: u32,
+    ) -> WGHandle {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                #[cfg(target_os = "linux")]
+                use_multi_queue: false,
+                tx_batch_linger,
+                tun_read_buffers,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer_sock
+            .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+            .unwrap();
+        let peer_key = Arc::new(X25519SecretKey::new());
+        let peer_ip = next_ip();
+        assert_eq!(
+            wg.wg_set_peer(
+                &peer_key.public_key(),
+                &peer_sock.local_addr().unwrap(),
+                &[AllowedIp {
+                    ip: peer_ip,
+                    cidr: 32
+                }]
+            ),
+            "errno=0\n\n"
+        );
+
+        wg.start();
+        Command::new("ip")
+            .args(&["route", "add", &format!("{}/32", peer_ip), "dev", &wg.name])
+            .status()
+            .expect("failed to add route");
+
+        let peer = Tunn::new(peer_key, public_key, None, None, 0, None).unwrap();
+
+        // The first datagram triggers a handshake, the rest are sent once it completes
+        let sender = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let target = SocketAddr::new(peer_ip, 9999);
+        sender.send_to(&0u32.to_be_bytes(), target).unwrap();
+
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        let mut received = vec![];
+
+        while let Ok((n, addr)) = peer_sock.recv_from(&mut buf) {
+            match peer.decapsulate(Some(addr.ip()), &buf[..n], &mut dst) {
+                TunnResult::WriteToNetwork(packet) => {
+                    peer_sock.send_to(packet, addr).unwrap();
+                    while let TunnResult::WriteToNetwork(packet) =
+                        peer.decapsulate(None, &[], &mut dst)
+                    {
+                        peer_sock.send_to(packet, addr).unwrap();
+                    }
+                }
+                TunnResult::WriteToTunnelV4(packet, _) => {
+                    // Skip the IPv4 and UDP headers
+                    received.push(u32::from_be_bytes(make_array(&packet[28..32])));
+
+                    if received.len() == 1 {
+                        let sender = sender.try_clone().unwrap();
+                        std::thread::spawn(move || {
+                            for i in 1..count {
+                                let _ = sender.send_to(&i.to_be_bytes(), target);
+                            }
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        assert!(received.len() > 1, "No packets went through the tunnel");
+        assert!(
+            received.windows(2).all(|w| w[0] < w[1]),
+            "Packets were reordered"
+        );
+        wg
+    }
+
+    #[test]
+    /// Test that the configured event batch size reaches the poller, and the device still works
+    fn test_wireguard_event_batch_size() {
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 2,
+                event_batch_size: 16,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(wg._device.device.read().queue.batch_size(), 16);
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+
+        let config = DeviceConfig {
+            event_batch_size: 0,
+            ..Default::default()
+        };
+        assert!(DeviceHandle::<TunSocket, UDPSocket>::new("utun-invalid", config).is_err());
+    }
+
+    #[test]
+    /// Test that a zero linger sends a packet at once, and a linger holds a lone packet for that
+    /// long before sending it in a batch
+    fn test_wg_tx_batch_linger() {
+        let linger = std::time::Duration::from_millis(300);
+        for &tx_batch_linger in &[std::time::Duration::ZERO, linger] {
+            let port = next_port();
+            let private_key = X25519SecretKey::new();
+            let public_key = Arc::new(private_key.public_key());
+
+            let mut wg = WGHandle::init_with_config(
+                next_ip(),
+                next_ip_v6(),
+                DeviceConfig {
+                    n_threads: 1,
+                    use_connected_socket: false,
+                    tx_batch_linger,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+            assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+            let peer_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+            peer_sock
+                .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+                .unwrap();
+            let peer_key = Arc::new(X25519SecretKey::new());
+            let peer_ip = next_ip();
+            assert_eq!(
+                wg.wg_set_peer(
+                    &peer_key.public_key(),
+                    &peer_sock.local_addr().unwrap(),
+                    &[AllowedIp {
+                        ip: peer_ip,
+                        cidr: 32
+                    }]
+                ),
+                "errno=0\n\n"
+            );
+            wg.start();
+            Command::new("ip")
+                .args(&["route", "add", &format!("{}/32", peer_ip), "dev", &wg.name])
+                .status()
+                .expect("failed to add route");
+
+            // Establish a session with the first datagram, which is queued during the handshake
+            let peer = Tunn::new(peer_key, public_key, None, None, 0, None).unwrap();
+            let sender = UdpSocket::bind("0.0.0.0:0").unwrap();
+            let target = SocketAddr::new(peer_ip, 9999);
+            sender.send_to(b"session", target).unwrap();
+            let mut buf = [0u8; 2048];
+            let mut dst = [0u8; 2048];
+            let mut receive = || loop {
+                let (n, addr) = peer_sock.recv_from(&mut buf).unwrap();
+                match peer.decapsulate(Some(addr.ip()), &buf[..n], &mut dst) {
+                    TunnResult::WriteToNetwork(packet) => {
+                        peer_sock.send_to(packet, addr).unwrap();
+                        while let TunnResult::WriteToNetwork(packet) =
+                            peer.decapsulate(None, &[], &mut dst)
+                        {
+                            peer_sock.send_to(packet, addr).unwrap();
+                        }
+                    }
+                    TunnResult::WriteToTunnelV4(packet, _) => return packet[28..].to_vec(),
+                    _ => {}
+                }
+            };
+            assert_eq!(receive(), b"session");
+
+            let sent = std::time::Instant::now();
+            sender.send_to(b"lone", target).unwrap();
+            assert_eq!(receive(), b"lone");
+            let elapsed = sent.elapsed();
+            let stats = || wg._device.device.read().tx_batch_stats();
+            if tx_batch_linger.is_zero() {
+                assert!(elapsed < linger);
+                assert_eq!(stats(), (0, 0));
+            } else {
+                assert!(elapsed >= linger);
+                // The batch is counted just after it was sent
+                while stats() == (0, 0) && sent.elapsed() < std::time::Duration::from_secs(2) {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                let (batched, sends) = stats();
+                assert!(batched >= 1);
+                assert_eq!(batched, sends);
+            }
+        }
+    }
+
+    #[test]
+    /// Test that batched packets are sent in order, in fewer system calls than packets
+    fn test_wg_tx_batch_order() {
+        let wg = send_in_order(std::time::Duration::from_micros(200), 1, 10_000);
+        let (batched, sends) = wg._device.device.read().tx_batch_stats();
+        assert!(batched > 0);
+        assert!(sends <= batched);
+    }
+
+    #[test]
+    /// Test that packets read ahead from the tunnel interface are sent in order, in batches
+    /// without a linger, and that the number of read buffers is validated
+    fn test_wg_tun_read_buffers() {
+        let wg = send_in_order(std::time::Duration::ZERO, 8, 10_000);
+        let (batched, sends) = wg._device.device.read().tx_batch_stats();
+        assert!(batched > 0);
+        assert!(sends <= batched);
+
+        for &tun_read_buffers in &[0, 65] {
+            let config = DeviceConfig {
+                tun_read_buffers,
+                ..Default::default()
+            };
+            assert!(matches!(
+                DeviceHandle::<TunSocket, UDPSocket>::new("utun-invalid", config),
+                Err(crate::device::Error::InvalidConfig(_))
+            ));
+        }
+    }
+
+    /// Connect to a Linux abstract namespace unix socket
+    #[cfg(target_os = "linux")]
+    fn connect_abstract(name: &str) -> UnixStream {
+        use std::os::unix::io::FromRawFd;
+
+        let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as _;
+        for (dst, src) in addr.sun_path[1..].iter_mut().zip(name.as_bytes()) {
+            *dst = *src as _;
+        }
+        let addr_len = std::mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+
+        unsafe {
+            let fd = libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0);
+            assert_ne!(fd, -1);
+            assert_ne!(
+                libc::connect(fd, &addr as *const libc::sockaddr_un as _, addr_len as _),
+                -1
+            );
+            UnixStream::from_raw_fd(fd)
+        }
+    }
+
+    /// Perform a single UAPI request over an established stream
+    fn api_request<S: Read + Write>(mut stream: S, request: &str) -> String {
+        write!(stream, "{}", request).unwrap();
+
+        let mut ret = String::new();
+        stream.read_to_string(&mut ret).unwrap();
+        ret
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Test a full get/set exchange over an abstract namespace unix socket
+    fn test_wireguard_abstract_socket() {
+        let name = format!("boringtun-test-{}", next_port());
+        let port = next_port();
+
+        let _device = DeviceHandle::<TunSocket, UDPSocket>::new(
+            &format!("utun{}", NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)),
+            DeviceConfig {
+                n_threads: 2,
+                api_socket: api::ApiSocket::Abstract(name.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            api_request(
+                connect_abstract(&name),
+                &format!("set=1\nlisten_port={}\n\n", port)
+            ),
+            "errno=0\n\n"
+        );
+        assert_eq!(
+            api_request(connect_abstract(&name), "get=1\n\n"),
+            format!("listen_port={}\nerrno=0\n\n", port)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "tcp-api")]
+    /// Test a full get/set exchange over the loopback TCP transport, with and without a token
+    fn test_wireguard_tcp_api() {
+        let api_port = next_port();
+        let port = next_port();
+        let token = "open-sesame-0123456789".to_owned();
+
+        // A token that is empty, or short enough to guess, is refused
+        for short in &["", "sesame"] {
+            assert!(DeviceHandle::<TunSocket, UDPSocket>::new(
+                &format!("utun{}", NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)),
+                DeviceConfig {
+                    api_socket: api::ApiSocket::Tcp {
+                        port: api_port,
+                        token: short.to_string(),
+                    },
+                    ..Default::default()
+                },
+            )
+            .is_err());
+        }
+
+        let _device = DeviceHandle::<TunSocket, UDPSocket>::new(
+            &format!("utun{}", NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)),
+            DeviceConfig {
+                n_threads: 2,
+                api_socket: api::ApiSocket::Tcp {
+                    port: api_port,
+                    token: token.clone(),
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let connect = || std::net::TcpStream::connect(("127.0.0.1", api_port)).unwrap();
+
+        // Without the right token nothing is served
+        assert_eq!(
+            api_request(connect(), "token=wrong\nget=1\n\n"),
+            format!("errno={}\n\n", libc::EACCES)
+        );
+        assert_eq!(
+            api_request(connect(), "get=1\n\n"),
+            format!("errno={}\n\n", libc::EACCES)
+        );
+
+        assert_eq!(
+            api_request(
+                connect(),
+                &format!("token={}\nset=1\nlisten_port={}\n\n", token, port)
+            ),
+            "errno=0\n\n"
+        );
+        assert_eq!(
+            api_request(connect(), &format!("token={}\nget=1\n\n", token)),
+            format!("listen_port={}\nerrno=0\n\n", port)
+        );
+    }
+
     /// Test if wireguard can handle simple ipv4 connections, don't use a connected socket
     #[test]
     fn test_wg_start_ipv4_non_connected() {
@@ -566,8 +1502,7 @@ mod tests {
                 n_threads: 2,
                 logger,
                 use_connected_socket: false,
-                #[cfg(target_os = "linux")]
-                use_multi_queue: true,
+                ..Default::default()
             },
         );
 
@@ -726,8 +1661,7 @@ mod tests {
                 n_threads: 2,
                 logger,
                 use_connected_socket: false,
-                #[cfg(target_os = "linux")]
-                use_multi_queue: true,
+                ..Default::default()
             },
         );
 
@@ -856,4 +1790,3224 @@ mod tests {
             t.join().unwrap();
         }
     }
+
+    /// Test that addresses assigned over the API are applied to the interface
+    #[test]
+    fn test_wg_set_address() {
+        let addr_v4 = next_ip();
+        let addr_v6 = next_ip_v6();
+        let wg = WGHandle::init(addr_v4, addr_v6);
+
+        assert_eq!(wg.wg_set(&format!("address={}/24", addr_v4)), "errno=0\n\n");
+        assert_eq!(wg.wg_set(&format!("address={}/64", addr_v6)), "errno=0\n\n");
+        assert_eq!(wg.wg_set("address=192.0.2.1/33"), "errno=22\n\n");
+
+        let output = Command::new("ifconfig")
+            .arg(&wg.name)
+            .output()
+            .expect("failed to show the interface");
+        let output = String::from_utf8_lossy(&output.stdout);
+
+        assert!(output.contains(&format!("inet {}", addr_v4)));
+        assert!(output.contains(&format!("inet6 {}", addr_v6)));
+        assert!(output.contains("UP"));
+    }
+
+    /// Test that the health of the device reflects a session established with a peer
+    #[test]
+    fn test_wg_health() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer_sock
+            .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+            .unwrap();
+        let peer_key = Arc::new(X25519SecretKey::new());
+        assert_eq!(
+            wg.wg_set_peer(
+                &peer_key.public_key(),
+                &peer_sock.local_addr().unwrap(),
+                &[AllowedIp {
+                    ip: next_ip(),
+                    cidr: 32
+                }]
+            ),
+            "errno=0\n\n"
+        );
+
+        let health = wg._device.device.read().health();
+        assert_eq!(health.peers, 1);
+        assert_eq!(health.live_sessions, 0);
+        assert_eq!(health.since_last_handshake, None);
+        assert!(health.iface_open && health.socket_open);
+        assert!(health.is_degraded());
+
+        // Complete a handshake with the device over loopback
+        let peer = Tunn::new(peer_key, public_key, None, None, 0, None).unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+
+        match peer.format_handshake_initiation(&mut dst, false) {
+            TunnResult::WriteToNetwork(packet) => peer_sock.send_to(packet, device_addr).unwrap(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let n = peer_sock.recv(&mut buf).unwrap();
+        match peer.decapsulate(None, &buf[..n], &mut dst) {
+            TunnResult::WriteToNetwork(packet) => peer_sock.send_to(packet, device_addr).unwrap(),
+            _ => panic!("Expected a keepalive"),
+        };
+
+        // The device only considers the session established once the keepalive is processed
+        let started = std::time::Instant::now();
+        let health = loop {
+            let health = wg._device.device.read().health();
+            if health.live_sessions > 0 || started.elapsed().as_secs() > 5 {
+                break health;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+
+        assert_eq!(health.live_sessions, 1);
+        assert!(health.since_last_handshake.unwrap().as_secs() < 5);
+        assert!(!health.is_degraded());
+        assert!(health.is_healthy());
+    }
+
+    /// Test that with ECN passthrough the ECN codepoint of the inner packet is copied to the
+    /// outer packet
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_wg_ecn_passthrough() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ecn_passthrough: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer_sock = UDPSocket::new().unwrap().bind(0).unwrap();
+        peer_sock.set_recv_ecn().unwrap();
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], peer_sock.port().unwrap()));
+        let peer_key = Arc::new(X25519SecretKey::new());
+        let peer_ip = next_ip();
+        assert_eq!(
+            wg.wg_set_peer(
+                &peer_key.public_key(),
+                &peer_addr,
+                &[AllowedIp {
+                    ip: peer_ip,
+                    cidr: 32
+                }]
+            ),
+            "errno=0\n\n"
+        );
+
+        wg.start();
+        Command::new("ip")
+            .args(&["route", "add", &format!("{}/32", peer_ip), "dev", &wg.name])
+            .status()
+            .expect("failed to add route");
+
+        // Send an ECT(0) datagram into the tunnel, the first one triggers the handshake
+        let sender = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let tos = libc::c_int::from(ecn::ECN_ECT0);
+        assert_eq!(
+            unsafe {
+                libc::setsockopt(
+                    sender.as_raw_fd(),
+                    libc::IPPROTO_IP,
+                    libc::IP_TOS,
+                    &tos as *const libc::c_int as _,
+                    std::mem::size_of_val(&tos) as _,
+                )
+            },
+            0
+        );
+        let target = SocketAddr::new(peer_ip, 9999);
+        sender.send_to(b"init", target).unwrap();
+
+        let peer = Tunn::new(peer_key, public_key, None, None, 0, None).unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+
+        // Packets queued during the handshake are sent without ECN, so only check a datagram sent
+        // once the queued one arrived
+        let started = std::time::Instant::now();
+        let (outer_ecn, inner_ecn) = loop {
+            assert!(
+                started.elapsed().as_secs() < 5,
+                "No packet went through the tunnel"
+            );
+            let (packet, outer_ecn) = match peer_sock.recvfrom_ecn(&mut buf) {
+                Ok((_, packet, ecn)) => (packet, ecn),
+                Err(_) => continue,
+            };
+
+            match peer.decapsulate(None, packet, &mut dst) {
+                TunnResult::WriteToNetwork(packet) => {
+                    peer_sock.sendto(packet, device_addr);
+                    while let TunnResult::WriteToNetwork(packet) =
+                        peer.decapsulate(None, &[], &mut dst)
+                    {
+                        peer_sock.sendto(packet, device_addr);
+                    }
+                }
+                TunnResult::WriteToTunnelV4(packet, _) if packet.ends_with(b"init") => {
+                    sender.send_to(b"ect0", target).unwrap();
+                }
+                TunnResult::WriteToTunnelV4(packet, _) if packet.ends_with(b"ect0") => {
+                    break (outer_ecn, ecn::inner_ecn(packet));
+                }
+                _ => {}
+            }
+        };
+
+        assert_eq!(inner_ecn, ecn::ECN_ECT0);
+        assert_eq!(outer_ecn, ecn::ECN_ECT0);
+    }
+
+    /// Test that sockets sharing a port with SO_REUSEPORT all receive traffic, and that the
+    /// device opens the configured number of listen sockets
+    #[test]
+    fn test_wg_listen_sockets() {
+        let port = next_port();
+        let socks: Vec<UDPSocket> = (0..4)
+            .map(|_| {
+                UDPSocket::new()
+                    .and_then(|s| s.set_non_blocking())
+                    .and_then(|s| s.set_reuse())
+                    .and_then(|s| s.set_reuse_port())
+                    .and_then(|s| s.bind(port))
+                    .unwrap()
+            })
+            .collect();
+
+        // The kernel hashes flows across the sockets, so use many source ports
+        let dst = SocketAddr::from(([127, 0, 0, 1], port));
+        for _ in 0..256 {
+            UdpSocket::bind("127.0.0.1:0")
+                .unwrap()
+                .send_to(b"shard", dst)
+                .unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let mut buf = [0u8; 16];
+        for sock in &socks {
+            let mut received = 0;
+            while sock.recvfrom(&mut buf).is_ok() {
+                received += 1;
+            }
+            assert!(received > 0, "A socket did not receive any traffic");
+        }
+
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                listen_sockets: 4,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+        let device = wg._device.device.read();
+        assert_eq!(device.udp_shards.len(), 6);
+
+        // Every worker serves a socket of each address family on its own poll
+        let listen_fds: Vec<_> = device
+            .udp4
+            .iter()
+            .chain(&device.udp6)
+            .chain(&device.udp_shards)
+            .map(|sock| sock.as_raw_fd())
+            .collect();
+        assert_eq!(device.worker_queues.len(), 4);
+        for queue in &device.worker_queues {
+            let served = queue.registered();
+            assert_eq!(
+                served
+                    .iter()
+                    .filter(|(fd, _)| listen_fds.contains(fd))
+                    .count(),
+                2
+            );
+        }
+        assert!(device
+            .queue
+            .registered()
+            .iter()
+            .all(|(fd, _)| !listen_fds.contains(fd)));
+
+        let config = DeviceConfig {
+            listen_sockets: 0,
+            ..Default::default()
+        };
+        assert!(DeviceHandle::<TunSocket, UDPSocket>::new("utun99", config).is_err());
+    }
+
+    /// Test that a data packet with a corrupted authentication tag is reported to the decryption
+    /// failure callback
+    #[test]
+    fn test_wg_decrypt_failure_callback() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                on_decrypt_failure: Some(Box::new(move |failure| {
+                    let _ = tx.lock().unwrap().send(failure);
+                })),
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer_sock = UDPSocket::new().unwrap().bind(0).unwrap();
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], peer_sock.port().unwrap()));
+        let peer_key = Arc::new(X25519SecretKey::new());
+        assert_eq!(
+            wg.wg_set_peer(
+                &peer_key.public_key(),
+                &peer_addr,
+                &[AllowedIp {
+                    ip: next_ip(),
+                    cidr: 32
+                }]
+            ),
+            "errno=0\n\n"
+        );
+        wg.start();
+
+        // Complete a handshake with the device, so it has a session to decrypt with
+        let peer = Tunn::new(peer_key, public_key, None, None, 0, None).unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        match peer.format_handshake_initiation(&mut dst, false) {
+            TunnResult::WriteToNetwork(packet) => peer_sock.sendto(packet, device_addr),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let (_, response) = peer_sock.recvfrom(&mut buf).unwrap();
+        if let TunnResult::WriteToNetwork(keepalive) = peer.decapsulate(None, response, &mut dst) {
+            peer_sock.sendto(keepalive, device_addr);
+        }
+
+        // Flip a bit of the authentication tag
+        let packet = [
+            0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2,
+        ];
+        let mut data = match peer.encapsulate(&packet, &mut dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        *data.last_mut().unwrap() ^= 1;
+        peer_sock.sendto(&data, device_addr);
+
+        let failure = rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("The callback did not fire");
+        assert_eq!(failure.reason, DecryptFailureReason::AuthFailed);
+        assert_eq!(failure.src, peer_addr);
+    }
+
+    /// Test that a packet injected with send_to_peer is encapsulated, sent after the handshake it
+    /// triggers, and decapsulates on the peer
+    #[test]
+    fn test_wg_send_to_peer() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer_sock = UDPSocket::new().unwrap().bind(0).unwrap();
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], peer_sock.port().unwrap()));
+        let peer_key = Arc::new(X25519SecretKey::new());
+        let peer_public_key = peer_key.public_key();
+        assert_eq!(
+            wg.wg_set_peer(
+                &peer_public_key,
+                &peer_addr,
+                &[AllowedIp {
+                    ip: next_ip(),
+                    cidr: 32
+                }]
+            ),
+            "errno=0\n\n"
+        );
+        wg.start();
+
+        let mut probe = vec![
+            0x45, 0, 0, 24, 0, 0, 0, 0, 64, 17, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2,
+        ];
+        probe.extend_from_slice(b"ping");
+        {
+            let device = wg._device.device.read();
+            device.send_to_peer(&peer_public_key, &probe).unwrap();
+            assert!(matches!(
+                device.send_to_peer(&X25519SecretKey::new().public_key(), &probe),
+                Err(crate::device::Error::UnknownPeer)
+            ));
+        }
+
+        // The probe triggered a handshake, complete it and wait for the queued probe
+        let peer = Tunn::new(peer_key, public_key, None, None, 0, None).unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        loop {
+            let (_, packet) = peer_sock.recvfrom(&mut buf).unwrap();
+            match peer.decapsulate(None, packet, &mut dst) {
+                TunnResult::WriteToNetwork(packet) => {
+                    peer_sock.sendto(packet, device_addr);
+                }
+                TunnResult::WriteToTunnelV4(packet, _) => {
+                    assert_eq!(packet, &probe[..]);
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Test that the UAPI socket is created with the configured file mode and owner, regardless
+    /// of the umask
+    #[test]
+    fn test_wg_api_socket_mode() {
+        use std::os::unix::fs::MetadataExt;
+
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        let path = format!("/var/run/wireguard/{}.sock", wg.name);
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        assert!(wg.wg_get().ends_with("errno=0\n\n"));
+
+        let old_mask = unsafe { libc::umask(0o077) };
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                api_socket_mode: 0o660,
+                api_socket_owner: Some((0, 1)),
+                ..Default::default()
+            },
+        );
+        unsafe { libc::umask(old_mask) };
+
+        let metadata = std::fs::metadata(format!("/var/run/wireguard/{}.sock", wg.name)).unwrap();
+        assert_eq!(metadata.mode() & 0o777, 0o660);
+        assert_eq!((metadata.uid(), metadata.gid()), (0, 1));
+    }
+
+    /// Test that a disabled peer passes no traffic but keeps its configuration, and that traffic
+    /// resumes once it is enabled again
+    #[test]
+    fn test_wg_peer_enabled() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer = loopback_peer(&mut wg, &private_key);
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+
+        // Send a datagram into the tunnel, and report whether it reached the peer in time
+        let send_and_receive = |payload: &[u8], timeout: std::time::Duration| {
+            peer.send_into_tunnel(payload);
+            let started = std::time::Instant::now();
+            while let Some((_, packet)) =
+                peer.pump_for(device_addr, timeout.saturating_sub(started.elapsed()))
+            {
+                if packet.ends_with(payload) {
+                    return true;
+                }
+            }
+            false
+        };
+
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(send_and_receive(b"before", timeout));
+
+        let set_enabled = |enabled| {
+            wg.wg_set(&format!(
+                "public_key={}\nenabled={}",
+                encode(peer.public_key.as_bytes()),
+                enabled
+            ))
+        };
+        assert_eq!(set_enabled(false), "errno=0\n\n");
+        assert!(wg.wg_get().contains("enabled=false\n"));
+        assert!(!send_and_receive(
+            b"paused",
+            std::time::Duration::from_millis(500)
+        ));
+
+        // The configuration survived, and traffic flows again
+        assert_eq!(set_enabled(true), "errno=0\n\n");
+        let config = wg.wg_get();
+        assert!(!config.contains("enabled=false"));
+        assert!(config.contains(&format!("allowed_ip={}/32", peer.ip)));
+        assert!(send_and_receive(b"after", timeout));
+    }
+
+    #[test]
+    /// Test that detailed accounting counts the inner packets of a peer by protocol
+    fn test_wg_detailed_accounting() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+        assert_eq!(wg.wg_set("accounting=detailed"), "errno=0\n\n");
+        assert!(wg.wg_get().contains("accounting=detailed\n"));
+
+        // One UDP datagram into the tunnel makes the device establish a session with the peer
+        let peer = loopback_peer(&mut wg, &private_key);
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        peer.send_into_tunnel(b"accounted");
+        assert!(peer.pump_until_delivered(device_addr).is_some());
+
+        // The peer sends TCP and ICMP packets with bad checksums to an address that is not
+        // local, so the kernel drops them without a reply
+        let inner_packet = |protocol: u8, len: usize| {
+            let mut packet = peer.inner_packet();
+            packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            packet[9] = protocol;
+            packet.resize(len, 0);
+            packet
+        };
+        for (protocol, len) in &[(6, 40), (1, 28), (6, 60), (1, 28), (6, 40)] {
+            peer.send_inner(&inner_packet(*protocol, *len), device_addr);
+        }
+
+        let stats = || wg._device.device.read().peers()[0].rx_protocols.unwrap();
+        let started = std::time::Instant::now();
+        while stats().icmp.packets + stats().tcp.packets < 5
+            && started.elapsed() < std::time::Duration::from_secs(5)
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let rx = stats();
+        assert_eq!((rx.tcp.packets, rx.tcp.bytes), (3, 140));
+        assert_eq!((rx.icmp.packets, rx.icmp.bytes), (2, 56));
+        assert_eq!(rx.udp.packets, 0);
+        let tx = wg._device.device.read().peers()[0].tx_protocols.unwrap();
+        assert_eq!(tx.udp.packets, 1);
+        assert_eq!(tx.tcp.packets, 0);
+
+        // Without detailed accounting nothing is reported
+        assert_eq!(wg.wg_set("accounting=basic"), "errno=0\n\n");
+        assert!(wg._device.device.read().peers()[0].rx_protocols.is_none());
+    }
+
+    /// Test that the decision for an outbound packet is explained without sending it
+    #[test]
+    fn test_wg_explain_outbound() {
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&X25519SecretKey::new()), "errno=0\n\n");
+
+        let peer_key = X25519SecretKey::new().public_key();
+        let endpoint = SocketAddr::from(([127, 0, 0, 1], next_port()));
+        let peer_ip = next_ip();
+        assert_eq!(
+            wg.wg_set_peer(
+                &peer_key,
+                &endpoint,
+                &[AllowedIp {
+                    ip: peer_ip,
+                    cidr: 32
+                }]
+            ),
+            "errno=0\n\n"
+        );
+
+        let packet_to = |dst: IpAddr| {
+            let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 192, 0, 2, 1];
+            match dst {
+                IpAddr::V4(ip) => packet.extend_from_slice(&ip.octets()),
+                _ => unreachable!(),
+            }
+            packet
+        };
+        let explain = |packet: &[u8]| wg._device.device.read().explain_outbound(packet);
+
+        let unrouted = IpAddr::from([198, 51, 100, 1]);
+        assert_eq!(
+            explain(&packet_to(unrouted)),
+            OutboundDecision::NoRoute(unrouted)
+        );
+        assert_eq!(
+            explain(&packet_to(peer_ip)),
+            OutboundDecision::WouldHandshake { peer_id: 1 }
+        );
+        assert_eq!(explain(&[0x45, 0, 0]), OutboundDecision::Malformed);
+
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nenabled=false",
+                encode(peer_key.as_bytes())
+            )),
+            "errno=0\n\n"
+        );
+        assert_eq!(
+            explain(&packet_to(peer_ip)),
+            OutboundDecision::PeerDisabled { peer_id: 1 }
+        );
+
+        // Explaining a packet does not start a handshake
+        let device = wg._device.device.read();
+        assert!(!device.peers[&peer_key].tunnel.is_handshake_in_progress());
+    }
+
+    /// Test that the connected sockets of peers are bound to their own local addresses
+    #[test]
+    fn test_wg_peer_bind_addr() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+        wg.start();
+
+        // Every address of 127.0.0.0/8 is assigned to the loopback interface
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let bind_addrs = [IpAddr::from([127, 0, 0, 2]), IpAddr::from([127, 0, 0, 3])];
+        let mut peers = vec![];
+        for bind_addr in &bind_addrs {
+            let peer_sock = UDPSocket::new()
+                .and_then(|s| s.set_non_blocking())
+                .and_then(|s| s.bind(0))
+                .unwrap();
+            let peer_key = Arc::new(X25519SecretKey::new());
+            let peer_public_key = peer_key.public_key();
+            assert_eq!(
+                wg.wg_set(&format!(
+                    "public_key={}\nendpoint=127.0.0.1:{}\npeer_bind_addr={}\nallowed_ip={}/32",
+                    encode(peer_public_key.as_bytes()),
+                    peer_sock.port().unwrap(),
+                    bind_addr,
+                    next_ip()
+                )),
+                "errno=0\n\n"
+            );
+            let tunn = Tunn::new(peer_key, Arc::clone(&public_key), None, None, 0, None).unwrap();
+            peers.push((peer_public_key, peer_sock, tunn));
+        }
+        assert!(wg
+            .wg_get()
+            .contains(&format!("peer_bind_addr={}\n", bind_addrs[0])));
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\npeer_bind_addr=nowhere",
+                encode(peers[0].0.as_bytes())
+            )),
+            "errno=22\n\n"
+        );
+
+        // A handshake from each peer makes the device connect a socket to it
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        for (_, peer_sock, tunn) in &peers {
+            match tunn.format_handshake_initiation(&mut buf, false) {
+                TunnResult::WriteToNetwork(packet) => peer_sock.sendto(packet, device_addr),
+                _ => panic!("Expected a handshake initiation"),
+            };
+            let started = std::time::Instant::now();
+            let mut connected = false;
+            while !connected && started.elapsed() < std::time::Duration::from_secs(5) {
+                match peer_sock.recvfrom(&mut buf) {
+                    Ok((_, packet)) => {
+                        connected = matches!(
+                            tunn.decapsulate(None, packet, &mut dst),
+                            TunnResult::WriteToNetwork(_)
+                        )
+                    }
+                    Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+                }
+            }
+            assert!(connected);
+        }
+
+        for ((peer_public_key, ..), bind_addr) in peers.iter().zip(&bind_addrs) {
+            let device = wg._device.device.read();
+            let peer = device.peers.get(peer_public_key).unwrap();
+            let fd = peer.endpoint().conn.as_ref().unwrap().as_raw_fd();
+            let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            assert_eq!(
+                unsafe {
+                    libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len)
+                },
+                0
+            );
+            let local = IpAddr::from(u32::from_be(addr.sin_addr.s_addr).to_be_bytes());
+            assert_eq!(&local, bind_addr);
+            assert_eq!(u16::from_be(addr.sin_port), port);
+        }
+    }
+
+    /// Test that the current session of a peer reports its age and the traffic it carried
+    #[test]
+    fn test_wg_active_sessions() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer = loopback_peer(&mut wg, &private_key);
+        assert!(wg._device.device.read().active_sessions().is_empty());
+
+        // A UDP datagram into the tunnel makes the device establish a session with the peer
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        peer.send_into_tunnel(b"session");
+        assert!(peer.pump_until_delivered(device_addr).is_some());
+
+        // The peer answers with two packets to an address that is not local
+        let inner_packet = peer.inner_packet();
+        for _ in 0..2 {
+            peer.send_inner(&inner_packet, device_addr);
+        }
+
+        let sessions = || wg._device.device.read().active_sessions();
+        let started = std::time::Instant::now();
+        while sessions()[0].rx_bytes < 80 && started.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let sessions = sessions();
+        assert_eq!(sessions.len(), 1);
+        let session = &sessions[0];
+        assert_eq!(session.peer_id, 1);
+        assert_eq!(session.public_key.as_bytes(), peer.public_key.as_bytes());
+        // The datagram is sent with its IPv4 and UDP headers, keepalives carry no bytes
+        assert_eq!(session.tx_bytes, 20 + 8 + b"session".len());
+        // The indices of the two sides are swapped
+        let peer_session = peer.tunn.session_stats().unwrap();
+        assert_eq!(session.local_index, peer_session.peer_index);
+        assert_eq!(session.peer_index, peer_session.local_index);
+        assert_eq!(session.rx_bytes, 80);
+        assert!(session.age <= std::time::Duration::from_secs(5));
+        assert!(session.time_to_rekey <= std::time::Duration::from_secs(120));
+        assert!(session.time_to_rekey >= std::time::Duration::from_secs(110));
+    }
+
+    #[test]
+    /// Test that expiring the session of a peer makes the next packet to it start a new handshake
+    fn test_wg_expire_session() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer = loopback_peer(&mut wg, &private_key);
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        // Send a datagram into the tunnel, answer the handshakes of the device until it arrives
+        let send = || {
+            peer.send_into_tunnel(b"session");
+            peer.pump_until_delivered(device_addr).is_some()
+        };
+
+        assert!(send());
+        assert_eq!(peer.initiations.get(), 1);
+        let sessions = || wg._device.device.read().active_sessions();
+        let index = sessions()[0].local_index;
+
+        wg._device
+            .device
+            .read()
+            .expire_session(&peer.public_key)
+            .unwrap();
+        assert!(sessions().is_empty());
+
+        // The session is not used again, a new one is established
+        assert!(send());
+        assert_eq!(peer.initiations.get(), 2);
+        assert_ne!(sessions()[0].local_index, index);
+    }
+
+    #[test]
+    /// Test that an idle peer has its session torn down, its configuration kept, and traffic
+    /// to it starts a new handshake
+    fn test_wg_idle_timeout() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer = loopback_peer(&mut wg, &private_key);
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        // Send a datagram into the tunnel, answer the handshakes of the device until it arrives
+        let send = || {
+            peer.send_into_tunnel(b"session");
+            peer.pump_until_delivered(device_addr).is_some()
+        };
+
+        assert!(send());
+        assert_eq!(peer.initiations.get(), 1);
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nidle_timeout=1",
+                encode(peer.public_key.as_bytes())
+            )),
+            "errno=0\n\n"
+        );
+        assert!(wg.wg_get().contains("idle_timeout=1\n"));
+
+        // Timers count whole seconds
+        let sessions = || wg._device.device.read().active_sessions();
+        let started = std::time::Instant::now();
+        while !sessions().is_empty() && started.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert!(sessions().is_empty());
+        assert!(started.elapsed() >= std::time::Duration::from_millis(500));
+
+        // The peer is still configured, and traffic to it resumes with a new handshake
+        let config = wg.wg_get();
+        assert!(config.contains(&format!("allowed_ip={}/32", peer.ip)));
+        assert!(config.contains(&format!("endpoint={}", peer.addr)));
+        assert!(send());
+        assert_eq!(peer.initiations.get(), 2);
+        assert_eq!(sessions().len(), 1);
+    }
+
+    #[test]
+    /// Test that a peer whose endpoint does not answer handshakes fails over to its backup
+    /// endpoint, stays there, and fails back once the primary answers a probe
+    fn test_wg_backup_endpoint() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                fast_handshake_retries: 10,
+                fast_handshake_retry_interval: std::time::Duration::from_millis(300),
+                failover_attempts: 2,
+                failover_probe_interval: std::time::Duration::from_secs(1),
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let bind = || {
+            UDPSocket::new()
+                .and_then(|s| s.set_non_blocking())
+                .and_then(|s| s.bind(0))
+                .unwrap()
+        };
+        let (primary_sock, backup_sock) = (bind(), bind());
+        let primary = SocketAddr::from(([127, 0, 0, 1], primary_sock.port().unwrap()));
+        let backup = SocketAddr::from(([127, 0, 0, 1], backup_sock.port().unwrap()));
+        let peer_key = Arc::new(X25519SecretKey::new());
+        let peer_public_key = peer_key.public_key();
+        let peer_ip = next_ip();
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nendpoint={}\npeer_backup_endpoint={}\nallowed_ip={}/32",
+                encode(peer_public_key.as_bytes()),
+                primary,
+                backup,
+                peer_ip
+            )),
+            "errno=0\n\n"
+        );
+        wg.start();
+        Command::new("ip")
+            .args(&["route", "add", &format!("{}/32", peer_ip), "dev", &wg.name])
+            .status()
+            .expect("failed to add route");
+
+        let peer = Tunn::new(peer_key, public_key, None, None, 0, None).unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        // Answer a datagram from sock as the peer, true once a packet came out of the tunnel
+        let mut answer = |sock: &UDPSocket| {
+            let packet = match sock.recvfrom(&mut buf) {
+                Ok((_, packet)) => packet,
+                Err(_) => return false,
+            };
+            let mut results = peer.decapsulate_iter(None, packet, &mut dst);
+            while let Some(result) = results.next_result() {
+                match result {
+                    TunnResult::WriteToNetwork(packet) => {
+                        sock.sendto(packet, device_addr);
+                    }
+                    TunnResult::WriteToTunnelV4(..) => return true,
+                    _ => {}
+                }
+            }
+            false
+        };
+
+        // The primary never answers, the handshake after the second attempt goes to the backup
+        UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .send_to(b"failover", SocketAddr::new(peer_ip, 9999))
+            .unwrap();
+        let mut primary_initiations = 0;
+        let mut arrived = false;
+        let started = std::time::Instant::now();
+        while !arrived && started.elapsed() < std::time::Duration::from_secs(5) {
+            if let Ok((_, packet)) = primary_sock.recvfrom(&mut [0u8; 2048]) {
+                assert!(Tunn::is_handshake_init(packet));
+                primary_initiations += 1;
+            }
+            arrived = answer(&backup_sock);
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert!(arrived);
+        assert_eq!(primary_initiations, 2);
+        let config = wg.wg_get();
+        assert!(config.contains(&format!("endpoint={}\n", backup)));
+        assert!(config.contains(&format!("peer_backup_endpoint={}\n", backup)));
+
+        // The first probe of the primary goes unanswered, which leaves the peer on the backup
+        let wait_probe = || {
+            let started = std::time::Instant::now();
+            while started.elapsed() < std::time::Duration::from_secs(3) {
+                match primary_sock.recvfrom(&mut [0u8; 2048]) {
+                    Ok((_, packet)) => return Tunn::is_handshake_init(packet),
+                    Err(_) => std::thread::sleep(std::time::Duration::from_millis(5)),
+                }
+            }
+            false
+        };
+        assert!(wait_probe());
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(wg.wg_get().contains(&format!("endpoint={}\n", backup)));
+
+        // Once the primary answers, the peer moves back to it
+        let started = std::time::Instant::now();
+        while !wg.wg_get().contains(&format!("endpoint={}\n", primary))
+            && started.elapsed() < std::time::Duration::from_secs(5)
+        {
+            answer(&primary_sock);
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert!(wg.wg_get().contains(&format!("endpoint={}\n", primary)));
+    }
+
+    #[test]
+    /// Test that packets for a peer without an endpoint are kept, and sent once the peer
+    /// initiated a handshake
+    fn test_wg_no_endpoint_buffer() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                no_endpoint_buffer: 3,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer = LoopbackPeer::new(&private_key);
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nallowed_ip={}/32",
+                encode(peer.public_key.as_bytes()),
+                peer.ip
+            )),
+            "errno=0\n\n"
+        );
+        wg.start();
+        add_route(&wg, peer.ip);
+
+        // Three of the five packets are kept, the others are dropped
+        for i in 0..5u8 {
+            peer.send_into_tunnel(&[i; 8]);
+        }
+        let drops = || {
+            wg._device
+                .device
+                .read()
+                .peer_stats(&peer.public_key)
+                .unwrap()
+                .no_endpoint_drops
+        };
+        let started = std::time::Instant::now();
+        while drops() < 2 && started.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(drops(), 2);
+        assert!(wg.wg_get().contains("no_endpoint_drops=2\n"));
+
+        // The peer initiates a handshake, which tells the device its endpoint
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        match peer
+            .tunn
+            .format_handshake_initiation(&mut [0u8; 2048], false)
+        {
+            TunnResult::WriteToNetwork(packet) => peer.sock.sendto(packet, device_addr),
+            _ => panic!("Expected a handshake initiation"),
+        };
+
+        // The payload follows the IPv4 and UDP headers
+        let payloads: Vec<_> = (0..3)
+            .filter_map(|_| peer.pump_until_delivered(device_addr))
+            .map(|(_, packet)| packet[28])
+            .collect();
+        // The kept packets are sent in order
+        assert_eq!(payloads, [0, 1, 2]);
+    }
+
+    #[test]
+    /// Test that sessions survive a change of the listen port, and the old port is still served
+    fn test_wg_change_listen_port() {
+        let port = next_port();
+        let new_port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer = loopback_peer(&mut wg, &private_key);
+        // Send a datagram into the tunnel, answer the device until it arrives at the peer.
+        // Returns the port of the device the datagram came from.
+        let send = |device_port: u16| {
+            peer.send_into_tunnel(b"session");
+            peer.pump_until_delivered(SocketAddr::from(([127, 0, 0, 1], device_port)))
+                .map(|(from, _)| from.port())
+        };
+        assert_eq!(send(port), Some(port));
+        let sessions = || wg._device.device.read().active_sessions();
+        let index = sessions()[0].local_index;
+
+        wg._device.device.read().try_writeable(
+            |d| d.trigger_yield(),
+            |d| {
+                d.cancel_yield();
+                d.change_listen_port(new_port).unwrap();
+            },
+        );
+        assert!(wg.wg_get().contains(&format!("listen_port={}\n", new_port)));
+
+        // Packets leave from the new port with the same session
+        assert_eq!(send(new_port), Some(new_port));
+        assert_eq!(sessions()[0].local_index, index);
+
+        // Packets to either port are received
+        let inner_packet = peer.inner_packet();
+        for device_port in [port, new_port] {
+            let device_addr = SocketAddr::from(([127, 0, 0, 1], device_port));
+            peer.send_inner(&inner_packet, device_addr);
+        }
+        let started = std::time::Instant::now();
+        while sessions()[0].rx_bytes < 80 && started.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(sessions()[0].rx_bytes, 80);
+        assert_eq!(sessions()[0].local_index, index);
+    }
+
+    #[test]
+    /// Test that waiting for a handshake returns once two devices connected over loopback
+    fn test_wg_wait_for_handshake() {
+        let keys = [X25519SecretKey::new(), X25519SecretKey::new()];
+        let ports = [next_port(), next_port()];
+        let mut devices = vec![];
+        for i in 0..2 {
+            let mut wg = WGHandle::init(next_ip(), next_ip_v6());
+            assert_eq!(wg.wg_set_port(ports[i]), "errno=0\n\n");
+            assert_eq!(wg.wg_set_key(&keys[i]), "errno=0\n\n");
+            wg.start();
+            devices.push(wg);
+        }
+
+        let timeout = std::time::Duration::from_secs(10);
+        let public_keys = [keys[0].public_key(), keys[1].public_key()];
+
+        // Nothing happens before there is a peer to handshake with
+        assert!(matches!(
+            devices[0]
+                ._device
+                .device
+                .read()
+                .wait_for_handshake(&public_keys[1], timeout),
+            Err(crate::device::Error::UnknownPeer)
+        ));
+        assert_eq!(
+            devices[1].wg_set(&format!(
+                "public_key={}\nallowed_ip={}/32",
+                encode(public_keys[0].as_bytes()),
+                next_ip()
+            )),
+            "errno=0\n\n"
+        );
+        assert!(matches!(
+            devices[1]
+                ._device
+                .device
+                .read()
+                .wait_for_handshake(&public_keys[0], std::time::Duration::from_millis(300)),
+            Err(crate::device::Error::HandshakeTimeout)
+        ));
+
+        // With a persistent keepalive the first device initiates a handshake on its own
+        assert_eq!(
+            devices[0].wg_set(&format!(
+                "public_key={}\nendpoint=127.0.0.1:{}\npersistent_keepalive_interval=1\nallowed_ip={}/32",
+                encode(public_keys[1].as_bytes()),
+                ports[1],
+                next_ip()
+            )),
+            "errno=0\n\n"
+        );
+        assert!(devices[0]
+            ._device
+            .device
+            .read()
+            .wait_for_handshake(&public_keys[1], timeout)
+            .is_ok());
+        assert!(devices[1]
+            ._device
+            .device
+            .read()
+            .wait_for_handshake(&public_keys[0], timeout)
+            .is_ok());
+    }
+
+    #[test]
+    /// Test that during a key rollover peers can handshake against either static key, and only
+    /// the new key remains once the window ends
+    fn test_wg_key_rollover() {
+        let port = next_port();
+        let old_key = X25519SecretKey::new();
+        let old_public = Arc::new(old_key.public_key());
+        let new_key = X25519SecretKey::new();
+        let new_public = Arc::new(new_key.public_key());
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&old_key), "errno=0\n\n");
+
+        // One peer still knows the old public key of the device, the other already the new one
+        let mut peers = vec![];
+        for device_public in &[&old_public, &new_public] {
+            let sock = UDPSocket::new()
+                .and_then(|s| s.set_non_blocking())
+                .and_then(|s| s.bind(0))
+                .unwrap();
+            let addr = SocketAddr::from(([127, 0, 0, 1], sock.port().unwrap()));
+            let key = Arc::new(X25519SecretKey::new());
+            let allowed_ip = AllowedIp {
+                ip: next_ip(),
+                cidr: 32,
+            };
+            assert_eq!(
+                wg.wg_set_peer(&key.public_key(), &addr, &[allowed_ip]),
+                "errno=0\n\n"
+            );
+            let device_public = Arc::new(X25519PublicKey::from(device_public.as_bytes()));
+            let tunn = Tunn::new(key, device_public, None, None, 0, None).unwrap();
+            peers.push((sock, tunn));
+        }
+        wg.start();
+
+        // Neither starting nor ending the rollover keeps references to the peers
+        let strong_counts = || {
+            let device = wg._device.device.read();
+            let mut counts: Vec<_> = device.peers.values().map(Arc::strong_count).collect();
+            counts.sort_unstable();
+            counts
+        };
+        let counts = strong_counts();
+        wg._device.device.read().try_writeable(
+            |d| d.trigger_yield(),
+            |d| {
+                d.cancel_yield();
+                d.set_next_private_key(new_key, std::time::Duration::from_secs(2));
+            },
+        );
+
+        // Send a handshake initiation to the device, and report whether it completed. A valid
+        // response is confirmed with a keepalive.
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let handshake = |sock: &UDPSocket, tunn: &Tunn| {
+            let mut buf = [0u8; 2048];
+            let mut dst = [0u8; 2048];
+            match tunn.format_handshake_initiation(&mut dst, true) {
+                TunnResult::WriteToNetwork(packet) => sock.sendto(packet, device_addr),
+                _ => panic!("Expected a handshake initiation"),
+            };
+            let started = std::time::Instant::now();
+            while started.elapsed() < std::time::Duration::from_secs(1) {
+                match sock.recvfrom(&mut buf) {
+                    Ok((_, packet)) => {
+                        if let TunnResult::WriteToNetwork(keepalive) =
+                            tunn.decapsulate(None, packet, &mut dst)
+                        {
+                            sock.sendto(keepalive, device_addr);
+                            return true;
+                        }
+                    }
+                    Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+                }
+            }
+            false
+        };
+
+        for (sock, tunn) in &peers {
+            assert!(handshake(sock, tunn));
+        }
+        assert_eq!(
+            wg._device.device.read().public_key().unwrap().as_bytes(),
+            old_public.as_bytes()
+        );
+
+        // After the window the old key is dropped
+        std::thread::sleep(std::time::Duration::from_secs(3));
+        assert_eq!(
+            wg._device.device.read().public_key().unwrap().as_bytes(),
+            new_public.as_bytes()
+        );
+        assert_eq!(strong_counts(), counts);
+        let (old_sock, old_tunn) = &peers[0];
+        let (new_sock, new_tunn) = &peers[1];
+        assert!(handshake(new_sock, new_tunn));
+        assert!(!handshake(old_sock, old_tunn));
+    }
+
+    /// Trace the packets of a peer, and read the trace with the typed API and get_trace
+    #[test]
+    fn test_wg_packet_trace() {
+        use crate::device::trace::{TraceDirection::*, TraceKind, TraceOutcome};
+
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+        assert_eq!(wg.wg_set("trace_buffer=100000"), "errno=22\n\n");
+        assert_eq!(wg.wg_set("trace_buffer=16"), "errno=0\n\n");
+        assert!(wg.wg_get().contains("trace_buffer=16\n"));
+
+        let peer = loopback_peer(&mut wg, &private_key);
+        let trace = || {
+            wg._device
+                .device
+                .read()
+                .peer_trace(&peer.public_key)
+                .unwrap()
+        };
+        assert!(trace().is_empty());
+
+        // A UDP datagram into the tunnel is queued until the handshake completes
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        peer.send_into_tunnel(b"trace");
+        assert!(peer.pump_until_delivered(device_addr).is_some());
+
+        // The peer answers with a packet from its allowed IP
+        let inner_packet = peer.inner_packet();
+        peer.send_inner(&inner_packet, device_addr);
+
+        let started = std::time::Instant::now();
+        while trace().len() < 5 && started.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let events = trace();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| (e.direction, e.kind, e.outcome))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Tx, TraceKind::Data, TraceOutcome::Queued),
+                (Tx, TraceKind::HandshakeInit, TraceOutcome::Sent),
+                (Rx, TraceKind::HandshakeResponse, TraceOutcome::Replied),
+                (Tx, TraceKind::Data, TraceOutcome::Sent),
+                (Rx, TraceKind::Data, TraceOutcome::Delivered),
+            ]
+        );
+        assert_eq!(events[1].len, 148);
+        assert_eq!(events[2].len, 92);
+        assert!(events.windows(2).all(|w| w[0].time <= w[1].time));
+
+        let path = format!("/var/run/wireguard/{}.sock", wg.name);
+        let mut socket = UnixStream::connect(path).unwrap();
+        write!(socket, "get_trace=1\n\n").unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).unwrap();
+        let lines: Vec<_> = response.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!("public_key={}", encode(peer.public_key.as_bytes()))
+        );
+        assert!(lines[2].ends_with(",tx,handshake_init,148,sent"));
+        assert!(lines[5].ends_with(",rx,data,72,delivered"));
+        assert_eq!(lines[6], "errno=0");
+    }
+
+    /// Test that the expiring hook fires once before the session expires, and that with prewarm
+    /// the device starts a handshake right away
+    #[test]
+    fn test_wg_session_expiring() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                on_session_expiring: Some(Box::new(move |key| {
+                    tx.lock().unwrap().send(encode(key.as_bytes())).unwrap();
+                })),
+                // Sessions are reported one second after their handshake
+                session_expiry_lead: std::time::Duration::from_secs(179),
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+        assert_eq!(wg.wg_set("prewarm=on"), "errno=0\n\n");
+        assert!(wg.wg_get().contains("prewarm=on\n"));
+
+        let peer = loopback_peer(&mut wg, &private_key);
+
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        peer.send_into_tunnel(b"expiring");
+        assert!(peer.pump_until_delivered(device_addr).is_some());
+        let established = std::time::Instant::now();
+
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(
+            rx.recv_timeout(timeout).unwrap(),
+            encode(peer.public_key.as_bytes())
+        );
+        assert!(established.elapsed() < std::time::Duration::from_secs(3));
+
+        // The device started a new handshake before the old session expired
+        let started = std::time::Instant::now();
+        let mut initiated = false;
+        while !initiated && started.elapsed() < timeout {
+            match peer.sock.recvfrom(&mut [0u8; 2048]) {
+                Ok((_, packet)) => initiated = packet.len() == 148 && packet[0] == 1,
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        assert!(initiated);
+
+        // Each session is reported only once
+        assert!(rx
+            .recv_timeout(std::time::Duration::from_millis(1000))
+            .is_err());
+    }
+
+    /// Test that the payload budget of a peer depends on the address family of its endpoint,
+    /// and follows the endpoint when it roams
+    #[test]
+    fn test_wg_payload_budget() {
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert_eq!(wg.wg_set_key(&X25519SecretKey::new()), "errno=0\n\n");
+
+        let v4_key = X25519SecretKey::new().public_key();
+        let v6_key = X25519SecretKey::new().public_key();
+        let v4_endpoint: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let v6_endpoint: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        assert_eq!(wg.wg_set_peer(&v4_key, &v4_endpoint, &[]), "errno=0\n\n");
+        assert_eq!(wg.wg_set_peer(&v6_key, &v6_endpoint, &[]), "errno=0\n\n");
+
+        let budgets = || {
+            wg._device
+                .device
+                .read()
+                .peers()
+                .iter()
+                .map(|peer| peer.payload_budget)
+                .collect::<Vec<_>>()
+        };
+        // 1500 bytes less the IP and UDP headers and the data message overhead
+        assert_eq!(budgets(), [Some(1440), Some(1420)]);
+
+        // A mapped IPv4 address is sent over IPv4
+        let v4_peer = Arc::clone(&wg._device.device.read().peers[&v4_key]);
+        v4_peer.set_endpoint("[::ffff:192.0.2.1]:51820".parse().unwrap());
+        assert_eq!(budgets(), [Some(1440), Some(1420)]);
+
+        v4_peer.set_endpoint("[2001:db8::2]:51820".parse().unwrap());
+        assert_eq!(budgets(), [Some(1420), Some(1420)]);
+        assert_eq!(v4_peer.tunnel.max_payload(), 1420);
+        v4_peer.set_endpoint(v4_endpoint);
+        assert_eq!(v4_peer.tunnel.max_payload(), 1440);
+    }
+
+    /// Test that with freebind a socket binds to a local address that is not assigned yet
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_wg_freebind() {
+        // Documentation addresses, which are not assigned to any interface of the host
+        let vips: [SocketAddr; 2] = [
+            "192.0.2.254:0".parse().unwrap(),
+            "[2001:db8::fe]:0".parse().unwrap(),
+        ];
+        for vip in &vips {
+            let new_sock = || match vip {
+                SocketAddr::V4(_) => UDPSocket::new().unwrap(),
+                SocketAddr::V6(_) => UDPSocket::new6().unwrap(),
+            };
+            assert!(new_sock().bind_addr(*vip).is_err());
+
+            let sock = new_sock();
+            sock.set_freebind(true).unwrap();
+            assert!(sock.bind_addr(*vip).is_ok());
+        }
+
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        assert_eq!(wg.wg_set("freebind=on"), "errno=0\n\n");
+        assert!(wg.wg_get().contains("freebind=on\n"));
+        assert_eq!(wg.wg_set("freebind=yes"), "errno=22\n\n");
+        assert_eq!(wg.wg_set("freebind=off"), "errno=0\n\n");
+        assert!(!wg.wg_get().contains("freebind"));
+    }
+
+    /// Test that a configuration is validated against the device without being applied
+    #[test]
+    fn test_wg_validate_config() {
+        use crate::device::api::{ConfigError, ConfigErrorKind};
+
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        let peer_key = X25519SecretKey::new().public_key();
+        let other_key = X25519SecretKey::new().public_key();
+        let endpoint: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let device = || wg._device.device.read();
+
+        // Peers can not be added before the device has a key
+        let cfg = format!(
+            "public_key={}\nallowed_ip=10.1.0.0/16",
+            encode(peer_key.as_bytes())
+        );
+        assert_eq!(
+            device().validate_config(&cfg),
+            Err(ConfigError {
+                line: 1,
+                key: "public_key".to_owned(),
+                kind: ConfigErrorKind::MissingPrivateKey,
+            })
+        );
+
+        wg.wg_set_key(&X25519SecretKey::new());
+        wg.wg_set_peer(
+            &peer_key,
+            &endpoint,
+            &[AllowedIp {
+                ip: "10.1.0.0".parse().unwrap(),
+                cidr: 16,
+            }],
+        );
+        let before = wg.wg_get();
+
+        // The allowed IP of the new peer is within the one of the existing peer
+        let cfg = format!(
+            "set=1\nlisten_port=0\npublic_key={}\nendpoint=192.0.2.2:51820\nallowed_ip=10.2.0.0/16\nallowed_ip=10.1.2.0/24\n\n",
+            encode(other_key.as_bytes())
+        );
+        assert_eq!(device().validate_config(&cfg), Ok(()));
+        assert_eq!(
+            device().validate_config_strict(&cfg),
+            Err(ConfigError {
+                line: 6,
+                key: "allowed_ip".to_owned(),
+                kind: ConfigErrorKind::OverlappingAllowedIp {
+                    peer: encode(peer_key.as_bytes()),
+                    allowed_ip: "10.1.0.0/16".to_owned(),
+                },
+            })
+        );
+
+        // Unless the existing peer is removed first
+        let cfg = format!(
+            "public_key={}\nremove=true\npublic_key={}\nallowed_ip=10.1.2.0/24",
+            encode(peer_key.as_bytes()),
+            encode(other_key.as_bytes())
+        );
+        assert_eq!(device().validate_config_strict(&cfg), Ok(()));
+
+        let cfg = format!(
+            "public_key={}\nendpoint=192.0.2.3:51820",
+            encode(peer_key.as_bytes())
+        );
+        assert_eq!(
+            device().validate_config(&cfg).map_err(|e| e.kind),
+            Err(ConfigErrorKind::PeerExists)
+        );
+
+        let cfg = "fwmark=1\nlisten_port=70000";
+        assert_eq!(
+            device().validate_config(cfg),
+            Err(ConfigError {
+                line: 2,
+                key: "listen_port".to_owned(),
+                kind: ConfigErrorKind::InvalidValue,
+            })
+        );
+        assert_eq!(
+            device()
+                .validate_config("set=1\nfwmark")
+                .map_err(|e| (e.line, e.kind)),
+            Err((2, ConfigErrorKind::Malformed))
+        );
+
+        // Nothing was applied
+        assert_eq!(wg.wg_get(), before);
+    }
+
+    /// Test that a peer added again within the resumption window resumes its session, without
+    /// a new handshake
+    #[test]
+    fn test_wg_session_resumption() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                resumption_window: std::time::Duration::from_secs(30),
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer = loopback_peer(&mut wg, &private_key);
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        // Send a datagram into the tunnel, and count the handshake initiations the peer answers
+        // before it arrives
+        let exchange = || {
+            let initiations = peer.initiations.get();
+            peer.send_into_tunnel(b"session");
+            peer.pump_until_delivered(device_addr)
+                .map(|_| peer.initiations.get() - initiations)
+        };
+        assert_eq!(exchange(), Some(1));
+
+        let remove = format!(
+            "public_key={}\nremove=true",
+            encode(peer.public_key.as_bytes())
+        );
+        assert_eq!(wg.wg_set(&remove), "errno=0\n\n");
+        assert!(!wg.wg_get().contains("public_key="));
+        assert_eq!(
+            wg.wg_set_peer(
+                &peer.public_key,
+                &peer.addr,
+                &[AllowedIp {
+                    ip: peer.ip,
+                    cidr: 32
+                }]
+            ),
+            "errno=0\n\n"
+        );
+        assert_eq!(exchange(), Some(0));
+        assert!(wg.wg_get().contains("last_handshake_time_sec="));
+    }
+
+    /// Test that data messages for unknown sessions are dropped before decapsulation, while the
+    /// messages of a live session are still decrypted
+    #[test]
+    fn test_wg_drop_unknown_indices() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                drop_unknown_indices: true,
+                on_decrypt_failure: Some(Box::new(move |failure| {
+                    let _ = tx.lock().unwrap().send(failure);
+                })),
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        // Establish a session, the device initiates it for a datagram into the tunnel
+        let peer = loopback_peer(&mut wg, &private_key);
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        peer.send_into_tunnel(b"session");
+        assert!(peer.pump_until_delivered(device_addr).is_some());
+
+        // A flood of data messages with random indices, and one with the index of the peer but
+        // of a session it does not have
+        let device_index = peer.tunn.session_stats().unwrap().peer_index;
+        let rng = SystemRandom::new();
+        let mut bogus = [0u8; 64];
+        for i in 0..100 {
+            rng.fill(&mut bogus).unwrap();
+            bogus[0..4].copy_from_slice(&4u32.to_le_bytes());
+            if i == 0 {
+                bogus[4..8].copy_from_slice(&(device_index ^ 1).to_le_bytes());
+            }
+            peer.sock.sendto(&bogus, device_addr);
+        }
+
+        // Followed by a data message of the session
+        peer.send_inner(&peer.inner_packet(), device_addr);
+
+        let rx_bytes = || wg._device.device.read().active_sessions()[0].rx_bytes;
+        let started = std::time::Instant::now();
+        while rx_bytes() < 40 && started.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(rx_bytes(), 40);
+        assert_eq!(wg._device.device.read().unknown_index_drops(), 100);
+        assert!(wg.wg_get().contains("unknown_index_drops=100\n"));
+        // None of them made it far enough to fail decryption
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// Add count peers without endpoints or allowed IPs in a single set request
+    fn add_peers(wg: &WGHandle, count: usize) -> Vec<X25519PublicKey> {
+        let keys: Vec<_> = (0..count)
+            .map(|_| X25519SecretKey::new().public_key())
+            .collect();
+        let req: Vec<_> = keys
+            .iter()
+            .map(|key| format!("public_key={}", encode(key.as_bytes())))
+            .collect();
+        assert_eq!(wg.wg_set(&req.join("\n")), "errno=0\n\n");
+        keys
+    }
+
+    /// Test that the bulk stats snapshot has every peer, with the counters of the per-peer API
+    #[test]
+    fn test_wg_all_stats() {
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        wg.wg_set_key(&X25519SecretKey::new());
+        let keys = add_peers(&wg, 3);
+
+        let device = wg._device.device.read();
+        let stats = device.all_stats();
+        let peer_ids: Vec<_> = device.peers().iter().map(|p| p.peer_id).collect();
+        assert_eq!(
+            stats
+                .iter()
+                .map(|(peer_id, _)| *peer_id)
+                .collect::<Vec<_>>(),
+            peer_ids
+        );
+        for (key, (_, peer_stats)) in keys.iter().zip(&stats) {
+            assert_eq!(device.peer_stats(key).unwrap(), *peer_stats);
+            assert_eq!(peer_stats.rx_bytes, 0);
+        }
+
+        let unknown = X25519SecretKey::new().public_key();
+        assert!(matches!(
+            device.peer_stats(&unknown),
+            Err(crate::device::Error::UnknownPeer)
+        ));
+    }
+
+    /// Test that an idle lean peer, which has no session or timers allocated, still completes a
+    /// handshake, and that the other peers stay idle
+    #[test]
+    fn test_wg_idle_peer_handshake() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                lean_peers: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+        let idle_keys = add_peers(&wg, 100);
+
+        let sock = UDPSocket::new()
+            .and_then(|s| s.set_non_blocking())
+            .and_then(|s| s.bind(0))
+            .unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], sock.port().unwrap()));
+        let key = Arc::new(X25519SecretKey::new());
+        let peer_public_key = key.public_key();
+        let allowed_ip = AllowedIp {
+            ip: next_ip(),
+            cidr: 32,
+        };
+        assert_eq!(
+            wg.wg_set_peer(&peer_public_key, &addr, &[allowed_ip]),
+            "errno=0\n\n"
+        );
+        wg.start();
+        assert!(wg._device.device.read().active_sessions().is_empty());
+
+        // Timer ticks pass over the peer with an endpoint without allocating its timers
+        std::thread::sleep(std::time::Duration::from_millis(600));
+        let is_idle = |key: &X25519PublicKey| {
+            let device = wg._device.device.read();
+            device.peers.get(key).unwrap().tunnel.is_idle()
+        };
+        assert!(is_idle(&peer_public_key));
+
+        let tunn = Tunn::new(key, public_key, None, None, 0, None).unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        match tunn.format_handshake_initiation(&mut dst, false) {
+            TunnResult::WriteToNetwork(packet) => sock.sendto(packet, device_addr),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let started = std::time::Instant::now();
+        let mut completed = false;
+        while !completed && started.elapsed() < std::time::Duration::from_secs(5) {
+            match sock.recvfrom(&mut buf) {
+                Ok((_, packet)) => {
+                    if let TunnResult::WriteToNetwork(keepalive) =
+                        tunn.decapsulate(None, packet, &mut dst)
+                    {
+                        sock.sendto(keepalive, device_addr);
+                        completed = true;
+                    }
+                }
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        assert!(completed);
+
+        // The session is established once the device receives the keepalive
+        let sessions = || wg._device.device.read().active_sessions();
+        let started = std::time::Instant::now();
+        while sessions().is_empty() && started.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let sessions = sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(
+            sessions[0].public_key.as_bytes(),
+            peer_public_key.as_bytes()
+        );
+        assert!(!is_idle(&peer_public_key));
+        assert!(idle_keys.iter().all(is_idle));
+    }
+
+    /// Test that malformed inner packets are dropped before the tunnel interface in strict mode
+    #[test]
+    fn test_wg_validate_inner() {
+        use crate::device::offload::{checksum_add, checksum_fold};
+
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+        assert_eq!(wg.wg_set("validate_inner=lax"), "errno=22\n\n");
+        assert_eq!(wg.wg_set("validate_inner=strict"), "errno=0\n\n");
+        assert!(wg
+            .wg_get()
+            .contains("validate_inner=strict\ninvalid_inner_drops=0\n"));
+
+        let peer = loopback_peer(&mut wg, &private_key);
+
+        // A UDP datagram into the tunnel makes the device establish a session with the peer
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        peer.send_into_tunnel(b"session");
+        assert!(peer.pump_until_delivered(device_addr).is_some());
+
+        // A packet with a valid header, then the same packet with a corrupted header checksum
+        let mut inner_packet = peer.inner_packet();
+        let csum = !checksum_fold(checksum_add(0, &inner_packet[..20]));
+        inner_packet[10..12].copy_from_slice(&csum.to_be_bytes());
+        let mut malformed = inner_packet.clone();
+        malformed[11] ^= 1;
+        for packet in &[&inner_packet, &malformed] {
+            peer.send_inner(packet, device_addr);
+        }
+
+        let drops = || wg._device.device.read().invalid_inner_drops();
+        let started = std::time::Instant::now();
+        while drops() == 0 && started.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(drops(), 1);
+        assert!(wg.wg_get().contains("invalid_inner_drops=1\n"));
+
+        assert_eq!(wg.wg_set("validate_inner=off"), "errno=0\n\n");
+        assert!(!wg.wg_get().contains("validate_inner"));
+    }
+
+    /// Test that flows are spread over the weighted endpoints of a peer, each flow to one of them
+    #[test]
+    fn test_wg_ecmp_endpoints() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer_ip = next_ip();
+        let bind = || {
+            let sock = UDPSocket::new()
+                .and_then(|s| s.set_non_blocking())
+                .and_then(|s| s.bind(0))
+                .unwrap();
+            let addr = SocketAddr::from(([127, 0, 0, 1], sock.port().unwrap()));
+            (sock, addr)
+        };
+        let (peer_sock, peer_addr) = bind();
+        let (sock_a, addr_a) = bind();
+        let (sock_b, addr_b) = bind();
+        let peer_key = Arc::new(X25519SecretKey::new());
+        let peer_public_key = peer_key.public_key();
+        let peer_section = format!(
+            "public_key={}\nendpoint={}\nallowed_ip={}/32",
+            encode(peer_public_key.as_bytes()),
+            peer_addr,
+            peer_ip
+        );
+        assert_eq!(
+            wg.wg_set(&format!("{}\necmp_endpoint={}/0", peer_section, addr_a)),
+            "errno=22\n\n"
+        );
+        assert_eq!(
+            wg.wg_set(&format!(
+                "{}\necmp_endpoint={}/3\necmp_endpoint={}",
+                peer_section, addr_a, addr_b
+            )),
+            "errno=0\n\n"
+        );
+        assert!(wg.wg_get().contains(&format!(
+            "ecmp_endpoint={}/3\necmp_endpoint={}/1\n",
+            addr_a, addr_b
+        )));
+        wg.start();
+        Command::new("ip")
+            .args(&["route", "add", &format!("{}/32", peer_ip), "dev", &wg.name])
+            .status()
+            .expect("failed to add route");
+
+        // The handshake goes to the endpoint of the peer, not to the weighted endpoints
+        let peer = Tunn::new(peer_key, public_key, None, None, 0, None).unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .send_to(b"session", SocketAddr::new(peer_ip, 9999))
+            .unwrap();
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        let has_session = || !wg._device.device.read().active_sessions().is_empty();
+        let started = std::time::Instant::now();
+        while !has_session() && started.elapsed() < std::time::Duration::from_secs(5) {
+            match peer_sock.recvfrom(&mut buf) {
+                Ok((_, packet)) => {
+                    if let TunnResult::WriteToNetwork(packet) =
+                        peer.decapsulate(None, packet, &mut dst)
+                    {
+                        peer_sock.sendto(packet, device_addr);
+                    }
+                }
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        assert!(has_session());
+
+        // The number of data packets each weighted endpoint received
+        let count = |sock: &UDPSocket| {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            let mut buf = [0u8; 2048];
+            let mut n = 0;
+            while let Ok((_, packet)) = sock.recvfrom(&mut buf) {
+                if packet[0] == 4 {
+                    n += 1;
+                }
+            }
+            n
+        };
+        count(&sock_a);
+        count(&sock_b);
+
+        // Every flow comes from a different port, few enough for the receive buffers
+        let n_flows = 200;
+        for _ in 0..n_flows {
+            UdpSocket::bind("0.0.0.0:0")
+                .unwrap()
+                .send_to(b"flow", SocketAddr::new(peer_ip, 9999))
+                .unwrap();
+        }
+        let (to_a, to_b) = (count(&sock_a), count(&sock_b));
+        assert_eq!(to_a + to_b, n_flows);
+        // 150 expected
+        assert!((120..180).contains(&to_a), "{} flows of {}", to_a, n_flows);
+
+        let sender = UdpSocket::bind("0.0.0.0:0").unwrap();
+        for _ in 0..20 {
+            sender
+                .send_to(b"pinned", SocketAddr::new(peer_ip, 9999))
+                .unwrap();
+        }
+        let mut pinned = [count(&sock_a), count(&sock_b)];
+        pinned.sort_unstable();
+        assert_eq!(pinned, [0, 20]);
+    }
+
+    #[test]
+    /// Drive a device without threads of its own from an external poll loop over its raw fds
+    fn test_wg_raw_fds() {
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 0,
+                listen_sockets: 2,
+                ..Default::default()
+            },
+        );
+
+        // The fds the device expects to be listed, as (tun, listen sockets)
+        let expected = || {
+            let d = wg._device.device.read();
+            let mut listen: Vec<RawFd> = d
+                .udp4
+                .iter()
+                .chain(d.udp6.iter())
+                .chain(d.udp_shards.iter())
+                .map(|s| s.as_raw_fd())
+                .collect();
+            listen.sort_unstable();
+            (d.iface.as_ref().unwrap().as_raw_fd(), listen)
+        };
+        let fds_with_role = |role: FdRole| {
+            let mut fds: Vec<RawFd> = wg
+                ._device
+                .raw_fds()
+                .into_iter()
+                .filter(|&(_, r)| r == role)
+                .map(|(fd, _)| fd)
+                .collect();
+            fds.sort_unstable();
+            fds
+        };
+
+        let (tun_fd, listen_fds) = expected();
+        assert_eq!(listen_fds.len(), 4);
+        assert_eq!(fds_with_role(FdRole::Tun), [tun_fd]);
+        assert_eq!(fds_with_role(FdRole::ListenSocket), listen_fds);
+        assert_eq!(fds_with_role(FdRole::Api).len(), 1);
+        assert!(!fds_with_role(FdRole::Timer).is_empty());
+
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    // The fd set is listed again on every iteration, it changes with the sockets
+                    let mut fds: Vec<libc::pollfd> = wg
+                        ._device
+                        .raw_fds()
+                        .into_iter()
+                        .map(|(fd, _)| libc::pollfd {
+                            fd,
+                            events: libc::POLLIN,
+                            revents: 0,
+                        })
+                        .collect();
+                    unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, 10) };
+                    for fd in fds.iter().filter(|fd| fd.revents & libc::POLLIN != 0) {
+                        wg._device.handle_readable(fd.fd);
+                    }
+                }
+            });
+
+            // The configuration socket is served by the external loop, and a new listen port
+            // replaces the listen sockets in the set
+            let port = next_port();
+            assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+            assert!(wg.wg_get().contains(&format!("listen_port={}\n", port)));
+            let (_, listen_fds) = expected();
+            assert_eq!(listen_fds.len(), 4);
+            assert_eq!(fds_with_role(FdRole::ListenSocket), listen_fds);
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    /// Test a get/set round trip through the UAPI client
+    #[test]
+    fn test_wg_uapi_client() {
+        use crate::device::uapi_client::{ClientError, UapiClient};
+
+        let wg = WGHandle::init(next_ip(), next_ip_v6());
+        let client = UapiClient::connect_interface(&wg.name).unwrap();
+        assert!(UapiClient::connect("/nonexistent.sock").is_err());
+
+        let peer_key = X25519SecretKey::new().public_key();
+        let port = next_port();
+        let config = format!(
+            "private_key={}\nlisten_port={}\npublic_key={}\nendpoint=192.0.2.1:51820\n\
+             allowed_ip=10.3.0.0/16\npersistent_keepalive_interval=25\n",
+            encode(X25519SecretKey::new().as_bytes()),
+            port,
+            encode(peer_key.as_bytes())
+        );
+        client.set(&config).unwrap();
+
+        let state = client.get().unwrap();
+        assert!(state.private_key.is_some());
+        assert_eq!(state.listen_port, Some(port));
+        assert_eq!(state.peers.len(), 1);
+        let peer = &state.peers[0];
+        assert_eq!(peer.public_key, peer_key);
+        assert_eq!(peer.endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+        assert_eq!(peer.persistent_keepalive_interval, Some(25));
+        assert_eq!(peer.allowed_ips.len(), 1);
+        assert_eq!(
+            peer.allowed_ips[0].addr,
+            "10.3.0.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(peer.allowed_ips[0].cidr, 16);
+        assert!(peer.other.iter().any(|(key, _)| key == "peer_id"));
+
+        // A malformed configuration is reported by the line at fault, without being sent
+        match client.set("fwmark=1\nlisten_port=x") {
+            Err(ClientError::Config(e)) => assert_eq!((e.line, e.key.as_str()), (2, "listen_port")),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(client.get().unwrap().fwmark, None);
+
+        #[cfg(feature = "async-uapi")]
+        {
+            use std::future::Future;
+            use std::task::{Context, Poll, Wake};
+
+            struct ThreadWaker(std::thread::Thread);
+            impl Wake for ThreadWaker {
+                fn wake(self: Arc<Self>) {
+                    self.0.unpark();
+                }
+            }
+            fn block_on<F: Future>(future: F) -> F::Output {
+                let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+                let mut cx = Context::from_waker(&waker);
+                let mut future = Box::pin(future);
+                loop {
+                    match future.as_mut().poll(&mut cx) {
+                        Poll::Ready(output) => return output,
+                        Poll::Pending => std::thread::park(),
+                    }
+                }
+            }
+
+            block_on(client.set_async("fwmark=7")).unwrap();
+            assert_eq!(block_on(client.get_async()).unwrap().fwmark, Some(7));
+        }
+    }
+
+    /// Test that a subscriber is told about peers added and removed, and gets stats snapshots
+    #[test]
+    fn test_wg_subscribe() {
+        use crate::device::events::DeviceEvent;
+
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 2,
+                stats_interval: std::time::Duration::from_millis(100),
+                ..Default::default()
+            },
+        );
+        wg.wg_set_key(&X25519SecretKey::new());
+        let events = wg._device.device.read().subscribe();
+
+        let key = X25519SecretKey::new().public_key();
+        let endpoint = "192.0.2.1:51820".parse().unwrap();
+        assert_eq!(wg.wg_set_peer(&key, &endpoint, &[]), "errno=0\n\n");
+
+        let next_peer_event = || loop {
+            match events
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .unwrap()
+            {
+                DeviceEvent::Stats(_) => continue,
+                event => return event,
+            }
+        };
+        let peer_id = match next_peer_event() {
+            DeviceEvent::PeerAdded {
+                peer_id,
+                public_key,
+            } => {
+                assert_eq!(*public_key, key);
+                peer_id
+            }
+            event => panic!("Unexpected event {:?}", event),
+        };
+        assert_eq!(wg._device.device.read().peers()[0].peer_id, peer_id);
+
+        // The snapshots carry the new peer
+        loop {
+            if let DeviceEvent::Stats(stats) = events
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .unwrap()
+            {
+                assert_eq!(stats.len(), 1);
+                assert_eq!(stats[0].0, peer_id);
+                break;
+            }
+        }
+
+        let req = format!("public_key={}\nremove=true", encode(key.as_bytes()));
+        assert_eq!(wg.wg_set(&req), "errno=0\n\n");
+        assert!(matches!(
+            next_peer_event(),
+            DeviceEvent::PeerRemoved { peer_id: id, .. } if id == peer_id
+        ));
+    }
+
+    /// A peer extracted from a device and injected into another one carries on with its session
+    #[test]
+    fn test_wg_extract_inject_peer() {
+        let private_key = X25519SecretKey::new();
+        let config = || DeviceConfig {
+            n_threads: 1,
+            use_connected_socket: false,
+            ..Default::default()
+        };
+        let (port_a, port_b) = (next_port(), next_port());
+        let mut wg_a = WGHandle::init_with_config(next_ip(), next_ip_v6(), config());
+        let mut wg_b = WGHandle::init_with_config(next_ip(), next_ip_v6(), config());
+        assert_eq!(wg_a.wg_set_port(port_a), "errno=0\n\n");
+        assert_eq!(wg_b.wg_set_port(port_b), "errno=0\n\n");
+        assert_eq!(wg_a.wg_set_key(&private_key), "errno=0\n\n");
+        assert_eq!(wg_b.wg_set_key(&private_key), "errno=0\n\n");
+
+        // A UDP datagram into the tunnel makes the first device establish a session with the peer
+        wg_b.start();
+        let peer = loopback_peer(&mut wg_a, &private_key);
+        let peer_public_key = &peer.public_key;
+        let addr_a = SocketAddr::from(([127, 0, 0, 1], port_a));
+        peer.send_into_tunnel(b"session");
+        assert!(peer.pump_until_delivered(addr_a).is_some());
+        let tx_bytes = wg_a
+            ._device
+            .device
+            .read()
+            .peer_stats(peer_public_key)
+            .unwrap()
+            .tx_bytes;
+        assert!(tx_bytes > 0);
+
+        let state = wg_a
+            ._device
+            .device
+            .read()
+            .try_writeable(
+                |device| device.trigger_yield(),
+                |device| {
+                    device.cancel_yield();
+                    device.extract_peer(peer_public_key)
+                },
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.public_key().as_bytes(), peer_public_key.as_bytes());
+        assert!(!wg_a.wg_get().contains("public_key="));
+        wg_b._device
+            .device
+            .read()
+            .try_writeable(
+                |device| device.trigger_yield(),
+                |device| {
+                    device.cancel_yield();
+                    device.inject_peer(state)
+                },
+            )
+            .unwrap()
+            .unwrap();
+
+        // The peer sends a data packet of the same session to the second device
+        let addr_b = SocketAddr::from(([127, 0, 0, 1], port_b));
+        peer.send_inner(&peer.inner_packet(), addr_b);
+
+        let sessions = || wg_b._device.device.read().active_sessions();
+        let started = std::time::Instant::now();
+        while sessions().first().map_or(0, |session| session.rx_bytes) < 40
+            && started.elapsed() < std::time::Duration::from_secs(5)
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let sessions = sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].rx_bytes, 40);
+        let peer_session = peer.tunn.session_stats().unwrap();
+        assert_eq!(sessions[0].local_index, peer_session.peer_index);
+
+        // The counters moved with the peer, and no handshake was started
+        let stats = wg_b
+            ._device
+            .device
+            .read()
+            .peer_stats(peer_public_key)
+            .unwrap();
+        assert_eq!((stats.tx_bytes, stats.rx_bytes), (tx_bytes, 40));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        while let Ok((_, packet)) = peer.sock.recvfrom(&mut [0u8; 2048]) {
+            assert_ne!(packet[0], 1, "Unexpected handshake initiation");
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Test that the capture socket picks a WireGuard datagram off a veth pair, and its reply
+    /// reaches the sender
+    fn test_wg_capture_socket() {
+        use crate::device::capture::CaptureSocket;
+
+        let ip = |args: &[&str]| {
+            assert!(Command::new("ip").args(args).status().unwrap().success());
+        };
+        let _ = Command::new("ip").args(&["link", "del", "wgcap0"]).status();
+        ip(&[
+            "link", "add", "wgcap0", "type", "veth", "peer", "name", "wgcap1",
+        ]);
+        ip(&["address", "add", "198.18.0.1/30", "dev", "wgcap0"]);
+        ip(&["link", "set", "wgcap0", "up"]);
+        ip(&["link", "set", "wgcap1", "up"]);
+        let capture_mac = std::fs::read_to_string("/sys/class/net/wgcap1/address").unwrap();
+        // Nothing answers for the intercepted address, frames for it go to the capturing end
+        ip(&[
+            "neigh",
+            "add",
+            "198.18.0.2",
+            "lladdr",
+            capture_mac.trim(),
+            "dev",
+            "wgcap0",
+        ]);
+
+        let port = next_port();
+        let config = DeviceConfig {
+            capture_interface: Some("wgcap1".to_owned()),
+            ..Default::default()
+        };
+        assert!(CaptureSocket::new().is_err());
+        let capture = CaptureSocket::new_with_config(&config)
+            .and_then(|sock| sock.set_non_blocking())
+            .and_then(|sock| sock.bind(port))
+            .unwrap();
+        let sender = UdpSocket::bind("198.18.0.1:0").unwrap();
+        let sender_addr = sender.local_addr().unwrap();
+        sender
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+
+        // A datagram to another port is not captured
+        let initiation = [1u8, 0, 0, 0, 0xaa, 0xbb, 0xcc, 0xdd];
+        sender
+            .send_to(b"not wireguard", ("198.18.0.2", port + 1))
+            .unwrap();
+        sender.send_to(&initiation, ("198.18.0.2", port)).unwrap();
+
+        let mut buf = [0u8; 1500];
+        let started = std::time::Instant::now();
+        let (src, payload) = loop {
+            match capture.recvfrom(&mut buf) {
+                Ok((src, payload)) => break (src, payload.to_vec()),
+                Err(_) if started.elapsed() < std::time::Duration::from_secs(5) => {
+                    std::thread::sleep(std::time::Duration::from_millis(10))
+                }
+                Err(e) => panic!("Nothing captured: {:?}", e),
+            }
+        };
+        assert_eq!(src, sender_addr);
+        assert_eq!(payload, initiation);
+
+        // The reply comes from the address the datagram was sent to
+        assert_eq!(capture.sendto(b"response", src), 8);
+        let (len, from) = sender.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"response");
+        assert_eq!(from, SocketAddr::from(([198, 18, 0, 2], port)));
+
+        // Endpoints that were never heard from can not be reached
+        assert_eq!(
+            capture.sendto(b"response", SocketAddr::from(([198, 18, 0, 3], port))),
+            0
+        );
+
+        drop(capture);
+        ip(&["link", "del", "wgcap0"]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Test that a fwmark set at runtime applies to every listen socket, including those opened
+    /// after it was set
+    fn test_wg_set_fwmark() {
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                listen_sockets: 2,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+        wg.start();
+
+        let marks = || {
+            wg._device
+                .raw_fds()
+                .into_iter()
+                .filter(|&(_, role)| role == FdRole::ListenSocket)
+                .map(|(fd, _)| {
+                    let mut mark = 0u32;
+                    let mut len = std::mem::size_of::<u32>() as libc::socklen_t;
+                    assert_eq!(
+                        unsafe {
+                            libc::getsockopt(
+                                fd,
+                                libc::SOL_SOCKET,
+                                libc::SO_MARK,
+                                &mut mark as *mut u32 as *mut libc::c_void,
+                                &mut len,
+                            )
+                        },
+                        0
+                    );
+                    mark
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(marks(), [0; 4]);
+        assert!(!wg.wg_get().contains("fwmark="));
+
+        assert_eq!(wg.wg_set("fwmark=51820"), "errno=0\n\n");
+        assert_eq!(marks(), [51820; 4]);
+        assert!(wg.wg_get().contains("fwmark=51820\n"));
+        assert_eq!(wg._device.device.read().fwmark(), Some(51820));
+
+        // Sockets opened later are marked too
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+        assert_eq!(marks(), [51820; 4]);
+
+        wg._device
+            .device
+            .read()
+            .try_writeable(
+                |device| device.trigger_yield(),
+                |device| {
+                    device.cancel_yield();
+                    device.set_fwmark(7)
+                },
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(marks(), [7; 4]);
+
+        // A mark of 0 clears it
+        assert_eq!(wg.wg_set("fwmark=0"), "errno=0\n\n");
+        assert_eq!(marks(), [0; 4]);
+        assert!(!wg.wg_get().contains("fwmark="));
+        assert_eq!(wg._device.device.read().fwmark(), None);
+    }
+
+    /// Test that no more than max_peers peers can be added, and that removing one frees a slot
+    #[test]
+    fn test_wg_max_peers() {
+        use crate::device::api::ConfigErrorKind;
+
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                max_peers: 2,
+                ..Default::default()
+            },
+        );
+        wg.wg_set_key(&X25519SecretKey::new());
+        let keys: Vec<_> = (0..3)
+            .map(|_| X25519SecretKey::new().public_key())
+            .collect();
+        let add =
+            |key: &X25519PublicKey| wg.wg_set(&format!("public_key={}", encode(key.as_bytes())));
+
+        assert_eq!(add(&keys[0]), "errno=0\n\n");
+        assert_eq!(add(&keys[1]), "errno=0\n\n");
+        let cfg = format!("public_key={}", encode(keys[2].as_bytes()));
+        assert_eq!(
+            wg._device
+                .device
+                .read()
+                .validate_config(&cfg)
+                .map_err(|e| e.kind),
+            Err(ConfigErrorKind::TooManyPeers)
+        );
+        assert_eq!(add(&keys[2]), "errno=28\n\n");
+        assert_eq!(wg._device.device.read().peers().len(), 2);
+
+        // A runtime option of an existing peer is not an add
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nenabled=false",
+                encode(keys[0].as_bytes())
+            )),
+            "errno=0\n\n"
+        );
+
+        // Replacing a peer in a single block frees its slot first
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nremove=true\npublic_key={}",
+                encode(keys[0].as_bytes()),
+                encode(keys[2].as_bytes())
+            )),
+            "errno=0\n\n"
+        );
+        assert!(!wg.wg_get().contains(&encode(keys[0].as_bytes())));
+
+        // Concurrent adds are serialized by the device lock, only as many as fit succeed
+        assert_eq!(wg.wg_set("replace_peers=true"), "errno=0\n\n");
+        let keys: Vec<_> = (0..8)
+            .map(|_| X25519SecretKey::new().public_key())
+            .collect();
+        let results: Vec<String> = std::thread::scope(|s| {
+            let handles: Vec<_> = keys.iter().map(|key| s.spawn(move || add(key))).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(results.iter().filter(|r| *r == "errno=0\n\n").count(), 2);
+        assert_eq!(results.iter().filter(|r| *r == "errno=28\n\n").count(), 6);
+        assert_eq!(wg._device.device.read().peers().len(), 2);
+    }
+
+    /// Test that decapsulated packets are written to the mirror interface as well as the tunnel
+    #[test]
+    fn test_wg_mirror_tun() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+        assert_eq!(
+            wg.wg_set(&format!("mirror_tun={}", wg.name)),
+            "errno=19\n\n"
+        );
+        let mirror = format!("utun{}", NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed));
+        assert_eq!(wg.wg_set(&format!("mirror_tun={}", mirror)), "errno=0\n\n");
+        assert!(wg
+            .wg_get()
+            .contains(&format!("mirror_tun={}\nmirror_drops=0\n", mirror)));
+
+        let peer = loopback_peer(&mut wg, &private_key);
+        Command::new("ip")
+            .args(&["link", "set", "up", "dev", &mirror])
+            .status()
+            .expect("failed to start the mirror");
+
+        // A UDP datagram into the tunnel makes the device establish a session with the peer
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        peer.send_into_tunnel(b"session");
+        assert!(peer.pump_until_delivered(device_addr).is_some());
+
+        // Packets arriving on an interface, as written to its TUN device
+        let capture = |name: &str| {
+            let protocol = (libc::ETH_P_ALL as u16).to_be();
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_PACKET,
+                    libc::SOCK_DGRAM | libc::SOCK_NONBLOCK,
+                    i32::from(protocol),
+                )
+            };
+            assert!(fd >= 0);
+            let name = std::ffi::CString::new(name).unwrap();
+            let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+            addr.sll_family = libc::AF_PACKET as u16;
+            addr.sll_protocol = protocol;
+            addr.sll_ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) } as i32;
+            let len = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+            assert_eq!(
+                unsafe { libc::bind(fd, &addr as *const _ as *const libc::sockaddr, len) },
+                0
+            );
+            fd
+        };
+        let captured = |fd: i32, payload: &[u8]| {
+            let mut buf = [0u8; 2048];
+            let started = std::time::Instant::now();
+            while started.elapsed() < std::time::Duration::from_secs(5) {
+                match unsafe { libc::recv(fd, buf.as_mut_ptr() as _, buf.len(), 0) } {
+                    n if n > 0 && buf[..n as usize].ends_with(payload) => return true,
+                    n if n > 0 => {}
+                    _ => std::thread::sleep(std::time::Duration::from_millis(10)),
+                }
+            }
+            false
+        };
+        let primary_capture = capture(&wg.name);
+        let mirror_capture = capture(&mirror);
+
+        let mut inner_packet = vec![0x45, 0, 0, 34, 0, 0, 0, 0, 64, 17, 0, 0];
+        for ip in &[peer.ip, wg.addr_v4] {
+            match ip {
+                IpAddr::V4(ip) => inner_packet.extend_from_slice(&ip.octets()),
+                _ => unreachable!(),
+            }
+        }
+        inner_packet.extend_from_slice(&[0x27, 0x0f, 0x27, 0x0f, 0, 14, 0, 0]);
+        inner_packet.extend_from_slice(b"mirror");
+        peer.send_inner(&inner_packet, device_addr);
+        assert!(captured(primary_capture, b"mirror"));
+        assert!(captured(mirror_capture, b"mirror"));
+        assert_eq!(wg._device.device.read().mirror_drops(), 0);
+
+        // A mirror that is down does not take the copies, the tunnel still does
+        Command::new("ip")
+            .args(&["link", "set", "down", "dev", &mirror])
+            .status()
+            .expect("failed to stop the mirror");
+        inner_packet.truncate(inner_packet.len() - 6);
+        inner_packet.extend_from_slice(b"dropme");
+        peer.send_inner(&inner_packet, device_addr);
+        assert!(captured(primary_capture, b"dropme"));
+        assert_eq!(wg._device.device.read().mirror_drops(), 1);
+        assert!(wg.wg_get().contains("mirror_drops=1\n"));
+
+        unsafe {
+            libc::close(primary_capture);
+            libc::close(mirror_capture);
+        }
+        assert_eq!(wg.wg_set("mirror_tun="), "errno=0\n\n");
+        assert!(!wg.wg_get().contains("mirror_tun"));
+    }
+
+    /// Test that the device does not start while the OS random number generator is not ready
+    #[test]
+    fn test_wg_entropy_unavailable() {
+        use crate::device::entropy::FORCE_UNAVAILABLE;
+
+        let name = format!("utun{}", NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed));
+        FORCE_UNAVAILABLE.with(|force| force.set(true));
+        let result = DeviceHandle::<TunSocket, UDPSocket>::new(&name, Default::default());
+        FORCE_UNAVAILABLE.with(|force| force.set(false));
+        match result {
+            Err(crate::device::Error::Entropy(msg)) => assert!(msg.contains("not seeded")),
+            _ => panic!("Expected the device to refuse to start"),
+        }
+
+        // The interface was never created
+        let status = Command::new("ip")
+            .args(&["link", "show", "dev", &name])
+            .output()
+            .unwrap()
+            .status;
+        assert!(!status.success());
+        assert!(DeviceHandle::<TunSocket, UDPSocket>::new(&name, Default::default()).is_ok());
+    }
+
+    /// Test that the metadata of a peer is reported with its stats and events
+    #[test]
+    fn test_wg_peer_metadata() {
+        use crate::device::events::DeviceEvent;
+
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+        let events = wg._device.device.read().subscribe();
+
+        let peer = LoopbackPeer::new(&private_key);
+        let key = encode(peer.public_key.as_bytes());
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nendpoint={}\nallowed_ip={}/32\npeer_metadata=inventory 42",
+                key, peer.addr, peer.ip
+            )),
+            "errno=0\n\n"
+        );
+        assert!(wg.wg_get().contains("peer_metadata=inventory 42\n"));
+        let too_long = "x".repeat(crate::device::peer::MAX_METADATA_LEN + 1);
+        for metadata in &[too_long.as_str(), "a\tb"] {
+            let req = format!("public_key={}\npeer_metadata={}", key, metadata);
+            assert_eq!(wg.wg_set(&req), "errno=22\n\n");
+        }
+
+        wg.start();
+        add_route(&wg, peer.ip);
+        let stats = || {
+            wg._device
+                .device
+                .read()
+                .peer_stats(&peer.public_key)
+                .unwrap()
+        };
+        assert_eq!(stats().metadata.as_deref(), Some("inventory 42"));
+
+        // A UDP datagram into the tunnel makes the device complete a handshake with the peer
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        peer.send_into_tunnel(b"session");
+        assert!(peer.pump_until_delivered(device_addr).is_some());
+
+        loop {
+            match events
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .unwrap()
+            {
+                DeviceEvent::HandshakeCompleted { metadata, .. } => {
+                    assert_eq!(metadata.as_deref(), Some("inventory 42"));
+                    break;
+                }
+                _ => continue,
+            }
+        }
+
+        assert_eq!(
+            wg.wg_set(&format!("public_key={}\npeer_metadata=", key)),
+            "errno=0\n\n"
+        );
+        assert_eq!(stats().metadata, None);
+        assert!(!wg.wg_get().contains("peer_metadata"));
+    }
+
+    #[test]
+    /// A peer given by name follows its DNS record to a new address on re-resolve
+    fn test_wg_reresolve_endpoints() {
+        let bind = || {
+            let sock = UDPSocket::new()
+                .and_then(|s| s.set_non_blocking())
+                .and_then(|s| s.bind(0))
+                .unwrap();
+            let addr = SocketAddr::from(([127, 0, 0, 1], sock.port().unwrap()));
+            (sock, addr)
+        };
+        let (_sock_a, addr_a) = bind();
+        let (sock_b, addr_b) = bind();
+
+        // The record the stub resolver answers with, or none
+        let record = Arc::new(parking_lot::Mutex::new(Some(addr_a)));
+        let resolver = {
+            let record = Arc::clone(&record);
+            Box::new(move |host: &str| {
+                assert_eq!(host, "home.example.net:51820");
+                match *record.lock() {
+                    Some(addr) => Ok(vec![addr]),
+                    None => Err(std::io::Error::from(std::io::ErrorKind::NotFound)),
+                }
+            })
+        };
+
+        let private_key = X25519SecretKey::new();
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                resolver,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+
+        let peer_key = X25519SecretKey::new().public_key();
+        let key = encode(peer_key.as_bytes());
+        assert_eq!(
+            wg.wg_set(&format!("public_key={}\nendpoint=home example:51820", key)),
+            "errno=22\n\n"
+        );
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nendpoint=home.example.net:51820\nallowed_ip={}/32",
+                key,
+                next_ip()
+            )),
+            "errno=0\n\n"
+        );
+        let get = wg.wg_get();
+        assert!(get.contains(&format!(
+            "endpoint={}\nendpoint_host=home.example.net:51820\n",
+            addr_a
+        )));
+
+        let endpoint = || wg._device.device.read().peers()[0].endpoint;
+        let reresolve = || wg._device.device.read().reresolve_endpoints();
+        assert_eq!(reresolve(), 0);
+
+        // The record changes, the peer moves with a handshake to the new address
+        *record.lock() = Some(addr_b);
+        assert_eq!(reresolve(), 1);
+        assert_eq!(endpoint(), Some(addr_b));
+        let mut buf = [0u8; 2048];
+        let started = std::time::Instant::now();
+        let initiation = loop {
+            match sock_b.recvfrom(&mut buf) {
+                Ok((_, packet)) => break Some(packet[0]),
+                Err(_) if started.elapsed() < std::time::Duration::from_secs(5) => {
+                    std::thread::sleep(std::time::Duration::from_millis(10))
+                }
+                Err(_) => break None,
+            }
+        };
+        assert_eq!(initiation, Some(1));
+
+        // A failed resolution leaves the endpoint alone
+        *record.lock() = None;
+        assert_eq!(reresolve(), 0);
+        assert_eq!(endpoint(), Some(addr_b));
+    }
+
+    #[test]
+    /// Handshakes beyond the rate limit get a cookie reply, and those with a wrong mac1 are
+    /// dropped, each counted apart
+    fn test_wg_rate_limit_stats() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let mut wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+        wg.start();
+
+        let initiator = Tunn::new(
+            Arc::new(X25519SecretKey::new()),
+            public_key,
+            None,
+            None,
+            0,
+            None,
+        )
+        .unwrap();
+        let mut buf = [0u8; 2048];
+        let init = match initiator.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let mut bad_mac1 = init.clone();
+        bad_mac1[init.len() - 20] ^= 1;
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        sender.send_to(&bad_mac1, device_addr).unwrap();
+        for _ in 0..150 {
+            sender.send_to(&init, device_addr).unwrap();
+        }
+
+        // The first cookie reply comes back once the limit of the second is exceeded
+        sender
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let cookie_reply = loop {
+            let (n, _) = sender.recv_from(&mut buf).unwrap();
+            if buf[0] == 3 {
+                break n;
+            }
+        };
+        assert_eq!(cookie_reply, 64);
+
+        let stats = wg._device.device.read().rate_limit_stats();
+        assert!(stats.cookie_replies > 0);
+        assert_eq!(stats.invalid_mac, 1);
+        let get = wg.wg_get();
+        assert!(get.contains("cookie_replies="));
+        assert!(get.contains("invalid_mac_drops=1\n"));
+    }
+
+    #[test]
+    /// Only clients with an allowed uid or gid may use the configuration socket
+    fn test_wg_api_allowed_uids() {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                api_allowed_uids: vec![uid],
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(next_port()), "errno=0\n\n");
+        assert!(wg.wg_get().ends_with("errno=0\n\n"));
+
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                api_allowed_gids: vec![gid],
+                ..Default::default()
+            },
+        );
+        assert!(wg.wg_get().ends_with("errno=0\n\n"));
+
+        // Neither the uid nor the gid of this process is allowed
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                api_allowed_uids: vec![uid.wrapping_add(1)],
+                api_allowed_gids: vec![gid.wrapping_add(1)],
+                ..Default::default()
+            },
+        );
+        let path = format!("/var/run/wireguard/{}.sock", wg.name);
+        let mut refused = String::new();
+        UnixStream::connect(path)
+            .unwrap()
+            .read_to_string(&mut refused)
+            .unwrap();
+        assert_eq!(refused, "errno=13\n\n");
+    }
+
+    #[test]
+    /// The digest of a device matches its declared config until a peer drifts from it
+    fn test_wg_config_digest() {
+        use crate::device::drift::{Config, ConfigChange};
+
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                ..Default::default()
+            },
+        );
+        let (a, b) = (X25519SecretKey::new(), X25519SecretKey::new());
+        let (a_ip, b_ip) = (next_ip(), next_ip());
+        let declared = format!(
+            "private_key={}\nlisten_port={}\npublic_key={}\nendpoint=127.0.0.1:51820\n\
+             allowed_ip={}/32\npublic_key={}\nallowed_ip={}/32",
+            &*X25519SecretKey::new().to_hex(),
+            next_port(),
+            encode(a.public_key().as_bytes()),
+            a_ip,
+            encode(b.public_key().as_bytes()),
+            b_ip
+        );
+        assert_eq!(wg.wg_set(&declared), "errno=0\n\n");
+        let declared: Config = declared.parse().unwrap();
+
+        let device = || wg._device.device.read();
+        let digest = device().config_digest();
+        assert_eq!(digest, declared.digest());
+        assert_eq!(device().config_digest(), digest);
+        assert!(device().diff_against(&declared).is_empty());
+
+        // Peer b is replaced with another allowed IP
+        let drifted_ip = next_ip();
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nremove=true\npublic_key={}\nallowed_ip={}/32",
+                encode(b.public_key().as_bytes()),
+                encode(b.public_key().as_bytes()),
+                drifted_ip
+            )),
+            "errno=0\n\n"
+        );
+        assert_ne!(device().config_digest(), digest);
+        assert_eq!(
+            device().diff_against(&declared),
+            [ConfigChange::ChangeAllowedIps {
+                public_key: b.public_key(),
+                add: vec![format!("{}/32", b_ip).parse().unwrap()],
+                remove: vec![format!("{}/32", drifted_ip).parse().unwrap()],
+            }]
+        );
+    }
+
+    /// Test that a peer with the liveness probe on is marked reachable once it answers the echo
+    /// request sent through the tunnel
+    #[test]
+    fn test_wg_liveness_probe() {
+        let port = next_port();
+        let private_key = X25519SecretKey::new();
+        let public_key = Arc::new(private_key.public_key());
+
+        let wg = WGHandle::init_with_config(
+            next_ip(),
+            next_ip_v6(),
+            DeviceConfig {
+                n_threads: 1,
+                use_connected_socket: false,
+                liveness_probe_interval: std::time::Duration::ZERO,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(&private_key), "errno=0\n\n");
+        let addr = next_ip();
+        assert_eq!(wg.wg_set(&format!("address={}/32", addr)), "errno=0\n\n");
+
+        let peer_sock = UDPSocket::new().unwrap().bind(0).unwrap();
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], peer_sock.port().unwrap()));
+        let peer_key = Arc::new(X25519SecretKey::new());
+        let peer_public_key = peer_key.public_key();
+        let peer_ip = next_ip();
+        assert_eq!(
+            wg.wg_set_peer(
+                &peer_public_key,
+                &peer_addr,
+                &[AllowedIp {
+                    ip: peer_ip,
+                    cidr: 32
+                }]
+            ),
+            "errno=0\n\n"
+        );
+        let device = || wg._device.device.read();
+        assert_eq!(device().send_liveness_probes(), 0);
+        assert_eq!(
+            wg.wg_set(&format!(
+                "public_key={}\nliveness_probe=on",
+                encode(peer_public_key.as_bytes())
+            )),
+            "errno=0\n\n"
+        );
+        assert!(wg.wg_get().contains("liveness_probe=on"));
+        assert_eq!(
+            device().peer_stats(&peer_public_key).unwrap().reachable,
+            None
+        );
+
+        // The probe starts a handshake, and is sent once it completes
+        assert_eq!(device().send_liveness_probes(), 1);
+        assert_eq!(device().send_liveness_probes(), 0);
+        let peer = Tunn::new(peer_key, public_key, None, None, 0, None).unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        let request = loop {
+            let (_, packet) = peer_sock.recvfrom(&mut buf).unwrap();
+            match peer.decapsulate(None, packet, &mut dst) {
+                TunnResult::WriteToNetwork(packet) => {
+                    peer_sock.sendto(packet, device_addr);
+                }
+                TunnResult::WriteToTunnelV4(packet, src) => {
+                    assert_eq!(src, addr);
+                    break packet.to_vec();
+                }
+                _ => {}
+            }
+        };
+        assert!(matches!(
+            liveness::echo_reply(&liveness::answer(&request)),
+            Some((ip, _, 0)) if ip == peer_ip
+        ));
+
+        match peer.encapsulate(&liveness::answer(&request), &mut dst) {
+            TunnResult::WriteToNetwork(packet) => {
+                peer_sock.sendto(packet, device_addr);
+            }
+            _ => panic!("Expected a data packet"),
+        }
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while device().peer_stats(&peer_public_key).unwrap().reachable != Some(true) {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "The peer is not reachable"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(wg.wg_get().contains("reachable=true"));
+    }
+
+    /// Two devices in userspace mode, without tunnel interfaces, exchange inner packets through
+    /// inject_inner and on_inner_packet only
+    #[test]
+    fn test_wg_userspace_mode() {
+        use std::sync::{mpsc, Mutex};
+        use std::time::Duration;
+
+        let userspace = || {
+            let (sender, receiver) = mpsc::channel();
+            let sender = Mutex::new(sender);
+            let wg = WGHandle::init_with_config(
+                next_ip(),
+                next_ip_v6(),
+                DeviceConfig {
+                    n_threads: 2,
+                    on_inner_packet: Some(Box::new(move |packet: &[u8]| {
+                        sender.lock().unwrap().send(packet.to_vec()).unwrap();
+                    })),
+                    ..Default::default()
+                },
+            );
+            (wg, receiver)
+        };
+        let (wg_a, inner_a) = userspace();
+        let (wg_b, inner_b) = userspace();
+        assert!(wg_a
+            ._device
+            .raw_fds()
+            .iter()
+            .all(|(_, role)| *role != FdRole::Tun));
+
+        let (key_a, key_b) = (X25519SecretKey::new(), X25519SecretKey::new());
+        let (port_a, port_b) = (next_port(), next_port());
+        let (ip_a, ip_b) = (next_ip(), next_ip());
+        for (wg, key, port, peer_key, peer_port, peer_ip) in [
+            (&wg_a, &key_a, port_a, &key_b, port_b, ip_b),
+            (&wg_b, &key_b, port_b, &key_a, port_a, ip_a),
+        ] {
+            assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
+            assert_eq!(wg.wg_set_key(key), "errno=0\n\n");
+            assert_eq!(
+                wg.wg_set_peer(
+                    &peer_key.public_key(),
+                    &SocketAddr::from(([127, 0, 0, 1], peer_port)),
+                    &[AllowedIp {
+                        ip: peer_ip,
+                        cidr: 32
+                    }]
+                ),
+                "errno=0\n\n"
+            );
+        }
+
+        // A UDP packet from src to dst, with a payload to tell it apart
+        let packet = |src: IpAddr, dst: IpAddr, payload: &[u8]| {
+            let (src, dst) = match (src, dst) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => (src.octets(), dst.octets()),
+                _ => unreachable!(),
+            };
+            let len = (28 + payload.len()) as u16;
+            let mut packet = vec![
+                0x45,
+                0,
+                (len >> 8) as u8,
+                len as u8,
+                0,
+                0,
+                0,
+                0,
+                64,
+                17,
+                0,
+                0,
+            ];
+            packet.extend_from_slice(&src);
+            packet.extend_from_slice(&dst);
+            packet.extend_from_slice(&[0x30, 0x39, 0x30, 0x39, 0, 8 + payload.len() as u8, 0, 0]);
+            packet.extend_from_slice(payload);
+            packet
+        };
+
+        // The first packet starts the handshake, and is delivered once it completes
+        let ping = packet(ip_a, ip_b, b"ping");
+        wg_a._device.inject_inner(&ping).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(inner_b.recv_timeout(timeout).unwrap(), ping);
+
+        let pong = packet(ip_b, ip_a, b"pong");
+        wg_b._device.inject_inner(&pong).unwrap();
+        assert_eq!(inner_a.recv_timeout(timeout).unwrap(), pong);
+        for i in 0..10u8 {
+            let packet = packet(ip_a, ip_b, &[i; 100]);
+            wg_a._device.inject_inner(&packet).unwrap();
+            assert_eq!(inner_b.recv_timeout(timeout).unwrap(), packet);
+        }
+        assert!(inner_a.try_recv().is_err());
+
+        // Packets no peer is routed to are refused
+        assert!(matches!(
+            wg_a._device.inject_inner(&packet(ip_a, next_ip(), b"lost")),
+            Err(crate::device::Error::NoRoute)
+        ));
+        assert!(matches!(
+            wg_a._device.inject_inner(&[0x45, 0, 0]),
+            Err(crate::device::Error::NoRoute)
+        ));
+    }
 }
@@ -5,7 +5,7 @@ use super::{errno_str, Error};
 use libc::*;
 use parking_lot::Mutex;
 use std::ops::Deref;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr::{null, null_mut};
 use std::time::Duration;
 
@@ -25,6 +25,15 @@ pub struct EventPoll<H: Sized> {
     custom: Mutex<Vec<Option<Box<Event<H>>>>>, // Other events (i.e. timers & notifiers)
     signals: Mutex<Vec<Option<Box<Event<H>>>>>, // Signal handlers
     kqueue: RawFd,                             // The OS kqueue
+    batch_size: usize, // The maximal number of events returned by a single kevent
+}
+
+/// Events returned by a single kevent call that were not yet handed out by EventPoll::wait
+/// Each thread waiting on the poll should own a batch
+pub struct EventBatch {
+    events: Vec<kevent>,
+    len: usize,  // The number of events returned by the last kevent
+    next: usize, // The next event to hand out
 }
 
 /// A type that hold a reference to a triggered Event
@@ -41,9 +50,11 @@ pub struct EventRef {
     trigger: RawFd,
 }
 
-#[derive(PartialEq)]
-enum EventKind {
-    FD,
+/// The kind of an event, as listed by EventPoll::registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// Triggered when the fd is readable
+    Fd,
     Notifier,
     Signal,
     Timer,
@@ -68,6 +79,12 @@ unsafe impl<H> Sync for EventPoll<H> {}
 impl<H: Send + Sync> EventPoll<H> {
     /// Create a new event registry
     pub fn new() -> Result<EventPoll<H>, Error> {
+        EventPoll::new_with_batch_size(1)
+    }
+
+    /// Create a new event registry, that retrieves up to batch_size triggered events from the OS
+    /// at once
+    pub fn new_with_batch_size(batch_size: usize) -> Result<EventPoll<H>, Error> {
         let kqueue = match unsafe { kqueue() } {
             -1 => return Err(Error::EventQueue(errno_str())),
             kqueue => kqueue,
@@ -78,9 +95,33 @@ impl<H: Send + Sync> EventPoll<H> {
             custom: Mutex::new(vec![]),
             signals: Mutex::new(vec![]),
             kqueue,
+            batch_size: batch_size.max(1),
         })
     }
 
+    /// The maximal number of events retrieved from the OS at once
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Create an empty batch to use with wait
+    pub fn new_batch(&self) -> EventBatch {
+        let event = kevent {
+            ident: 0,
+            filter: 0,
+            flags: 0,
+            fflags: 0,
+            data: 0,
+            udata: null_mut(),
+        };
+
+        EventBatch {
+            events: vec![event; self.batch_size],
+            len: 0,
+            next: 0,
+        }
+    }
+
     /// Add and enable a new event with the factory.
     /// The event is triggered when a Read operation on the provided trigger becomes available
     /// If the trigger fd is closed, the event won't be triggered anymore, but it's data won't be
@@ -107,7 +148,7 @@ impl<H: Send + Sync> EventPoll<H> {
                 udata: null_mut(),
             },
             handler,
-            kind: EventKind::FD,
+            kind: EventKind::Fd,
         };
 
         self.register_event(ev)
@@ -176,20 +217,59 @@ impl<H: Send + Sync> EventPoll<H> {
     /// is triggered, a single caller thread gets the handler for that event.
     /// In case a notifier is triggered, all waiting threads will receive the same
     /// handler.
-    pub fn wait(&'_ self) -> WaitResult<'_, H> {
-        let mut event = kevent {
-            ident: 0,
-            filter: 0,
-            flags: 0,
-            fflags: 0,
-            data: 0,
-            udata: null_mut(),
+    /// Events are retrieved from the OS into the batch, and handed out from it one at a time,
+    /// until the batch is exhausted.
+    pub fn wait(&'_ self, batch: &mut EventBatch) -> WaitResult<'_, H> {
+        match self.next_event(batch, null()) {
+            Some(result) => result,
+            None => WaitResult::Error("unexpected number of events returned".to_string()),
+        }
+    }
+
+    /// Like wait, but returns None instead of blocking when no event is triggered
+    pub fn poll(&'_ self, batch: &mut EventBatch) -> Option<WaitResult<'_, H>> {
+        let timeout = timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
         };
+        self.next_event(batch, &timeout)
+    }
 
-        if unsafe { kevent(self.kqueue, null(), 0, &mut event, 1, null()) } == -1 {
-            return WaitResult::Error(errno_str());
+    // Hand out the next event of the batch, retrieving events from the OS if it is empty
+    fn next_event(
+        &'_ self,
+        batch: &mut EventBatch,
+        timeout: *const timespec,
+    ) -> Option<WaitResult<'_, H>> {
+        if batch.next == batch.len {
+            let max_events = batch.events.len() as c_int;
+            match unsafe {
+                kevent(
+                    self.kqueue,
+                    null(),
+                    0,
+                    batch.events.as_mut_ptr(),
+                    max_events,
+                    timeout,
+                )
+            } {
+                -1 => return Some(WaitResult::Error(errno_str())),
+                0 if !timeout.is_null() => return None,
+                n if n > 0 && n <= max_events => {
+                    batch.len = n as usize;
+                    batch.next = 0;
+                }
+                _ => {
+                    return Some(WaitResult::Error(
+                        "unexpected number of events returned".to_string(),
+                    ))
+                }
+            }
         }
 
+        let event = batch.events[batch.next];
+        batch.next += 1;
+
         let event_data = unsafe { (event.udata as *mut Event<H>).as_ref().unwrap() };
 
         let guard = EventGuard {
@@ -199,22 +279,63 @@ impl<H: Send + Sync> EventPoll<H> {
         };
 
         if event.flags & EV_EOF != 0 {
-            WaitResult::EoF(guard)
+            Some(WaitResult::EoF(guard))
         } else {
-            WaitResult::Ok(guard)
+            Some(WaitResult::Ok(guard))
         }
     }
 
+    /// Re-enable the events in the batch that were not handed out yet, and empty it.
+    /// Must be called before giving up the device lock, since the events may be removed while
+    /// the lock is not held.
+    pub fn release_batch(&self, batch: &mut EventBatch) {
+        for event in &batch.events[batch.next..batch.len] {
+            let event_data = unsafe { (event.udata as *mut Event<H>).as_ref().unwrap() };
+            // Dropping the guard re-enables the event
+            drop(EventGuard {
+                kqueue: self.kqueue,
+                event: &event_data,
+                poll: self,
+            });
+        }
+        batch.len = 0;
+        batch.next = 0;
+    }
+
+    /// The fds of the registered events, with their kind. Timers, notifiers and signals have no
+    /// fd with kqueue, they are not listed.
+    pub fn registered(&self) -> Vec<(RawFd, EventKind)> {
+        self.events
+            .lock()
+            .iter()
+            .flatten()
+            .map(|event| (event.event.ident as RawFd, event.kind))
+            .collect()
+    }
+
+    /// Call f with the handler of the event registered for fd, for callers that poll the fds
+    /// themselves instead of waiting on the poll. Returns None if there is no event for fd.
+    /// Like for wait, events must not be removed while f runs.
+    pub fn with_handler<R>(&self, fd: RawFd, f: impl FnOnce(&H) -> R) -> Option<R> {
+        let event = {
+            // The lock is not held while f runs, the handler may register new events
+            let events = self.events.lock();
+            let event = events.get(fd as usize)?.as_ref()?; // A negative fd is out of range
+            event.as_ref() as *const Event<H>
+        };
+        Some(f(unsafe { &(*event).handler }))
+    }
+
     // Register an event with this poll.
     fn register_event(&self, ev: Event<H>) -> Result<EventRef, Error> {
         let mut events = match ev.kind {
-            EventKind::FD => self.events.lock(),
+            EventKind::Fd => self.events.lock(),
             EventKind::Timer | EventKind::Notifier => self.custom.lock(),
             EventKind::Signal => self.signals.lock(),
         };
 
         let (trigger, index) = match ev.kind {
-            EventKind::FD | EventKind::Signal => (ev.event.ident as RawFd, ev.event.ident as usize),
+            EventKind::Fd | EventKind::Signal => (ev.event.ident as RawFd, ev.event.ident as usize),
             EventKind::Timer | EventKind::Notifier => (-(events.len() as RawFd) - 1, events.len()), // Custom events get negative identifiers, hopefully we will never have more than 2^31 events of each type
         };
 
@@ -298,7 +419,8 @@ impl<H> EventPoll<H> {
             (self.custom.lock(), (-index - 1) as usize)
         };
 
-        if let Some(mut event) = events[index].take() {
+        // The fd may be registered with another poll instead
+        if let Some(Some(mut event)) = events.get_mut(index).map(Option::take) {
             // Properly remove any previous event first
             event.event.flags = EV_DELETE;
             kevent(self.kqueue, &event.event, 1, null_mut(), 0, null());
@@ -306,6 +428,20 @@ impl<H> EventPoll<H> {
     }
 }
 
+impl<H> AsRawFd for EventPoll<H> {
+    // The fd is readable when an event of the poll is triggered, so a poll can wait on another
+    fn as_raw_fd(&self) -> RawFd {
+        self.kqueue
+    }
+}
+
+impl EventBatch {
+    /// Whether every event retrieved into the batch was handed out
+    pub fn is_empty(&self) -> bool {
+        self.next == self.len
+    }
+}
+
 impl<'a, H> Deref for EventGuard<'a, H> {
     type Target = H;
     fn deref(&self) -> &H {
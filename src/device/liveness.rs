@@ -0,0 +1,356 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Liveness probes. A peer with `liveness_probe=on` is sent an ICMP echo request through the
+//! tunnel every `DeviceConfig::liveness_probe_interval`, from the address of the interface,
+//! set with `address=`, to the first of its allowed IPs of the same family, or to the first
+//! host of it when it is a network, which is usually the gateway on the side of the peer. The
+//! peer is reachable once a reply comes back, and unreachable when the next probe is due before
+//! the reply to the last one. Unlike the age of the last handshake, this shows that the peer
+//! still decrypts and routes what it is sent. Replies to probes never reach the tunnel
+//! interface.
+
+use super::offload::{checksum_add, checksum_fold};
+use crate::crypto::x25519::{OsRng, Rng};
+use parking_lot::Mutex;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+/// Probes of the same peer are at least this far apart, however often they are asked for
+pub const MIN_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_ICMPV6: u8 = 58;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const ICMP_HEADER_SIZE: usize = 8;
+const TTL: u8 = 64;
+
+// The payload of every probe, so they are easy to tell apart in a capture
+const PROBE_PAYLOAD: &[u8] = b"wg-alive";
+
+const UNKNOWN: u8 = 0;
+const REACHABLE: u8 = 1;
+const UNREACHABLE: u8 = 2;
+
+/// The liveness probe of a peer
+#[derive(Debug)]
+pub struct Liveness {
+    enabled: AtomicBool,
+    reachable: AtomicU8, // UNKNOWN until the first probe was answered or missed
+    ident: u16,          // The identifier of the echo requests, drawn at random
+    probe: Mutex<Probe>,
+}
+
+#[derive(Debug, Default)]
+struct Probe {
+    next_seq: u16,
+    sent: Option<Instant>,
+    outstanding: Option<(IpAddr, u16)>, // The target and sequence number of the last probe
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        let mut ident = [0u8; 2];
+        OsRng.fill(&mut ident);
+        Liveness {
+            enabled: AtomicBool::new(false),
+            reachable: AtomicU8::new(UNKNOWN),
+            ident: u16::from_ne_bytes(ident),
+            probe: Mutex::new(Probe::default()),
+        }
+    }
+}
+
+impl Liveness {
+    pub fn new() -> Liveness {
+        Default::default()
+    }
+
+    /// Turning the probe off forgets whether the peer was reachable
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            self.reachable.store(UNKNOWN, Ordering::Relaxed);
+            *self.probe.lock() = Probe::default();
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Whether the last probe was answered, None before the first one was answered or missed
+    /// and when the probe is off
+    pub fn reachable(&self) -> Option<bool> {
+        match self.reachable.load(Ordering::Relaxed) {
+            REACHABLE => Some(true),
+            UNREACHABLE => Some(false),
+            _ => None,
+        }
+    }
+
+    /// The echo request of the next probe from src to dst, or None when the probe is off, the
+    /// addresses are of different families, or the last probe was sent less than
+    /// `MIN_PROBE_INTERVAL` ago. The peer is unreachable if the last probe is still waiting for
+    /// its reply.
+    pub fn next_probe(&self, src: IpAddr, dst: IpAddr) -> Option<Vec<u8>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut probe = self.probe.lock();
+        if matches!(probe.sent, Some(sent) if sent.elapsed() < MIN_PROBE_INTERVAL) {
+            return None;
+        }
+        let packet = echo_request(src, dst, self.ident, probe.next_seq)?;
+        if probe.outstanding.is_some() {
+            self.reachable.store(UNREACHABLE, Ordering::Relaxed);
+        }
+        probe.outstanding = Some((dst, probe.next_seq));
+        probe.next_seq = probe.next_seq.wrapping_add(1);
+        probe.sent = Some(Instant::now());
+        Some(packet)
+    }
+
+    /// Returns true if the decapsulated packet is the reply to a probe, which marks the peer
+    /// reachable when it answers the last one
+    pub fn take_reply(&self, packet: &[u8]) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let (src, _, seq) = match echo_reply(packet) {
+            Some(reply) if reply.1 == self.ident => reply,
+            _ => return false,
+        };
+        let mut probe = self.probe.lock();
+        match probe.outstanding {
+            Some((target, last)) if target == src => {
+                if seq == last {
+                    probe.outstanding = None;
+                    self.reachable.store(REACHABLE, Ordering::Relaxed);
+                }
+                // Late replies to earlier probes are dropped as well
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The address probes are sent to for an allowed IP: the address itself for a single host,
+/// and the first host of a network otherwise
+pub fn target(addr: IpAddr, cidr: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) if cidr < 32 => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(cidr)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from((u32::from(addr) & mask).wrapping_add(1)))
+        }
+        IpAddr::V6(addr) if cidr < 128 => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(cidr)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from((u128::from(addr) & mask).wrapping_add(1)))
+        }
+        addr => addr,
+    }
+}
+
+/// An ICMP or ICMPv6 echo request, None if src and dst are of different families
+pub fn echo_request(src: IpAddr, dst: IpAddr, ident: u16, seq: u16) -> Option<Vec<u8>> {
+    let icmp_len = ICMP_HEADER_SIZE + PROBE_PAYLOAD.len();
+    let mut icmp = vec![0u8; icmp_len];
+    icmp[4..6].copy_from_slice(&ident.to_be_bytes());
+    icmp[6..8].copy_from_slice(&seq.to_be_bytes());
+    icmp[ICMP_HEADER_SIZE..].copy_from_slice(PROBE_PAYLOAD);
+
+    let mut packet = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            icmp[0] = ICMP_ECHO_REQUEST;
+            let mut header = vec![0u8; IPV4_HEADER_SIZE];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((IPV4_HEADER_SIZE + icmp_len) as u16).to_be_bytes());
+            header[8] = TTL;
+            header[9] = IPPROTO_ICMP;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let sum = !checksum_fold(checksum_add(0, &header));
+            header[10..12].copy_from_slice(&sum.to_be_bytes());
+            header
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            icmp[0] = ICMPV6_ECHO_REQUEST;
+            let mut header = vec![0u8; IPV6_HEADER_SIZE];
+            header[0] = 0x60;
+            header[4..6].copy_from_slice(&(icmp_len as u16).to_be_bytes());
+            header[6] = IPPROTO_ICMPV6;
+            header[7] = TTL;
+            header[8..24].copy_from_slice(&src.octets());
+            header[24..40].copy_from_slice(&dst.octets());
+            header
+        }
+        _ => return None,
+    };
+    set_icmp_checksum(header_addrs(&packet), &mut icmp);
+    packet.extend_from_slice(&icmp);
+    Some(packet)
+}
+
+// The source and destination addresses in the IP header of a packet
+fn header_addrs(packet: &[u8]) -> &[u8] {
+    match packet[0] >> 4 {
+        4 => &packet[12..20],
+        _ => &packet[8..40],
+    }
+}
+
+// The checksum of ICMP only covers the message, the checksum of ICMPv6 also covers a pseudo
+// header of the addresses, the length and the protocol
+fn set_icmp_checksum(addrs: &[u8], icmp: &mut [u8]) {
+    icmp[2..4].copy_from_slice(&[0, 0]);
+    let sum = match addrs.len() {
+        8 => 0,
+        _ => checksum_add(0, addrs) + icmp.len() as u32 + u32::from(IPPROTO_ICMPV6),
+    };
+    let sum = !checksum_fold(checksum_add(sum, icmp));
+    icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// The source, identifier and sequence number of an ICMP or ICMPv6 echo reply
+pub fn echo_reply(packet: &[u8]) -> Option<(IpAddr, u16, u16)> {
+    let (src, icmp) = match packet.first()? >> 4 {
+        4 if packet.len() >= IPV4_HEADER_SIZE => {
+            let ip_len = usize::from(packet[0] & 0x0f) * 4;
+            let frag = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff; // MF and offset
+            if packet[9] != IPPROTO_ICMP || frag != 0 || ip_len < IPV4_HEADER_SIZE {
+                return None;
+            }
+            let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+            (IpAddr::V4(src), packet.get(ip_len..)?)
+        }
+        6 if packet.len() >= IPV6_HEADER_SIZE => {
+            if packet[6] != IPPROTO_ICMPV6 {
+                return None;
+            }
+            let mut src = [0u8; 16];
+            src.copy_from_slice(&packet[8..24]);
+            (IpAddr::V6(Ipv6Addr::from(src)), &packet[IPV6_HEADER_SIZE..])
+        }
+        _ => return None,
+    };
+
+    let reply_type = match src {
+        IpAddr::V4(_) => ICMP_ECHO_REPLY,
+        IpAddr::V6(_) => ICMPV6_ECHO_REPLY,
+    };
+    if icmp.len() < ICMP_HEADER_SIZE || icmp[0] != reply_type || icmp[1] != 0 {
+        return None;
+    }
+    let ident = u16::from_be_bytes([icmp[4], icmp[5]]);
+    let seq = u16::from_be_bytes([icmp[6], icmp[7]]);
+    Some((src, ident, seq))
+}
+
+/// The reply a host sends to an echo request, for tests
+#[cfg(test)]
+pub fn answer(request: &[u8]) -> Vec<u8> {
+    let mut reply = request.to_vec();
+    let (header_len, reply_type) = match reply[0] >> 4 {
+        4 => {
+            let (src, dst) = reply.split_at_mut(16);
+            src[12..16].swap_with_slice(&mut dst[..4]);
+            (IPV4_HEADER_SIZE, ICMP_ECHO_REPLY)
+        }
+        _ => {
+            let (src, dst) = reply.split_at_mut(24);
+            src[8..24].swap_with_slice(&mut dst[..16]);
+            (IPV6_HEADER_SIZE, ICMPV6_ECHO_REPLY)
+        }
+    };
+    let (header, icmp) = reply.split_at_mut(header_len);
+    icmp[0] = reply_type;
+    set_icmp_checksum(header_addrs(header), icmp);
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_liveness_probe() {
+        let (src, dst): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let liveness = Liveness::new();
+        assert!(liveness.next_probe(src, dst).is_none());
+
+        liveness.set_enabled(true);
+        let request = liveness.next_probe(src, dst).unwrap();
+        assert_eq!(
+            checksum_fold(checksum_add(0, &request[..IPV4_HEADER_SIZE])),
+            0xffff
+        );
+        assert_eq!(
+            checksum_fold(checksum_add(0, &request[IPV4_HEADER_SIZE..])),
+            0xffff
+        );
+        assert!(echo_reply(&request).is_none());
+        assert_eq!(liveness.reachable(), None);
+
+        // Probes are rate limited
+        assert!(liveness.next_probe(src, dst).is_none());
+
+        let reply = answer(&request);
+        assert_eq!(
+            checksum_fold(checksum_add(0, &reply[IPV4_HEADER_SIZE..])),
+            0xffff
+        );
+        assert_eq!(echo_reply(&reply), Some((dst, liveness.ident, 0)));
+        assert!(liveness.take_reply(&reply));
+        assert_eq!(liveness.reachable(), Some(true));
+
+        // A probe that is not answered before the next one marks the peer unreachable
+        liveness.probe.lock().sent = None;
+        let second = liveness.next_probe(src, dst).unwrap();
+        liveness.probe.lock().sent = None;
+        assert!(liveness.next_probe(src, dst).is_some());
+        assert_eq!(liveness.reachable(), Some(false));
+        assert!(liveness.take_reply(&answer(&second)));
+        assert_eq!(liveness.reachable(), Some(false));
+
+        // Replies to other pings are left alone
+        let other = answer(&echo_request(src, dst, liveness.ident.wrapping_add(1), 2).unwrap());
+        assert!(!liveness.take_reply(&other));
+
+        liveness.set_enabled(false);
+        assert_eq!(liveness.reachable(), None);
+    }
+
+    #[test]
+    fn test_liveness_echo_v6() {
+        let (src, dst): (IpAddr, IpAddr) = ("fd00::1".parse().unwrap(), "fd00::2".parse().unwrap());
+        let request = echo_request(src, dst, 7, 9).unwrap();
+        let pseudo = |packet: &[u8]| {
+            let mut sum = checksum_add(0, &packet[8..40]);
+            sum += (packet.len() - IPV6_HEADER_SIZE) as u32 + u32::from(IPPROTO_ICMPV6);
+            checksum_fold(checksum_add(sum, &packet[IPV6_HEADER_SIZE..]))
+        };
+        assert_eq!(pseudo(&request), 0xffff);
+        let reply = answer(&request);
+        assert_eq!(pseudo(&reply), 0xffff);
+        assert_eq!(echo_reply(&reply), Some((dst, 7, 9)));
+        assert!(echo_request(src, "10.0.0.1".parse().unwrap(), 7, 9).is_none());
+
+        assert_eq!(
+            target("10.0.0.0".parse().unwrap(), 24),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            target("10.0.0.7".parse().unwrap(), 32),
+            "10.0.0.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(target("fd00::".parse().unwrap(), 64), src);
+    }
+}
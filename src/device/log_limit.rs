@@ -0,0 +1,175 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Rate limiting of errors that can repeat for every packet, so a flapping peer or a scanner can
+//! not flood the log. Every category of error has a token bucket of its own.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use slog::{warn, Logger};
+
+/// The errors that are rate limited, each category is limited separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogCategory {
+    Decapsulate,
+    Encapsulate,
+    NoEndpoint,
+    Timer,
+}
+
+const CATEGORIES: [LogCategory; 4] = [
+    LogCategory::Decapsulate,
+    LogCategory::Encapsulate,
+    LogCategory::NoEndpoint,
+    LogCategory::Timer,
+];
+
+impl LogCategory {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogCategory::Decapsulate => "decapsulate",
+            LogCategory::Encapsulate => "encapsulate",
+            LogCategory::NoEndpoint => "no_endpoint",
+            LogCategory::Timer => "timer",
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    suppressed: u64, // Messages dropped since the last one logged
+}
+
+/// Lets through a burst of messages of each category per interval, refilled continuously
+pub struct LogLimiter {
+    burst: u32, // 0 lets every message through
+    interval: Duration,
+    buckets: [Mutex<Bucket>; CATEGORIES.len()],
+}
+
+impl LogLimiter {
+    pub fn new(burst: u32, interval: Duration) -> LogLimiter {
+        let bucket = || {
+            Mutex::new(Bucket {
+                tokens: f64::from(burst),
+                refilled: Instant::now(),
+                suppressed: 0,
+            })
+        };
+        LogLimiter {
+            burst,
+            interval,
+            buckets: [bucket(), bucket(), bucket(), bucket()],
+        }
+    }
+
+    /// Returns the number of messages suppressed since the last one if this one may be logged
+    pub fn allow(&self, category: LogCategory) -> Option<u64> {
+        if self.burst == 0 {
+            return Some(0);
+        }
+
+        let index = CATEGORIES.iter().position(|&c| c == category).unwrap();
+        let mut bucket = self.buckets[index].lock();
+        let now = Instant::now();
+        let rate = f64::from(self.burst) / self.interval.as_secs_f64().max(f64::EPSILON);
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * rate)
+            .min(f64::from(self.burst));
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Some(std::mem::take(&mut bucket.suppressed))
+        } else {
+            bucket.suppressed += 1;
+            None
+        }
+    }
+
+    /// Log a message of category with f, unless the category is over its limit. The first
+    /// message logged after some were suppressed is preceded by their number.
+    pub fn log<F: FnOnce(&Logger)>(&self, logger: &Logger, category: LogCategory, f: F) {
+        match self.allow(category) {
+            Some(0) => f(logger),
+            Some(suppressed) => {
+                warn!(logger, "Suppressed repeated messages"; "category" => category.as_str(), "suppressed" => suppressed);
+                f(logger)
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{error, o, Drain, OwnedKVList, Record, KV};
+    use std::sync::{Arc, Mutex};
+
+    // Collects every message with its key value pairs
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    struct Serializer<'a>(&'a mut String);
+
+    impl<'a> slog::Serializer for Serializer<'a> {
+        fn emit_arguments(&mut self, key: slog::Key, val: &std::fmt::Arguments) -> slog::Result {
+            self.0.push_str(&format!(" {}={}", key, val));
+            Ok(())
+        }
+    }
+
+    impl Drain for Capture {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &Record, _: &OwnedKVList) -> Result<(), slog::Never> {
+            let mut line = format!("{}", record.msg());
+            record
+                .kv()
+                .serialize(record, &mut Serializer(&mut line))
+                .ok();
+            self.0.lock().unwrap().push(line);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_log_limiter() {
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+        let limiter = LogLimiter::new(10, Duration::from_secs(1));
+
+        for _ in 0..1000 {
+            limiter.log(
+                &logger,
+                LogCategory::Decapsulate,
+                |logger| error!(logger, "Decapsulate error"; "src" => "192.0.2.1:51820"),
+            );
+        }
+        // Other categories have buckets of their own
+        limiter.log(&logger, LogCategory::Timer, |logger| {
+            error!(logger, "Timer error")
+        });
+        assert_eq!(capture.0.lock().unwrap().len(), 11);
+
+        std::thread::sleep(Duration::from_millis(150));
+        limiter.log(
+            &logger,
+            LogCategory::Decapsulate,
+            |logger| error!(logger, "Decapsulate error"; "src" => "192.0.2.1:51820"),
+        );
+        let lines = capture.0.lock().unwrap();
+        assert_eq!(lines.len(), 13);
+        assert_eq!(
+            lines[11],
+            "Suppressed repeated messages suppressed=990 category=decapsulate"
+        );
+        assert_eq!(lines[12], "Decapsulate error src=192.0.2.1:51820");
+
+        let unlimited = LogLimiter::new(0, Duration::from_secs(1));
+        assert!((0..1000).all(|_| unlimited.allow(LogCategory::Timer) == Some(0)));
+    }
+}
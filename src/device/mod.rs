@@ -1,12 +1,34 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+pub mod accounting;
+pub mod affinity;
 pub mod allowed_ips;
 pub mod api;
+pub mod backoff;
+#[cfg(target_os = "linux")]
+pub mod bpf;
+#[cfg(target_os = "linux")]
+pub mod capture;
 mod dev_lock;
+pub mod diagnostics;
+pub mod drift;
 pub mod drop_privileges;
+pub mod ecmp;
+pub mod ecn;
+pub mod entropy;
+pub mod events;
+pub mod gro;
 mod integration_tests;
+pub mod liveness;
+pub mod log_limit;
+pub mod offload;
 pub mod peer;
+pub mod resolve;
+pub mod trace;
+pub mod tx_batch;
+pub mod uapi_client;
+pub mod validate;
 
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 #[path = "kqueue.rs"]
@@ -28,34 +50,56 @@ pub mod tun;
 #[path = "udp_unix.rs"]
 pub mod udp;
 
+#[cfg(feature = "socks5")]
+pub mod socks5;
+#[cfg(all(feature = "websocket", not(target_arch = "arm")))]
+pub mod websocket;
+
 use std::collections::HashMap;
 use std::convert::From;
 use std::io;
 use std::net::{IpAddr, SocketAddr};
-use std::os::unix::io::AsRawFd;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::crypto::x25519::*;
 use crate::noise::errors::*;
 use crate::noise::handshake::parse_handshake_anon;
-use crate::noise::rate_limiter::RateLimiter;
+use crate::noise::histogram::{Histogram, DEFAULT_LATENCY_BUCKETS};
+use crate::noise::pool::BufferPool;
+use crate::noise::rate_limiter::{RateLimitResult, RateLimiter};
 use crate::noise::*;
+use accounting::ProtocolStats;
 use allowed_ips::*;
+use backoff::Backoff;
+use diagnostics::*;
+use events::{DeviceEvent, Subscribers};
+use log_limit::{LogCategory, LogLimiter};
+use offload::*;
 use peer::*;
 use poll::*;
 use tun::*;
+use tx_batch::TxBatch;
 use udp::*;
 
 use dev_lock::{Lock, LockReadGuard};
-use slog::{error, info, o, Discard, Logger};
+use slog::{error, info, o, warn, Discard, Logger};
 
+const MAX_PEER_INDEX: usize = 1 << 24; // Peer indices are the upper 24 bits of receiver indices
 const HANDSHAKE_RATE_LIMIT: u64 = 100; // The number of handshakes per second we can tolerate before using cookies
 
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
 const MAX_ITR: usize = 100; // Number of packets to handle per handler call
+const MAX_EVENT_BATCH_SIZE: usize = 1024; // Upper bound for DeviceConfig::event_batch_size
+const MAX_LISTEN_SOCKETS: usize = 64; // Upper bound for DeviceConfig::listen_sockets
+const MAX_TUN_READ_BUFFERS: usize = 64; // Upper bound for DeviceConfig::tun_read_buffers
+const LEAN_POOL_BUFFERS: usize = 1024; // Free packet buffers kept for the queues of lean peers
+const USERSPACE_MTU: usize = 1420; // The largest inner packet read in userspace mode, as on a default tunnel interface
+const LISTEN_PORT_GRACE: Duration = Duration::from_secs(30); // The old port is served this long after a change
 
 #[derive(Debug)]
 pub enum Error {
@@ -67,7 +111,7 @@ pub enum Error {
     Connect(String),
     SetSockOpt(String),
     InvalidTunnelName,
-    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    InvalidConfig(String),
     GetSockOpt(String),
     GetSockName(String),
     UDPRead(i32),
@@ -76,6 +120,15 @@ pub enum Error {
     IfaceRead(i32),
     DropPrivileges(String),
     ApiSocket(std::io::Error),
+    UnknownPeer,
+    TooManyPeers,
+    PeerDisabled,
+    HandshakeTimeout,
+    NoEndpoint,
+    NoRoute,
+    Encapsulate(WireGuardError),
+    Affinity(String),
+    Entropy(String),
 }
 
 // What the event loop should do after a handler returns
@@ -92,6 +145,10 @@ type Handler<T, S> =
 // The trait satisfied by tunnel device implementations.
 pub trait Tun: 'static + AsRawFd + Sized + Send + Sync {
     fn new(name: &str) -> Result<Self, Error>;
+    /// Create a tunnel device that prefixes packets with a virtio-net header, if supported
+    fn new_with_offload(name: &str, _offload: bool) -> Result<Self, Error> {
+        Self::new(name)
+    }
     fn set_non_blocking(self) -> Result<Self, Error>;
 
     fn name(&self) -> Result<String, Error>;
@@ -100,19 +157,110 @@ pub trait Tun: 'static + AsRawFd + Sized + Send + Sync {
     fn write4(&self, src: &[u8]) -> usize;
     fn write6(&self, src: &[u8]) -> usize;
     fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error>;
+
+    /// Assign an address to the interface and bring it up
+    fn set_address(&self, _addr: IpAddr, _prefix_len: u8) -> Result<(), Error> {
+        Err(Error::IOCtl(
+            "Setting an address is not supported".to_owned(),
+        ))
+    }
+
+    /// Is the virtio-net header enabled on this device
+    fn offload(&self) -> bool {
+        false
+    }
+    /// Write a packet with an explicit virtio-net header, only valid when offload is enabled
+    fn write_offload(&self, _hdr: &VirtioNetHdr, _src: &[u8]) -> usize {
+        0
+    }
 }
 
 // The trait satisfied by UDP socket implementations.
 pub trait Sock: 'static + AsRawFd + Sized + Send + Sync {
     fn new() -> Result<Self, Error>;
     fn new6() -> Result<Self, Error>;
+    /// Open an IPv4 socket of a device, for transports that take settings of their own from its
+    /// config
+    fn new_with_config(_config: &DeviceConfig) -> Result<Self, Error> {
+        Self::new()
+    }
+    /// Open an IPv6 socket of a device, see `new_with_config`
+    fn new6_with_config(_config: &DeviceConfig) -> Result<Self, Error> {
+        Self::new6()
+    }
+    /// Whether peers can get a socket connected to their endpoint, see
+    /// `DeviceConfig::use_connected_socket`. Transports that carry all the traffic of a device
+    /// over one connection have nothing to connect.
+    fn can_connect() -> bool {
+        true
+    }
 
     fn bind(self, port: u16) -> Result<Self, Error>;
+    /// Bind to a local address instead of all addresses, so packets leave with it as source
+    fn bind_addr(self, _addr: SocketAddr) -> Result<Self, Error> {
+        Err(Error::Bind(
+            "Binding a local address is not supported".to_owned(),
+        ))
+    }
     fn connect(self, dst: &SocketAddr) -> Result<Self, Error>;
 
     fn set_non_blocking(self) -> Result<Self, Error>;
     fn set_reuse(self) -> Result<Self, Error>;
+    /// Set SO_REUSEPORT, so the kernel spreads flows across all sockets bound to the same port
+    fn set_reuse_port(self) -> Result<Self, Error> {
+        Err(Error::SetSockOpt(
+            "SO_REUSEPORT is not supported".to_owned(),
+        ))
+    }
     fn set_fwmark(&self, mark: u32) -> Result<(), Error>;
+    /// Limit the rate the kernel sends at using SO_MAX_PACING_RATE, which only takes effect when
+    /// the egress interface uses the `fq` qdisc
+    fn set_pacing_rate(&self, _bytes_per_sec: u64) -> Result<(), Error> {
+        Err(Error::SetSockOpt(
+            "SO_MAX_PACING_RATE is not supported".to_owned(),
+        ))
+    }
+    /// Set the priority of outgoing packets with SO_PRIORITY, which selects the qdisc band they
+    /// are queued in. Does nothing where unsupported.
+    fn set_priority(&self, _prio: u32) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Set the TTL of outgoing IPv4 packets, or the hop limit of IPv6 packets
+    fn set_ttl(&self, _ttl: u32) -> Result<(), Error> {
+        Err(Error::SetSockOpt(
+            "Setting the TTL is not supported".to_owned(),
+        ))
+    }
+    /// The TTL or hop limit of outgoing packets
+    fn ttl(&self) -> Result<u32, Error> {
+        Err(Error::GetSockOpt(
+            "Reading the TTL is not supported".to_owned(),
+        ))
+    }
+    /// Set or clear the don't fragment bit on outgoing packets, which disables path MTU
+    /// discovery when cleared
+    fn set_dont_fragment(&self, _df: bool) -> Result<(), Error> {
+        Err(Error::SetSockOpt(
+            "Controlling the DF bit is not supported".to_owned(),
+        ))
+    }
+
+    /// Allow binding to a local address that is not assigned to any interface yet, such as a
+    /// virtual IP that is only moved to the host on failover
+    fn set_freebind(&self, _on: bool) -> Result<(), Error> {
+        Err(Error::SetSockOpt(
+            "Binding free addresses is not supported".to_owned(),
+        ))
+    }
+
+    /// Attach a classic BPF program that the kernel runs on every datagram before it is
+    /// queued to the socket, dropping those the program rejects
+    #[cfg(target_os = "linux")]
+    fn attach_bpf_filter(&self, _prog: &bpf::BpfProgram) -> Result<(), Error> {
+        Err(Error::SetSockOpt(
+            "Socket filters are not supported".to_owned(),
+        ))
+    }
 
     fn port(&self) -> Result<u16, Error>;
     fn sendto(&self, buf: &[u8], dst: SocketAddr) -> usize;
@@ -120,12 +268,76 @@ pub trait Sock: 'static + AsRawFd + Sized + Send + Sync {
     fn write(&self, buf: &[u8]) -> usize;
     fn read<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], Error>;
 
+    /// Report the ECN codepoint of the outer header of received packets, if supported
+    fn set_recv_ecn(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Send with the given ECN codepoint in the outer IP header
+    fn sendto_ecn(&self, buf: &[u8], dst: SocketAddr, _ecn: u8) -> usize {
+        self.sendto(buf, dst)
+    }
+    fn write_ecn(&self, buf: &[u8], _ecn: u8) -> usize {
+        self.write(buf)
+    }
+    /// Receive a packet along with the ECN codepoint of its outer IP header
+    fn recvfrom_ecn<'a>(&self, buf: &'a mut [u8]) -> Result<(SocketAddr, &'a mut [u8], u8), Error> {
+        let (addr, packet) = self.recvfrom(buf)?;
+        Ok((addr, packet, ecn::ECN_NOT_ECT))
+    }
+    fn read_ecn<'a>(&self, buf: &'a mut [u8]) -> Result<(&'a mut [u8], u8), Error> {
+        Ok((self.read(buf)?, ecn::ECN_NOT_ECT))
+    }
+
+    /// Have the kernel coalesce datagrams received from the same source with UDP_GRO, see
+    /// `recv_coalesced`
+    fn set_gro(&self) -> Result<(), Error> {
+        Err(Error::SetSockOpt("UDP_GRO is not supported".to_owned()))
+    }
+    /// Receive a datagram, or after `set_gro` several coalesced ones, along with the size of
+    /// each but the last one, and the ECN codepoint of the outer header if requested
+    fn recv_coalesced<'a>(
+        &self,
+        buf: &'a mut [u8],
+        with_ecn: bool,
+    ) -> Result<(SocketAddr, &'a mut [u8], usize, u8), Error> {
+        let (addr, packet, ecn) = if with_ecn {
+            self.recvfrom_ecn(buf)?
+        } else {
+            let (addr, packet) = self.recvfrom(buf)?;
+            (addr, packet, ecn::ECN_NOT_ECT)
+        };
+        let len = packet.len();
+        Ok((addr, packet, len, ecn))
+    }
+
+    /// Send the packets of a batch, each to its destination or over the connected socket, with
+    /// as few system calls as supported. Returns the number of packets sent and of calls made.
+    fn send_batch(&self, batch: &TxBatch) -> (usize, usize) {
+        let sent = batch
+            .iter()
+            .map(|(packet, dst)| match dst {
+                Some(dst) => self.sendto(packet, dst),
+                None => self.write(packet),
+            })
+            .filter(|&n| n > 0)
+            .count();
+        (sent, batch.len())
+    }
+
     fn shutdown(&self);
 }
 
+/// Called with the public key of a peer whose session is about to expire, see
+/// `DeviceConfig::on_session_expiring`
+pub type SessionExpiringCallback = Box<dyn Fn(&X25519PublicKey) + Send + Sync>;
+
+/// Called with every decapsulated packet in userspace mode, see `DeviceConfig::on_inner_packet`
+pub type InnerPacketCallback = Box<dyn Fn(&[u8]) + Send + Sync>;
+
 pub struct DeviceHandle<T: Tun = TunSocket, S: Sock = UDPSocket> {
     device: Arc<Lock<Device<T, S>>>, // The interface this handle owns
     threads: Vec<JoinHandle<()>>,
+    external: parking_lot::Mutex<Option<Box<ThreadData<T>>>>, // Used by handle_readable
 }
 
 pub struct DeviceConfig {
@@ -134,6 +346,181 @@ pub struct DeviceConfig {
     pub logger: Logger,
     #[cfg(target_os = "linux")]
     pub use_multi_queue: bool,
+    /// Coalesce decapsulated TCP segments into single offloaded TUN writes
+    #[cfg(target_os = "linux")]
+    pub use_tun_offload: bool,
+    /// Attach a socket filter to the listen sockets that drops datagrams which can not be
+    /// WireGuard messages in the kernel, before they reach the device
+    #[cfg(target_os = "linux")]
+    pub use_bpf_filter: bool,
+    /// Let the kernel coalesce datagrams received on the listen sockets from the same source
+    /// with UDP_GRO, so a burst is read with one system call
+    #[cfg(target_os = "linux")]
+    pub use_udp_gro: bool,
+    /// The most datagrams of a coalesced buffer that are decapsulated before the packets they
+    /// carry are written to the tunnel interface, between 1 and `gro::MAX_GRO_SEGMENTS`. Each
+    /// chunk counts as one of the packets a thread handles before it returns to the poller.
+    pub gro_max_segments: usize,
+    pub api_socket: api::ApiSocket,
+    /// The file mode of the UAPI socket, when it is served on a path
+    pub api_socket_mode: u32,
+    /// The uid and gid to give the UAPI socket to, when it is served on a path
+    pub api_socket_owner: Option<(libc::uid_t, libc::gid_t)>,
+    /// When either is not empty, only clients running with one of these uids, or one of these
+    /// gids as their primary group, may use a UAPI unix socket. Others are answered with EACCES
+    /// and disconnected before their command is read. Root is not exempt.
+    pub api_allowed_uids: Vec<libc::uid_t>,
+    pub api_allowed_gids: Vec<libc::gid_t>,
+    /// A UAPI connection is answered with ETIMEDOUT and closed when the client sends nothing
+    /// for this long in the middle of a request
+    pub api_idle_timeout: Duration,
+    /// The longest time a client may take to send a whole UAPI request
+    pub api_request_timeout: Duration,
+    /// The largest UAPI set request in bytes, larger ones are answered with E2BIG
+    pub api_max_request_size: u64,
+    /// When not empty, handshake initiations are only accepted from source addresses in these networks
+    pub handshake_source_allow: Vec<AllowedIP>,
+    /// The maximal number of events each thread retrieves from the poller per wakeup. Larger
+    /// batches reduce the per event overhead under heavy load, smaller batches let other threads
+    /// pick up events sooner, which is better for latency.
+    pub event_batch_size: usize,
+    /// How long a data packet read from the tunnel interface may wait for more packets to go
+    /// out of the same socket with it, in as few system calls as the socket supports. A batch is
+    /// sent once it holds `tx_batch::MAX_TX_BATCH` packets, or when no more packets are read
+    /// within the linger. Packets with an ECN codepoint are not batched. Zero sends every packet
+    /// at once.
+    pub tx_batch_linger: Duration,
+    /// The number of packets read ahead from the tunnel interface before they are encapsulated.
+    /// With more than one, the packets read together go out of each socket in a batch, as with
+    /// `tx_batch_linger`, which is sent at the latest once they are all encapsulated.
+    pub tun_read_buffers: usize,
+    /// Copy the ECN codepoint of inner packets to the outer header on encapsulation, and signal
+    /// congestion experienced by the outer packet to the inner packet on decapsulation. Packets
+    /// queued while a handshake is in progress are sent without ECN.
+    pub ecn_passthrough: bool,
+    /// Drop data messages whose receiver index is not the index of a session of any peer as
+    /// soon as they are received, before any other work and without reporting them as decryption
+    /// failures, only counting them. This keeps the cost of a flood of bogus messages low.
+    pub drop_unknown_indices: bool,
+    /// The largest number of peers the device holds, adding another one fails with
+    /// `Error::TooManyPeers` until a peer is removed. 0 for no limit.
+    pub max_peers: usize,
+    /// The number of sockets bound to the listen port of each address family. With more than one
+    /// the sockets share the port using SO_REUSEPORT, and the kernel spreads flows across them.
+    /// Each socket of an address family is served by a worker of its own, with more sockets than
+    /// workers they are spread over the workers in turn, so a flow is always received by the
+    /// same worker.
+    pub listen_sockets: usize,
+    /// Called when a packet received from the network fails to decapsulate, with the reason and
+    /// the source address. Reports are limited to a few per second.
+    pub on_decrypt_failure: Option<DecryptFailureCallback>,
+    /// Called once per session, `session_expiry_lead` before the current session of a peer
+    /// expires, so the embedder can start a handshake that replaces it in time
+    pub on_session_expiring: Option<SessionExpiringCallback>,
+    /// Run as a pure userspace endpoint: no tunnel interface is opened, every decapsulated packet
+    /// is handed to this callback instead, on the worker that decapsulated it, and outbound
+    /// packets are given to `Device::inject_inner`. For a userspace network stack.
+    pub on_inner_packet: Option<InnerPacketCallback>,
+    /// How long before its expiry a session is reported as expiring, and with `prewarm=on` a
+    /// handshake is started
+    pub session_expiry_lead: Duration,
+    /// How long the session of a removed peer is kept. A peer added again within the window,
+    /// with the same preshared key, resumes that session instead of starting a handshake, as
+    /// long as the session is younger than REJECT_AFTER_TIME. Zero drops it with the peer.
+    pub resumption_window: Duration,
+    /// How often subscribers receive the counters of every peer, see `Device::subscribe`. Zero
+    /// sends none.
+    pub stats_interval: Duration,
+    /// The number of consecutive unanswered handshake initiations after which a peer backs off,
+    /// doubling the retry interval with every further attempt. 0 retries every 5 seconds.
+    pub max_handshake_attempts: usize,
+    /// The longest interval between handshake attempts of a backed off peer
+    pub handshake_backoff_ceiling: Duration,
+    /// The number of first handshake initiations to a peer that are retried after
+    /// `fast_handshake_retry_interval` instead of 5 seconds. 0 keeps the standard timeout.
+    pub fast_handshake_retries: usize,
+    /// The retry interval of the first handshake initiations, at most 5 seconds
+    pub fast_handshake_retry_interval: Duration,
+    /// The number of consecutive unanswered handshake initiations to its endpoint after which a
+    /// peer with a backup endpoint fails over to it
+    pub failover_attempts: usize,
+    /// How often a peer on its backup endpoint sends a handshake initiation to its primary
+    /// endpoint, to fail back once the primary answers
+    pub failover_probe_interval: Duration,
+    /// The wait after the first failure to connect the socket of a peer, doubled with every
+    /// further failure
+    pub reconnect_backoff_base: Duration,
+    /// The longest wait between attempts to connect the socket of a peer
+    pub reconnect_backoff_ceiling: Duration,
+    /// Pad inner packets before encryption to hide their size from observers, at a bandwidth
+    /// cost. The padding is stripped by any receiver, so it works with standard peers.
+    pub traffic_padding: Padding,
+    /// Whether inner packets sent to a peer without a session, such as the one that starts the
+    /// first handshake, are queued and sent once the handshake completes, or dropped
+    pub handshake_queue_policy: HandshakeQueuePolicy,
+    /// Pad handshake messages with random bytes to a random size up to this, so they are not
+    /// told apart by their fixed sizes. This is a boringtun extension of the wire format, not
+    /// interoperable with standard peers: only boringtun peers with `accept_handshake_padding`
+    /// complete such handshakes, the Linux kernel and wireguard-go drop them. 0 does not pad.
+    pub handshake_padding: usize,
+    /// Accept handshake messages longer than their fixed sizes and ignore the extra bytes, as
+    /// sent by peers with `handshake_padding`. Off by default, when only messages of exactly
+    /// the size of their type are accepted, as the protocol specifies.
+    pub accept_handshake_padding: bool,
+    /// A copy of the last handshake initiation of a peer received within this long is answered
+    /// with the response already sent, sparing the key exchange a retransmitted or replayed
+    /// initiation costs otherwise. The device still decrypts the static key of every initiation
+    /// to find its peer. 0 computes every handshake, and drops the copies.
+    pub duplicate_init_window: Duration,
+    /// Also log handshake, keepalive and key expiry events with the messages of the Linux
+    /// WireGuard module, at debug level, the peer named by its base64 key instead of a number.
+    pub wg_compat_log: bool,
+    /// The upper bounds of the buckets of the handshake latency histogram of every peer, see
+    /// `PeerInfo::handshake_latency`
+    pub handshake_latency_buckets: Vec<Duration>,
+    /// The MTU of the network endpoints are reached over. Padding never grows a packet beyond
+    /// what fits a datagram over it, which depends on the address family of each endpoint.
+    pub link_mtu: usize,
+    /// A secret shared by the nodes of a cluster behind one address, that the cookie secret is
+    /// derived from instead of being random, so cookies sent by one node are accepted by the
+    /// others. The clocks of the nodes must be synchronized.
+    pub cookie_seed: Option<[u8; 32]>,
+    /// The number of errors of each kind that can repeat for every packet, such as failures to
+    /// decapsulate, logged per `log_burst_interval`. Further errors are only counted, and their
+    /// number is logged before the next error that is let through. 0 logs every error.
+    pub log_burst: u32,
+    pub log_burst_interval: Duration,
+    /// The number of inner packets kept for a peer without an endpoint, until the endpoint is
+    /// learned from a handshake of the peer. Further packets, or all of them with 0, are dropped
+    /// and counted, see `PeerStats::no_endpoint_drops`.
+    pub no_endpoint_buffer: usize,
+    /// Keep idle peers small, for devices with very many peers that are rarely active at once.
+    /// A lean peer allocates its timers on its first handshake or packet, shares the rate
+    /// limiter of the device, and copies the packets it queues to buffers from a pool shared by
+    /// every peer, freeing its queues once they drain. It still accepts a handshake at any time.
+    pub lean_peers: bool,
+    /// The CPUs worker threads are pinned to, worker i to the CPU at i modulo the length. Every
+    /// CPU must be online. Empty leaves the workers free to run anywhere, as does a platform
+    /// other than Linux.
+    pub worker_affinity: Vec<usize>,
+    /// Resolves the names of endpoints given as HOST:PORT, see `Device::set_peer_endpoint_host`
+    pub resolver: resolve::Resolver,
+    /// How often the names of endpoints are resolved again, to follow peers whose DNS records
+    /// change. Zero only resolves them when configured and on `Device::reresolve_endpoints`.
+    pub reresolve_interval: Duration,
+    /// How often peers with `liveness_probe=on` are sent an echo request, see `liveness`. Zero
+    /// only sends them on `Device::send_liveness_probes`.
+    pub liveness_probe_interval: Duration,
+    /// The relay the sockets of a device using `websocket::WebSocketTransport` connect to. They
+    /// all share one connection to it.
+    #[cfg(all(feature = "websocket", not(target_arch = "arm")))]
+    pub websocket_relay: Option<websocket::WebSocketRelay>,
+    /// The proxy the sockets of a device using `socks5::Socks5Transport` associate with
+    #[cfg(feature = "socks5")]
+    pub socks5_proxy: Option<socks5::Socks5Proxy>,
+    /// The interface the sockets of a device using `capture::CaptureSocket` capture on
+    #[cfg(target_os = "linux")]
+    pub capture_interface: Option<String>,
 }
 
 impl Default for DeviceConfig {
@@ -144,20 +531,289 @@ impl Default for DeviceConfig {
             logger: Logger::root(Discard, o!()),
             #[cfg(target_os = "linux")]
             use_multi_queue: true,
+            #[cfg(target_os = "linux")]
+            use_tun_offload: false,
+            #[cfg(target_os = "linux")]
+            use_bpf_filter: false,
+            #[cfg(target_os = "linux")]
+            use_udp_gro: false,
+            gro_max_segments: 16,
+            api_socket: Default::default(),
+            api_socket_mode: 0o600,
+            api_socket_owner: None,
+            api_allowed_uids: vec![],
+            api_allowed_gids: vec![],
+            api_idle_timeout: Duration::from_secs(5),
+            api_request_timeout: Duration::from_secs(30),
+            api_max_request_size: 1 << 20,
+            handshake_source_allow: vec![],
+            event_batch_size: 1,
+            tx_batch_linger: Duration::ZERO,
+            tun_read_buffers: 1,
+            ecn_passthrough: false,
+            drop_unknown_indices: false,
+            max_peers: 0,
+            listen_sockets: 1,
+            on_decrypt_failure: None,
+            on_session_expiring: None,
+            on_inner_packet: None,
+            session_expiry_lead: Duration::from_secs(10),
+            resumption_window: Duration::ZERO,
+            stats_interval: Duration::from_secs(10),
+            max_handshake_attempts: 0,
+            handshake_backoff_ceiling: Duration::from_secs(300),
+            fast_handshake_retries: 0,
+            fast_handshake_retry_interval: Duration::from_secs(1),
+            failover_attempts: 3,
+            failover_probe_interval: Duration::from_secs(30),
+            reconnect_backoff_base: Duration::from_millis(100),
+            reconnect_backoff_ceiling: Duration::from_secs(30),
+            traffic_padding: Padding::None,
+            handshake_queue_policy: HandshakeQueuePolicy::default(),
+            handshake_padding: 0,
+            accept_handshake_padding: false,
+            duplicate_init_window: Duration::ZERO,
+            wg_compat_log: false,
+            handshake_latency_buckets: DEFAULT_LATENCY_BUCKETS.to_vec(),
+            link_mtu: DEFAULT_LINK_MTU,
+            cookie_seed: None,
+            log_burst: 10,
+            log_burst_interval: Duration::from_secs(1),
+            no_endpoint_buffer: 16,
+            lean_peers: false,
+            worker_affinity: vec![],
+            resolver: resolve::system_resolver(),
+            reresolve_interval: Duration::ZERO,
+            liveness_probe_interval: Duration::from_secs(10),
+            #[cfg(all(feature = "websocket", not(target_arch = "arm")))]
+            websocket_relay: None,
+            #[cfg(feature = "socks5")]
+            socks5_proxy: None,
+            #[cfg(target_os = "linux")]
+            capture_interface: None,
+        }
+    }
+}
+
+// Sessions can not be used this long after their handshake
+const SESSION_LIFETIME: Duration = Duration::from_secs(180);
+
+/// The handshake messages received on the listen sockets that the rate limiter did not let
+/// through, as returned by `Device::rate_limit_stats`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RateLimitStats {
+    /// Handshakes answered with a cookie reply under load
+    pub cookie_replies: u64,
+    /// Handshakes dropped for a wrong mac1
+    pub invalid_mac: u64,
+}
+
+/// The aggregate status of a device, as returned by `Device::health`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+    pub peers: usize,
+    /// Peers with a session that can still be used
+    pub live_sessions: usize,
+    /// Time since the most recent handshake with any peer
+    pub since_last_handshake: Option<Duration>,
+    pub iface_open: bool,
+    pub socket_open: bool,
+}
+
+/// A peer of the device, as returned by `Device::peers`
+#[derive(Debug)]
+pub struct PeerInfo {
+    /// Assigned in the order peers are added, and never reused after a peer is removed
+    pub peer_id: u64,
+    pub public_key: X25519PublicKey,
+    pub endpoint: Option<SocketAddr>,
+    /// Time of the last handshake since the epoch
+    pub last_handshake: Option<Duration>,
+    /// Bytes of inner packets
+    pub rx_bytes: usize,
+    pub tx_bytes: usize,
+    /// Bytes of handshake, cookie and keepalive messages
+    pub rx_control_bytes: usize,
+    pub tx_control_bytes: usize,
+    pub enabled: bool,
+    /// The largest inner packet that fits a datagram to the endpoint without fragmentation,
+    /// which is 20 bytes less for an IPv6 endpoint than for an IPv4 one
+    pub payload_budget: Option<usize>,
+    /// Inner packets received from the peer by protocol, only counted with
+    /// `accounting=detailed`
+    pub rx_protocols: Option<ProtocolStats>,
+    /// Inner packets sent to the peer by protocol, only counted with `accounting=detailed`
+    pub tx_protocols: Option<ProtocolStats>,
+    /// The latencies of the handshakes completed as the initiator, see
+    /// `Tunn::handshake_latency`
+    pub handshake_latency: Histogram,
+}
+
+/// The `peer_id` of a peer, see `PeerInfo::peer_id`
+pub type PeerId = u64;
+
+/// The traffic counters of a peer, as returned by `Device::peer_stats` and `Device::all_stats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Bytes of inner packets
+    pub rx_bytes: usize,
+    pub tx_bytes: usize,
+    /// Bytes of handshake, cookie and keepalive messages
+    pub rx_control_bytes: usize,
+    pub tx_control_bytes: usize,
+    /// Handshakes completed as the initiator and as the responder, see `Tunn::handshake_stats`
+    pub initiated_handshakes: usize,
+    pub responded_handshakes: usize,
+    /// Handshakes that replaced an established session, see `Tunn::rekeys`
+    pub rekeys: usize,
+    /// Inner packets dropped because the peer had no endpoint, see
+    /// `DeviceConfig::no_endpoint_buffer`
+    pub no_endpoint_drops: u64,
+    /// The label set with `peer_metadata`
+    pub metadata: Option<String>,
+    /// Whether the last liveness probe was answered, None while the probe is off or before
+    /// the first one was answered or missed
+    pub reachable: Option<bool>,
+}
+
+impl PeerStats {
+    fn of<S: Sock>(peer: &Peer<S>) -> PeerStats {
+        let (tx_bytes, rx_bytes) = peer.tunnel.data_stats();
+        let (tx_control_bytes, rx_control_bytes) = peer.tunnel.control_stats();
+        let (initiated_handshakes, responded_handshakes) = peer.tunnel.handshake_stats();
+        PeerStats {
+            rx_bytes,
+            tx_bytes,
+            rx_control_bytes,
+            tx_control_bytes,
+            initiated_handshakes,
+            responded_handshakes,
+            rekeys: peer.tunnel.rekeys(),
+            no_endpoint_drops: peer.no_endpoint_drops(),
+            metadata: peer.metadata(),
+            reachable: peer.liveness().reachable(),
         }
     }
 }
 
+/// What the device would do with an inner packet read from the tunnel interface, as returned by
+/// `Device::explain_outbound`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboundDecision {
+    /// Dropped, the packet has no IPv4 or IPv6 destination address
+    Malformed,
+    /// Dropped, no peer has an allowed IP that matches the destination
+    NoRoute(IpAddr),
+    /// Dropped, the peer the destination routes to is paused
+    PeerDisabled { peer_id: u64 },
+    /// Kept until the endpoint of the peer is learned, or dropped if `no_endpoint_buffer`
+    /// packets are already kept
+    NoEndpoint { peer_id: u64 },
+    /// Queued, and a handshake initiation is sent to the peer
+    WouldHandshake { peer_id: u64 },
+    /// Queued until the handshake in progress with the peer completes
+    AwaitingHandshake { peer_id: u64 },
+    /// Queued until the peer initiates a handshake, as it is responder only
+    AwaitingPeer { peer_id: u64 },
+    /// Queued, but no handshake is sent before the backoff interval of the peer has passed
+    HandshakeBackedOff { peer_id: u64 },
+    /// Encrypted with the current session and sent to the endpoint
+    Send { peer_id: u64, endpoint: SocketAddr },
+}
+
+/// A peer removed from a device by `Device::extract_peer`, to be added to another device of the
+/// process with `Device::inject_peer`
+pub struct PeerState<S: Sock> {
+    public_key: Arc<X25519PublicKey>,
+    peer: Arc<Peer<S>>,
+}
+
+impl<S: Sock> PeerState<S> {
+    pub fn public_key(&self) -> &X25519PublicKey {
+        &self.public_key
+    }
+}
+
+/// What a file descriptor listed by `Device::raw_fds` is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdRole {
+    /// A queue of the tunnel interface
+    Tun,
+    /// A socket bound to the listen port, including the reuseport shards
+    ListenSocket,
+    /// A socket connected to the endpoint of a peer
+    PeerSocket,
+    /// The listener of the configuration socket
+    Api,
+    /// A timer of the periodic tasks of the device
+    Timer,
+    /// An event that wakes up the event loop, to yield to a writer or to exit
+    Notifier,
+    /// A signal the device handles, such as SIGINT or SIGTERM
+    Signal,
+}
+
+/// The current session of a peer, as returned by `Device::active_sessions`
+#[derive(Debug)]
+pub struct SessionInfo {
+    pub peer_id: u64,
+    pub public_key: X25519PublicKey,
+    /// Time since the handshake that established the session
+    pub age: Duration,
+    /// Estimated time until a handshake replaces the session
+    pub time_to_rekey: Duration,
+    /// Inner bytes sent and received in this session only
+    pub tx_bytes: usize,
+    pub rx_bytes: usize,
+    /// The receiver index of the data messages of the peer, and of ours, as seen on the wire
+    pub local_index: u32,
+    pub peer_index: u32,
+    /// When the handshake that established the session completed
+    pub created: SystemTime,
+    /// The counters of the messages received, which tell why one was dropped as a replay
+    pub replay_window: ReplayWindow,
+}
+
+impl Health {
+    /// The device has peers, but none of them has a usable session
+    pub fn is_degraded(&self) -> bool {
+        self.peers > 0 && self.live_sessions == 0
+    }
+
+    /// The device is ready to pass traffic
+    pub fn is_healthy(&self) -> bool {
+        self.iface_open && self.socket_open && !self.is_degraded()
+    }
+}
+
 pub struct Device<T: Tun, S: Sock> {
     key_pair: Option<(Arc<X25519SecretKey>, Arc<X25519PublicKey>)>,
+    next_key: Option<NextKey>, // The key being rolled over to
     queue: Arc<EventPoll<Handler<T, S>>>,
+    worker_queues: Vec<Arc<EventPoll<Handler<T, S>>>>, // With listen shards, the own poll of each worker
 
     listen_port: u16,
     fwmark: Option<u32>,
-
-    iface: Arc<T>,
+    pacing_rate: Option<u64>, // Bytes per second
+    dont_fragment: Option<bool>,
+    priority: Option<u32>,
+    ttl: Option<u32>,          // TTL or hop limit of outgoing packets
+    freebind: bool, // Connected sockets may bind local addresses that are not assigned yet
+    detailed_accounting: bool, // Count inner packets of every peer by protocol
+    validate_inner: bool, // Drop malformed decapsulated packets before the tunnel interface
+    trace_buffer: usize, // The number of packet events traced per peer, 0 disables tracing
+    prewarm: bool,  // Start a handshake when the session of a peer is about to expire
+
+    name: String,           // As given, the tunnel interface may be named differently
+    iface: Option<Arc<T>>,  // None in userspace mode
+    mirror: Option<Arc<T>>, // Gets a copy of every decapsulated packet written to iface
     udp4: Option<Arc<S>>,
     udp6: Option<Arc<S>>,
+    udp_shards: Vec<Arc<S>>, // Additional listen sockets sharing the port of udp4 and udp6
+    draining: Option<(Instant, Vec<Arc<S>>)>, // The sockets of the previous listen port, until they are closed
+    tun_fds: parking_lot::Mutex<Vec<RawFd>>, // The queues of iface, with multi-queue there is one per thread
+    addresses: Vec<IpAddr>, // Assigned to iface with the address key, the sources of liveness probes
+    api_fd: Option<RawFd>,  // The listener of the configuration socket
 
     yield_notice: Option<EventRef>,
     exit_notice: Option<EventRef>,
@@ -165,7 +821,9 @@ pub struct Device<T: Tun, S: Sock> {
     peers: HashMap<Arc<X25519PublicKey>, Arc<Peer<S>>>,
     peers_by_ip: AllowedIps<Arc<Peer<S>>>,
     peers_by_idx: HashMap<u32, Arc<Peer<S>>>,
-    next_index: u32,
+    resumable: HashMap<Arc<X25519PublicKey>, (Instant, Arc<Peer<S>>)>, // Removed peers, by the time they were removed
+    next_index: u32, // Starts at random, so peers moved between devices rarely need a new index
+    next_peer_id: u64,
 
     config: DeviceConfig,
 
@@ -174,17 +832,160 @@ pub struct Device<T: Tun, S: Sock> {
     mtu: AtomicUsize,
 
     rate_limiter: Option<Arc<RateLimiter>>,
+    buffers: Arc<BufferPool>, // For the queues of lean peers
+
+    handshake_source_allow: Option<AllowedIps<()>>,
+
+    decrypt_failure_limiter: ReportLimiter,
+    log_limiter: LogLimiter, // Limits the errors logged for every packet
+    unknown_index_drops: AtomicU64,
+    invalid_inner_drops: AtomicU64,
+    mirror_drops: AtomicU64,
+    tx_batched: AtomicU64, // Packets sent in batches
+    cookie_replies: AtomicU64,
+    invalid_mac: AtomicU64,
+    tx_batch_sends: AtomicU64, // The system calls that sent them
+
+    subscribers: Subscribers,
+}
+
+// A private key that handshakes are accepted for alongside the current one, until it replaces
+// the current key at the deadline
+struct NextKey {
+    private_key: Arc<X25519SecretKey>,
+    public_key: Arc<X25519PublicKey>,
+    rate_limiter: Arc<RateLimiter>,
+    deadline: Instant,
+}
+
+// Write a decapsulated packet to the tunnel interface, coalescing it with other segments of the
+// same flow when offload is enabled. The batch must be flushed once the handler is done.
+fn write_to_iface<T: Tun>(iface: &T, gso: &mut GsoBatch, packet: &[u8], is_v6: bool) {
+    if iface.offload() {
+        gso.push(packet, |hdr, packet| {
+            iface.write_offload(hdr, packet);
+        });
+    } else if is_v6 {
+        iface.write6(packet);
+    } else {
+        iface.write4(packet);
+    }
+}
+
+// Send an encapsulated packet to the endpoint of a peer, preferring its connected socket. A data
+// packet with the inner packet it carries goes to the weighted endpoint of its flow instead,
+// when the peer has weighted endpoints.
+fn send_to_endpoint<S: Sock>(
+    peer: &Peer<S>,
+    udp4: &Arc<S>,
+    udp6: &Arc<S>,
+    packet: &[u8],
+    inner: Option<&[u8]>,
+    ecn: u8,
+) -> Result<(), Error> {
+    // A handshake started by the inner packet goes to the endpoint of the peer
+    let weighted = inner
+        .filter(|_| trace::TraceKind::of(packet) == trace::TraceKind::Data)
+        .and_then(|inner| peer.ecmp_endpoint(inner));
+    let endpoint = peer.endpoint();
+    match endpoint_route(&endpoint, weighted, udp4, udp6) {
+        Some((conn, None)) => {
+            let sent = match ecn {
+                ecn::ECN_NOT_ECT => conn.write(packet),
+                ecn => conn.write_ecn(packet, ecn),
+            };
+            if sent > 0 {
+                peer.connected();
+            }
+        }
+        Some((sock, Some(addr))) => {
+            match ecn {
+                ecn::ECN_NOT_ECT => sock.sendto(packet, addr),
+                ecn => sock.sendto_ecn(packet, addr, ecn),
+            };
+        }
+        None => return Err(Error::NoEndpoint),
+    }
+    Ok(())
+}
+
+// The socket a packet to the endpoint, or to the weighted endpoint the packet is sent to leaves
+// through, and the destination, None when the socket is connected to it
+fn endpoint_route<'a, S: Sock>(
+    endpoint: &'a Endpoint<S>,
+    weighted: Option<SocketAddr>,
+    udp4: &'a Arc<S>,
+    udp6: &'a Arc<S>,
+) -> Option<(&'a Arc<S>, Option<SocketAddr>)> {
+    if let (Some(conn), None) = (&endpoint.conn, weighted) {
+        return Some((conn, None));
+    }
+    let addr = weighted.or(endpoint.addr)?;
+    // Reply through the socket the endpoint reached us on
+    let sock = match (&endpoint.sock, addr) {
+        (Some(sock), _) if weighted.is_none() => sock,
+        (_, SocketAddr::V4(_)) => udp4,
+        (_, SocketAddr::V6(_)) => udp6,
+    };
+    Some((sock, Some(addr)))
+}
+
+// The route of a data packet that may wait in a batch, the packets send_to_endpoint would send
+// without an ECN codepoint
+fn batch_route<S: Sock>(
+    peer: &Peer<S>,
+    udp4: &Arc<S>,
+    udp6: &Arc<S>,
+    packet: &[u8],
+    inner: &[u8],
+    ecn: u8,
+) -> Option<(Arc<S>, Option<SocketAddr>)> {
+    if ecn != ecn::ECN_NOT_ECT || trace::TraceKind::of(packet) != trace::TraceKind::Data {
+        return None;
+    }
+    let weighted = peer.ecmp_endpoint(inner);
+    let endpoint = peer.endpoint();
+    endpoint_route(&endpoint, weighted, udp4, udp6).map(|(sock, dst)| (Arc::clone(sock), dst))
+}
+
+fn read_datagram<'a, S: Sock>(
+    udp: &S,
+    buf: &'a mut [u8],
+    with_ecn: bool,
+) -> Result<(&'a mut [u8], u8), Error> {
+    if with_ecn {
+        udp.read_ecn(buf)
+    } else {
+        Ok((udp.read(buf)?, ecn::ECN_NOT_ECT))
+    }
 }
 
 struct ThreadData<T: Tun> {
-    iface: Arc<T>,
+    iface: Option<Arc<T>>,
     src_buf: [u8; MAX_UDP_SIZE],
     dst_buf: [u8; MAX_UDP_SIZE],
+    gso: GsoBatch,
+    tx_batch: TxBatch,
+    tun_bufs: Vec<Vec<u8>>, // Read ahead buffers for the tunnel interface, allocated on first use
+}
+
+impl<T: Tun> ThreadData<T> {
+    fn new(iface: Option<Arc<T>>) -> ThreadData<T> {
+        ThreadData {
+            iface,
+            src_buf: [0u8; MAX_UDP_SIZE],
+            dst_buf: [0u8; MAX_UDP_SIZE],
+            gso: GsoBatch::new(),
+            tx_batch: TxBatch::new(),
+            tun_bufs: vec![],
+        }
+    }
 }
 
 impl<T: Tun, S: Sock> DeviceHandle<T, S> {
     pub fn new(name: &str, config: DeviceConfig) -> Result<DeviceHandle<T, S>, Error> {
         let n_threads = config.n_threads;
+        affinity::validate(&config.worker_affinity)?;
         let mut wg_interface = Device::<T, S>::new(name, config)?;
         wg_interface.open_listen_socket(0)?; // Start listening on a random port
 
@@ -202,9 +1003,49 @@ impl<T: Tun, S: Sock> DeviceHandle<T, S> {
         Ok(DeviceHandle {
             device: interface_lock,
             threads,
+            external: Default::default(),
         })
     }
 
+    /// The fds of the device and their roles, see `Device::raw_fds`
+    pub fn raw_fds(&self) -> Vec<(RawFd, FdRole)> {
+        self.device.read().raw_fds()
+    }
+
+    /// Send an inner packet into the tunnel, see `Device::inject_inner`
+    pub fn inject_inner(&self, inner_packet: &[u8]) -> Result<(), Error> {
+        self.device.read().inject_inner(inner_packet)
+    }
+
+    /// The counters of a peer, see `Device::peer_stats`
+    pub fn peer_stats(&self, key: &X25519PublicKey) -> Result<PeerStats, Error> {
+        self.device.read().peer_stats(key)
+    }
+
+    /// The counters of every peer, see `Device::all_stats`
+    pub fn all_stats(&self) -> Vec<(PeerId, PeerStats)> {
+        self.device.read().all_stats()
+    }
+
+    /// Handle fd becoming readable, for a device created with `n_threads` 0 whose fds are polled
+    /// by an external event loop. Returns false once the device exits, after which the fds
+    /// should no longer be polled. Fds that do not belong to the device are ignored.
+    pub fn handle_readable(&self, fd: RawFd) -> bool {
+        let mut thread_local = self.external.lock();
+        let mut device_lock = self.device.read();
+        let thread_local = thread_local
+            .get_or_insert_with(|| Box::new(ThreadData::new(device_lock.iface.clone())));
+
+        let queue = Arc::clone(&device_lock.queue);
+        match queue.with_handler(fd, |handler| (*handler)(&mut device_lock, thread_local)) {
+            Some(Action::Exit) => {
+                device_lock.trigger_exit();
+                false
+            }
+            _ => true,
+        }
+    }
+
     pub fn wait(&mut self) {
         while let Some(thread) = self.threads.pop() {
             thread.join().unwrap();
@@ -218,18 +1059,23 @@ impl<T: Tun, S: Sock> DeviceHandle<T, S> {
         }
     }
 
-    fn event_loop(_i: usize, device: &Lock<Device<T, S>>) {
+    fn event_loop(i: usize, device: &Lock<Device<T, S>>) {
+        let cpus = device.read().config.worker_affinity.clone();
+        if !cpus.is_empty() {
+            let cpu = cpus[i % cpus.len()];
+            if let Err(e) = affinity::pin_current_thread(cpu) {
+                error!(device.read().config.logger, "Failed to pin worker"; "worker" => i, "cpu" => cpu, "error" => ?e);
+            }
+        }
+
         #[cfg(target_os = "linux")]
-        let mut thread_local = ThreadData {
-            src_buf: [0u8; MAX_UDP_SIZE],
-            dst_buf: [0u8; MAX_UDP_SIZE],
-            iface: if _i == 0 || !device.read().config.use_multi_queue {
-                // For the first thread use the original iface
-                Arc::clone(&device.read().iface)
-            } else {
-                // For for the rest create a new iface queue
+        let iface = device.read().iface.clone();
+        #[cfg(target_os = "linux")]
+        let mut thread_local = ThreadData::new(match iface {
+            // For the rest of the threads create a new iface queue
+            Some(iface) if i > 0 && device.read().config.use_multi_queue => {
                 let iface_local = Arc::new(
-                    T::new(&device.read().iface.name().unwrap())
+                    T::new_with_offload(&iface.name().unwrap(), iface.offload())
                         .unwrap()
                         .set_non_blocking()
                         .unwrap(),
@@ -240,29 +1086,40 @@ impl<T: Tun, S: Sock> DeviceHandle<T, S> {
                     .register_iface_handler(Arc::clone(&iface_local))
                     .ok();
 
-                iface_local
-            },
-        };
+                Some(iface_local)
+            }
+            // For the first thread use the original iface
+            iface => iface,
+        });
 
         #[cfg(not(target_os = "linux"))]
-        let mut thread_local = ThreadData {
-            src_buf: [0u8; MAX_UDP_SIZE],
-            dst_buf: [0u8; MAX_UDP_SIZE],
-            iface: Arc::clone(&device.read().iface),
+        let mut thread_local = ThreadData::new(device.read().iface.clone());
+
+        // With listen shards the worker waits on a poll of its own, which waits on the shared one
+        let queue = {
+            let device = device.read();
+            match device.worker_queues.get(i) {
+                Some(queue) => Arc::clone(queue),
+                None => Arc::clone(&device.queue),
+            }
         };
+        let mut events = queue.new_batch();
 
         loop {
             // The event loop keeps a read lock on the device, because we assume write access is rarely needed
             let mut device_lock = device.read();
-            let queue = Arc::clone(&device_lock.queue);
 
             loop {
-                match queue.wait() {
+                match queue.wait(&mut events) {
                     WaitResult::Ok(handler) => {
                         let action = (*handler)(&mut device_lock, &mut thread_local);
                         match action {
                             Action::Continue => {}
-                            Action::Yield => break,
+                            Action::Yield => {
+                                // Events may be removed once the lock is released
+                                queue.release_batch(&mut events);
+                                break;
+                            }
                             Action::Exit => {
                                 device_lock.trigger_exit();
                                 return;
@@ -288,21 +1145,82 @@ impl<T: Tun, S: Sock> Drop for DeviceHandle<T, S> {
 
 impl<T: Tun, S: Sock> Device<T, S> {
     fn next_index(&mut self) -> u32 {
-        let next_index = self.next_index;
-        self.next_index += 1;
-        assert!(next_index < (1 << 24), "Too many peers created");
-        next_index
+        assert!(
+            self.peers_by_idx.len() < MAX_PEER_INDEX,
+            "Too many peers created"
+        );
+        loop {
+            let next_index = self.next_index;
+            self.next_index = (next_index + 1) % MAX_PEER_INDEX as u32;
+            // Peers injected from another device keep their index
+            if !self.peers_by_idx.contains_key(&next_index) {
+                return next_index;
+            }
+        }
     }
 
     fn remove_peer(&mut self, pub_key: &X25519PublicKey) {
-        if let Some(peer) = self.peers.remove(pub_key) {
-            // Found a peer to remove, now purge all references to it:
-            peer.shutdown_endpoint(); // close open udp socket and free the closure
-            self.peers_by_idx.remove(&peer.index()); // peers_by_idx
-            self.peers_by_ip
-                .remove(&|p: &Arc<Peer<S>>| Arc::ptr_eq(&peer, p)); // peers_by_ip
+        if let Some((pub_key, peer)) = self.take_peer(pub_key) {
+            self.keep_resumable(pub_key, peer);
+        }
+    }
 
-            info!(peer.tunnel.logger, "Peer removed");
+    // Remove a peer from the device, and return it
+    fn take_peer(
+        &mut self,
+        pub_key: &X25519PublicKey,
+    ) -> Option<(Arc<X25519PublicKey>, Arc<Peer<S>>)> {
+        let (pub_key, peer) = self.peers.remove_entry(pub_key)?;
+        // Found a peer to remove, now purge all references to it:
+        peer.shutdown_endpoint(); // close open udp socket and free the closure
+        self.peers_by_idx.remove(&peer.index()); // peers_by_idx
+        self.rebuild_peers_by_ip(); // peers_by_ip, other peers may take over its allowed IPs
+
+        info!(peer.tunnel.logger, "Peer removed");
+        self.subscribers.publish(DeviceEvent::PeerRemoved {
+            peer_id: peer.peer_id(),
+            public_key: Arc::clone(&pub_key),
+            metadata: peer.metadata(),
+        });
+        Some((pub_key, peer))
+    }
+
+    // Keep a removed peer for the resumption window, so its session can be resumed when the peer
+    // is added again
+    fn keep_resumable(&mut self, pub_key: Arc<X25519PublicKey>, peer: Arc<Peer<S>>) {
+        if !self.config.resumption_window.is_zero() {
+            self.resumable.insert(pub_key, (Instant::now(), peer));
+        }
+    }
+
+    // Drop the removed peers whose resumption window has passed, along with their sessions
+    fn expire_resumable(&mut self) {
+        let window = self.config.resumption_window;
+        self.resumable
+            .retain(|_, (removed, _)| removed.elapsed() < window);
+    }
+
+    // Add the allowed IPs of a peer to the routing trie. When another peer already has the same
+    // prefix the one with the lower metric keeps it, on a tie the newer peer takes over.
+    fn insert_peer_ips(&mut self, peer: &Arc<Peer<S>>) {
+        for (_, addr, cidr) in peer.allowed_ips() {
+            if let Some(old) = self.peers_by_ip.insert(addr, cidr, Arc::clone(peer)) {
+                if old.route_metric() < peer.route_metric() {
+                    self.peers_by_ip.insert(addr, cidr, old);
+                }
+            }
+        }
+    }
+
+    // Recreate the routing trie from scratch, so prefixes shared by several peers go to the
+    // preferred one of the remaining peers
+    fn rebuild_peers_by_ip(&mut self) {
+        let mut peers: Vec<_> = self.peers.values().cloned().collect();
+        peers.sort_by_key(|peer| peer.peer_id());
+
+        self.peers_by_ip.clear();
+        for peer in &peers {
+            self.insert_peer_ips(peer);
         }
     }
 
@@ -316,12 +1234,16 @@ impl<T: Tun, S: Sock> Device<T, S> {
         allowed_ips: Vec<AllowedIP>,
         keepalive: Option<u16>,
         preshared_key: Option<[u8; 32]>,
-    ) {
+        route_metric: u32,
+        bind_addr: Option<IpAddr>,
+        responder_only: bool,
+    ) -> Result<(), Error> {
         let pub_key = Arc::new(pub_key);
 
         if remove {
             // Completely remove a peer
-            return self.remove_peer(&pub_key);
+            self.remove_peer(&pub_key);
+            return Ok(());
         }
 
         // Update an existing peer
@@ -329,81 +1251,272 @@ impl<T: Tun, S: Sock> Device<T, S> {
             // We already have a peer, we need to merge the existing config into the newly created one
             panic!("Modifying existing peers is not yet supported. Remove and add again instead.");
         }
+        self.check_peer_limit()?;
+
+        // A peer removed within the resumption window keeps its index, which its session is
+        // addressed by, unless its preshared key changed
+        self.expire_resumable();
+        let resumed = self
+            .resumable
+            .remove(&pub_key)
+            .map(|(_, peer)| peer)
+            .filter(|peer| peer.preshared_key() == preshared_key.as_ref())
+            .filter(|peer| !self.peers_by_idx.contains_key(&peer.index()));
+        self.add_peer(
+            pub_key,
+            endpoint,
+            allowed_ips,
+            keepalive,
+            preshared_key,
+            route_metric,
+            bind_addr,
+            responder_only,
+            resumed,
+        );
+        Ok(())
+    }
+
+    // Peers are only added under the write lock, so no other add can slip in between the check
+    // and the insertion
+    fn check_peer_limit(&self) -> Result<(), Error> {
+        match self.config.max_peers {
+            max if max > 0 && self.peers.len() >= max => Err(Error::TooManyPeers),
+            _ => Ok(()),
+        }
+    }
 
-        let next_index = self.next_index();
+    // Add a new peer. A peer removed from this device or another one, with the same key, can be
+    // resumed: the new peer takes over its index and current session.
+    #[allow(clippy::too_many_arguments)]
+    fn add_peer(
+        &mut self,
+        pub_key: Arc<X25519PublicKey>,
+        endpoint: Option<SocketAddr>,
+        allowed_ips: Vec<AllowedIP>,
+        keepalive: Option<u16>,
+        preshared_key: Option<[u8; 32]>,
+        route_metric: u32,
+        bind_addr: Option<IpAddr>,
+        responder_only: bool,
+        resumed: Option<Arc<Peer<S>>>,
+    ) -> Arc<Peer<S>> {
+        let next_index = match &resumed {
+            Some(peer) => peer.index(),
+            None => self.next_index(),
+        };
         let device_key_pair = self
             .key_pair
             .as_ref()
             .expect("Private key must be set first");
 
+        let lean = self.config.lean_peers;
         let mut tunn = Tunn::new(
             Arc::clone(&device_key_pair.0),
             Arc::clone(&pub_key),
             preshared_key,
             keepalive,
             next_index,
-            None,
+            self.rate_limiter.clone().filter(|_| lean),
         )
         .unwrap();
+        if lean {
+            tunn.set_lean(Arc::clone(&self.buffers));
+        }
 
         {
-            let pub_key = base64::encode(pub_key.as_bytes());
+            let pub_key = pub_key.to_base64();
             let peer_name = format!("{}…{}", &pub_key[0..4], &pub_key[pub_key.len() - 4..]);
             let peer_logger = self.config.logger.new(o!("peer" => peer_name));
             tunn.set_logger(peer_logger);
         }
+        tunn.set_handshake_backoff(
+            self.config.max_handshake_attempts,
+            self.config.handshake_backoff_ceiling,
+        );
+        tunn.set_fast_handshake_retry(
+            self.config.fast_handshake_retries,
+            self.config.fast_handshake_retry_interval,
+        );
+        tunn.set_padding(self.config.traffic_padding.clone());
+        tunn.set_handshake_queue_policy(self.config.handshake_queue_policy);
+        tunn.set_handshake_padding(self.config.handshake_padding);
+        tunn.set_duplicate_init_window(self.config.duplicate_init_window);
+        tunn.set_wg_compat_log(self.config.wg_compat_log);
+        tunn.set_handshake_latency_buckets(&self.config.handshake_latency_buckets);
+        tunn.set_responder_only(responder_only);
+        if let Some(next) = &self.next_key {
+            tunn.set_next_static_private(
+                Arc::clone(&next.private_key),
+                Arc::clone(&next.public_key),
+                Some(Arc::clone(&next.rate_limiter)),
+            )
+            .unwrap();
+        }
+        if let Some(old) = resumed {
+            if tunn.resume_session(&old.tunnel) {
+                info!(tunn.logger, "Session resumed");
+            }
+        }
 
-        let peer = Peer::new(tunn, next_index, endpoint, &allowed_ips, preshared_key);
+        let peer_id = self.next_peer_id;
+        self.next_peer_id += 1;
+
+        let mut peer = Peer::new(
+            tunn,
+            next_index,
+            peer_id,
+            endpoint,
+            &allowed_ips,
+            preshared_key,
+            route_metric,
+            Backoff::new(
+                self.config.reconnect_backoff_base,
+                self.config.reconnect_backoff_ceiling,
+            ),
+        );
+        peer.set_bind_addr(bind_addr);
+        peer.set_link_mtu(self.config.link_mtu);
+        peer.set_trace(self.trace_buffer);
 
         let peer = Arc::new(peer);
-        self.peers.insert(pub_key, Arc::clone(&peer));
+        self.peers.insert(Arc::clone(&pub_key), Arc::clone(&peer));
         self.peers_by_idx.insert(next_index, Arc::clone(&peer));
 
-        for AllowedIP { addr, cidr } in allowed_ips {
-            self.peers_by_ip.insert(addr, cidr as _, Arc::clone(&peer));
-        }
+        self.insert_peer_ips(&peer);
 
         info!(peer.tunnel.logger, "Peer added");
+        self.subscribers.publish(DeviceEvent::PeerAdded {
+            peer_id,
+            public_key: pub_key,
+        });
+        peer
     }
 
     pub fn new(name: &str, config: DeviceConfig) -> Result<Device<T, S>, Error> {
-        let poll = EventPoll::<Handler<T, S>>::new()?;
+        // Ephemeral keys are only as good as the generator they are drawn from
+        entropy::check_os_rng()?;
+
+        if config.event_batch_size == 0 || config.event_batch_size > MAX_EVENT_BATCH_SIZE {
+            return Err(Error::InvalidConfig(format!(
+                "event_batch_size must be between 1 and {}",
+                MAX_EVENT_BATCH_SIZE
+            )));
+        }
+
+        if config.gro_max_segments == 0 || config.gro_max_segments > gro::MAX_GRO_SEGMENTS {
+            return Err(Error::InvalidConfig(format!(
+                "gro_max_segments must be between 1 and {}",
+                gro::MAX_GRO_SEGMENTS
+            )));
+        }
+
+        if config.listen_sockets == 0 || config.listen_sockets > MAX_LISTEN_SOCKETS {
+            return Err(Error::InvalidConfig(format!(
+                "listen_sockets must be between 1 and {}",
+                MAX_LISTEN_SOCKETS
+            )));
+        }
+
+        if config.tun_read_buffers == 0 || config.tun_read_buffers > MAX_TUN_READ_BUFFERS {
+            return Err(Error::InvalidConfig(format!(
+                "tun_read_buffers must be between 1 and {}",
+                MAX_TUN_READ_BUFFERS
+            )));
+        }
+
+        let poll = EventPoll::<Handler<T, S>>::new_with_batch_size(config.event_batch_size)?;
 
         // Create a tunnel device
-        let iface = Arc::new(T::new(name)?.set_non_blocking()?);
-        let mtu = iface.mtu()?;
+        #[cfg(target_os = "linux")]
+        let offload = config.use_tun_offload;
+        #[cfg(not(target_os = "linux"))]
+        let offload = false;
+
+        let (iface, mtu) = if config.on_inner_packet.is_some() {
+            (None, USERSPACE_MTU)
+        } else {
+            let iface = Arc::new(T::new_with_offload(name, offload)?.set_non_blocking()?);
+            let mtu = iface.mtu()?;
+            (Some(iface), mtu)
+        };
+
+        let handshake_source_allow = if config.handshake_source_allow.is_empty() {
+            None
+        } else {
+            Some(config.handshake_source_allow.iter().collect())
+        };
+        let log_limiter = LogLimiter::new(config.log_burst, config.log_burst_interval);
 
         let mut device = Device {
             queue: Arc::new(poll),
+            worker_queues: vec![],
+            name: name.to_owned(),
             iface,
             config,
             exit_notice: Default::default(),
             yield_notice: Default::default(),
             fwmark: Default::default(),
+            pacing_rate: None,
+            dont_fragment: None,
+            priority: None,
+            ttl: None,
+            freebind: false,
+            detailed_accounting: false,
+            validate_inner: false,
+            trace_buffer: 0,
+            prewarm: false,
+            mirror: None,
             key_pair: Default::default(),
+            next_key: None,
             listen_port: Default::default(),
-            next_index: Default::default(),
+            next_index: {
+                let mut start = [0u8; 4];
+                OsRng.fill(&mut start);
+                u32::from_le_bytes(start) % MAX_PEER_INDEX as u32
+            },
+            next_peer_id: 1,
             peers: Default::default(),
             peers_by_idx: Default::default(),
             peers_by_ip: Default::default(),
+            resumable: Default::default(),
             udp4: Default::default(),
             udp6: Default::default(),
+            udp_shards: Default::default(),
+            draining: None,
+            tun_fds: Default::default(),
+            api_fd: None,
             cleanup_paths: Default::default(),
             mtu: AtomicUsize::new(mtu),
             rate_limiter: None,
+            buffers: Arc::new(BufferPool::new(LEAN_POOL_BUFFERS)),
+            handshake_source_allow,
+            decrypt_failure_limiter: Default::default(),
+            log_limiter,
+            unknown_index_drops: AtomicU64::new(0),
+            invalid_inner_drops: AtomicU64::new(0),
+            mirror_drops: AtomicU64::new(0),
+            tx_batched: AtomicU64::new(0),
+            cookie_replies: AtomicU64::new(0),
+            invalid_mac: AtomicU64::new(0),
+            tx_batch_sends: AtomicU64::new(0),
+            subscribers: Default::default(),
+            addresses: vec![],
         };
 
         device.register_api_handler()?;
-        device.register_iface_handler(Arc::clone(&device.iface))?;
+        if let Some(iface) = &device.iface {
+            device.register_iface_handler(Arc::clone(iface))?;
+        }
         device.register_notifiers()?;
         device.register_timers()?;
+        device.register_worker_queues()?;
 
         #[cfg(target_os = "macos")]
         {
             // Only for macOS write the actual socket name into WG_TUN_NAME_FILE
             if let Ok(name_file) = std::env::var("WG_TUN_NAME_FILE") {
                 if name == "utun" {
-                    std::fs::write(&name_file, device.iface.name().unwrap().as_bytes()).unwrap();
+                    std::fs::write(&name_file, device.iface_name().unwrap().as_bytes()).unwrap();
                     device.cleanup_paths.push(name_file);
                 }
             }
@@ -412,65 +1525,927 @@ impl<T: Tun, S: Sock> Device<T, S> {
         Ok(device)
     }
 
-    fn open_listen_socket(&mut self, mut port: u16) -> Result<(), Error> {
-        // Binds the network facing interfaces
-        // First close any existing open socket, and remove them from the event loop
-        self.udp4.take().and_then(|s| unsafe {
-            // This is safe because the event loop is not running yet
-            self.queue.clear_event_by_fd(s.as_raw_fd());
-            Some(())
-        });
+    // With accept_handshake_padding, drop the padding of a handshake message before anything
+    // looks at the datagram, so padded messages go through the same checks as others
+    fn strip_handshake_padding<'a>(&self, datagram: &'a [u8]) -> &'a [u8] {
+        match self.config.accept_handshake_padding {
+            true => Tunn::strip_handshake_padding(datagram),
+            false => datagram,
+        }
+    }
 
-        self.udp6.take().and_then(|s| unsafe {
-            self.queue.clear_event_by_fd(s.as_raw_fd());
-            Some(())
-        });
+    // Check a datagram against the handshake source allow list. Only handshake initiations are
+    // filtered, and only by looking at the message type, so this is cheap enough to run before
+    // any crypto.
+    fn handshake_source_allowed(&self, datagram: &[u8], src_addr: IpAddr) -> bool {
+        match self.handshake_source_allow {
+            Some(ref allowed) if Tunn::is_handshake_init(datagram) => {
+                allowed.find(src_addr).is_some()
+            }
+            _ => true,
+        }
+    }
 
-        for peer in self.peers.values() {
-            peer.shutdown_endpoint();
+    // With drop_unknown_indices, check whether a datagram is a data message for none of the
+    // sessions, and count it if so. This only takes a lookup of the peer and of its session.
+    fn drop_unknown_index(&self, datagram: &[u8]) -> bool {
+        if !self.config.drop_unknown_indices {
+            return false;
         }
+        let idx = match Tunn::data_receiver_index(datagram) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        match self.peers_by_idx.get(&(idx >> 8)) {
+            Some(peer) if peer.tunnel.has_session(idx) => false,
+            _ => {
+                self.unknown_index_drops.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        }
+    }
 
-        // Then open new sockets and bind to the port
-        let udp_sock4 = Arc::new(S::new()?.set_non_blocking()?.set_reuse()?.bind(port)?);
+    /// The number of data messages dropped by `DeviceConfig::drop_unknown_indices`
+    pub fn unknown_index_drops(&self) -> u64 {
+        self.unknown_index_drops.load(Ordering::Relaxed)
+    }
 
-        if port == 0 {
-            // Random port was assigned
-            port = udp_sock4.port()?;
+    // With strict validation of inner packets, check a decapsulated packet before it is written to
+    // the tunnel interface, and count it if it is dropped
+    fn accept_inner(&self, packet: &[u8]) -> bool {
+        if !self.validate_inner || validate::is_well_formed(packet) {
+            return true;
         }
+        self.invalid_inner_drops.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// The number of decapsulated packets dropped as malformed with `validate_inner=strict`
+    pub fn invalid_inner_drops(&self) -> u64 {
+        self.invalid_inner_drops.load(Ordering::Relaxed)
+    }
 
-        let udp_sock6 = Arc::new(S::new6()?.set_non_blocking()?.set_reuse()?.bind(port)?);
+    /// The handshakes the rate limiter answered with a cookie or dropped
+    pub fn rate_limit_stats(&self) -> RateLimitStats {
+        RateLimitStats {
+            cookie_replies: self.cookie_replies.load(Ordering::Relaxed),
+            invalid_mac: self.invalid_mac.load(Ordering::Relaxed),
+        }
+    }
 
-        self.register_udp_handler(Arc::clone(&udp_sock4))?;
-        self.register_udp_handler(Arc::clone(&udp_sock6))?;
-        self.udp4 = Some(udp_sock4);
-        self.udp6 = Some(udp_sock6);
+    /// The number of packets sent in batches with `tx_batch_linger`, and the number of system
+    /// calls that sent them
+    pub fn tx_batch_stats(&self) -> (u64, u64) {
+        (
+            self.tx_batched.load(Ordering::Relaxed),
+            self.tx_batch_sends.load(Ordering::Relaxed),
+        )
+    }
 
-        self.listen_port = port;
+    // Send the batched packets out of the socket they were queued for. A connected socket is
+    // accompanied by its peer.
+    fn send_tx_batch(&self, batch: &mut TxBatch, sock: Option<(Arc<S>, Option<&Peer<S>>)>) {
+        if let (Some((sock, conn_peer)), false) = (sock, batch.is_empty()) {
+            let (sent, calls) = sock.send_batch(batch);
+            if let (Some(peer), true) = (conn_peer, sent > 0) {
+                peer.connected();
+            }
+            self.tx_batched.fetch_add(sent as u64, Ordering::Relaxed);
+            self.tx_batch_sends
+                .fetch_add(calls as u64, Ordering::Relaxed);
+        }
+        batch.clear();
+    }
 
+    /// Write a copy of every decapsulated packet to the tunnel interface name as well, for
+    /// monitoring, or stop with None. The copy is written without waiting: it is dropped when
+    /// the interface does not take it at once, or is down, and counted in `mirror_drops`.
+    pub fn set_mirror_tun(&mut self, name: Option<&str>) -> Result<(), Error> {
+        self.mirror = match name {
+            // Another queue of the same interface would loop the packets back in
+            Some(name) if self.iface_name().ok().as_deref() == Some(name) => {
+                return Err(Error::InvalidConfig(
+                    "The mirror must be another interface".to_owned(),
+                ))
+            }
+            Some(name) => Some(Arc::new(T::new(name)?.set_non_blocking()?)),
+            None => None,
+        };
         Ok(())
     }
 
-    fn set_key(&mut self, private_key: X25519SecretKey) {
-        let mut bad_peers = vec![];
+    /// The name of the interface decapsulated packets are mirrored to
+    pub fn mirror_tun(&self) -> Option<String> {
+        self.mirror.as_ref().and_then(|mirror| mirror.name().ok())
+    }
 
-        let private_key = Arc::new(private_key);
-        let public_key = Arc::new(private_key.public_key());
+    /// The number of decapsulated packets the mirror interface did not take
+    pub fn mirror_drops(&self) -> u64 {
+        self.mirror_drops.load(Ordering::Relaxed)
+    }
 
-        let rate_limiter = Arc::new(RateLimiter::new(&public_key, HANDSHAKE_RATE_LIMIT));
+    // The name of the tunnel interface, or the name the device was created with in userspace mode
+    fn iface_name(&self) -> Result<String, Error> {
+        match &self.iface {
+            Some(iface) => iface.name(),
+            None => Ok(self.name.clone()),
+        }
+    }
 
-        for peer in self.peers.values_mut() {
-            // Taking a pointer should be Ok as long as all other threads are stopped
-            let mut_ptr = Arc::into_raw(Arc::clone(peer)) as *mut Peer<S>;
+    // Write a decapsulated packet to the tunnel interface, or hand it to on_inner_packet
+    fn write_inner(&self, iface: Option<&T>, gso: &mut GsoBatch, packet: &[u8], is_v6: bool) {
+        match (iface, &self.config.on_inner_packet) {
+            (Some(iface), _) => write_to_iface(iface, gso, packet, is_v6),
+            (None, Some(callback)) => callback(packet),
+            (None, None) => {}
+        }
+    }
 
-            if unsafe {
-                mut_ptr.as_mut().unwrap().tunnel.set_static_private(
-                    Arc::clone(&private_key),
-                    Arc::clone(&public_key),
-                    Some(Arc::clone(&rate_limiter)),
-                )
-            }
-            .is_err()
-            {
+    /// Encapsulate an outbound inner packet and send it to the peer its destination is routed
+    /// to by the allowed IPs, as if it was read from the tunnel interface. This is how packets
+    /// enter the tunnel in userspace mode, see `DeviceConfig::on_inner_packet`. Without a
+    /// session the packet is queued and a handshake is started instead.
+    pub fn inject_inner(&self, inner_packet: &[u8]) -> Result<(), Error> {
+        let peer = match Tunn::dst_address(inner_packet).and_then(|dst| self.peers_by_ip.find(dst))
+        {
+            Some(peer) if peer.is_enabled() => peer,
+            Some(_) => return Err(Error::PeerDisabled),
+            None => return Err(Error::NoRoute),
+        };
+
+        if !peer.has_endpoint() {
+            peer.hold_for_endpoint(inner_packet, self.config.no_endpoint_buffer);
+            return Ok(());
+        }
+
+        if self.detailed_accounting {
+            peer.account_tx(inner_packet);
+        }
+        self.send_inner(peer, inner_packet)
+    }
+
+    // Copy a decapsulated packet to the mirror interface, if there is one
+    fn mirror_inner(&self, packet: &[u8], is_v6: bool) {
+        if let Some(mirror) = &self.mirror {
+            let written = match is_v6 {
+                true => mirror.write6(packet),
+                false => mirror.write4(packet),
+            };
+            if written < packet.len() {
+                self.mirror_drops.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The fds the device polls and their roles, for event loops that poll them alongside their
+    /// own fds and call `DeviceHandle::handle_readable` when one is readable. The set changes
+    /// when sockets are opened or closed, such as when the listen port changes or a peer gets a
+    /// connected socket, so it should be listed again after the device is configured. With
+    /// kqueue, timers and notifiers have no fds and are not listed.
+    pub fn raw_fds(&self) -> Vec<(RawFd, FdRole)> {
+        let tun_fds = self.tun_fds.lock();
+        let listen_fds: Vec<RawFd> = self
+            .udp4
+            .iter()
+            .chain(self.udp6.iter())
+            .chain(self.udp_shards.iter())
+            .chain(self.draining.iter().flat_map(|(_, sockets)| sockets))
+            .map(|sock| sock.as_raw_fd())
+            .collect();
+
+        // The polls of the workers serve listen sockets, and wait on the shared poll
+        let worker_fds = self
+            .worker_queues
+            .iter()
+            .flat_map(|queue| queue.registered());
+        self.queue
+            .registered()
+            .into_iter()
+            .chain(worker_fds.filter(|&(fd, _)| fd != self.queue.as_raw_fd()))
+            .map(|(fd, kind)| {
+                let role = match kind {
+                    EventKind::Timer => FdRole::Timer,
+                    EventKind::Notifier => FdRole::Notifier,
+                    EventKind::Signal => FdRole::Signal,
+                    EventKind::Fd if tun_fds.contains(&fd) => FdRole::Tun,
+                    EventKind::Fd if listen_fds.contains(&fd) => FdRole::ListenSocket,
+                    EventKind::Fd if self.api_fd == Some(fd) => FdRole::Api,
+                    // The remaining sockets are connected to the endpoints of peers
+                    EventKind::Fd => FdRole::PeerSocket,
+                };
+                (fd, role)
+            })
+            .collect()
+    }
+
+    // Pass a failed decapsulation to the diagnostic callback, if the error was caused by the
+    // packet and the rate limit allows
+    fn report_decrypt_failure(&self, reason: Option<DecryptFailureReason>, src: SocketAddr) {
+        if let (Some(callback), Some(reason)) = (&self.config.on_decrypt_failure, reason) {
+            if let Some(suppressed) = self.decrypt_failure_limiter.allow() {
+                callback(DecryptFailure {
+                    reason,
+                    src,
+                    suppressed,
+                });
+            }
+        }
+    }
+
+    /// The public key of the device, or None if no private key is set. The private key itself
+    /// is never exposed through this API.
+    pub fn public_key(&self) -> Option<X25519PublicKey> {
+        self.key_pair
+            .as_ref()
+            .map(|(_, public_key)| X25519PublicKey::from(public_key.as_bytes()))
+    }
+
+    /// Find the peer packets destined to dst would be routed to, using the same longest-prefix
+    /// match on allowed IPs as the data path. Returns the public key of that peer, if any.
+    pub fn route_lookup(&self, dst: IpAddr) -> Option<X25519PublicKey> {
+        self.peers_by_ip
+            .find(dst)
+            .map(|peer| X25519PublicKey::from(peer.tunnel.peer_static_public().as_bytes()))
+    }
+
+    /// Explain the routing and session decision for an inner packet, as if it was read from the
+    /// tunnel interface, without sending, queueing or counting it
+    pub fn explain_outbound(&self, inner_packet: &[u8]) -> OutboundDecision {
+        let dst_addr = match Tunn::dst_address(inner_packet) {
+            Some(addr) => addr,
+            None => return OutboundDecision::Malformed,
+        };
+        let peer = match self.peers_by_ip.find(dst_addr) {
+            Some(peer) => peer,
+            None => return OutboundDecision::NoRoute(dst_addr),
+        };
+
+        let peer_id = peer.peer_id();
+        let endpoint = peer.endpoint().addr;
+        match endpoint {
+            _ if !peer.is_enabled() => OutboundDecision::PeerDisabled { peer_id },
+            None => OutboundDecision::NoEndpoint { peer_id },
+            Some(endpoint) if peer.time_since_last_handshake().is_some() => {
+                OutboundDecision::Send { peer_id, endpoint }
+            }
+            Some(_) if peer.tunnel.is_handshake_in_progress() => {
+                OutboundDecision::AwaitingHandshake { peer_id }
+            }
+            Some(_) if peer.tunnel.is_responder_only() => {
+                OutboundDecision::AwaitingPeer { peer_id }
+            }
+            Some(_) if peer.tunnel.is_handshake_backed_off() => {
+                OutboundDecision::HandshakeBackedOff { peer_id }
+            }
+            Some(_) => OutboundDecision::WouldHandshake { peer_id },
+        }
+    }
+
+    /// Encapsulate an inner packet for the peer with the given key and send it to the peer's
+    /// endpoint, bypassing the tunnel interface. Without a session the packet is queued and a
+    /// handshake is started instead.
+    pub fn send_to_peer(&self, key: &X25519PublicKey, inner_packet: &[u8]) -> Result<(), Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        if !peer.is_enabled() {
+            return Err(Error::PeerDisabled);
+        }
+        self.send_inner(peer, inner_packet)
+    }
+
+    // Encapsulate an inner packet for a peer and send it, or queue it until the handshake
+    // started for it completes
+    fn send_inner(&self, peer: &Peer<S>, inner_packet: &[u8]) -> Result<(), Error> {
+        let (udp4, udp6) = match (&self.udp4, &self.udp6) {
+            (Some(udp4), Some(udp6)) => (udp4, udp6),
+            _ => return Err(Error::Socket("Not listening".to_owned())),
+        };
+
+        let mut dst = vec![0u8; MAX_UDP_SIZE];
+        match peer.tunnel.encapsulate(inner_packet, &mut dst) {
+            TunnResult::Done => Ok(()),
+            TunnResult::Err(e) => Err(Error::Encapsulate(e)),
+            TunnResult::WriteToNetwork(packet) => {
+                send_to_endpoint(peer, udp4, udp6, packet, None, ecn::ECN_NOT_ECT)
+            }
+            _ => panic!("Unexpected result from encapsulate"),
+        }
+    }
+
+    // Send the packets kept while the peer had no endpoint, once a session with it is established
+    fn send_held(&self, peer: &Peer<S>, dst: &mut [u8]) {
+        if peer.time_since_last_handshake().is_none() {
+            return;
+        }
+        let (udp4, udp6) = match (&self.udp4, &self.udp6) {
+            (Some(udp4), Some(udp6)) => (udp4, udp6),
+            _ => return,
+        };
+        for packet in peer.take_held() {
+            if self.detailed_accounting {
+                peer.account_tx(&packet);
+            }
+            let result = peer.tunnel.encapsulate(&packet, dst);
+            peer.trace_tx(&packet, &result);
+            if let TunnResult::WriteToNetwork(datagram) = result {
+                let _ =
+                    send_to_endpoint(peer, udp4, udp6, datagram, Some(&packet), ecn::ECN_NOT_ECT);
+            }
+            if let Some(buffers) = peer.tunnel.buffer_pool() {
+                buffers.recycle(packet);
+            }
+        }
+    }
+
+    /// Spread the data packets to a peer over several endpoints, each endpoint getting a share of
+    /// the inner flows proportional to its weight. All packets of a flow go to the same endpoint.
+    /// Handshakes and keepalives still go to the endpoint the peer was last heard from.
+    pub fn set_peer_ecmp_endpoints(
+        &self,
+        key: &X25519PublicKey,
+        endpoints: Vec<(SocketAddr, u32)>,
+    ) -> Result<(), Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        peer.set_ecmp_endpoints(endpoints);
+        Ok(())
+    }
+
+    /// Give a peer a backup endpoint, for active/standby redundancy: once `failover_attempts`
+    /// handshake initiations to its endpoint go unanswered, the peer switches to the backup
+    /// until its primary endpoint answers one of the probes sent every `failover_probe_interval`.
+    /// None removes the backup.
+    pub fn set_peer_backup_endpoint(
+        &self,
+        key: &X25519PublicKey,
+        addr: Option<SocketAddr>,
+    ) -> Result<(), Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        peer.set_backup_endpoint(addr);
+        Ok(())
+    }
+
+    /// Give a peer its endpoint by name, as HOST:PORT, and resolve it. The name is kept, and
+    /// resolved again by `reresolve_endpoints`. A name that does not resolve yet leaves the
+    /// endpoint as it is. None forgets the name, keeping the endpoint it resolved to.
+    pub fn set_peer_endpoint_host(
+        &self,
+        key: &X25519PublicKey,
+        host: Option<String>,
+    ) -> Result<(), Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        if let Some(host) = &host {
+            if !resolve::is_valid_host_endpoint(host) {
+                return Err(Error::InvalidConfig(format!(
+                    "Invalid endpoint {}, expected HOST:PORT",
+                    host
+                )));
+            }
+        }
+        peer.set_endpoint_host(host);
+        self.reresolve_peer(peer);
+        Ok(())
+    }
+
+    /// Resolve the names of the endpoints of peers again, and move each peer whose name now
+    /// resolves to another address there, starting a handshake to the new address. Returns the
+    /// number of peers that moved. This blocks until every name is resolved.
+    pub fn reresolve_endpoints(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| self.reresolve_peer(peer))
+            .count()
+    }
+
+    // Resolve the name of the endpoint of a peer, returns true if the endpoint moved
+    fn reresolve_peer(&self, peer: &Peer<S>) -> bool {
+        match peer.reresolve(&self.config.resolver) {
+            Ok(Some(_)) => {}
+            Ok(None) => return false,
+            Err(e) => {
+                warn!(
+                    peer.tunnel.logger,
+                    "Failed to resolve endpoint {}: {}",
+                    peer.endpoint_host().unwrap_or_default(),
+                    e
+                );
+                return false;
+            }
+        }
+
+        // Confirm with a handshake that the peer is at the new address
+        if let (true, Some(udp4), Some(udp6)) = (peer.is_enabled(), &self.udp4, &self.udp6) {
+            let mut dst = vec![0u8; MAX_UDP_SIZE];
+            if let TunnResult::WriteToNetwork(packet) =
+                peer.tunnel.format_handshake_initiation(&mut dst, true)
+            {
+                peer.trace_sent(packet);
+                let _ = send_to_endpoint(peer, udp4, udp6, packet, None, ecn::ECN_NOT_ECT);
+            }
+        }
+        true
+    }
+
+    /// Attach an opaque label to a peer, carried by its stats, events and log lines, or remove it
+    /// with None
+    pub fn set_peer_metadata(
+        &self,
+        key: &X25519PublicKey,
+        metadata: Option<String>,
+    ) -> Result<(), Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        if let Some(metadata) = &metadata {
+            if !peer::is_valid_metadata(metadata) {
+                return Err(Error::InvalidConfig(format!(
+                    "Metadata must be at most {} bytes without control characters",
+                    peer::MAX_METADATA_LEN
+                )));
+            }
+        }
+        peer.set_metadata(metadata);
+        Ok(())
+    }
+
+    /// Turn the liveness probe of a peer on or off, see `liveness`
+    pub fn set_peer_liveness_probe(&self, key: &X25519PublicKey, on: bool) -> Result<(), Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        peer.liveness().set_enabled(on);
+        Ok(())
+    }
+
+    /// Send an echo request to every enabled peer whose liveness probe is on, marking those
+    /// that did not answer the last one unreachable. Peers probed less than
+    /// `liveness::MIN_PROBE_INTERVAL` ago, and peers without an allowed IP of the family of an
+    /// address of the interface, are skipped. Returns the number of probes sent.
+    pub fn send_liveness_probes(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.is_enabled() && peer.liveness().is_enabled())
+            .filter_map(|peer| Some((peer, peer.liveness_probe(&self.addresses)?)))
+            .filter(|(peer, probe)| self.send_inner(peer, probe).is_ok())
+            .count()
+    }
+
+    // Assign an address to the interface, and remember it as a source of liveness probes
+    fn set_address(&mut self, addr: IpAddr, prefix_len: u8) -> Result<(), Error> {
+        if let Some(iface) = &self.iface {
+            iface.set_address(addr, prefix_len)?;
+        }
+        if !self.addresses.contains(&addr) {
+            self.addresses.push(addr);
+        }
+        Ok(())
+    }
+
+    /// Tear down the sessions of a peer that exchanged no data for timeout, keeping its
+    /// configuration, until traffic to it resumes. None keeps its sessions up. Peers with
+    /// persistent keepalive are exempt.
+    pub fn set_peer_idle_timeout(
+        &self,
+        key: &X25519PublicKey,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        peer.tunnel.set_idle_timeout(timeout);
+        Ok(())
+    }
+
+    /// Invalidate the sessions of a peer at once, for example when its keys may be compromised.
+    /// Its traffic stops until a new handshake completes, which the next packet to it starts.
+    pub fn expire_session(&self, key: &X25519PublicKey) -> Result<(), Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        peer.tunnel.expire_sessions();
+        Ok(())
+    }
+
+    /// Drop the sessions of all peers, removed peers kept for resumption included, that are past
+    /// REJECT_AFTER_TIME and can no longer carry traffic, and return how many were dropped. The
+    /// timers drop them too, but only on their next tick, so this frees their keys at once.
+    pub fn reap_expired_sessions(&self) -> usize {
+        self.peers
+            .values()
+            .chain(self.resumable.values().map(|(_, peer)| peer))
+            .map(|peer| peer.tunnel.reap_expired_sessions())
+            .sum()
+    }
+
+    /// Pause or resume a peer. A disabled peer keeps its configuration and counters, but all
+    /// packets to and from it are dropped, and no keepalives or handshakes are sent. Once
+    /// enabled again, traffic to the peer starts a new handshake if its session expired.
+    pub fn set_peer_enabled(&self, key: &X25519PublicKey, enabled: bool) -> Result<(), Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        peer.set_enabled(enabled);
+        info!(
+            peer.tunnel.logger,
+            "{}",
+            if enabled {
+                "Peer enabled"
+            } else {
+                "Peer disabled"
+            }
+        );
+        Ok(())
+    }
+
+    /// Remove a peer to hand it over to another device of the process with `inject_peer`,
+    /// along with its keys, session, endpoint and traffic counters. The caller holds the write
+    /// lock of the device, so once this returns no packet of the peer is processed here.
+    pub fn extract_peer(&mut self, key: &X25519PublicKey) -> Option<PeerState<S>> {
+        let (public_key, peer) = self.take_peer(key)?;
+        Some(PeerState { public_key, peer })
+    }
+
+    /// Add a peer extracted from another device with `extract_peer`. The peer carries on with
+    /// its session, so traffic continues without a handshake once its messages reach this
+    /// device, and with its counters, although it gets a new `peer_id`. If the index of the
+    /// session is taken on this device the session is dropped, and the next packet starts a
+    /// handshake. Both devices should have the same private key, or the next handshake with the
+    /// peer fails.
+    pub fn inject_peer(&mut self, state: PeerState<S>) -> Result<(), Error> {
+        if self.key_pair.is_none() {
+            return Err(Error::InvalidConfig(
+                "Private key must be set first".to_owned(),
+            ));
+        }
+        let PeerState {
+            public_key,
+            peer: old,
+        } = state;
+        if self.peers.contains_key(&public_key) {
+            return Err(Error::InvalidConfig("The peer already exists".to_owned()));
+        }
+        self.check_peer_limit()?;
+
+        let allowed_ips = old
+            .allowed_ips()
+            .map(|(_, addr, cidr)| AllowedIP {
+                addr,
+                cidr: cidr as u8,
+            })
+            .collect();
+        let resumed =
+            Some(Arc::clone(&old)).filter(|old| !self.peers_by_idx.contains_key(&old.index()));
+        let peer = self.add_peer(
+            public_key,
+            old.endpoint().addr,
+            allowed_ips,
+            old.persistent_keepalive(),
+            old.preshared_key().copied(),
+            old.route_metric(),
+            old.bind_addr(),
+            old.tunnel.is_responder_only(),
+            resumed,
+        );
+        peer.tunnel.add_counters(&old.tunnel);
+        peer.set_enabled(old.is_enabled());
+        peer.set_ecmp_endpoints(old.ecmp_endpoints());
+        peer.set_backup_endpoint(old.backup_endpoint());
+        peer.set_metadata(old.metadata());
+        peer.tunnel.set_idle_timeout(old.tunnel.idle_timeout());
+        Ok(())
+    }
+
+    /// The peers of the device, ordered by `peer_id`
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peers_by_id()
+            .into_iter()
+            .map(|(public_key, peer)| {
+                let (_, tx_bytes, rx_bytes, ..) = peer.tunnel.stats();
+                let (tx_control_bytes, rx_control_bytes) = peer.tunnel.control_stats();
+                let (rx_protocols, tx_protocols) = if self.detailed_accounting {
+                    let (rx, tx) = peer.protocol_stats();
+                    (Some(rx), Some(tx))
+                } else {
+                    (None, None)
+                };
+                PeerInfo {
+                    peer_id: peer.peer_id(),
+                    public_key: X25519PublicKey::from(public_key.as_bytes()),
+                    endpoint: peer.endpoint().addr,
+                    last_handshake: peer.time_since_last_handshake(),
+                    rx_bytes,
+                    tx_bytes,
+                    rx_control_bytes,
+                    tx_control_bytes,
+                    enabled: peer.is_enabled(),
+                    payload_budget: peer.payload_budget(),
+                    rx_protocols,
+                    tx_protocols,
+                    handshake_latency: peer.tunnel.handshake_latency(),
+                }
+            })
+            .collect()
+    }
+
+    /// The traffic counters of a single peer
+    pub fn peer_stats(&self, key: &X25519PublicKey) -> Result<PeerStats, Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        Ok(PeerStats::of(peer))
+    }
+
+    /// The traffic counters of every peer, ordered by `peer_id`. Only the counters of each peer
+    /// are read, in a single pass that takes no lock besides the device lock the caller holds,
+    /// so scraping many peers costs little more than copying. Peers are visited in the order
+    /// they were added, which is mostly the order they are laid out in memory. The counters of
+    /// different peers are read one after the other, not at a single instant.
+    pub fn all_stats(&self) -> Vec<(PeerId, PeerStats)> {
+        self.peers_by_id()
+            .into_iter()
+            .map(|(_, peer)| (peer.peer_id(), PeerStats::of(peer)))
+            .collect()
+    }
+
+    /// A stream of the changes of the device: peers added and removed, handshakes completed,
+    /// endpoints changed and, every `stats_interval`, the counters of every peer. The stream is
+    /// bounded, when the receiver falls behind events are dropped and counted in a
+    /// `DeviceEvent::Lagged`. The subscription ends when the receiver is dropped.
+    pub fn subscribe(&self) -> std::sync::mpsc::Receiver<DeviceEvent> {
+        self.subscribers.subscribe()
+    }
+
+    // Report a new session or endpoint of a peer after it received a datagram
+    fn publish_rx_events(&self, peer: &Peer<S>, new_endpoint: Option<SocketAddr>) {
+        if peer.tunnel.take_new_session() {
+            self.subscribers.publish(DeviceEvent::HandshakeCompleted {
+                peer_id: peer.peer_id(),
+                metadata: peer.metadata(),
+            });
+        }
+        if let Some(endpoint) = new_endpoint {
+            self.subscribers.publish(DeviceEvent::EndpointChanged {
+                peer_id: peer.peer_id(),
+                endpoint,
+                metadata: peer.metadata(),
+            });
+        }
+    }
+
+    /// The current sessions of all peers that have one, in the order the peers were added
+    pub fn active_sessions(&self) -> Vec<SessionInfo> {
+        self.peers_by_id()
+            .into_iter()
+            .filter_map(|(public_key, peer)| {
+                let stats = peer.tunnel.session_stats()?;
+                Some(SessionInfo {
+                    peer_id: peer.peer_id(),
+                    public_key: X25519PublicKey::from(public_key.as_bytes()),
+                    age: stats.age,
+                    time_to_rekey: stats.time_to_rekey,
+                    tx_bytes: stats.tx_bytes,
+                    rx_bytes: stats.rx_bytes,
+                    local_index: stats.local_index,
+                    peer_index: stats.peer_index,
+                    created: stats.created,
+                    replay_window: stats.replay_window,
+                })
+            })
+            .collect()
+    }
+
+    /// The last packet events of a peer, oldest first, when tracing is enabled with
+    /// `trace_buffer`
+    pub fn peer_trace(&self, key: &X25519PublicKey) -> Result<Vec<trace::TraceEvent>, Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        Ok(peer.trace())
+    }
+
+    // The peers in the order they were added, so listings do not depend on hashing
+    fn peers_by_id(&self) -> Vec<(&Arc<X25519PublicKey>, &Arc<Peer<S>>)> {
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|(_, peer)| peer.peer_id());
+        peers
+    }
+
+    /// Block until a handshake with the peer completed, or return `Error::HandshakeTimeout` after
+    /// `timeout`. Returns right away if there already is a session.
+    /// The caller holds the device lock while waiting, so configuration changes through the API
+    /// wait as well. Must not be called from a device thread.
+    pub fn wait_for_handshake(
+        &self,
+        key: &X25519PublicKey,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let peer = self.peers.get(key).ok_or(Error::UnknownPeer)?;
+        if peer.tunnel.wait_for_session(timeout) {
+            Ok(())
+        } else {
+            Err(Error::HandshakeTimeout)
+        }
+    }
+
+    /// Aggregate status of the device and its peers, cheap enough for a liveness probe
+    pub fn health(&self) -> Health {
+        let handshake_ages = self
+            .peers
+            .values()
+            .filter_map(|peer| peer.tunnel.time_since_handshake());
+
+        let mut live_sessions = 0;
+        let mut since_last_handshake: Option<Duration> = None;
+        for age in handshake_ages {
+            if age < SESSION_LIFETIME {
+                live_sessions += 1;
+            }
+            since_last_handshake = Some(since_last_handshake.map_or(age, |last| last.min(age)));
+        }
+
+        let is_open = |fd: RawFd| unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1;
+
+        Health {
+            peers: self.peers.len(),
+            live_sessions,
+            since_last_handshake,
+            iface_open: match &self.iface {
+                Some(iface) => is_open(iface.as_raw_fd()),
+                None => true,
+            },
+            socket_open: self
+                .udp4
+                .iter()
+                .chain(&self.udp6)
+                .any(|s| is_open(s.as_raw_fd())),
+        }
+    }
+
+    fn open_listen_socket(&mut self, port: u16) -> Result<(), Error> {
+        // Binds the network facing interfaces
+        // First close any existing open socket, and remove them from the event loop
+        let sockets: Vec<_> = self
+            .udp4
+            .take()
+            .into_iter()
+            .chain(self.udp6.take())
+            .chain(self.udp_shards.drain(..))
+            .collect();
+        for s in sockets {
+            // This is safe because the event loop is not running yet
+            unsafe { self.clear_listen_event(&s) };
+        }
+
+        self.close_draining();
+
+        for peer in self.peers.values() {
+            peer.shutdown_endpoint();
+        }
+
+        // Then open new sockets and bind to the port
+        let (port, sockets) = self.bind_listen_sockets(port)?;
+        self.use_listen_sockets(port, sockets)
+    }
+
+    /// Move the device to a new listen port, 0 for a random one, without interrupting sessions.
+    /// The sockets of the new port are bound first, so if that fails nothing changes. Packets
+    /// are sent from the new port right away, and peers learn it from them like they learn any
+    /// roaming endpoint. Packets to the old port are still received for 30 seconds.
+    pub fn change_listen_port(&mut self, port: u16) -> Result<(), Error> {
+        if port != 0 && port == self.listen_port {
+            return Ok(());
+        }
+        let (port, sockets) = self.bind_listen_sockets(port)?;
+
+        self.close_draining();
+        let old = self
+            .udp4
+            .take()
+            .into_iter()
+            .chain(self.udp6.take())
+            .chain(self.udp_shards.drain(..))
+            .collect();
+        self.draining = Some((Instant::now() + LISTEN_PORT_GRACE, old));
+
+        // Connected sockets are bound to the old port
+        for peer in self.peers.values() {
+            peer.shutdown_endpoint();
+        }
+
+        self.use_listen_sockets(port, sockets)
+    }
+
+    // Stop receiving on the sockets of the previous listen port
+    fn close_draining(&mut self) {
+        if let Some((_, sockets)) = self.draining.take() {
+            for sock in sockets {
+                unsafe { self.clear_listen_event(&sock) };
+                for peer in self.peers.values() {
+                    peer.forget_listen_sock(&sock);
+                }
+            }
+        }
+    }
+
+    // Bind the listen sockets of port, returns the port they are bound to and the sockets, the
+    // IPv4 socket first, the IPv6 socket second and then the shards
+    fn bind_listen_sockets(&self, mut port: u16) -> Result<(u16, Vec<Arc<S>>), Error> {
+        let n_sockets = self.config.listen_sockets;
+        let ecn_passthrough = self.config.ecn_passthrough;
+        let pacing_rate = self.pacing_rate;
+        let dont_fragment = self.dont_fragment;
+        let priority = self.priority;
+        let ttl = self.ttl;
+        let fwmark = self.fwmark;
+        #[cfg(target_os = "linux")]
+        let bpf_filter = Some(bpf::BpfProgram::wireguard()).filter(|_| self.config.use_bpf_filter);
+        #[cfg(target_os = "linux")]
+        let udp_gro = self.config.use_udp_gro;
+        let listen_socket = |sock: Result<S, Error>, port: u16| -> Result<Arc<S>, Error> {
+            let mut sock = sock?.set_non_blocking()?.set_reuse()?;
+            if n_sockets > 1 {
+                sock = sock.set_reuse_port()?;
+            }
+            let sock = sock.bind(port)?;
+            if ecn_passthrough {
+                sock.set_recv_ecn()?;
+            }
+            if let Some(rate) = pacing_rate {
+                sock.set_pacing_rate(rate)?;
+            }
+            if let Some(df) = dont_fragment {
+                sock.set_dont_fragment(df)?;
+            }
+            if let Some(prio) = priority {
+                sock.set_priority(prio)?;
+            }
+            if let Some(ttl) = ttl {
+                sock.set_ttl(ttl)?;
+            }
+            if let Some(mark) = fwmark {
+                sock.set_fwmark(mark)?;
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(prog) = &bpf_filter {
+                sock.attach_bpf_filter(prog)?;
+            }
+            #[cfg(target_os = "linux")]
+            if udp_gro {
+                sock.set_gro()?;
+            }
+            Ok(Arc::new(sock))
+        };
+
+        let config = &self.config;
+        let udp_sock4 = listen_socket(S::new_with_config(config), port)?;
+
+        if port == 0 {
+            // Random port was assigned
+            port = udp_sock4.port()?;
+        }
+
+        let udp_sock6 = listen_socket(S::new6_with_config(config), port)?;
+
+        let mut sockets = vec![udp_sock4, udp_sock6];
+        for _ in 1..n_sockets {
+            sockets.push(listen_socket(S::new_with_config(config), port)?);
+            sockets.push(listen_socket(S::new6_with_config(config), port)?);
+        }
+        Ok((port, sockets))
+    }
+
+    // Serve the sockets returned by bind_listen_sockets. With worker polls, each pair of sockets
+    // is served by one worker, so a flow hashed to a socket is always received by that worker.
+    fn use_listen_sockets(&mut self, port: u16, sockets: Vec<Arc<S>>) -> Result<(), Error> {
+        for (i, sock) in sockets.iter().enumerate() {
+            let queue = match self.worker_queues.len() {
+                0 => &self.queue,
+                n => &self.worker_queues[i / 2 % n],
+            };
+            self.register_udp_handler(queue, Arc::clone(sock))?;
+        }
+        let mut sockets = sockets.into_iter();
+        self.udp4 = sockets.next();
+        self.udp6 = sockets.next();
+        self.udp_shards = sockets.collect();
+
+        self.listen_port = port;
+
+        Ok(())
+    }
+
+    fn new_rate_limiter(&self, public_key: &X25519PublicKey) -> Arc<RateLimiter> {
+        Arc::new(match &self.config.cookie_seed {
+            Some(seed) => RateLimiter::new_with_seed(public_key, HANDSHAKE_RATE_LIMIT, seed),
+            None => RateLimiter::new(public_key, HANDSHAKE_RATE_LIMIT),
+        })
+    }
+
+    fn set_key(&mut self, private_key: X25519SecretKey) {
+        let mut bad_peers = vec![];
+
+        let private_key = Arc::new(private_key);
+        let public_key = Arc::new(private_key.public_key());
+
+        let rate_limiter = self.new_rate_limiter(&public_key);
+
+        for peer in self.peers.values_mut() {
+            // Taking a pointer should be Ok as long as all other threads are stopped
+            let mut_ptr = Arc::into_raw(Arc::clone(peer)) as *mut Peer<S>;
+
+            if unsafe {
+                mut_ptr.as_mut().unwrap().tunnel.set_static_private(
+                    Arc::clone(&private_key),
+                    Arc::clone(&public_key),
+                    Some(Arc::clone(&rate_limiter)),
+                )
+            }
+            .is_err()
+            {
                 // In case we encounter an error, we will remove that peer
                 // An error will be a result of bad public key/secret key combination
                 bad_peers.push(peer);
@@ -479,6 +2454,8 @@ impl<T: Tun, S: Sock> Device<T, S> {
 
         self.key_pair = Some((private_key, public_key));
         self.rate_limiter = Some(rate_limiter);
+        // Setting a key explicitly abandons any rollover in progress
+        self.next_key = None;
 
         // Remove all the bad peers
         for _ in bad_peers {
@@ -486,22 +2463,131 @@ impl<T: Tun, S: Sock> Device<T, S> {
         }
     }
 
-    fn set_fwmark(&mut self, mark: u32) -> Result<(), Error> {
-        self.fwmark = Some(mark);
+    /// Start a rollover to a new private key. For the duration of `window` handshakes addressed
+    /// to either the current or the new key succeed, so peers can be reconfigured with the new
+    /// public key one at a time. Once the window ends the current key is dropped and replaced by
+    /// the new one, without interrupting established sessions.
+    /// If no private key is set yet, the new key is used right away.
+    pub fn set_next_private_key(&mut self, private_key: X25519SecretKey, window: Duration) {
+        if self.key_pair.is_none() {
+            return self.set_key(private_key);
+        }
 
-        // First set fwmark on listeners
-        if let Some(ref sock) = self.udp4 {
-            sock.set_fwmark(mark)?;
+        let private_key = Arc::new(private_key);
+        let public_key = Arc::new(private_key.public_key());
+        let rate_limiter = self.new_rate_limiter(&public_key);
+
+        for peer in self.peers.values() {
+            peer.tunnel
+                .set_next_static_private(
+                    Arc::clone(&private_key),
+                    Arc::clone(&public_key),
+                    Some(Arc::clone(&rate_limiter)),
+                )
+                .unwrap();
         }
 
-        if let Some(ref sock) = self.udp6 {
-            sock.set_fwmark(mark)?;
+        self.next_key = Some(NextKey {
+            private_key,
+            public_key,
+            rate_limiter,
+            deadline: Instant::now() + window,
+        });
+    }
+
+    // Replace the current private key with the next one, at the end of the rollover window
+    fn commit_next_private_key(&mut self) {
+        let next = match self.next_key.take() {
+            Some(next) => next,
+            None => return,
+        };
+
+        for peer in self.peers.values() {
+            peer.tunnel.commit_static_private(&next.public_key);
+        }
+
+        info!(self.config.logger, "Key rollover complete");
+        self.key_pair = Some((next.private_key, next.public_key));
+        self.rate_limiter = Some(next.rate_limiter);
+    }
+
+    // The key pair and rate limiter for a datagram: during a key rollover handshakes addressed to
+    // the next key are verified against it
+    #[allow(clippy::type_complexity)]
+    fn keys_for(
+        &self,
+        packet: &[u8],
+    ) -> (
+        &Arc<X25519SecretKey>,
+        &Arc<X25519PublicKey>,
+        &Arc<RateLimiter>,
+    ) {
+        match &self.next_key {
+            Some(next) if next.rate_limiter.matches_mac1(packet) => {
+                (&next.private_key, &next.public_key, &next.rate_limiter)
+            }
+            _ => {
+                let (private_key, public_key) = self.key_pair.as_ref().expect("Key not set");
+                (private_key, public_key, self.rate_limiter.as_ref().unwrap())
+            }
+        }
+    }
+
+    /// Mark the packets the device sends with mark, for policy routing. Applies to the listen
+    /// sockets and connected sockets at once, and to every socket opened later, such as after
+    /// the listen port changes. A mark of 0 clears it.
+    pub fn set_fwmark(&mut self, mark: u32) -> Result<(), Error> {
+        self.fwmark = Some(mark).filter(|&mark| mark != 0);
+        self.for_each_socket(|sock| sock.set_fwmark(mark))
+    }
+
+    /// The mark of the packets the device sends, None if they are not marked
+    pub fn fwmark(&self) -> Option<u32> {
+        self.fwmark
+    }
+
+    fn set_pacing_rate(&mut self, bytes_per_sec: u64) -> Result<(), Error> {
+        self.pacing_rate = Some(bytes_per_sec);
+        self.for_each_socket(|sock| sock.set_pacing_rate(bytes_per_sec))
+    }
+
+    // Trace the last capacity packet events of every peer, or stop tracing with 0
+    fn set_trace_buffer(&mut self, capacity: usize) {
+        self.trace_buffer = capacity;
+        for peer in self.peers.values() {
+            peer.set_trace(capacity);
+        }
+    }
+
+    fn set_dont_fragment(&mut self, df: bool) -> Result<(), Error> {
+        self.dont_fragment = Some(df);
+        self.for_each_socket(|sock| sock.set_dont_fragment(df))
+    }
+
+    fn set_priority(&mut self, prio: u32) -> Result<(), Error> {
+        self.priority = Some(prio);
+        self.for_each_socket(|sock| sock.set_priority(prio))
+    }
+
+    fn set_ttl(&mut self, ttl: u32) -> Result<(), Error> {
+        self.ttl = Some(ttl);
+        self.for_each_socket(|sock| sock.set_ttl(ttl))
+    }
+
+    // Apply a socket option to the listen sockets and all connected sockets
+    fn for_each_socket<F: Fn(&S) -> Result<(), Error>>(&self, f: F) -> Result<(), Error> {
+        for sock in self
+            .udp4
+            .iter()
+            .chain(self.udp6.iter())
+            .chain(self.udp_shards.iter())
+        {
+            f(sock)?;
         }
 
-        // Then on all currently connected sockets
         for peer in self.peers.values() {
             if let Some(ref sock) = peer.endpoint().conn {
-                sock.set_fwmark(mark)?
+                f(sock)?;
             }
         }
 
@@ -509,7 +2595,14 @@ impl<T: Tun, S: Sock> Device<T, S> {
     }
 
     fn clear_peers(&mut self) {
-        self.peers.clear();
+        for (pub_key, peer) in std::mem::take(&mut self.peers) {
+            self.subscribers.publish(DeviceEvent::PeerRemoved {
+                peer_id: peer.peer_id(),
+                public_key: Arc::clone(&pub_key),
+                metadata: peer.metadata(),
+            });
+            self.keep_resumable(pub_key, peer);
+        }
         self.peers_by_idx.clear();
         self.peers_by_ip.clear();
     }
@@ -529,6 +2622,51 @@ impl<T: Tun, S: Sock> Device<T, S> {
         Ok(())
     }
 
+    // With more than one listen socket, give every worker a poll of its own to serve its shards
+    // of the listen sockets on. The poll also waits on the shared poll, for everything else.
+    fn register_worker_queues(&mut self) -> Result<(), Error> {
+        if self.config.listen_sockets < 2 {
+            return Ok(());
+        }
+        for _ in 0..self.config.n_threads {
+            let queue =
+                EventPoll::<Handler<T, S>>::new_with_batch_size(self.config.event_batch_size)?;
+            let events = parking_lot::Mutex::new(self.queue.new_batch());
+            queue.new_event(
+                self.queue.as_raw_fd(),
+                Box::new(move |d, t| {
+                    // Hand out the events of the shared poll retrieved at once, then wait on both
+                    // polls again, so the shards of the worker are not held up
+                    let queue = Arc::clone(&d.queue);
+                    let mut events = events.lock();
+                    loop {
+                        match queue.poll(&mut events) {
+                            None => return Action::Continue,
+                            Some(WaitResult::Ok(handler)) => match (*handler)(d, t) {
+                                Action::Continue => {}
+                                action => {
+                                    // Events may be removed once the lock is released
+                                    queue.release_batch(&mut events);
+                                    return action;
+                                }
+                            },
+                            Some(WaitResult::EoF(handler)) => handler.cancel(),
+                            Some(WaitResult::Error(e)) => {
+                                error!(d.config.logger, "Poll error {:}", e);
+                                return Action::Continue;
+                            }
+                        }
+                        if events.is_empty() {
+                            return Action::Continue;
+                        }
+                    }
+                }),
+            )?;
+            self.worker_queues.push(Arc::new(queue));
+        }
+        Ok(())
+    }
+
     fn register_timers(&self) -> Result<(), Error> {
         self.queue.new_periodic_event(
             // Reset the rate limiter every second give or take
@@ -536,11 +2674,77 @@ impl<T: Tun, S: Sock> Device<T, S> {
                 if let Some(r) = d.rate_limiter.as_ref() {
                     r.reset_count()
                 }
+                if let Some(next) = d.next_key.as_ref() {
+                    next.rate_limiter.reset_count();
+                    if next.deadline <= Instant::now() {
+                        d.try_writeable(
+                            |device| device.trigger_yield(),
+                            |device| {
+                                device.cancel_yield();
+                                device.commit_next_private_key();
+                            },
+                        );
+                    }
+                }
+                if matches!(&d.draining, Some((until, _)) if *until <= Instant::now()) {
+                    d.try_writeable(
+                        |device| device.trigger_yield(),
+                        |device| {
+                            device.cancel_yield();
+                            device.close_draining();
+                        },
+                    );
+                }
+                let window = d.config.resumption_window;
+                if d.resumable
+                    .values()
+                    .any(|(removed, _)| removed.elapsed() >= window)
+                {
+                    d.try_writeable(
+                        |device| device.trigger_yield(),
+                        |device| {
+                            device.cancel_yield();
+                            device.expire_resumable();
+                        },
+                    );
+                }
                 Action::Continue
             }),
             std::time::Duration::from_secs(1),
         )?;
 
+        if !self.config.reresolve_interval.is_zero() {
+            self.queue.new_periodic_event(
+                Box::new(|d, _| {
+                    d.reresolve_endpoints();
+                    Action::Continue
+                }),
+                self.config.reresolve_interval,
+            )?;
+        }
+
+        if !self.config.liveness_probe_interval.is_zero() {
+            self.queue.new_periodic_event(
+                Box::new(|d, _| {
+                    d.send_liveness_probes();
+                    Action::Continue
+                }),
+                self.config.liveness_probe_interval,
+            )?;
+        }
+
+        if !self.config.stats_interval.is_zero() {
+            self.queue.new_periodic_event(
+                Box::new(|d, _| {
+                    if !d.subscribers.is_empty() {
+                        d.subscribers.publish(DeviceEvent::Stats(d.all_stats()));
+                    }
+                    Action::Continue
+                }),
+                self.config.stats_interval,
+            )?;
+        }
+
         self.queue.new_periodic_event(
             // Execute the timed function of every peer in the list
             Box::new(|d, t| {
@@ -553,22 +2757,82 @@ impl<T: Tun, S: Sock> Device<T, S> {
 
                 // Go over each peer and invoke the timer function
                 for peer in peer_map.values() {
-                    let endpoint_addr = match peer.endpoint().addr {
-                        Some(addr) => addr,
-                        None => continue,
+                    if !peer.is_enabled() {
+                        continue;
+                    }
+
+                    let (endpoint_addr, endpoint_sock) = match *peer.endpoint() {
+                        Endpoint {
+                            addr: Some(addr),
+                            ref sock,
+                            ..
+                        } => (addr, sock.clone()),
+                        _ => continue,
                     };
+                    let send_to = |packet: &[u8], addr: SocketAddr, sock: Option<&Arc<S>>| {
+                        peer.trace_sent(packet);
+                        match (sock, addr) {
+                            (Some(sock), _) => sock.sendto(packet, addr),
+                            (None, SocketAddr::V4(_)) => udp4.sendto(packet, addr),
+                            (None, SocketAddr::V6(_)) => udp6.sendto(packet, addr),
+                        };
+                    };
+                    let send =
+                        |packet: &[u8]| send_to(packet, endpoint_addr, endpoint_sock.as_ref());
+
+                    // A peer on its backup endpoint tries its primary now and then, and moves
+                    // back once the primary answers
+                    if let Some(primary) = peer.probe_primary(d.config.failover_probe_interval) {
+                        if let TunnResult::WriteToNetwork(packet) = peer
+                            .tunnel
+                            .format_handshake_initiation(&mut t.dst_buf[..], true)
+                        {
+                            send_to(packet, primary, None);
+                        }
+                    }
+
+                    if (d.prewarm || d.config.on_session_expiring.is_some())
+                        && peer
+                            .tunnel
+                            .is_session_expiring(d.config.session_expiry_lead)
+                    {
+                        if let Some(callback) = &d.config.on_session_expiring {
+                            callback(&peer.tunnel.peer_static_public());
+                        }
+                        if d.prewarm {
+                            if let TunnResult::WriteToNetwork(packet) = peer
+                                .tunnel
+                                .format_handshake_initiation(&mut t.dst_buf[..], false)
+                            {
+                                send(packet);
+                            }
+                        }
+                    }
 
                     match peer.update_timers(&mut t.dst_buf[..]) {
                         TunnResult::Done => {}
                         TunnResult::Err(WireGuardError::ConnectionExpired) => {
                             peer.shutdown_endpoint(); // close open udp socket
                         }
-                        TunnResult::Err(e) => error!(d.config.logger, "Timer error {:?}", e),
+                        TunnResult::Err(e) => {
+                            d.log_limiter
+                                .log(&d.config.logger, LogCategory::Timer, |logger| {
+                                    error!(logger, "Timer error {:?}", e)
+                                })
+                        }
                         TunnResult::WriteToNetwork(packet) => {
-                            match endpoint_addr {
-                                SocketAddr::V4(_) => udp4.sendto(packet, endpoint_addr),
-                                SocketAddr::V6(_) => udp6.sendto(packet, endpoint_addr),
+                            // A handshake retry goes to the backup endpoint once the endpoint
+                            // left too many unanswered
+                            let backup = match Tunn::is_handshake_init(
+                                Tunn::strip_handshake_padding(packet),
+                            ) {
+                                true => peer.fail_over(d.config.failover_attempts),
+                                false => None,
                             };
+                            match backup {
+                                Some(addr) => send_to(packet, addr, None),
+                                None => send(packet),
+                            }
                         }
                         _ => panic!("Unexpected result from update_timers"),
                     };
@@ -595,96 +2859,231 @@ impl<T: Tun, S: Sock> Device<T, S> {
             .stop_notification(self.yield_notice.as_ref().unwrap())
     }
 
-    fn register_udp_handler(&self, udp: Arc<S>) -> Result<(), Error> {
-        self.queue.new_event(
+    // Remove the event of a listen socket from the poll serving it. Only safe to call while the
+    // event loop is not running.
+    unsafe fn clear_listen_event(&self, sock: &S) {
+        for queue in std::iter::once(&self.queue).chain(&self.worker_queues) {
+            queue.clear_event_by_fd(sock.as_raw_fd());
+        }
+    }
+
+    fn register_udp_handler(
+        &self,
+        queue: &EventPoll<Handler<T, S>>,
+        udp: Arc<S>,
+    ) -> Result<(), Error> {
+        queue.new_event(
             udp.as_raw_fd(),
             Box::new(move |d, t| {
                 // Handler that handles anonymous packets over UDP
                 let mut iter = MAX_ITR;
-                let (private_key, public_key) = d.key_pair.as_ref().expect("Key not set");
-
-                let rate_limiter = d.rate_limiter.as_ref().unwrap();
 
                 // Loop while we have packets on the anonymous connection
-                while let Ok((addr, packet)) = udp.recvfrom(&mut t.src_buf[..]) {
-                    // The rate limiter initially checks mac1 and mac2, and optionally asks to send a cookie
-                    let parsed_packet =
-                        match rate_limiter.verify_packet(Some(addr.ip()), packet, &mut t.dst_buf) {
-                            Ok(packet) => packet,
-                            Err(TunnResult::WriteToNetwork(cookie)) => {
-                                udp.sendto(cookie, addr);
+                let with_ecn = d.config.ecn_passthrough;
+                let max_segments = d.config.gro_max_segments;
+                while let Ok((addr, buf, segment_size, outer_ecn)) =
+                    udp.recv_coalesced(&mut t.src_buf[..], with_ecn)
+                {
+                    for (n, chunk) in gro::chunks(buf, segment_size, max_segments).enumerate() {
+                        if let Some(iface) = t.iface.as_ref().filter(|_| n > 0) {
+                            // Do not hold back the packets of the previous chunk any longer
+                            t.gso.flush(|hdr, packet| {
+                                iface.write_offload(hdr, packet);
+                            });
+                        }
+                        iter = iter.saturating_sub(1);
+
+                        for packet in chunk {
+                            let packet = d.strip_handshake_padding(packet);
+                            if d.drop_unknown_index(packet)
+                                || !d.handshake_source_allowed(packet, addr.ip())
+                            {
                                 continue;
                             }
-                            Err(_) => continue,
-                        };
 
-                    let peer = match &parsed_packet {
-                        Packet::HandshakeInit(p) => {
-                            parse_handshake_anon(&private_key, &public_key, &p)
-                                .ok()
-                                .and_then(|hh| {
-                                    d.peers
-                                        .get(&X25519PublicKey::from(&hh.peer_static_public[..]))
-                                })
-                        }
-                        Packet::HandshakeResponse(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                        Packet::PacketCookieReply(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                        Packet::PacketData(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                    };
+                            let (private_key, public_key, rate_limiter) = d.keys_for(packet);
+
+                            // The rate limiter initially checks mac1 and mac2, and optionally asks to send a cookie
+                            let parsed_packet = match rate_limiter.verify_packet(
+                                Some(addr.ip()),
+                                packet,
+                                &mut t.dst_buf,
+                            ) {
+                                Ok(RateLimitResult::Allowed(packet)) => packet,
+                                Ok(RateLimitResult::CookieRequired(cookie)) => {
+                                    d.cookie_replies.fetch_add(1, Ordering::Relaxed);
+                                    udp.sendto(cookie, addr);
+                                    continue;
+                                }
+                                // Not without an address to send a cookie to
+                                Ok(RateLimitResult::RateLimited) => {
+                                    d.report_decrypt_failure(
+                                        Some(DecryptFailureReason::RateLimited),
+                                        addr,
+                                    );
+                                    continue;
+                                }
+                                Ok(RateLimitResult::InvalidMac) => {
+                                    d.invalid_mac.fetch_add(1, Ordering::Relaxed);
+                                    d.report_decrypt_failure(
+                                        Some(DecryptFailureReason::AuthFailed),
+                                        addr,
+                                    );
+                                    continue;
+                                }
+                                Err(e) => {
+                                    d.report_decrypt_failure(
+                                        DecryptFailureReason::from_error(&e),
+                                        addr,
+                                    );
+                                    continue;
+                                }
+                            };
 
-                    let peer = match peer {
-                        None => continue,
-                        Some(peer) => peer,
-                    };
+                            let peer = match &parsed_packet {
+                                Packet::HandshakeInit(p) => {
+                                    parse_handshake_anon(&private_key, &public_key, &p)
+                                        .ok()
+                                        .and_then(|hh| {
+                                            d.peers.get(&X25519PublicKey::from(
+                                                &hh.peer_static_public[..],
+                                            ))
+                                        })
+                                }
+                                Packet::HandshakeResponse(p) => {
+                                    d.peers_by_idx.get(&(p.receiver_idx >> 8))
+                                }
+                                Packet::PacketCookieReply(p) => {
+                                    d.peers_by_idx.get(&(p.receiver_idx >> 8))
+                                }
+                                Packet::PacketData(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
+                            };
 
-                    // We found a peer, use it to decapsulate the message+
-                    let mut flush = false; // Are there packets to send from the queue?
-                    match peer
-                        .tunnel
-                        .handle_verified_packet(parsed_packet, &mut t.dst_buf[..])
-                    {
-                        TunnResult::Done => {}
-                        TunnResult::Err(_) => continue,
-                        TunnResult::WriteToNetwork(packet) => {
-                            flush = true;
-                            udp.sendto(packet, addr);
-                        }
-                        TunnResult::WriteToTunnelV4(packet, addr) => {
-                            if peer.is_allowed_ip(addr) {
-                                t.iface.write4(packet);
-                            }
-                        }
-                        TunnResult::WriteToTunnelV6(packet, addr) => {
-                            if peer.is_allowed_ip(addr) {
-                                t.iface.write6(packet);
-                            }
-                        }
-                    };
+                            let peer = match peer {
+                                Some(peer) if !peer.is_enabled() => continue,
+                                None => {
+                                    // Initiations for unknown keys are not decryption failures
+                                    if !matches!(parsed_packet, Packet::HandshakeInit(_)) {
+                                        d.report_decrypt_failure(
+                                            Some(DecryptFailureReason::UnknownReceiverIndex),
+                                            addr,
+                                        );
+                                    }
+                                    continue;
+                                }
+                                Some(peer) => peer,
+                            };
 
-                    if flush {
-                        // Flush pending queue
-                        while let TunnResult::WriteToNetwork(packet) =
-                            peer.tunnel.decapsulate(None, &[], &mut t.dst_buf[..])
-                        {
-                            udp.sendto(packet, addr);
-                        }
-                    }
+                            // We found a peer, use it to decapsulate the message+
+                            let mut flush = false; // Are there packets to send from the queue?
+                            let result = peer
+                                .tunnel
+                                .handle_verified_packet(parsed_packet, &mut t.dst_buf[..]);
+                            peer.trace_rx(packet, &result);
+                            match result {
+                                TunnResult::Done => {}
+                                TunnResult::Err(e) => {
+                                    d.report_decrypt_failure(
+                                        DecryptFailureReason::from_error(&e),
+                                        addr,
+                                    );
+                                    continue;
+                                }
+                                TunnResult::WriteToNetwork(packet) => {
+                                    flush = true;
+                                    udp.sendto(packet, addr);
+                                }
+                                TunnResult::WriteToTunnelV4(packet, addr) => {
+                                    if peer.is_allowed_ip(addr)
+                                        && !peer.liveness().take_reply(packet)
+                                        && d.accept_inner(packet)
+                                        && ecn::decapsulate(packet, outer_ecn)
+                                    {
+                                        if d.detailed_accounting {
+                                            peer.account_rx(packet);
+                                        }
+                                        d.write_inner(
+                                            t.iface.as_deref(),
+                                            &mut t.gso,
+                                            packet,
+                                            false,
+                                        );
+                                        d.mirror_inner(packet, false);
+                                    }
+                                }
+                                TunnResult::WriteToTunnelV6(packet, addr) => {
+                                    if peer.is_allowed_ip(addr)
+                                        && !peer.liveness().take_reply(packet)
+                                        && d.accept_inner(packet)
+                                        && ecn::decapsulate(packet, outer_ecn)
+                                    {
+                                        if d.detailed_accounting {
+                                            peer.account_rx(packet);
+                                        }
+                                        d.write_inner(t.iface.as_deref(), &mut t.gso, packet, true);
+                                        d.mirror_inner(packet, true);
+                                    }
+                                }
+                            };
 
-                    // This packet was OK, that means we want to create a connected socket for this peer
-                    let ip_addr = addr.ip();
-                    peer.set_endpoint(addr);
-                    if d.config.use_connected_socket {
-                        if let Ok(sock) = peer.connect_endpoint(d.listen_port, d.fwmark) {
-                            d.register_conn_handler(Arc::clone(peer), sock, ip_addr)
-                                .unwrap();
+                            if flush || peer.tunnel.wants_queue_flush() {
+                                // Flush pending queue
+                                while let TunnResult::WriteToNetwork(packet) =
+                                    peer.tunnel.decapsulate(None, &[], &mut t.dst_buf[..])
+                                {
+                                    peer.trace_sent(packet);
+                                    udp.sendto(packet, addr);
+                                }
+                            }
+
+                            // This packet was OK, that means we want to create a connected socket for this peer
+                            let ip_addr = addr.ip();
+                            let changed = peer.set_endpoint_from(addr, &udp);
+                            d.send_held(peer, &mut t.dst_buf[..]);
+                            d.publish_rx_events(peer, Some(addr).filter(|_| changed));
+                            if d.config.use_connected_socket && S::can_connect() {
+                                if let Ok(sock) = peer.connect_endpoint(
+                                    &d.config,
+                                    d.listen_port,
+                                    d.fwmark,
+                                    d.freebind,
+                                ) {
+                                    if with_ecn {
+                                        let _ = sock.set_recv_ecn();
+                                    }
+                                    if let Some(rate) = d.pacing_rate {
+                                        let _ = sock.set_pacing_rate(rate);
+                                    }
+                                    if let Some(df) = d.dont_fragment {
+                                        let _ = sock.set_dont_fragment(df);
+                                    }
+                                    if let Some(prio) = d.priority {
+                                        let _ = sock.set_priority(prio);
+                                    }
+                                    if let Some(ttl) = d.ttl {
+                                        let _ = sock.set_ttl(ttl);
+                                    }
+                                    #[cfg(target_os = "linux")]
+                                    if d.config.use_bpf_filter {
+                                        let _ =
+                                            sock.attach_bpf_filter(&bpf::BpfProgram::wireguard());
+                                    }
+                                    d.register_conn_handler(Arc::clone(peer), sock, ip_addr)
+                                        .unwrap();
+                                }
+                            }
                         }
                     }
 
-                    iter -= 1;
                     if iter == 0 {
                         break;
                     }
                 }
+                if let Some(iface) = &t.iface {
+                    t.gso.flush(|hdr, packet| {
+                        iface.write_offload(hdr, packet);
+                    });
+                }
                 Action::Continue
             }),
         )?;
@@ -699,51 +3098,99 @@ impl<T: Tun, S: Sock> Device<T, S> {
     ) -> Result<(), Error> {
         self.queue.new_event(
             udp.as_raw_fd(),
-            Box::new(move |_, t| {
+            Box::new(move |d, t| {
                 // The conn_handler handles packet received from a connected UDP socket, associated
                 // with a known peer, this saves us the hustle of finding the right peer. If another
                 // peer gets the same ip, it will be ignored until the socket does not expire.
-                let iface = &t.iface;
+                let iface = t.iface.as_deref();
                 let mut iter = MAX_ITR;
 
-                while let Ok(src) = udp.read(&mut t.src_buf[..]) {
+                let with_ecn = d.config.ecn_passthrough;
+                while let Ok((src, outer_ecn)) = read_datagram(&*udp, &mut t.src_buf[..], with_ecn)
+                {
+                    let src = d.strip_handshake_padding(src);
+                    if d.drop_unknown_index(src)
+                        || !d.handshake_source_allowed(src, peer_addr)
+                        || !peer.is_enabled()
+                    {
+                        continue;
+                    }
+
                     let mut flush = false;
-                    match peer
+                    let result = peer
                         .tunnel
-                        .decapsulate(Some(peer_addr), src, &mut t.dst_buf[..])
-                    {
+                        .decapsulate(Some(peer_addr), src, &mut t.dst_buf[..]);
+                    if !matches!(result, TunnResult::Err(_)) {
+                        peer.connected();
+                    }
+                    peer.trace_rx(src, &result);
+                    match result {
                         TunnResult::Done => {}
-                        TunnResult::Err(e) => eprintln!("Decapsulate error {:?}", e),
+                        TunnResult::Err(e) => {
+                            d.log_limiter
+                                .log(&d.config.logger, LogCategory::Decapsulate, |logger| {
+                                    error!(logger, "Decapsulate error {:?}", e; "peer_id" => peer.peer_id())
+                                });
+                            if let Some(addr) = peer.endpoint().addr {
+                                d.report_decrypt_failure(
+                                    DecryptFailureReason::from_error(&e),
+                                    addr,
+                                );
+                            }
+                        }
                         TunnResult::WriteToNetwork(packet) => {
                             flush = true;
                             udp.write(packet);
                         }
                         TunnResult::WriteToTunnelV4(packet, addr) => {
-                            if peer.is_allowed_ip(addr) {
-                                iface.write4(packet);
+                            if peer.is_allowed_ip(addr)
+                                && !peer.liveness().take_reply(packet)
+                                && d.accept_inner(packet)
+                                && ecn::decapsulate(packet, outer_ecn)
+                            {
+                                if d.detailed_accounting {
+                                    peer.account_rx(packet);
+                                }
+                                d.write_inner(iface, &mut t.gso, packet, false);
+                                d.mirror_inner(packet, false);
                             }
                         }
                         TunnResult::WriteToTunnelV6(packet, addr) => {
-                            if peer.is_allowed_ip(addr) {
-                                iface.write6(packet);
+                            if peer.is_allowed_ip(addr)
+                                && !peer.liveness().take_reply(packet)
+                                && d.accept_inner(packet)
+                                && ecn::decapsulate(packet, outer_ecn)
+                            {
+                                if d.detailed_accounting {
+                                    peer.account_rx(packet);
+                                }
+                                d.write_inner(iface, &mut t.gso, packet, true);
+                                d.mirror_inner(packet, true);
                             }
                         }
                     };
 
-                    if flush {
+                    if flush || peer.tunnel.wants_queue_flush() {
                         // Flush pending queue
                         while let TunnResult::WriteToNetwork(packet) =
                             peer.tunnel.decapsulate(None, &[], &mut t.dst_buf[..])
                         {
+                            peer.trace_sent(packet);
                             udp.write(packet);
                         }
                     }
+                    d.publish_rx_events(&peer, None);
 
                     iter -= 1;
                     if iter == 0 {
                         break;
                     }
                 }
+                if let Some(iface) = iface {
+                    t.gso.flush(|hdr, packet| {
+                        iface.write_offload(hdr, packet);
+                    });
+                }
                 Action::Continue
             }),
         )?;
@@ -751,6 +3198,7 @@ impl<T: Tun, S: Sock> Device<T, S> {
     }
 
     fn register_iface_handler(&self, iface: Arc<T>) -> Result<(), Error> {
+        self.tun_fds.lock().push(iface.as_raw_fd());
         self.queue.new_event(
             iface.as_raw_fd(),
             Box::new(move |d, t| {
@@ -766,52 +3214,147 @@ impl<T: Tun, S: Sock> Device<T, S> {
                 let udp6 = d.udp6.as_ref().expect("Not connected");
 
                 let peers = &d.peers_by_ip;
-                for _ in 0..MAX_ITR {
-                    let src = match iface.read(&mut t.src_buf[..mtu]) {
-                        Ok(src) => src,
-                        Err(Error::IfaceRead(errno)) => {
-                            let ek = io::Error::from_raw_os_error(errno).kind();
-                            if ek == io::ErrorKind::Interrupted || ek == io::ErrorKind::WouldBlock {
-                                break;
+                let linger = d.config.tx_batch_linger;
+                let read_ahead = d.config.tun_read_buffers;
+                // Packets read ahead go out in batches, as those held for a linger do
+                let batching = !linger.is_zero() || read_ahead > 1;
+                if t.tun_bufs.len() < read_ahead {
+                    t.tun_bufs.resize_with(read_ahead, || vec![0u8; MAX_UDP_SIZE]);
+                }
+                let mut batch_sock = None; // The socket the packets in t.tx_batch go out of
+                let mut iter = MAX_ITR;
+                'rounds: while iter > 0 {
+                    // Read ahead as many packets as we have buffers, then encapsulate them in order
+                    let mut lens = [0usize; MAX_TUN_READ_BUFFERS];
+                    let mut n_read = 0;
+                    while n_read < read_ahead.min(iter) {
+                        match iface.read(&mut t.tun_bufs[n_read][..mtu]) {
+                            Ok(src) => {
+                                lens[n_read] = src.len();
+                                n_read += 1;
                             }
-                            eprintln!("Fatal read error on tun interface: errno {:?}", errno);
-                            return Action::Exit;
-                        }
-                        Err(e) => {
-                            eprintln!("Unexpected error on tun interface: {:?}", e);
-                            return Action::Exit;
+                            Err(Error::IfaceRead(errno)) => {
+                                let ek = io::Error::from_raw_os_error(errno).kind();
+                                if ek == io::ErrorKind::Interrupted
+                                    || ek == io::ErrorKind::WouldBlock
+                                {
+                                    if n_read > 0 {
+                                        break;
+                                    }
+                                    // A batch waits for more packets, for the rest of its linger
+                                    if t.tx_batch.is_empty()
+                                        || !tx_batch::wait_readable(
+                                            iface.as_raw_fd(),
+                                            t.tx_batch.linger_left(linger),
+                                        )
+                                    {
+                                        break 'rounds;
+                                    }
+                                    continue;
+                                }
+                                eprintln!("Fatal read error on tun interface: errno {:?}", errno);
+                                return Action::Exit;
+                            }
+                            Err(e) => {
+                                eprintln!("Unexpected error on tun interface: {:?}", e);
+                                return Action::Exit;
+                            }
+                        };
+                    }
+                    iter -= n_read;
+
+                    for (buf, &len) in t.tun_bufs[..n_read].iter().zip(&lens[..n_read]) {
+                        let src = &buf[..len];
+
+                        let dst_addr = match Tunn::dst_address(src) {
+                            Some(addr) => addr,
+                            None => continue,
+                        };
+
+                        let peer = match peers.find(dst_addr) {
+                            Some(peer) if peer.is_enabled() => peer,
+                            _ => continue,
+                        };
+
+                        if !peer.has_endpoint() {
+                            peer.hold_for_endpoint(src, d.config.no_endpoint_buffer);
+                            continue;
                         }
-                    };
 
-                    let dst_addr = match Tunn::dst_address(src) {
-                        Some(addr) => addr,
-                        None => continue,
-                    };
+                        if d.detailed_accounting {
+                            peer.account_tx(src);
+                        }
 
-                    let peer = match peers.find(dst_addr) {
-                        Some(peer) => peer,
-                        None => continue,
-                    };
+                        let ecn = if d.config.ecn_passthrough {
+                            ecn::inner_ecn(src)
+                        } else {
+                            ecn::ECN_NOT_ECT
+                        };
 
-                    match peer.tunnel.encapsulate(src, &mut t.dst_buf[..]) {
-                        TunnResult::Done => {}
-                        TunnResult::Err(e) => error!(d.config.logger, "Encapsulate error {:?}", e),
-                        TunnResult::WriteToNetwork(packet) => {
-                            let endpoint = peer.endpoint();
-                            if let Some(ref conn) = endpoint.conn {
-                                // Prefer to send using the connected socket
-                                conn.write(packet);
-                            } else if let Some(addr @ SocketAddr::V4(_)) = endpoint.addr {
-                                udp4.sendto(packet, addr);
-                            } else if let Some(addr @ SocketAddr::V6(_)) = endpoint.addr {
-                                udp6.sendto(packet, addr);
-                            } else {
-                                error!(d.config.logger, "No endpoint");
+                        let result = if !batching {
+                            peer.tunnel.encapsulate(src, &mut t.dst_buf[..])
+                        } else {
+                            peer.tunnel.encapsulate(src, t.tx_batch.spare())
+                        };
+                        peer.trace_tx(src, &result);
+                        match result {
+                            TunnResult::Done => {}
+                            TunnResult::Err(e) => d.log_limiter.log(
+                                &d.config.logger,
+                                LogCategory::Encapsulate,
+                                |logger| error!(logger, "Encapsulate error {:?}", e),
+                            ),
+                            TunnResult::WriteToNetwork(packet) if batching => {
+                                let len = packet.len();
+                                match batch_route(peer, udp4, udp6, packet, src, ecn) {
+                                    Some((sock, dst)) => {
+                                        if !matches!(&batch_sock, Some((queued, _)) if Arc::ptr_eq(queued, &sock))
+                                        {
+                                            d.send_tx_batch(&mut t.tx_batch, batch_sock.take());
+                                            let conn_peer = if dst.is_none() { Some(&**peer) } else { None };
+                                            batch_sock = Some((sock, conn_peer));
+                                        }
+                                        if t.tx_batch.push(len, dst) {
+                                            d.send_tx_batch(&mut t.tx_batch, batch_sock.take());
+                                        }
+                                    }
+                                    None => {
+                                        // Sent at once, after the packets batched before it
+                                        d.send_tx_batch(&mut t.tx_batch, batch_sock.take());
+                                        let packet = t.tx_batch.staged(len);
+                                        if send_to_endpoint(peer, udp4, udp6, packet, Some(src), ecn)
+                                            .is_err()
+                                        {
+                                            d.log_limiter.log(
+                                                &d.config.logger,
+                                                LogCategory::NoEndpoint,
+                                                |logger| error!(logger, "No endpoint"; "peer_id" => peer.peer_id()),
+                                            );
+                                        }
+                                    }
+                                }
                             }
-                        }
-                        _ => panic!("Unexpected result from encapsulate"),
-                    };
+                            TunnResult::WriteToNetwork(packet) => {
+                                if send_to_endpoint(peer, udp4, udp6, packet, Some(src), ecn)
+                                    .is_err()
+                                {
+                                    d.log_limiter.log(
+                                        &d.config.logger,
+                                        LogCategory::NoEndpoint,
+                                        |logger| error!(logger, "No endpoint"; "peer_id" => peer.peer_id()),
+                                    );
+                                }
+                            }
+                            _ => panic!("Unexpected result from encapsulate"),
+                        };
+                    }
+
+                    // Without a linger nothing waits past the round it was read in
+                    if linger.is_zero() {
+                        d.send_tx_batch(&mut t.tx_batch, batch_sock.take());
+                    }
                 }
+                d.send_tx_batch(&mut t.tx_batch, batch_sock.take());
                 Action::Continue
             }),
         )?;
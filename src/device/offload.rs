@@ -0,0 +1,377 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Coalescing of decapsulated TCP segments into a single TUN write, for interfaces opened
+//! with a virtio-net header (IFF_VNET_HDR). The kernel segments the coalesced packet again.
+
+pub const VIRTIO_NET_HDR_LEN: usize = 10;
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+pub const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+pub const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+
+const MAX_COALESCED_SIZE: usize = (1 << 16) - 1;
+
+const IPV4_MIN_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const TCP_MIN_HEADER_SIZE: usize = 20;
+const IPPROTO_TCP: u8 = 6;
+const TCP_CSUM_OFFSET: usize = 16;
+
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+/// The header that prefixes every packet on a TUN interface with IFF_VNET_HDR set
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    /// The header in the native byte order, as expected by the TUN driver
+    pub fn to_bytes(&self) -> [u8; VIRTIO_NET_HDR_LEN] {
+        let mut hdr = [0u8; VIRTIO_NET_HDR_LEN];
+        hdr[0] = self.flags;
+        hdr[1] = self.gso_type;
+        hdr[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        hdr[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        hdr[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        hdr[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        hdr
+    }
+}
+
+// The layout of a TCP segment that is eligible for coalescing
+#[derive(Debug, Clone, Copy)]
+struct TcpSegment {
+    is_v6: bool,
+    ip_len: usize,  // Length of the IP header
+    hdr_len: usize, // Length of the IP and TCP headers
+    seq: u32,
+    flags: u8,
+    payload_len: usize,
+}
+
+fn parse_tcp_segment(packet: &[u8]) -> Option<TcpSegment> {
+    let (is_v6, ip_len) = match packet.first()? >> 4 {
+        4 => {
+            if packet.len() < IPV4_MIN_HEADER_SIZE {
+                return None;
+            }
+            let ip_len = usize::from(packet[0] & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+            let frag = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff; // MF and offset
+            if ip_len != IPV4_MIN_HEADER_SIZE
+                || total_len != packet.len()
+                || frag != 0
+                || packet[9] != IPPROTO_TCP
+            {
+                return None;
+            }
+            (false, ip_len)
+        }
+        6 => {
+            if packet.len() < IPV6_HEADER_SIZE {
+                return None;
+            }
+            let payload_len = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+            if payload_len + IPV6_HEADER_SIZE != packet.len() || packet[6] != IPPROTO_TCP {
+                return None;
+            }
+            (true, IPV6_HEADER_SIZE)
+        }
+        _ => return None,
+    };
+
+    let tcp = &packet[ip_len..];
+    if tcp.len() < TCP_MIN_HEADER_SIZE {
+        return None;
+    }
+    let tcp_len = usize::from(tcp[12] >> 4) * 4;
+    if tcp_len < TCP_MIN_HEADER_SIZE || tcp_len > tcp.len() {
+        return None;
+    }
+
+    Some(TcpSegment {
+        is_v6,
+        ip_len,
+        hdr_len: ip_len + tcp_len,
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        flags: tcp[13],
+        payload_len: tcp.len() - tcp_len,
+    })
+}
+
+// Ones' complement sum of 16 bit words, not folded
+pub(super) fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+pub(super) fn checksum_fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Accumulates consecutive segments of a single TCP flow, so they can be written to the TUN
+/// interface as one packet with a GSO virtio-net header
+pub struct GsoBatch {
+    buf: Vec<u8>, // The headers of the first segment, followed by the payloads of all segments
+    first: Option<TcpSegment>,
+    n_segments: usize,
+    next_seq: u32,
+    closed: bool, // The last segment was short or pushed, no more segments may follow
+}
+
+impl Default for GsoBatch {
+    fn default() -> Self {
+        GsoBatch {
+            buf: Vec::with_capacity(MAX_COALESCED_SIZE),
+            first: None,
+            n_segments: 0,
+            next_seq: 0,
+            closed: false,
+        }
+    }
+}
+
+impl GsoBatch {
+    pub fn new() -> GsoBatch {
+        Default::default()
+    }
+
+    /// Add a decapsulated packet to the batch. If the packet does not continue the current
+    /// batch, the batch is written out first. Packets that can't be coalesced at all are
+    /// written out immediately.
+    pub fn push<F: FnMut(&VirtioNetHdr, &[u8])>(&mut self, packet: &[u8], mut write: F) {
+        let seg = match parse_tcp_segment(packet) {
+            Some(seg) if seg.payload_len > 0 && seg.flags & !(TCP_FLAG_ACK | TCP_FLAG_PSH) == 0 => {
+                seg
+            }
+            _ => {
+                self.flush(&mut write);
+                write(&VirtioNetHdr::default(), packet);
+                return;
+            }
+        };
+
+        if !self.can_append(&seg, packet) {
+            self.flush(&mut write);
+            self.buf.extend_from_slice(packet);
+            self.first = Some(seg);
+            self.n_segments = 1;
+        } else {
+            self.buf.extend_from_slice(&packet[seg.hdr_len..]);
+            self.n_segments += 1;
+            if seg.flags & TCP_FLAG_PSH != 0 {
+                let first = self.first.as_ref().unwrap();
+                self.buf[first.ip_len + 13] |= TCP_FLAG_PSH;
+            }
+        }
+
+        let gso_size = self.first.as_ref().unwrap().payload_len;
+        self.next_seq = seg.seq.wrapping_add(seg.payload_len as u32);
+        self.closed = seg.flags & TCP_FLAG_PSH != 0 || seg.payload_len < gso_size;
+    }
+
+    /// Write out the current batch, if any
+    pub fn flush<F: FnMut(&VirtioNetHdr, &[u8])>(&mut self, mut write: F) {
+        let first = match self.first.take() {
+            Some(first) => first,
+            None => return,
+        };
+
+        if self.n_segments == 1 {
+            write(&VirtioNetHdr::default(), &self.buf);
+        } else {
+            let hdr = self.finalize(&first);
+            write(&hdr, &self.buf);
+        }
+
+        self.buf.clear();
+        self.n_segments = 0;
+        self.closed = false;
+    }
+
+    fn can_append(&self, seg: &TcpSegment, packet: &[u8]) -> bool {
+        let first = match &self.first {
+            Some(first) if !self.closed => first,
+            _ => return false,
+        };
+
+        if seg.is_v6 != first.is_v6
+            || seg.hdr_len != first.hdr_len
+            || seg.seq != self.next_seq
+            || seg.payload_len > first.payload_len
+            || self.buf.len() + seg.payload_len > MAX_COALESCED_SIZE
+        {
+            return false;
+        }
+
+        let head = &self.buf[..first.hdr_len];
+        let ip_len = first.ip_len;
+        if seg.is_v6 {
+            // Traffic class, flow label, next header, hop limit and addresses must match
+            if head[..4] != packet[..4] || head[6..IPV6_HEADER_SIZE] != packet[6..ip_len] {
+                return false;
+            }
+        } else if head[1] != packet[1] // TOS
+            || head[8..10] != packet[8..10] // TTL and protocol
+            || head[12..20] != packet[12..20]
+        // Addresses
+        {
+            return false;
+        }
+
+        // Ports, ack number, window and options must match, only the sequence number and flags differ
+        let (a, b) = (&head[ip_len..], &packet[ip_len..first.hdr_len]);
+        a[..4] == b[..4] && a[8..13] == b[8..13] && a[14..16] == b[14..16] && a[18..] == b[18..]
+    }
+
+    // Fix up the lengths and checksums of the coalesced packet and produce its header
+    fn finalize(&mut self, first: &TcpSegment) -> VirtioNetHdr {
+        let total_len = self.buf.len();
+        let ip_len = first.ip_len;
+        let tcp_len = total_len - ip_len;
+
+        let (gso_type, addrs) = if first.is_v6 {
+            let payload_len = (total_len - IPV6_HEADER_SIZE) as u16;
+            self.buf[4..6].copy_from_slice(&payload_len.to_be_bytes());
+            (VIRTIO_NET_HDR_GSO_TCPV6, 8..40)
+        } else {
+            self.buf[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+            self.buf[10..12].copy_from_slice(&[0, 0]);
+            let csum = !checksum_fold(checksum_add(0, &self.buf[..ip_len]));
+            self.buf[10..12].copy_from_slice(&csum.to_be_bytes());
+            (VIRTIO_NET_HDR_GSO_TCPV4, 12..20)
+        };
+
+        // With NEEDS_CSUM the checksum field holds the pseudo header sum, the rest is offloaded
+        let pseudo = checksum_add(u32::from(IPPROTO_TCP) + tcp_len as u32, &self.buf[addrs]);
+        let csum_at = ip_len + TCP_CSUM_OFFSET;
+        self.buf[csum_at..csum_at + 2].copy_from_slice(&checksum_fold(pseudo).to_be_bytes());
+
+        VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type,
+            hdr_len: first.hdr_len as u16,
+            gso_size: first.payload_len as u16,
+            csum_start: ip_len as u16,
+            csum_offset: TCP_CSUM_OFFSET as u16,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp4_segment(seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let total_len = (IPV4_MIN_HEADER_SIZE + TCP_MIN_HEADER_SIZE + payload.len()) as u16;
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, IPPROTO_TCP, 0, 0];
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&[0x1f, 0x90, 0xc3, 0x50]); // Ports
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 1, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn collect(batch: &mut GsoBatch, packets: &[Vec<u8>]) -> Vec<(VirtioNetHdr, Vec<u8>)> {
+        let mut writes = vec![];
+        for p in packets {
+            batch.push(p, |hdr, p| writes.push((*hdr, p.to_vec())));
+        }
+        batch.flush(|hdr, p| writes.push((*hdr, p.to_vec())));
+        writes
+    }
+
+    #[test]
+    fn test_coalesce_tcp4() {
+        let mut batch = GsoBatch::new();
+        let packets = vec![
+            tcp4_segment(1000, TCP_FLAG_ACK, &[1; 100]),
+            tcp4_segment(1100, TCP_FLAG_ACK, &[2; 100]),
+            tcp4_segment(1200, TCP_FLAG_ACK | TCP_FLAG_PSH, &[3; 50]),
+        ];
+
+        let writes = collect(&mut batch, &packets);
+        assert_eq!(writes.len(), 1);
+
+        let (hdr, packet) = &writes[0];
+        assert_eq!(
+            *hdr,
+            VirtioNetHdr {
+                flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+                gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+                hdr_len: 40,
+                gso_size: 100,
+                csum_start: 20,
+                csum_offset: 16,
+            }
+        );
+
+        assert_eq!(packet.len(), 40 + 250);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), 290);
+        assert_eq!(checksum_fold(checksum_add(0, &packet[..20])), 0xffff);
+        assert_eq!(packet[33], TCP_FLAG_ACK | TCP_FLAG_PSH);
+        assert_eq!(&packet[40..140], &[1; 100][..]);
+        assert_eq!(&packet[240..], &[3; 50][..]);
+
+        let pseudo = checksum_add(u32::from(IPPROTO_TCP) + 270, &packet[12..20]);
+        assert_eq!(
+            u16::from_be_bytes([packet[36], packet[37]]),
+            checksum_fold(pseudo)
+        );
+    }
+
+    #[test]
+    fn test_no_coalesce_across_flows() {
+        let mut batch = GsoBatch::new();
+        let mut other = tcp4_segment(1100, TCP_FLAG_ACK, &[2; 100]);
+        other[19] = 3; // Different destination
+
+        let packets = vec![
+            tcp4_segment(1000, TCP_FLAG_ACK, &[1; 100]),
+            other,
+            tcp4_segment(5000, TCP_FLAG_ACK, &[1; 100]), // Sequence gap
+        ];
+
+        let writes = collect(&mut batch, &packets);
+        assert_eq!(writes.len(), 3);
+        for ((hdr, written), original) in writes.iter().zip(packets.iter()) {
+            assert_eq!(*hdr, VirtioNetHdr::default());
+            assert_eq!(written, original);
+        }
+    }
+
+    #[test]
+    fn test_no_coalesce_after_short_segment() {
+        let mut batch = GsoBatch::new();
+        let packets = vec![
+            tcp4_segment(1000, TCP_FLAG_ACK, &[1; 100]),
+            tcp4_segment(1100, TCP_FLAG_ACK, &[2; 60]),
+            tcp4_segment(1160, TCP_FLAG_ACK, &[3; 100]),
+        ];
+
+        let writes = collect(&mut batch, &packets);
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0].0.gso_size, 100);
+        assert_eq!(writes[0].1.len(), 40 + 160);
+        assert_eq!(writes[1].0, VirtioNetHdr::default());
+    }
+}
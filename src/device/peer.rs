@@ -1,27 +1,106 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+use crate::device::accounting::{ProtocolCounters, ProtocolStats};
+use crate::device::backoff::Backoff;
+use crate::device::ecmp::{flow_hash, WeightedEndpoints};
+use crate::device::liveness::{self, Liveness};
+use crate::device::resolve::{self, Resolver};
+use crate::device::trace::{TraceDirection, TraceEvent, TraceKind, TraceOutcome, TraceRing};
 use crate::device::*;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use slog::warn;
+use std::collections::VecDeque;
 use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::time::{Duration, Instant};
+
+// The outer headers of a data message, besides those of the IP version
+const UDP_HEADER_SIZE: usize = 8;
+const DATA_OVERHEAD_SIZE: usize = 32;
+
+/// The MTU of the path to endpoints, unless configured otherwise
+pub const DEFAULT_LINK_MTU: usize = 1500;
+
+/// The longest metadata of a peer, in bytes
+pub const MAX_METADATA_LEN: usize = 256;
+
+/// Metadata fits in a UAPI line and a log line: it is at most `MAX_METADATA_LEN` bytes without
+/// control characters
+pub fn is_valid_metadata(metadata: &str) -> bool {
+    metadata.len() <= MAX_METADATA_LEN && !metadata.chars().any(char::is_control)
+}
+
+// The opaque label of a peer, shared with the logger of its tunnel so log lines carry it from the
+// moment it is set
+#[derive(Clone, Default)]
+struct Metadata(Arc<RwLock<Option<String>>>);
+
+// The lock is never held across anything that can panic
+impl std::panic::RefUnwindSafe for Metadata {}
+
+impl slog::KV for Metadata {
+    fn serialize(&self, _: &slog::Record, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        match &*self.0.read() {
+            Some(metadata) => serializer.emit_str("metadata", metadata),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The largest inner packet that fits a single datagram to the endpoint over a link with the
+/// given MTU. IPv6 endpoints have 20 bytes less room than IPv4 endpoints, for the larger header.
+pub fn payload_budget(link_mtu: usize, endpoint: SocketAddr) -> usize {
+    let ip_header_size = match endpoint {
+        SocketAddr::V4(_) => 20,
+        // A mapped address is reached over IPv4 by a dual stack socket
+        SocketAddr::V6(addr) if addr.ip().to_ipv4_mapped().is_some() => 20,
+        SocketAddr::V6(_) => 40,
+    };
+    link_mtu.saturating_sub(ip_header_size + UDP_HEADER_SIZE + DATA_OVERHEAD_SIZE)
+}
 
 #[derive(Default, Debug)]
 pub struct Endpoint<S: Sock> {
     pub addr: Option<SocketAddr>,
     pub conn: Option<Arc<S>>,
+    pub sock: Option<Arc<S>>, // The listen socket the last packet from addr arrived on
+}
+
+// The standby endpoint handshakes move to once the endpoint of a peer stops answering
+struct Backup {
+    addr: SocketAddr,
+    primary: Option<SocketAddr>, // The endpoint failed over from, while the backup is active
+    probed: Instant,             // When the primary was last sent a handshake probe
 }
 
 pub struct Peer<S: Sock> {
     pub(crate) tunnel: Box<Tunn>, // The associated tunnel struct
     index: u32,                   // The index the tunnel uses
+    peer_id: u64,                 // Never reused by another peer of the same device
     endpoint: RwLock<Endpoint<S>>,
+    ecmp: RwLock<WeightedEndpoints>, // Data packets are spread over these, when there are any
+    backup: Mutex<Option<Backup>>,
     allowed_ips: AllowedIps<()>,
     preshared_key: Option<[u8; 32]>,
+    route_metric: u32, // Peers with a lower metric win lookups of allowed IPs shared with others
+    bind_addr: Option<IpAddr>, // The local address the connected socket is bound to
+    link_mtu: usize,   // The MTU of the path to the endpoint
+    enabled: AtomicBool, // A disabled peer keeps its configuration, but passes no traffic
+    reconnect: Mutex<Backoff>, // Delays attempts to connect the endpoint after failures
+    rx_protocols: ProtocolCounters, // Only counted with detailed accounting
+    tx_protocols: ProtocolCounters,
+    trace: RwLock<Option<Arc<TraceRing>>>, // The last packets of the peer, when tracing is enabled
+    held: Mutex<VecDeque<Vec<u8>>>,        // Inner packets waiting for the endpoint to be learned
+    no_endpoint_drops: AtomicU64,
+    metadata: Metadata,
+    endpoint_host: Mutex<Option<(String, Option<SocketAddr>)>>, // The name and what it resolved to
+    liveness: Liveness,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct AllowedIP {
     pub addr: IpAddr,
     pub cidr: u8,
@@ -46,22 +125,164 @@ impl FromStr for AllowedIP {
 }
 
 impl<S: Sock> Peer<S> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        tunnel: Box<Tunn>,
+        mut tunnel: Box<Tunn>,
         index: u32,
+        peer_id: u64,
         endpoint: Option<SocketAddr>,
         allowed_ips: &[AllowedIP],
         preshared_key: Option<[u8; 32]>,
+        route_metric: u32,
+        reconnect: Backoff,
     ) -> Peer<S> {
+        let metadata = Metadata::default();
+        tunnel.logger = tunnel.logger.new(slog::OwnedKV(metadata.clone()));
         Peer {
             tunnel,
             index,
+            peer_id,
             endpoint: RwLock::new(Endpoint {
                 addr: endpoint,
                 conn: None,
+                sock: None,
             }),
+            ecmp: Default::default(),
+            backup: Default::default(),
             allowed_ips: allowed_ips.iter().collect(),
             preshared_key,
+            route_metric,
+            bind_addr: None,
+            link_mtu: DEFAULT_LINK_MTU,
+            enabled: AtomicBool::new(true),
+            reconnect: Mutex::new(reconnect),
+            rx_protocols: Default::default(),
+            tx_protocols: Default::default(),
+            trace: RwLock::new(None),
+            held: Default::default(),
+            no_endpoint_drops: AtomicU64::new(0),
+            metadata,
+            endpoint_host: Default::default(),
+            liveness: Liveness::new(),
+        }
+    }
+
+    /// Attach an opaque label to the peer, such as the id of the peer in an inventory. Stats,
+    /// events and log lines of the peer carry it. See `is_valid_metadata` for its limits.
+    pub fn set_metadata(&self, metadata: Option<String>) {
+        *self.metadata.0.write() = metadata;
+    }
+
+    pub fn metadata(&self) -> Option<String> {
+        self.metadata.0.read().clone()
+    }
+
+    /// Bind the connected socket to a local address, so its traffic leaves from that address
+    /// and the interface it is assigned to. Without one the system picks the source.
+    pub fn set_bind_addr(&mut self, addr: Option<IpAddr>) {
+        self.bind_addr = addr;
+    }
+
+    pub fn bind_addr(&self) -> Option<IpAddr> {
+        self.bind_addr
+    }
+
+    /// Set the MTU of the path to the endpoint, which with the address family of the endpoint
+    /// limits how far packets to the peer are padded
+    pub fn set_link_mtu(&mut self, mtu: usize) {
+        self.link_mtu = mtu;
+        if let Some(addr) = self.endpoint.get_mut().addr {
+            self.tunnel.set_max_payload(payload_budget(mtu, addr));
+        }
+    }
+
+    /// The largest inner packet that fits a datagram to the current endpoint
+    pub fn payload_budget(&self) -> Option<usize> {
+        let addr = self.endpoint().addr?;
+        Some(payload_budget(self.link_mtu, addr))
+    }
+
+    /// Keep the last capacity packet events of the peer, or stop tracing with a capacity of 0.
+    /// Changing the capacity drops the events recorded so far.
+    pub fn set_trace(&self, capacity: usize) {
+        let mut trace = self.trace.write();
+        if trace.as_ref().map(|t| t.capacity()) != Some(capacity) {
+            *trace = Some(capacity)
+                .filter(|&c| c > 0)
+                .map(|c| Arc::new(TraceRing::new(c)));
+        }
+    }
+
+    /// The recorded packet events, oldest first
+    pub fn trace(&self) -> Vec<TraceEvent> {
+        let trace = self.trace.read().clone();
+        trace.map(|t| t.events()).unwrap_or_default()
+    }
+
+    fn is_tracing(&self) -> bool {
+        self.trace.read().is_some()
+    }
+
+    pub fn trace_event(
+        &self,
+        direction: TraceDirection,
+        kind: TraceKind,
+        len: usize,
+        outcome: TraceOutcome,
+    ) {
+        // The ring is recorded into outside the lock, so a resize does not wait for it
+        let trace = self.trace.read().clone();
+        if let Some(trace) = trace {
+            trace.record(TraceEvent::new(direction, kind, len, outcome));
+        }
+    }
+
+    /// Record a message sent by the timers, or from the queue of packets awaiting a handshake
+    pub fn trace_sent(&self, packet: &[u8]) {
+        let kind = TraceKind::of(packet);
+        self.trace_event(TraceDirection::Tx, kind, packet.len(), TraceOutcome::Sent);
+    }
+
+    /// Record a datagram received from the network, and what decapsulating it resulted in
+    pub fn trace_rx(&self, datagram: &[u8], result: &TunnResult) {
+        if !self.is_tracing() {
+            return;
+        }
+
+        let outcome = match result {
+            TunnResult::WriteToTunnelV4(_, addr) if !self.is_allowed_ip(*addr) => {
+                TraceOutcome::Rejected
+            }
+            TunnResult::WriteToTunnelV6(_, addr) if !self.is_allowed_ip(*addr) => {
+                TraceOutcome::Rejected
+            }
+            result => TraceOutcome::of_rx(result),
+        };
+        let kind = TraceKind::of(datagram);
+        self.trace_event(TraceDirection::Rx, kind, datagram.len(), outcome);
+    }
+
+    /// Record a packet from the tunnel interface, and what encapsulating it resulted in
+    pub fn trace_tx(&self, src: &[u8], result: &TunnResult) {
+        if !self.is_tracing() {
+            return;
+        }
+
+        let (kind, len) = (TraceKind::Data, src.len());
+        match result {
+            TunnResult::WriteToNetwork(packet) if TraceKind::of(packet) == TraceKind::Data => {
+                self.trace_event(TraceDirection::Tx, kind, packet.len(), TraceOutcome::Sent)
+            }
+            TunnResult::WriteToNetwork(packet) => {
+                // Without a session the packet is queued, and a handshake is sent instead
+                self.trace_event(TraceDirection::Tx, kind, len, TraceOutcome::Queued);
+                let kind = TraceKind::of(packet);
+                self.trace_event(TraceDirection::Tx, kind, packet.len(), TraceOutcome::Sent)
+            }
+            TunnResult::Done => {
+                self.trace_event(TraceDirection::Tx, kind, len, TraceOutcome::Queued)
+            }
+            _ => self.trace_event(TraceDirection::Tx, kind, len, TraceOutcome::Failed),
         }
     }
 
@@ -74,15 +295,37 @@ impl<S: Sock> Peer<S> {
     }
 
     pub fn shutdown_endpoint(&self) {
-        if let Some(conn) = self.endpoint.write().conn.take() {
+        let mut endpoint = self.endpoint.write();
+        endpoint.sock = None;
+        if let Some(conn) = endpoint.conn.take() {
             info!(self.tunnel.logger, "Disconnecting from endpoint");
             conn.shutdown();
         }
     }
 
-    pub fn set_endpoint(&self, addr: SocketAddr) {
+    /// Stop replying through sock, a listen socket that is being closed
+    pub fn forget_listen_sock(&self, sock: &Arc<S>) {
         let mut endpoint = self.endpoint.write();
-        if endpoint.addr != Some(addr) {
+        if matches!(&endpoint.sock, Some(cur) if Arc::ptr_eq(cur, sock)) {
+            endpoint.sock = None;
+        }
+    }
+
+    /// Returns true if the endpoint changed
+    pub fn set_endpoint(&self, addr: SocketAddr) -> bool {
+        self.update_endpoint(addr, None)
+    }
+
+    /// Set the endpoint, and remember the listen socket its packet arrived on, so packets for the
+    /// endpoint leave through the same socket
+    pub fn set_endpoint_from(&self, addr: SocketAddr, sock: &Arc<S>) -> bool {
+        self.update_endpoint(addr, Some(sock))
+    }
+
+    fn update_endpoint(&self, addr: SocketAddr, sock: Option<&Arc<S>>) -> bool {
+        let mut endpoint = self.endpoint.write();
+        let changed = endpoint.addr != Some(addr);
+        if changed {
             // We only need to update the endpoint if it differs from the current one
             if let Some(conn) = endpoint.conn.take() {
                 conn.shutdown();
@@ -91,35 +334,82 @@ impl<S: Sock> Peer<S> {
             *endpoint = Endpoint {
                 addr: Some(addr),
                 conn: None,
+                sock: None,
+            };
+            // The endpoint may have roamed to the other address family
+            self.tunnel
+                .set_max_payload(payload_budget(self.link_mtu, addr));
+
+            // Heard from anywhere but the backup, the peer is back to its primary endpoint
+            if let Some(backup) = self.backup.lock().as_mut().filter(|b| b.addr != addr) {
+                if backup.primary.take().is_some() {
+                    info!(self.tunnel.logger, "Failing back from the backup endpoint"; "endpoint" => addr);
+                }
             }
         };
+
+        if let Some(sock) = sock {
+            match endpoint.sock {
+                Some(ref cur) if Arc::ptr_eq(cur, sock) => {}
+                _ => endpoint.sock = Some(Arc::clone(sock)),
+            }
+        }
+        changed
     }
 
-    pub fn connect_endpoint(&self, port: u16, fwmark: Option<u32>) -> Result<Arc<S>, Error> {
+    pub fn connect_endpoint(
+        &self,
+        config: &DeviceConfig,
+        port: u16,
+        fwmark: Option<u32>,
+        freebind: bool,
+    ) -> Result<Arc<S>, Error> {
         let mut endpoint = self.endpoint.write();
 
         if endpoint.conn.is_some() {
             return Err(Error::Connect("Connected".to_owned()));
         }
 
-        let udp_conn = Arc::new(match endpoint.addr {
-            Some(addr @ SocketAddr::V4(_)) => S::new()?
-                .set_non_blocking()?
-                .set_reuse()?
-                .bind(port)?
-                .connect(&addr)?,
-            Some(addr @ SocketAddr::V6(_)) => S::new6()?
-                .set_non_blocking()?
-                .set_reuse()?
-                .bind(port)?
-                .connect(&addr)?,
-            None => panic!("Attempt to connect to undefined endpoint"),
-        });
-
-        if let Some(fwmark) = fwmark {
-            udp_conn.set_fwmark(fwmark)?;
+        let now = Instant::now();
+        if !self.reconnect.lock().is_ready(now) {
+            return Err(Error::Connect("Backing off".to_owned()));
         }
 
+        let connect = |addr: &SocketAddr| -> Result<S, Error> {
+            let sock = match addr {
+                SocketAddr::V4(_) => S::new_with_config(config)?,
+                SocketAddr::V6(_) => S::new6_with_config(config)?,
+            };
+            let sock = sock.set_non_blocking()?.set_reuse()?;
+            if freebind {
+                sock.set_freebind(true)?;
+            }
+            let sock = match self.bind_addr {
+                Some(ip) => sock.bind_addr(SocketAddr::new(ip, port))?,
+                None => sock.bind(port)?,
+            };
+            let sock = sock.connect(addr)?;
+            if let Some(fwmark) = fwmark {
+                sock.set_fwmark(fwmark)?;
+            }
+            Ok(sock)
+        };
+
+        let addr = endpoint
+            .addr
+            .expect("Attempt to connect to undefined endpoint");
+        let udp_conn = match connect(&addr) {
+            Ok(sock) => Arc::new(sock),
+            Err(e) => {
+                let retry_in = self.reconnect.lock().failed(now);
+                info!(
+                    self.tunnel.logger,
+                    "Failed to connect endpoint {}: {:?}, retrying in {:?}", addr, e, retry_in
+                );
+                return Err(e);
+            }
+        };
+
         info!(
             self.tunnel.logger,
             "Connected endpoint :{}->{}",
@@ -132,6 +422,11 @@ impl<S: Sock> Peer<S> {
         Ok(udp_conn)
     }
 
+    /// Traffic went through the connected socket, so connecting is no longer backed off
+    pub fn connected(&self) {
+        self.reconnect.lock().reset()
+    }
+
     pub fn is_allowed_ip<I: Into<IpAddr>>(&self, addr: I) -> bool {
         self.allowed_ips.find(addr.into()).is_some()
     }
@@ -155,4 +450,204 @@ impl<S: Sock> Peer<S> {
     pub fn index(&self) -> u32 {
         self.index
     }
+
+    /// A stable identifier, assigned in the order peers are added and kept for their lifetime.
+    /// Unlike the receiver index it has no meaning on the wire.
+    pub fn peer_id(&self) -> u64 {
+        self.peer_id
+    }
+
+    /// Count a decapsulated inner packet by its protocol
+    pub fn account_rx(&self, packet: &[u8]) {
+        self.rx_protocols.count(packet)
+    }
+
+    /// Count an inner packet about to be encapsulated by its protocol
+    pub fn account_tx(&self, packet: &[u8]) {
+        self.tx_protocols.count(packet)
+    }
+
+    /// Inner packets received and sent, by protocol
+    pub fn protocol_stats(&self) -> (ProtocolStats, ProtocolStats) {
+        (self.rx_protocols.stats(), self.tx_protocols.stats())
+    }
+
+    pub fn route_metric(&self) -> u32 {
+        self.route_metric
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed)
+    }
+
+    /// True if there is an address to send packets to the peer to
+    pub fn has_endpoint(&self) -> bool {
+        let endpoint = self.endpoint.read();
+        endpoint.addr.is_some() || endpoint.conn.is_some() || !self.ecmp.read().is_empty()
+    }
+
+    /// Keep an inner packet for a peer without an endpoint, to be sent once the endpoint is
+    /// learned. When capacity packets are already kept it is dropped and counted instead. A lean
+    /// peer copies it to a buffer from the pool of its tunnel.
+    pub fn hold_for_endpoint(&self, packet: &[u8], capacity: usize) {
+        let mut held = self.held.lock();
+        if held.len() < capacity {
+            held.push_back(match self.tunnel.buffer_pool() {
+                Some(buffers) => buffers.copy(packet),
+                None => packet.to_vec(),
+            });
+        } else {
+            self.no_endpoint_drops.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The inner packets kept by `hold_for_endpoint`, oldest first
+    pub fn take_held(&self) -> VecDeque<Vec<u8>> {
+        std::mem::take(&mut *self.held.lock())
+    }
+
+    /// The number of packets dropped because the peer had no endpoint
+    pub fn no_endpoint_drops(&self) -> u64 {
+        self.no_endpoint_drops.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ecmp_endpoints(&self, endpoints: Vec<(SocketAddr, u32)>) {
+        *self.ecmp.write() = WeightedEndpoints::new(endpoints);
+    }
+
+    pub fn ecmp_endpoints(&self) -> Vec<(SocketAddr, u32)> {
+        self.ecmp.read().endpoints().to_vec()
+    }
+
+    /// Set the standby endpoint the peer fails over to when handshakes to its endpoint go
+    /// unanswered, None to have none
+    pub fn set_backup_endpoint(&self, addr: Option<SocketAddr>) {
+        *self.backup.lock() = addr.map(|addr| Backup {
+            addr,
+            primary: None,
+            probed: Instant::now(),
+        });
+    }
+
+    /// Give the endpoint by name, as HOST:PORT, to be resolved by `reresolve`. None forgets the
+    /// name, keeping the endpoint.
+    pub fn set_endpoint_host(&self, host: Option<String>) {
+        *self.endpoint_host.lock() = host.map(|host| (host, None));
+    }
+
+    pub fn endpoint_host(&self) -> Option<String> {
+        self.endpoint_host
+            .lock()
+            .as_ref()
+            .map(|(host, _)| host.clone())
+    }
+
+    pub fn liveness(&self) -> &Liveness {
+        &self.liveness
+    }
+
+    /// The next liveness probe, from the first of the addresses that has the family of one of
+    /// the allowed IPs, see `liveness::target`
+    pub fn liveness_probe(&self, addrs: &[IpAddr]) -> Option<Vec<u8>> {
+        let (src, dst) = addrs.iter().find_map(|src| {
+            self.allowed_ips()
+                .find(|(_, ip, _)| ip.is_ipv4() == src.is_ipv4())
+                .map(|(_, ip, cidr)| (*src, liveness::target(ip, cidr as u8)))
+        })?;
+        self.liveness.next_probe(src, dst)
+    }
+
+    /// Resolve the name of the endpoint, and move the endpoint to the address it resolved to if
+    /// that differs from the last resolution. Returns the new endpoint if it changed. An endpoint
+    /// learned from the peer since the last resolution is only replaced once the name resolves
+    /// to a new address, so a roaming peer is not pulled back.
+    pub fn reresolve(&self, resolver: &Resolver) -> std::io::Result<Option<SocketAddr>> {
+        let host = match self.endpoint_host() {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+        // The lock is not held while resolving, which may take a while
+        let addrs = resolver(&host)?;
+
+        let mut entry = self.endpoint_host.lock();
+        let last = match entry.as_mut() {
+            Some((cur, last)) if *cur == host => last,
+            _ => return Ok(None), // The name changed in the meantime
+        };
+        let addr = resolve::pick(&addrs, *last)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No address found"))?;
+        if *last == Some(addr) {
+            return Ok(None);
+        }
+        *last = Some(addr);
+        drop(entry);
+
+        if !self.set_endpoint(addr) {
+            return Ok(None);
+        }
+        info!(self.tunnel.logger, "Endpoint resolved"; "host" => host, "endpoint" => addr);
+        Ok(Some(addr))
+    }
+
+    pub fn backup_endpoint(&self) -> Option<SocketAddr> {
+        self.backup.lock().as_ref().map(|backup| backup.addr)
+    }
+
+    /// Whether the peer failed over to its backup endpoint, and has not heard from its primary
+    /// endpoint since
+    pub fn is_on_backup(&self) -> bool {
+        matches!(
+            &*self.backup.lock(),
+            Some(Backup {
+                primary: Some(_),
+                ..
+            })
+        )
+    }
+
+    /// Switch to the backup endpoint if more than max_attempts handshake initiations to the
+    /// endpoint went unanswered, returning the backup. The switch is sticky: the peer stays on
+    /// the backup until it is heard from elsewhere, such as in reply to `probe_primary`.
+    pub fn fail_over(&self, max_attempts: usize) -> Option<SocketAddr> {
+        let addr = {
+            let mut backup = self.backup.lock();
+            let backup = backup.as_mut().filter(|b| b.primary.is_none())?;
+            let primary = self.endpoint.read().addr.filter(|&a| a != backup.addr)?;
+            if self.tunnel.handshake_attempts() <= max_attempts {
+                return None;
+            }
+            backup.primary = Some(primary);
+            backup.probed = Instant::now();
+            backup.addr
+        };
+        warn!(self.tunnel.logger, "Failing over to the backup endpoint"; "endpoint" => addr);
+        self.update_endpoint(addr, None);
+        Some(addr)
+    }
+
+    /// The primary endpoint to send a handshake probe to, if the peer is on its backup endpoint
+    /// and was not probed for interval
+    pub fn probe_primary(&self, interval: Duration) -> Option<SocketAddr> {
+        let mut backup = self.backup.lock();
+        let backup = backup.as_mut()?;
+        let primary = backup.primary?;
+        if backup.probed.elapsed() < interval {
+            return None;
+        }
+        backup.probed = Instant::now();
+        Some(primary)
+    }
+
+    /// The weighted endpoint the flow of the inner packet is sent to, if the peer has any
+    pub fn ecmp_endpoint(&self, inner: &[u8]) -> Option<SocketAddr> {
+        let ecmp = self.ecmp.read();
+        if ecmp.is_empty() {
+            return None;
+        }
+        ecmp.pick(flow_hash(inner)?)
+    }
 }
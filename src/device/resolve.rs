@@ -0,0 +1,82 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Endpoints given by name. A peer configured with `endpoint=HOST:PORT` keeps the name, and the
+//! device resolves it again on `Device::reresolve_endpoints` or every `reresolve_interval`, so a
+//! peer behind a dynamic DNS record is followed to its new address. Resolution blocks the thread
+//! that runs it.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// Resolves a name and port, as in vpn.example.com:51820, to the addresses it stands for, see
+/// `DeviceConfig::resolver`
+pub type Resolver = Box<dyn Fn(&str) -> io::Result<Vec<SocketAddr>> + Send + Sync>;
+
+/// The longest name an endpoint can be given by, in bytes
+pub const MAX_HOST_LEN: usize = 253;
+
+/// Resolve with the resolver of the system, getaddrinfo(3)
+pub fn system_resolver() -> Resolver {
+    Box::new(|host| host.to_socket_addrs().map(Iterator::collect))
+}
+
+/// A HOST:PORT endpoint is a DNS name of at most `MAX_HOST_LEN` bytes, made of labels of
+/// letters, digits, hyphens and underscores, and a port
+pub fn is_valid_host_endpoint(endpoint: &str) -> bool {
+    let (host, port) = match endpoint.rsplit_once(':') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    port.parse::<u16>().is_ok()
+        && !host.is_empty()
+        && host.len() <= MAX_HOST_LEN
+        && host.trim_end_matches('.').split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+/// Pick the endpoint of a peer among the addresses its name resolved to: the address resolved
+/// last time if it is still there, so a name with several addresses does not flap between them,
+/// and otherwise the first one
+pub fn pick(addrs: &[SocketAddr], last: Option<SocketAddr>) -> Option<SocketAddr> {
+    match last {
+        Some(last) if addrs.contains(&last) => Some(last),
+        _ => addrs.first().copied(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_endpoint() {
+        assert!(is_valid_host_endpoint("vpn.example.com:51820"));
+        assert!(is_valid_host_endpoint("home-server.dyn_dns.example.:1"));
+        assert!(is_valid_host_endpoint("localhost:0"));
+        assert!(!is_valid_host_endpoint("vpn.example.com"));
+        assert!(!is_valid_host_endpoint("vpn.example.com:65536"));
+        assert!(!is_valid_host_endpoint(":51820"));
+        assert!(!is_valid_host_endpoint("vpn..example.com:51820"));
+        assert!(!is_valid_host_endpoint("-vpn.example.com:51820"));
+        assert!(!is_valid_host_endpoint("vpn example.com:51820"));
+        assert!(!is_valid_host_endpoint(&format!(
+            "{}:51820",
+            "a".repeat(64)
+        )));
+
+        let (a, b) = (
+            "192.0.2.1:1".parse().unwrap(),
+            "192.0.2.2:1".parse().unwrap(),
+        );
+        assert_eq!(pick(&[a, b], None), Some(a));
+        assert_eq!(pick(&[a, b], Some(b)), Some(b));
+        assert_eq!(pick(&[a], Some(b)), Some(a));
+        assert_eq!(pick(&[], Some(b)), None);
+    }
+}
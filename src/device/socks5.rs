@@ -0,0 +1,576 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A transport that sends WireGuard datagrams through a SOCKS5 proxy with UDP ASSOCIATE (RFC
+//! 1928), for networks whose only egress is a proxy. Every datagram to the relay of the proxy
+//! carries the address of its destination in a short header, and every datagram from the relay
+//! the address of its source.
+//!
+//! The UDP socket registered with the event loop stays the same for the lifetime of the
+//! transport. A background thread holds the TCP connection the association lives as long as,
+//! and associates again when the proxy drops it.
+//!
+//! The proxy is set with `DeviceConfig::socks5_proxy`, every socket of the device has an
+//! association of its own.
+
+use super::{errno, DeviceConfig, Error};
+use crate::device::Sock;
+use parking_lot::{Mutex, RwLock};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE: u8 = 0xff;
+const PASSWORD_VERSION: u8 = 1; // Of the username/password subnegotiation, RFC 1929
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+
+const MAX_HEADER_SIZE: usize = 4 + 16 + 2;
+const CHECK_INTERVAL: Duration = Duration::from_secs(1); // How often the thread checks for close
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// The proxy Socks5Transport associates with
+#[derive(Debug, Clone)]
+pub struct Socks5Proxy {
+    pub addr: SocketAddr,
+    /// The username and password to authenticate with, None to offer no authentication
+    pub credentials: Option<(String, String)>,
+}
+
+// The state the transport shares with the thread that keeps the association up
+#[derive(Debug)]
+struct Association {
+    udp: UdpSocket,
+    relay: RwLock<Option<SocketAddr>>, // The relay address of the proxy, while associated
+    control: Mutex<Option<TcpStream>>, // A clone of the connection the association lives as long as
+    closed: AtomicBool,
+}
+
+impl Association {
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        *self.relay.write() = None;
+        if let Some(control) = self.control.lock().take() {
+            let _ = control.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Sends and receives WireGuard datagrams through a SOCKS5 proxy
+#[derive(Debug)]
+pub struct Socks5Transport {
+    association: Arc<Association>,
+    peer: Option<SocketAddr>, // The destination of write, once connected
+    port: u16,
+}
+
+impl Socks5Transport {
+    fn open(config: &DeviceConfig) -> Result<Socks5Transport, Error> {
+        let proxy = match &config.socks5_proxy {
+            Some(proxy) => proxy.clone(),
+            None => return Err(Error::Socket("No SOCKS5 proxy set".to_owned())),
+        };
+
+        // The relay is in the address family of the proxy, whatever the destinations are
+        let local = match proxy.addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let udp = UdpSocket::bind(local).map_err(|e| Error::Socket(e.to_string()))?;
+        let association = Arc::new(Association {
+            udp,
+            relay: RwLock::new(None),
+            control: Mutex::new(None),
+            closed: AtomicBool::new(false),
+        });
+
+        let thread_association = Arc::clone(&association);
+        thread::spawn(move || run_association(proxy, thread_association));
+
+        Ok(Socks5Transport {
+            association,
+            peer: None,
+            port: 0,
+        })
+    }
+
+    /// Whether the transport is associated with the proxy, so datagrams go through
+    pub fn is_associated(&self) -> bool {
+        self.association.relay.read().is_some()
+    }
+
+    fn send_through(&self, buf: &[u8], dst: SocketAddr) -> usize {
+        let relay = match *self.association.relay.read() {
+            Some(relay) => relay,
+            None => return 0, // Datagrams are dropped while associating, just like UDP would
+        };
+        let mut datagram = Vec::with_capacity(MAX_HEADER_SIZE + buf.len());
+        datagram.extend_from_slice(&[0, 0, 0]); // Reserved, and not a fragment
+        encode_addr(dst, &mut datagram);
+        datagram.extend_from_slice(buf);
+        match self.association.udp.send_to(&datagram, relay) {
+            Ok(n) => n.saturating_sub(datagram.len() - buf.len()),
+            Err(_) => 0,
+        }
+    }
+
+    // Receive the next datagram from the relay, skipping those with a malformed header
+    fn recv_through<'a>(&self, buf: &'a mut [u8]) -> Result<(SocketAddr, &'a mut [u8]), Error> {
+        loop {
+            let (n, src) = self
+                .association
+                .udp
+                .recv_from(buf)
+                .map_err(|e| Error::UDPRead(e.raw_os_error().unwrap_or_else(errno)))?;
+            if Some(src) != *self.association.relay.read() {
+                continue;
+            }
+            // Fragments are rare and optional to support, they are dropped
+            if n < 4 || buf[..3] != [0, 0, 0] {
+                continue;
+            }
+            if let Some((addr, len)) = parse_addr(&buf[3..n]) {
+                let header_len = 3 + len;
+                buf.copy_within(header_len..n, 0);
+                return Ok((addr, &mut buf[..n - header_len]));
+            }
+        }
+    }
+}
+
+impl Drop for Socks5Transport {
+    fn drop(&mut self) {
+        self.association.close();
+    }
+}
+
+impl AsRawFd for Socks5Transport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.association.udp.as_raw_fd()
+    }
+}
+
+impl Sock for Socks5Transport {
+    // A transport can only be opened through the proxy of a device
+    fn new() -> Result<Socks5Transport, Error> {
+        Err(Error::Socket("No SOCKS5 proxy set".to_owned()))
+    }
+
+    fn new6() -> Result<Socks5Transport, Error> {
+        Err(Error::Socket("No SOCKS5 proxy set".to_owned()))
+    }
+
+    fn new_with_config(config: &DeviceConfig) -> Result<Socks5Transport, Error> {
+        Socks5Transport::open(config)
+    }
+
+    fn new6_with_config(config: &DeviceConfig) -> Result<Socks5Transport, Error> {
+        Socks5Transport::open(config)
+    }
+
+    // There is nothing to bind, all traffic goes through the relay
+    fn bind(mut self, port: u16) -> Result<Socks5Transport, Error> {
+        self.port = port;
+        Ok(self)
+    }
+
+    fn connect(mut self, dst: &SocketAddr) -> Result<Socks5Transport, Error> {
+        self.peer = Some(*dst);
+        Ok(self)
+    }
+
+    fn set_non_blocking(self) -> Result<Socks5Transport, Error> {
+        self.association
+            .udp
+            .set_nonblocking(true)
+            .map_err(|e| Error::FCntl(e.to_string()))?;
+        Ok(self)
+    }
+
+    fn set_reuse(self) -> Result<Socks5Transport, Error> {
+        Ok(self)
+    }
+
+    fn set_fwmark(&self, _mark: u32) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn port(&self) -> Result<u16, Error> {
+        Ok(self.port)
+    }
+
+    fn sendto(&self, buf: &[u8], dst: SocketAddr) -> usize {
+        self.send_through(buf, dst)
+    }
+
+    fn recvfrom<'a>(&self, buf: &'a mut [u8]) -> Result<(SocketAddr, &'a mut [u8]), Error> {
+        self.recv_through(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        match self.peer {
+            Some(peer) => self.send_through(buf, peer),
+            None => 0,
+        }
+    }
+
+    fn read<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        loop {
+            let (src, packet) = self.recv_through(buf)?;
+            if self.peer.is_none() || self.peer == Some(src) {
+                // Reborrow, as the loop would otherwise hold buf for good
+                let len = packet.len();
+                return Ok(&mut buf[..len]);
+            }
+        }
+    }
+
+    fn shutdown(&self) {
+        self.association.close();
+    }
+}
+
+// Append the address in the format of SOCKS5 requests: type, address and port
+fn encode_addr(addr: SocketAddr, out: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+// Parse an address in the format of encode_addr, returns it with the number of bytes it took.
+// Domain names are not supported.
+fn parse_addr(buf: &[u8]) -> Option<(SocketAddr, usize)> {
+    let (ip, len) = match *buf.first()? {
+        ATYP_IPV4 if buf.len() >= 7 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&buf[1..5]);
+            (IpAddr::from(octets), 7)
+        }
+        ATYP_IPV6 if buf.len() >= 19 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[1..17]);
+            (IpAddr::from(octets), 19)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+    Some((SocketAddr::new(ip, port), len))
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Connect to the proxy, authenticate and request a UDP association for local. Returns the
+// control connection and the relay address datagrams go to.
+fn associate(proxy: &Socks5Proxy, local: SocketAddr) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut stream = TcpStream::connect(proxy.addr)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    let methods: &[u8] = match proxy.credentials {
+        Some(_) => &[AUTH_NONE, AUTH_PASSWORD],
+        None => &[AUTH_NONE],
+    };
+    let mut greeting = vec![VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting)?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    match (choice, &proxy.credentials) {
+        ([VERSION, AUTH_NONE], _) => {}
+        ([VERSION, AUTH_PASSWORD], Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(protocol_error("Credentials too long"));
+            }
+            let mut request = vec![PASSWORD_VERSION, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request)?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status)?;
+            if status[1] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Authentication rejected",
+                ));
+            }
+        }
+        ([VERSION, AUTH_NO_ACCEPTABLE], _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "No acceptable authentication method",
+            ))
+        }
+        _ => return Err(protocol_error("Unexpected authentication method")),
+    }
+
+    let mut request = vec![VERSION, CMD_UDP_ASSOCIATE, 0];
+    encode_addr(local, &mut request);
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4 + 16 + 2];
+    stream.read_exact(&mut reply[..4])?;
+    if reply[0] != VERSION || reply[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("UDP ASSOCIATE refused with {}", reply[1]),
+        ));
+    }
+    let len = match reply[3] {
+        ATYP_IPV4 => 4 + 4 + 2,
+        ATYP_IPV6 => 4 + 16 + 2,
+        _ => return Err(protocol_error("Unsupported relay address")),
+    };
+    stream.read_exact(&mut reply[4..len])?;
+    let (mut relay, _) = parse_addr(&reply[3..len]).unwrap();
+    // An unspecified relay address is the address of the proxy
+    if relay.ip().is_unspecified() {
+        relay.set_ip(proxy.addr.ip());
+    }
+
+    stream.set_read_timeout(Some(CHECK_INTERVAL))?;
+    Ok((stream, relay))
+}
+
+// Keep the association up for the lifetime of the transport, associating again whenever the
+// proxy closes the control connection
+fn run_association(proxy: Socks5Proxy, association: Arc<Association>) {
+    let local = association
+        .udp
+        .local_addr()
+        .unwrap_or_else(|_| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+
+    while !association.closed.load(Ordering::Relaxed) {
+        let (mut stream, relay) = match associate(&proxy, local) {
+            Ok(associated) => associated,
+            Err(_) => {
+                thread::sleep(RECONNECT_DELAY);
+                continue;
+            }
+        };
+        match stream.try_clone() {
+            Ok(control) => *association.control.lock() = Some(control),
+            Err(_) => continue,
+        }
+        if association.closed.load(Ordering::Relaxed) {
+            break;
+        }
+        *association.relay.write() = Some(relay);
+
+        // The proxy sends nothing more on the connection, until it closes it
+        let mut buf = [0u8; 256];
+        while !association.closed.load(Ordering::Relaxed) {
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(_) => break,
+            }
+        }
+
+        *association.relay.write() = None;
+        association.control.lock().take();
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::x25519::X25519SecretKey;
+    use crate::noise::{Tunn, TunnResult};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    // A minimal proxy that only serves UDP ASSOCIATE, with username/password authentication
+    struct Proxy {
+        addr: SocketAddr,
+        controls: Arc<Mutex<Vec<TcpStream>>>,
+    }
+
+    impl Proxy {
+        fn start() -> Proxy {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let controls = Arc::new(Mutex::new(vec![]));
+
+            let accept_controls = Arc::clone(&controls);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let controls = Arc::clone(&accept_controls);
+                    thread::spawn(move || Proxy::serve(stream.unwrap(), controls));
+                }
+            });
+
+            Proxy { addr, controls }
+        }
+
+        fn serve(mut stream: TcpStream, controls: Arc<Mutex<Vec<TcpStream>>>) {
+            let mut greeting = [0u8; 4];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [VERSION, 2, AUTH_NONE, AUTH_PASSWORD]);
+            stream.write_all(&[VERSION, AUTH_PASSWORD]).unwrap();
+
+            let mut auth = [0u8; 13];
+            stream.read_exact(&mut auth).unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x06secret");
+            stream.write_all(&[PASSWORD_VERSION, 0]).unwrap();
+
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request[..4], [VERSION, CMD_UDP_ASSOCIATE, 0, ATYP_IPV4]);
+
+            let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let mut reply = vec![VERSION, 0, 0];
+            encode_addr(relay.local_addr().unwrap(), &mut reply);
+            stream.write_all(&reply).unwrap();
+            controls.lock().push(stream.try_clone().unwrap());
+
+            // Relay datagrams until the control connection closes
+            relay
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+            let mut client = None;
+            let mut buf = [0u8; 2048];
+            let mut control = [0u8; 1];
+            stream.set_nonblocking(true).unwrap();
+            loop {
+                match stream.read(&mut control) {
+                    Ok(0) => return,
+                    Err(e) if e.kind() != io::ErrorKind::WouldBlock => return,
+                    _ => {}
+                }
+                let (n, src) = match relay.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                };
+                // Everything is on loopback, but only datagrams of the client start with a zero
+                // byte: WireGuard messages never do
+                if buf[..3] == [0, 0, 0] {
+                    if let Some((dst, len)) = parse_addr(&buf[3..n]) {
+                        client = Some(src);
+                        relay.send_to(&buf[3 + len..n], dst).unwrap();
+                        continue;
+                    }
+                }
+                if let Some(client) = client {
+                    let mut datagram = vec![0, 0, 0];
+                    encode_addr(src, &mut datagram);
+                    datagram.extend_from_slice(&buf[..n]);
+                    relay.send_to(&datagram, client).unwrap();
+                }
+            }
+        }
+
+        // Drop the control connections, which ends their associations
+        fn disconnect_all(&self) {
+            for control in self.controls.lock().drain(..) {
+                let _ = control.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    // Keep sending the datagram to the peer through the transport until the peer receives it
+    fn send_to_peer(transport: &Socks5Transport, peer: &UdpSocket, datagram: &[u8]) -> SocketAddr {
+        let started = Instant::now();
+        let mut buf = [0u8; 2048];
+        let dst = peer.local_addr().unwrap();
+        while started.elapsed() < Duration::from_secs(10) {
+            transport.sendto(datagram, dst);
+            if let Ok((n, src)) = peer.recv_from(&mut buf) {
+                assert_eq!(&buf[..n], datagram);
+                return src;
+            }
+        }
+        panic!("Datagram did not go through the proxy");
+    }
+
+    #[test]
+    fn test_socks5_handshake_through_proxy() {
+        let proxy = Proxy::start();
+        let config = DeviceConfig {
+            socks5_proxy: Some(Socks5Proxy {
+                addr: proxy.addr,
+                credentials: Some(("user".to_owned(), "secret".to_owned())),
+            }),
+            ..Default::default()
+        };
+        assert!(Socks5Transport::new().is_err());
+
+        let transport = Socks5Transport::new_with_config(&config).unwrap();
+        transport
+            .association
+            .udp
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let b_public = Arc::new(b_key.public_key());
+        let a_tunn = Tunn::new(a_key, b_public, None, None, 0, None).unwrap();
+        let b_tunn = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+
+        let init = match a_tunn.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let relay = send_to_peer(&transport, &peer, &init);
+        let response = match b_tunn.decapsulate(None, &init, &mut dst) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        peer.send_to(&response, relay).unwrap();
+
+        // The response comes from the address of the peer, not from the relay
+        let (src, received) = transport.recvfrom(&mut buf).unwrap();
+        assert_eq!(src, peer.local_addr().unwrap());
+        let keepalive = match a_tunn.decapsulate(None, received, &mut dst) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a keepalive"),
+        };
+
+        // The transport associates again once the proxy drops the association
+        proxy.disconnect_all();
+        let started = Instant::now();
+        while *transport.association.relay.read() == Some(relay)
+            && started.elapsed() < Duration::from_secs(5)
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_ne!(send_to_peer(&transport, &peer, &keepalive), relay);
+        assert!(transport.is_associated());
+        assert!(matches!(
+            b_tunn.decapsulate(None, &keepalive, &mut dst),
+            TunnResult::Done
+        ));
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A flight recorder of the recent packets of a peer. Events are kept in a fixed size ring that
+//! is written without locks, overwriting the oldest events.
+
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::noise::TunnResult;
+
+/// The largest number of events kept per peer
+pub const MAX_TRACE_EVENTS: usize = 1 << 16;
+
+const HANDSHAKE_INIT: u8 = 1;
+const HANDSHAKE_RESP: u8 = 2;
+const COOKIE_REPLY: u8 = 3;
+const DATA: u8 = 4;
+const KEEPALIVE_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceDirection {
+    Rx,
+    Tx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    HandshakeInit,
+    HandshakeResponse,
+    CookieReply,
+    Data,
+    Keepalive,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOutcome {
+    /// A received data packet was written to the tunnel interface
+    Delivered,
+    /// A received packet was answered, with a handshake response or a keepalive
+    Replied,
+    /// A received packet was processed without anything to deliver or answer
+    Accepted,
+    /// A received packet was from an address that is not an allowed IP of the peer
+    Rejected,
+    /// A packet was sent to the peer
+    Sent,
+    /// A packet was queued until a handshake completes
+    Queued,
+    /// The packet failed to decapsulate or encapsulate
+    Failed,
+}
+
+const DIRECTIONS: [TraceDirection; 2] = [TraceDirection::Rx, TraceDirection::Tx];
+const KINDS: [TraceKind; 6] = [
+    TraceKind::HandshakeInit,
+    TraceKind::HandshakeResponse,
+    TraceKind::CookieReply,
+    TraceKind::Data,
+    TraceKind::Keepalive,
+    TraceKind::Unknown,
+];
+const OUTCOMES: [TraceOutcome; 7] = [
+    TraceOutcome::Delivered,
+    TraceOutcome::Replied,
+    TraceOutcome::Accepted,
+    TraceOutcome::Rejected,
+    TraceOutcome::Sent,
+    TraceOutcome::Queued,
+    TraceOutcome::Failed,
+];
+
+impl TraceDirection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TraceDirection::Rx => "rx",
+            TraceDirection::Tx => "tx",
+        }
+    }
+}
+
+impl TraceKind {
+    /// The kind of a WireGuard message as sent on the wire
+    pub fn of(packet: &[u8]) -> TraceKind {
+        match packet.first() {
+            Some(&HANDSHAKE_INIT) => TraceKind::HandshakeInit,
+            Some(&HANDSHAKE_RESP) => TraceKind::HandshakeResponse,
+            Some(&COOKIE_REPLY) => TraceKind::CookieReply,
+            Some(&DATA) if packet.len() == KEEPALIVE_SIZE => TraceKind::Keepalive,
+            Some(&DATA) => TraceKind::Data,
+            _ => TraceKind::Unknown,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TraceKind::HandshakeInit => "handshake_init",
+            TraceKind::HandshakeResponse => "handshake_response",
+            TraceKind::CookieReply => "cookie_reply",
+            TraceKind::Data => "data",
+            TraceKind::Keepalive => "keepalive",
+            TraceKind::Unknown => "unknown",
+        }
+    }
+}
+
+impl TraceOutcome {
+    /// The outcome of decapsulating a received datagram
+    pub fn of_rx(result: &TunnResult) -> TraceOutcome {
+        match result {
+            TunnResult::Done => TraceOutcome::Accepted,
+            TunnResult::Err(_) => TraceOutcome::Failed,
+            TunnResult::WriteToNetwork(_) => TraceOutcome::Replied,
+            TunnResult::WriteToTunnelV4(..) | TunnResult::WriteToTunnelV6(..) => {
+                TraceOutcome::Delivered
+            }
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TraceOutcome::Delivered => "delivered",
+            TraceOutcome::Replied => "replied",
+            TraceOutcome::Accepted => "accepted",
+            TraceOutcome::Rejected => "rejected",
+            TraceOutcome::Sent => "sent",
+            TraceOutcome::Queued => "queued",
+            TraceOutcome::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// Time since the epoch, with microsecond precision
+    pub time: Duration,
+    pub direction: TraceDirection,
+    pub kind: TraceKind,
+    /// The size of the message on the wire, or of the inner packet when it was queued
+    pub len: usize,
+    pub outcome: TraceOutcome,
+}
+
+impl TraceEvent {
+    pub fn new(
+        direction: TraceDirection,
+        kind: TraceKind,
+        len: usize,
+        outcome: TraceOutcome,
+    ) -> TraceEvent {
+        TraceEvent {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            direction,
+            kind,
+            len,
+            outcome,
+        }
+    }
+
+    // Everything but the time, packed into a single word
+    fn pack(&self) -> u64 {
+        let index_of = |i: usize| i as u64;
+        u64::from(self.len.min(u32::MAX as usize) as u32)
+            | index_of(
+                DIRECTIONS
+                    .iter()
+                    .position(|&d| d == self.direction)
+                    .unwrap(),
+            ) << 32
+            | index_of(KINDS.iter().position(|&k| k == self.kind).unwrap()) << 40
+            | index_of(OUTCOMES.iter().position(|&o| o == self.outcome).unwrap()) << 48
+    }
+
+    fn unpack(time: u64, info: u64) -> Option<TraceEvent> {
+        let field = |shift: u32| ((info >> shift) & 0xff) as usize;
+        Some(TraceEvent {
+            time: Duration::from_micros(time),
+            direction: *DIRECTIONS.get(field(32))?,
+            kind: *KINDS.get(field(40))?,
+            len: (info & 0xffff_ffff) as usize,
+            outcome: *OUTCOMES.get(field(48))?,
+        })
+    }
+}
+
+// Each slot is a seqlock: seq is odd while the slot is written, and 2n + 2 once it holds event n
+#[derive(Default)]
+struct Slot {
+    seq: AtomicU64,
+    time: AtomicU64,
+    info: AtomicU64,
+}
+
+/// The last events of a peer. Writers never block each other or readers, a reader skips the
+/// events that are overwritten while it reads them.
+pub struct TraceRing {
+    head: AtomicU64, // The number of events recorded so far
+    slots: Box<[Slot]>,
+}
+
+impl TraceRing {
+    pub fn new(capacity: usize) -> TraceRing {
+        TraceRing {
+            head: AtomicU64::new(0),
+            slots: (0..capacity.clamp(1, MAX_TRACE_EVENTS))
+                .map(|_| Slot::default())
+                .collect(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn record(&self, event: TraceEvent) {
+        let n = self.head.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[(n % self.slots.len() as u64) as usize];
+        slot.seq.store(2 * n + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.time
+            .store(event.time.as_micros() as u64, Ordering::Relaxed);
+        slot.info.store(event.pack(), Ordering::Relaxed);
+        slot.seq.store(2 * n + 2, Ordering::Release);
+    }
+
+    /// The recorded events, oldest first
+    pub fn events(&self) -> Vec<TraceEvent> {
+        let mut events: Vec<(u64, TraceEvent)> = self
+            .slots
+            .iter()
+            .filter_map(|slot| {
+                let seq = slot.seq.load(Ordering::Acquire);
+                let time = slot.time.load(Ordering::Relaxed);
+                let info = slot.info.load(Ordering::Relaxed);
+                fence(Ordering::Acquire);
+                if seq == 0 || seq % 2 == 1 || slot.seq.load(Ordering::Relaxed) != seq {
+                    return None;
+                }
+                Some((seq, TraceEvent::unpack(time, info)?))
+            })
+            .collect();
+        events.sort_by_key(|(seq, _)| *seq);
+        events.into_iter().map(|(_, event)| event).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(len: usize) -> TraceEvent {
+        TraceEvent::new(TraceDirection::Tx, TraceKind::Data, len, TraceOutcome::Sent)
+    }
+
+    #[test]
+    fn test_trace_ring_overwrites_oldest() {
+        let ring = TraceRing::new(4);
+        assert!(ring.events().is_empty());
+        for len in 0..3 {
+            ring.record(event(len));
+        }
+        let lens = |ring: &TraceRing| ring.events().iter().map(|e| e.len).collect::<Vec<_>>();
+        assert_eq!(lens(&ring), [0, 1, 2]);
+
+        for len in 3..10 {
+            ring.record(event(len));
+        }
+        assert_eq!(lens(&ring), [6, 7, 8, 9]);
+        assert_eq!(ring.events()[0].kind, TraceKind::Data);
+        assert_eq!(ring.events()[0].outcome, TraceOutcome::Sent);
+    }
+
+    #[test]
+    fn test_trace_kind() {
+        assert_eq!(TraceKind::of(&[1; 148]), TraceKind::HandshakeInit);
+        assert_eq!(TraceKind::of(&[4; 32]), TraceKind::Keepalive);
+        assert_eq!(TraceKind::of(&[4; 64]), TraceKind::Data);
+        assert_eq!(TraceKind::of(&[]), TraceKind::Unknown);
+    }
+}
@@ -5,6 +5,7 @@ use super::Error;
 use libc::*;
 use std::mem::size_of;
 use std::mem::size_of_val;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr::null_mut;
 
@@ -58,6 +59,89 @@ pub struct ifreq {
 
 const CTLIOCGINFO: u64 = 0x0000_0000_c064_4e03;
 const SIOCGIFMTU: u64 = 0x0000_0000_c020_6933;
+const SIOCGIFFLAGS: u64 = 0x0000_0000_c020_6911;
+const SIOCSIFFLAGS: u64 = 0x0000_0000_8020_6910;
+const SIOCAIFADDR: u64 = 0x0000_0000_8040_691a;
+const SIOCAIFADDR_IN6: u64 = 0x0000_0000_8080_691a;
+
+const ND6_INFINITE_LIFETIME: u32 = 0xffff_ffff;
+
+#[repr(C)]
+struct ifaliasreq {
+    ifra_name: [c_uchar; IF_NAMESIZE],
+    ifra_addr: sockaddr_in,
+    ifra_broadaddr: sockaddr_in, // The destination address of a point to point interface
+    ifra_mask: sockaddr_in,
+}
+
+#[repr(C)]
+struct in6_addrlifetime {
+    ia6t_expire: time_t,
+    ia6t_preferred: time_t,
+    ia6t_vltime: u32,
+    ia6t_pltime: u32,
+}
+
+#[repr(C)]
+struct in6_aliasreq {
+    ifra_name: [c_uchar; IF_NAMESIZE],
+    ifra_addr: sockaddr_in6,
+    ifra_dstaddr: sockaddr_in6,
+    ifra_prefixmask: sockaddr_in6,
+    ifra_flags: c_int,
+    ifra_lifetime: in6_addrlifetime,
+}
+
+fn sockaddr_v4(addr: Ipv4Addr) -> sockaddr_in {
+    sockaddr_in {
+        sin_len: size_of::<sockaddr_in>() as u8,
+        sin_family: AF_INET as _,
+        sin_port: 0,
+        sin_addr: in_addr {
+            s_addr: u32::from(addr).to_be(),
+        },
+        sin_zero: [0; 8],
+    }
+}
+
+fn sockaddr_v6(addr: Ipv6Addr) -> sockaddr_in6 {
+    sockaddr_in6 {
+        sin6_len: size_of::<sockaddr_in6>() as u8,
+        sin6_family: AF_INET6 as _,
+        sin6_port: 0,
+        sin6_flowinfo: 0,
+        sin6_addr: in6_addr {
+            s6_addr: addr.octets(),
+        },
+        sin6_scope_id: 0,
+    }
+}
+
+fn iface_name(name: &str) -> [c_uchar; IF_NAMESIZE] {
+    let mut ifr_name = [0; IF_NAMESIZE];
+    ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+    ifr_name
+}
+
+// Perform an interface ioctl on a socket of the given family
+fn iface_ioctl<T>(family: c_int, request: u64, arg: &mut T) -> Result<(), Error> {
+    let fd = match unsafe { socket(family, SOCK_DGRAM, 0) } {
+        -1 => return Err(Error::Socket(errno_str())),
+        fd => fd,
+    };
+
+    let res = match unsafe { ioctl(fd, request, arg as *mut T) } {
+        -1 if errno() == EPERM => Err(Error::IOCtl(format!(
+            "{} (did you run with sudo?)",
+            errno_str()
+        ))),
+        -1 => Err(Error::IOCtl(errno_str())),
+        _ => Ok(()),
+    };
+
+    unsafe { close(fd) };
+    res
+}
 
 #[derive(Default, Debug)]
 pub struct TunSocket {
@@ -228,6 +312,67 @@ impl Tun for TunSocket {
         Ok(unsafe { ifr.ifr_ifru.ifru_mtu } as _)
     }
 
+    fn set_address(&self, addr: IpAddr, prefix_len: u8) -> Result<(), Error> {
+        let name = self.name()?;
+
+        match addr {
+            IpAddr::V4(addr) => {
+                if prefix_len > 32 {
+                    return Err(Error::InvalidConfig(format!(
+                        "Invalid prefix /{}",
+                        prefix_len
+                    )));
+                }
+
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                let mut req = ifaliasreq {
+                    ifra_name: iface_name(&name),
+                    ifra_addr: sockaddr_v4(addr),
+                    ifra_broadaddr: sockaddr_v4(addr),
+                    ifra_mask: sockaddr_v4(mask.into()),
+                };
+                iface_ioctl(AF_INET, SIOCAIFADDR, &mut req)?;
+            }
+            IpAddr::V6(addr) => {
+                if prefix_len > 128 {
+                    return Err(Error::InvalidConfig(format!(
+                        "Invalid prefix /{}",
+                        prefix_len
+                    )));
+                }
+
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                let mut req = in6_aliasreq {
+                    ifra_name: iface_name(&name),
+                    ifra_addr: sockaddr_v6(addr),
+                    ifra_dstaddr: unsafe { std::mem::zeroed() }, // No destination, the prefix is routed
+                    ifra_prefixmask: sockaddr_v6(mask.into()),
+                    ifra_flags: 0,
+                    ifra_lifetime: in6_addrlifetime {
+                        ia6t_expire: 0,
+                        ia6t_preferred: 0,
+                        ia6t_vltime: ND6_INFINITE_LIFETIME,
+                        ia6t_pltime: ND6_INFINITE_LIFETIME,
+                    },
+                };
+                iface_ioctl(AF_INET6, SIOCAIFADDR_IN6, &mut req)?;
+            }
+        }
+
+        // Bring the interface up
+        let mut ifr = ifreq {
+            ifr_name: iface_name(&name),
+            ifr_ifru: IfrIfru { ifru_flags: 0 },
+        };
+        iface_ioctl(AF_INET, SIOCGIFFLAGS, &mut ifr)?;
+        unsafe { ifr.ifr_ifru.ifru_flags |= IFF_UP as c_short };
+        iface_ioctl(AF_INET, SIOCSIFFLAGS, &mut ifr)
+    }
+
     fn write4(&self, src: &[u8]) -> usize {
         self.write(src, AF_INET as u8)
     }
@@ -3,8 +3,10 @@
 
 use super::Error;
 use libc::*;
+use std::net::{IpAddr, Ipv4Addr};
 use std::os::unix::io::{AsRawFd, RawFd};
 
+use crate::device::offload::{VirtioNetHdr, VIRTIO_NET_HDR_LEN};
 use crate::device::Tun;
 
 pub fn errno() -> i32 {
@@ -18,6 +20,7 @@ pub fn errno_str() -> String {
 }
 
 const TUNSETIFF: u64 = 0x4004_54ca;
+const SIOCGIFINDEX: u64 = 0x8933;
 
 #[repr(C)]
 union IfrIfru {
@@ -47,10 +50,49 @@ pub struct ifreq {
     ifr_ifru: IfrIfru,
 }
 
+#[repr(C)]
+struct in6_ifreq {
+    ifr6_addr: in6_addr,
+    ifr6_prefixlen: u32,
+    ifr6_ifindex: c_int,
+}
+
+fn sockaddr_v4(addr: Ipv4Addr) -> sockaddr_in {
+    sockaddr_in {
+        sin_family: AF_INET as _,
+        sin_port: 0,
+        sin_addr: in_addr {
+            s_addr: u32::from(addr).to_be(),
+        },
+        sin_zero: [0; 8],
+    }
+}
+
+// Perform an interface ioctl on a socket of the given family
+fn iface_ioctl<T>(family: c_int, request: u64, arg: &mut T) -> Result<(), Error> {
+    let fd = match unsafe { socket(family, SOCK_DGRAM, 0) } {
+        -1 => return Err(Error::Socket(errno_str())),
+        fd => fd,
+    };
+
+    let res = match unsafe { ioctl(fd, request as _, arg as *mut T) } {
+        -1 if errno() == EPERM => Err(Error::IOCtl(format!(
+            "{}, configuring the interface requires CAP_NET_ADMIN",
+            errno_str()
+        ))),
+        -1 => Err(Error::IOCtl(errno_str())),
+        _ => Ok(()),
+    };
+
+    unsafe { close(fd) };
+    res
+}
+
 #[derive(Default, Debug)]
 pub struct TunSocket {
     fd: RawFd,
     name: String,
+    vnet_hdr: bool, // Every packet is prefixed with a virtio-net header
 }
 
 impl Drop for TunSocket {
@@ -66,7 +108,20 @@ impl AsRawFd for TunSocket {
 }
 
 impl TunSocket {
+    fn ifreq(&self, ifr_ifru: IfrIfru) -> ifreq {
+        let mut ifr = ifreq {
+            ifr_name: [0; IFNAMSIZ],
+            ifr_ifru,
+        };
+        ifr.ifr_name[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        ifr
+    }
+
     fn write(&self, buf: &[u8]) -> usize {
+        if self.vnet_hdr {
+            return self.write_offload(&VirtioNetHdr::default(), buf);
+        }
+
         match unsafe { write(self.fd, buf.as_ptr() as _, buf.len() as _) } {
             -1 => 0,
             n => n as usize,
@@ -76,16 +131,25 @@ impl TunSocket {
 
 impl Tun for TunSocket {
     fn new(name: &str) -> Result<TunSocket, Error> {
+        TunSocket::new_with_offload(name, false)
+    }
+
+    fn new_with_offload(name: &str, offload: bool) -> Result<TunSocket, Error> {
         let fd = match unsafe { open(b"/dev/net/tun\0".as_ptr() as _, O_RDWR) } {
             -1 => return Err(Error::Socket(errno_str())),
             fd => fd,
         };
 
+        let mut flags = IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE;
+        if offload {
+            flags |= IFF_VNET_HDR;
+        }
+
         let iface_name = name.as_bytes();
         let mut ifr = ifreq {
             ifr_name: [0; IFNAMSIZ],
             ifr_ifru: IfrIfru {
-                ifru_flags: (IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE) as _,
+                ifru_flags: flags as _,
             },
         };
 
@@ -100,7 +164,11 @@ impl Tun for TunSocket {
         }
 
         let name = name.to_string();
-        Ok(TunSocket { fd, name })
+        Ok(TunSocket {
+            fd,
+            name,
+            vnet_hdr: offload,
+        })
     }
 
     fn set_non_blocking(self) -> Result<TunSocket, Error> {
@@ -142,6 +210,58 @@ impl Tun for TunSocket {
         Ok(unsafe { ifr.ifr_ifru.ifru_mtu } as _)
     }
 
+    fn set_address(&self, addr: IpAddr, prefix_len: u8) -> Result<(), Error> {
+        // Bring the interface up first, the kernel discards IPv6 addresses of an interface that is down
+        let mut ifr = self.ifreq(IfrIfru { ifru_flags: 0 });
+        iface_ioctl(AF_INET, SIOCGIFFLAGS, &mut ifr)?;
+        unsafe { ifr.ifr_ifru.ifru_flags |= IFF_UP as c_short };
+        iface_ioctl(AF_INET, SIOCSIFFLAGS, &mut ifr)?;
+
+        match addr {
+            IpAddr::V4(addr) => {
+                if prefix_len > 32 {
+                    return Err(Error::InvalidConfig(format!(
+                        "Invalid prefix /{}",
+                        prefix_len
+                    )));
+                }
+
+                let mut ifr = self.ifreq(IfrIfru {
+                    ifru_addr_v4: sockaddr_v4(addr),
+                });
+                iface_ioctl(AF_INET, SIOCSIFADDR, &mut ifr)?;
+
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                ifr.ifr_ifru = IfrIfru {
+                    ifru_addr_v4: sockaddr_v4(mask.into()),
+                };
+                iface_ioctl(AF_INET, SIOCSIFNETMASK, &mut ifr)
+            }
+            IpAddr::V6(addr) => {
+                if prefix_len > 128 {
+                    return Err(Error::InvalidConfig(format!(
+                        "Invalid prefix /{}",
+                        prefix_len
+                    )));
+                }
+
+                let mut ifr = self.ifreq(IfrIfru { ifru_intval: 0 });
+                iface_ioctl(AF_INET6, SIOCGIFINDEX, &mut ifr)?;
+
+                let mut ifr6 = in6_ifreq {
+                    ifr6_addr: in6_addr {
+                        s6_addr: addr.octets(),
+                    },
+                    ifr6_prefixlen: prefix_len.into(),
+                    ifr6_ifindex: unsafe { ifr.ifr_ifru.ifru_intval },
+                };
+                iface_ioctl(AF_INET6, SIOCSIFADDR, &mut ifr6)
+            }
+        }
+    }
+
     fn write4(&self, src: &[u8]) -> usize {
         self.write(src)
     }
@@ -151,9 +271,53 @@ impl Tun for TunSocket {
     }
 
     fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        if self.vnet_hdr {
+            // Without TUNSETOFFLOAD the kernel never hands us GSO packets, the header is discarded
+            let mut hdr = [0u8; VIRTIO_NET_HDR_LEN];
+            let iov = [
+                iovec {
+                    iov_base: hdr.as_mut_ptr() as _,
+                    iov_len: hdr.len(),
+                },
+                iovec {
+                    iov_base: dst.as_mut_ptr() as _,
+                    iov_len: dst.len(),
+                },
+            ];
+
+            return match unsafe { readv(self.fd, iov.as_ptr(), iov.len() as _) } {
+                -1 => Err(Error::IfaceRead(errno())),
+                n if (n as usize) < VIRTIO_NET_HDR_LEN => Ok(&mut dst[..0]),
+                n => Ok(&mut dst[..n as usize - VIRTIO_NET_HDR_LEN]),
+            };
+        }
+
         match unsafe { read(self.fd, dst.as_mut_ptr() as _, dst.len()) } {
             -1 => Err(Error::IfaceRead(errno())),
             n => Ok(&mut dst[..n as usize]),
         }
     }
+
+    fn offload(&self) -> bool {
+        self.vnet_hdr
+    }
+
+    fn write_offload(&self, hdr: &VirtioNetHdr, src: &[u8]) -> usize {
+        let hdr = hdr.to_bytes();
+        let iov = [
+            iovec {
+                iov_base: hdr.as_ptr() as _,
+                iov_len: hdr.len(),
+            },
+            iovec {
+                iov_base: src.as_ptr() as _,
+                iov_len: src.len(),
+            },
+        ];
+
+        match unsafe { writev(self.fd, iov.as_ptr(), iov.len() as _) } {
+            -1 => 0,
+            n => (n as usize).saturating_sub(VIRTIO_NET_HDR_LEN),
+        }
+    }
 }
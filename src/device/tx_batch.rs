@@ -0,0 +1,166 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Batching of encapsulated packets on their way to the network. With a linger configured, data
+//! packets read from the tunnel interface in a burst are held for up to that long, then sent to
+//! their socket together, with a single sendmmsg(2) on Linux instead of a sendto(2) each.
+
+use super::MAX_UDP_SIZE;
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+/// The most packets held in a batch, a full batch is sent without waiting for the linger
+pub const MAX_TX_BATCH: usize = 64;
+
+/// Encapsulated packets waiting to be sent over the same socket, each to its destination, or
+/// over the connected socket when it has none
+pub struct TxBatch {
+    buf: Vec<u8>,
+    used: usize,
+    packets: Vec<(usize, usize, Option<SocketAddr>)>, // The offset, length and destination
+    since: Option<Instant>,                           // When the first packet was added
+}
+
+impl Default for TxBatch {
+    fn default() -> Self {
+        TxBatch {
+            buf: vec![],
+            used: 0,
+            packets: Vec::with_capacity(MAX_TX_BATCH),
+            since: None,
+        }
+    }
+}
+
+impl TxBatch {
+    pub fn new() -> TxBatch {
+        Default::default()
+    }
+
+    /// A buffer for the next packet, which is only added to the batch by a following push. The
+    /// buffer keeps its contents when the batch is taken in the meantime.
+    pub fn spare(&mut self) -> &mut [u8] {
+        if self.packets.is_empty() {
+            self.used = 0;
+        }
+        if self.buf.len() < self.used + MAX_UDP_SIZE {
+            self.buf.resize(self.used + MAX_UDP_SIZE, 0);
+        }
+        &mut self.buf[self.used..self.used + MAX_UDP_SIZE]
+    }
+
+    /// Add the first len bytes of the last spare buffer to the batch. Returns true once the
+    /// batch is full.
+    pub fn push(&mut self, len: usize, dst: Option<SocketAddr>) -> bool {
+        if self.packets.is_empty() {
+            // The batch was taken after spare, move the packet to the start of the buffer
+            self.buf.copy_within(self.used..self.used + len, 0);
+            self.used = 0;
+            self.since = Some(Instant::now());
+        }
+        self.packets.push((self.used, len, dst));
+        self.used += len;
+        self.packets.len() >= MAX_TX_BATCH
+    }
+
+    /// The first len bytes of the last spare buffer, for a packet that is sent without batching
+    pub fn staged(&self, len: usize) -> &[u8] {
+        &self.buf[self.used..self.used + len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// How much longer the first packet may wait for others to join it
+    pub fn linger_left(&self, linger: Duration) -> Duration {
+        self.since
+            .map(|since| linger.saturating_sub(since.elapsed()))
+            .unwrap_or(linger)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], Option<SocketAddr>)> {
+        self.packets
+            .iter()
+            .map(move |&(offset, len, dst)| (&self.buf[offset..offset + len], dst))
+    }
+
+    /// Empty the batch once it was sent. The last spare buffer is kept.
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.since = None;
+    }
+}
+
+/// Wait until fd is readable, for at most timeout. Returns false if the timeout elapsed first.
+pub fn wait_readable(fd: RawFd, timeout: Duration) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    #[cfg(target_os = "linux")]
+    let ready = {
+        let timeout = libc::timespec {
+            tv_sec: timeout.as_secs() as _,
+            tv_nsec: timeout.subsec_nanos() as _,
+        };
+        unsafe { libc::ppoll(&mut pollfd, 1, &timeout, std::ptr::null()) }
+    };
+    // Elsewhere the timeout is only as precise as a millisecond, rounded up
+    #[cfg(not(target_os = "linux"))]
+    let ready = unsafe {
+        let millis = (timeout.as_micros() + 999) / 1000;
+        libc::poll(&mut pollfd, 1, millis.min(i32::MAX as u128) as _)
+    };
+
+    ready > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(batch: &mut TxBatch, packet: &[u8]) {
+        batch.spare()[..packet.len()].copy_from_slice(packet);
+    }
+
+    #[test]
+    fn test_tx_batch() {
+        let dst: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let mut batch = TxBatch::new();
+        assert!(batch.is_empty());
+
+        stage(&mut batch, b"first");
+        assert!(!batch.push(5, Some(dst)));
+        stage(&mut batch, b"second");
+        assert!(!batch.push(6, None));
+        assert_eq!(
+            batch.iter().collect::<Vec<_>>(),
+            [(&b"first"[..], Some(dst)), (&b"second"[..], None)]
+        );
+
+        // A batch taken between spare and push only holds the new packet
+        stage(&mut batch, b"third");
+        batch.clear();
+        assert_eq!(batch.staged(5), b"third");
+        assert!(!batch.push(5, None));
+        assert_eq!(batch.iter().collect::<Vec<_>>(), [(&b"third"[..], None)]);
+        assert!(batch.linger_left(Duration::from_secs(60)) > Duration::from_secs(59));
+        assert_eq!(batch.linger_left(Duration::ZERO), Duration::ZERO);
+
+        for i in 1..MAX_TX_BATCH {
+            stage(&mut batch, b"more");
+            assert_eq!(batch.push(4, None), i == MAX_TX_BATCH - 1);
+        }
+        assert_eq!(batch.len(), MAX_TX_BATCH);
+        batch.clear();
+        assert!(batch.is_empty());
+    }
+}
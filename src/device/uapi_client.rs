@@ -0,0 +1,307 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A client for the configuration protocol served by `api.rs`, to configure another device from
+//! Rust without writing the text protocol by hand. With the `async-uapi` feature every request
+//! also has a variant that returns a future.
+
+use super::api::{check_set_block, ConfigError, SOCK_DIR};
+use super::{make_array, AllowedIP};
+use crate::crypto::x25519::{X25519PublicKey, X25519SecretKey};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    /// The configuration passed to `set` is invalid, it was not sent
+    Config(ConfigError),
+    /// The device answered with a non zero errno
+    Errno(i32),
+    /// The response of the device could not be parsed
+    Malformed(String),
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(err: std::io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+/// The configuration and state of a device, as returned by `get`
+#[derive(Debug, Default)]
+pub struct DeviceState {
+    pub private_key: Option<X25519SecretKey>,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    /// Any other key of the device, such as the extensions of boringtun, in order
+    pub other: Vec<(String, String)>,
+    pub peers: Vec<PeerState>,
+}
+
+#[derive(Debug)]
+pub struct PeerState {
+    pub public_key: X25519PublicKey,
+    pub preshared_key: Option<[u8; 32]>,
+    pub endpoint: Option<SocketAddr>,
+    pub persistent_keepalive_interval: Option<u16>,
+    pub allowed_ips: Vec<AllowedIP>,
+    /// Time of the last handshake since the epoch
+    pub last_handshake: Option<Duration>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Any other key of the peer, in order
+    pub other: Vec<(String, String)>,
+}
+
+impl PeerState {
+    fn new(public_key: X25519PublicKey) -> PeerState {
+        PeerState {
+            public_key,
+            preshared_key: None,
+            endpoint: None,
+            persistent_keepalive_interval: None,
+            allowed_ips: vec![],
+            last_handshake: None,
+            rx_bytes: 0,
+            tx_bytes: 0,
+            other: vec![],
+        }
+    }
+}
+
+/// A client for the configuration socket of a device. Every request is made on a connection of
+/// its own, as the device closes the connection once it answered.
+#[derive(Debug, Clone)]
+pub struct UapiClient {
+    path: PathBuf,
+}
+
+impl UapiClient {
+    /// A client for the socket at path, which must exist
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<UapiClient, ClientError> {
+        let path = path.as_ref().to_owned();
+        if !std::fs::metadata(&path)?.file_type().is_socket() {
+            return Err(ClientError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "not a socket",
+            )));
+        }
+        Ok(UapiClient { path })
+    }
+
+    /// A client for the socket of the interface name, in the standard directory
+    pub fn connect_interface(name: &str) -> Result<UapiClient, ClientError> {
+        UapiClient::connect(format!("{}/{}.sock", SOCK_DIR, name))
+    }
+
+    /// The configuration and state of the device
+    pub fn get(&self) -> Result<DeviceState, ClientError> {
+        parse_get(&self.request("get=1\n\n")?)
+    }
+
+    /// Apply config, key=value lines in the format of a set command. The configuration is
+    /// checked like the device checks it before it is sent, so a malformed one reports the line
+    /// at fault. The device applies all of it or, if it refuses a line, none of it.
+    pub fn set(&self, config: &str) -> Result<(), ClientError> {
+        let lines: Vec<String> = config.trim_end().lines().map(str::to_owned).collect();
+        check_set_block(&lines).map_err(ClientError::Config)?;
+
+        let response = self.request(&format!("set=1\n{}\n\n", lines.join("\n")))?;
+        match response.lines().next() {
+            Some(line) => parse_errno(line),
+            None => Err(ClientError::Malformed("empty response".to_owned())),
+        }
+    }
+
+    fn request(&self, request: &str) -> Result<String, ClientError> {
+        let mut stream = UnixStream::connect(&self.path)?;
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+}
+
+fn parse_errno(line: &str) -> Result<(), ClientError> {
+    match line.strip_prefix("errno=").map(str::parse::<i32>) {
+        Some(Ok(0)) => Ok(()),
+        Some(Ok(errno)) => Err(ClientError::Errno(errno)),
+        _ => Err(ClientError::Malformed(line.to_owned())),
+    }
+}
+
+// Parse the response to a get command, up to and including the errno line
+fn parse_get(response: &str) -> Result<DeviceState, ClientError> {
+    let mut state = DeviceState::default();
+    let malformed = |line: &str| ClientError::Malformed(line.to_owned());
+
+    for line in response.lines() {
+        let (key, val) = match line.split_once('=') {
+            Some(pair) => pair,
+            None => return Err(malformed(line)),
+        };
+        if key == "errno" {
+            return parse_errno(line).map(|_| state);
+        }
+        if key == "public_key" {
+            let key = X25519PublicKey::from_hex(val).map_err(|_| malformed(line))?;
+            state.peers.push(PeerState::new(key));
+            continue;
+        }
+
+        match state.peers.last_mut() {
+            None => match key {
+                "private_key" => {
+                    state.private_key =
+                        Some(X25519SecretKey::from_hex(val).map_err(|_| malformed(line))?)
+                }
+                "listen_port" => {
+                    state.listen_port = Some(val.parse().map_err(|_| malformed(line))?)
+                }
+                "fwmark" => state.fwmark = Some(val.parse().map_err(|_| malformed(line))?),
+                _ => state.other.push((key.to_owned(), val.to_owned())),
+            },
+            Some(peer) => match key {
+                "preshared_key" => {
+                    let key = X25519PublicKey::from_hex(val).map_err(|_| malformed(line))?;
+                    peer.preshared_key = Some(make_array(key.as_bytes()));
+                }
+                "endpoint" => peer.endpoint = Some(val.parse().map_err(|_| malformed(line))?),
+                "persistent_keepalive_interval" => {
+                    peer.persistent_keepalive_interval =
+                        Some(val.parse().map_err(|_| malformed(line))?)
+                }
+                "allowed_ip" => peer
+                    .allowed_ips
+                    .push(val.parse().map_err(|_| malformed(line))?),
+                "last_handshake_time_sec" => {
+                    let secs = val.parse().map_err(|_| malformed(line))?;
+                    peer.last_handshake = Some(Duration::from_secs(secs));
+                }
+                "last_handshake_time_nsec" => {
+                    let nanos = val.parse().map_err(|_| malformed(line))?;
+                    let secs = peer.last_handshake.unwrap_or_default();
+                    peer.last_handshake = Some(secs + Duration::from_nanos(nanos));
+                }
+                "rx_bytes" => peer.rx_bytes = val.parse().map_err(|_| malformed(line))?,
+                "tx_bytes" => peer.tx_bytes = val.parse().map_err(|_| malformed(line))?,
+                _ => peer.other.push((key.to_owned(), val.to_owned())),
+            },
+        }
+    }
+
+    Err(ClientError::Malformed("missing errno".to_owned()))
+}
+
+#[cfg(feature = "async-uapi")]
+pub use self::nonblocking::Request;
+
+#[cfg(feature = "async-uapi")]
+mod nonblocking {
+    use parking_lot::Mutex;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Waker};
+
+    struct Shared<T> {
+        result: Option<T>,
+        waker: Option<Waker>,
+    }
+
+    /// A request in progress on a thread of its own, so it can be awaited on any executor
+    pub struct Request<T> {
+        shared: Arc<Mutex<Shared<T>>>,
+    }
+
+    impl<T: Send + 'static> Request<T> {
+        pub(super) fn spawn<F: FnOnce() -> T + Send + 'static>(f: F) -> Request<T> {
+            let shared = Arc::new(Mutex::new(Shared {
+                result: None,
+                waker: None,
+            }));
+            let done = Arc::clone(&shared);
+            std::thread::spawn(move || {
+                let result = f();
+                let mut done = done.lock();
+                done.result = Some(result);
+                if let Some(waker) = done.waker.take() {
+                    waker.wake();
+                }
+            });
+            Request { shared }
+        }
+    }
+
+    impl<T> Future for Request<T> {
+        type Output = T;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+            let mut shared = self.shared.lock();
+            match shared.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    shared.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async-uapi")]
+impl UapiClient {
+    /// Like `get`, without blocking the caller
+    pub fn get_async(&self) -> Request<Result<DeviceState, ClientError>> {
+        let client = self.clone();
+        Request::spawn(move || client.get())
+    }
+
+    /// Like `set`, without blocking the caller
+    pub fn set_async(&self, config: &str) -> Request<Result<(), ClientError>> {
+        let (client, config) = (self.clone(), config.to_owned());
+        Request::spawn(move || client.set(&config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_get() {
+        let key = X25519SecretKey::new().public_key();
+        let response = format!(
+            "listen_port=51820\ndf=on\npublic_key={}\npeer_id=1\nendpoint=192.0.2.1:51820\n\
+             allowed_ip=10.0.0.0/8\nlast_handshake_time_sec=2\nlast_handshake_time_nsec=5\n\
+             rx_bytes=10\ntx_bytes=20\nerrno=0\n\n",
+            hex::encode(key.as_bytes())
+        );
+        let state = parse_get(&response).unwrap();
+        assert_eq!(state.listen_port, Some(51820));
+        assert!(state.private_key.is_none());
+        assert_eq!(state.other, [("df".to_owned(), "on".to_owned())]);
+
+        let peer = &state.peers[0];
+        assert_eq!(peer.public_key, key);
+        assert_eq!(peer.endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+        assert_eq!(peer.allowed_ips[0].cidr, 8);
+        assert_eq!(peer.last_handshake, Some(Duration::new(2, 5)));
+        assert_eq!((peer.rx_bytes, peer.tx_bytes), (10, 20));
+        assert_eq!(peer.other, [("peer_id".to_owned(), "1".to_owned())]);
+
+        assert!(matches!(
+            parse_get("errno=5\n\n"),
+            Err(ClientError::Errno(5))
+        ));
+        assert!(matches!(
+            parse_get("listen_port=x\nerrno=0\n"),
+            Err(ClientError::Malformed(_))
+        ));
+    }
+}
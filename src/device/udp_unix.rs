@@ -3,11 +3,43 @@
 
 use super::{errno, errno_str, Error};
 use libc::*;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::os::unix::io::{AsRawFd, RawFd};
 
+#[cfg(target_os = "linux")]
+use crate::device::tx_batch::{TxBatch, MAX_TX_BATCH};
 use crate::device::Sock;
 
+// Missing from libc for these targets
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const IP_DONTFRAG: c_int = 28;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const IPV6_DONTFRAG: c_int = 62;
+
+/// The optional socket features the running kernel accepts, see `UDPSocket::supported_features`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SocketFeatures {
+    /// Segmentation offload of sent datagrams with UDP_SEGMENT
+    pub gso: bool,
+    /// Coalescing of received datagrams with UDP_GRO
+    pub gro: bool,
+    /// Marking sent packets with SO_MARK, the `fwmark` option
+    pub fwmark: bool,
+    /// Pacing with SO_MAX_PACING_RATE, the `pacing_rate` option
+    pub pacing: bool,
+    /// Binding to an interface with SO_BINDTODEVICE
+    pub bind_to_device: bool,
+    /// Binding to addresses that are not assigned yet, the `freebind` option
+    pub freebind: bool,
+    /// Sharing the listen port with SO_REUSEPORT, see `DeviceConfig::listen_sockets`
+    pub reuse_port: bool,
+    /// Control of the DF bit, the `df` option
+    pub dont_fragment: bool,
+}
+
+// A received message: its origin, contents, ECN codepoint and GRO segment size
+type Received<'a> = (Option<SocketAddr>, &'a mut [u8], u8, usize);
+
 /// Receives and sends UDP packets over the network
 #[derive(Debug)]
 pub struct UDPSocket {
@@ -16,13 +48,55 @@ pub struct UDPSocket {
 }
 
 impl UDPSocket {
-    fn bind4(self, port: u16) -> Result<UDPSocket, Error> {
+    fn set_int_option(&self, level: c_int, option: c_int, value: c_int) -> Result<(), Error> {
+        match unsafe {
+            setsockopt(
+                self.fd,
+                level,
+                option,
+                &value as *const c_int as *const c_void,
+                std::mem::size_of_val(&value) as _,
+            )
+        } {
+            -1 => Err(Error::SetSockOpt(errno_str())),
+            _ => Ok(()),
+        }
+    }
+
+    fn get_int_option(&self, level: c_int, option: c_int) -> Result<c_int, Error> {
+        let mut value: c_int = 0;
+        let mut len = std::mem::size_of_val(&value) as socklen_t;
+        match unsafe {
+            getsockopt(
+                self.fd,
+                level,
+                option,
+                &mut value as *mut c_int as *mut c_void,
+                &mut len,
+            )
+        } {
+            -1 => Err(Error::GetSockOpt(errno_str())),
+            _ => Ok(value),
+        }
+    }
+
+    // The option for the TTL of IPv4 packets, or the hop limit of IPv6 packets
+    fn ttl_option(&self) -> (c_int, c_int) {
+        match self.version {
+            4 => (IPPROTO_IP, IP_TTL),
+            _ => (IPPROTO_IPV6, IPV6_UNICAST_HOPS),
+        }
+    }
+
+    fn bind4(self, ip: Ipv4Addr, port: u16) -> Result<UDPSocket, Error> {
         let addr = sockaddr_in {
             #[cfg(any(target_os = "macos", target_os = "ios"))]
             sin_len: std::mem::size_of::<sockaddr_in>() as u8,
             sin_family: AF_INET as _,
             sin_port: port.to_be(),
-            sin_addr: in_addr { s_addr: INADDR_ANY },
+            sin_addr: in_addr {
+                s_addr: u32::from(ip).to_be(),
+            },
             sin_zero: [0; 8],
         };
 
@@ -38,10 +112,11 @@ impl UDPSocket {
         }
     }
 
-    fn bind6(self, port: u16) -> Result<UDPSocket, Error> {
+    fn bind6(self, ip: Ipv6Addr, port: u16) -> Result<UDPSocket, Error> {
         let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
         addr.sin6_family = AF_INET6 as _;
         addr.sin6_port = port.to_be();
+        addr.sin6_addr.s6_addr = ip.octets();
 
         match unsafe {
             bind(
@@ -214,6 +289,141 @@ impl UDPSocket {
         Ok((SocketAddr::V4(origin), &mut buf[..n as usize]))
     }
 
+    // Send buf with the given ECN codepoint in the outer IP header, to dst or to the connected
+    // address
+    // Fill addr with dst, returning the length of the address, 0 without a destination
+    fn write_sockaddr(addr: &mut sockaddr_storage, dst: Option<SocketAddr>) -> usize {
+        match dst {
+            None => 0,
+            Some(SocketAddr::V4(dst)) => {
+                let sin = unsafe { &mut *(addr as *mut sockaddr_storage as *mut sockaddr_in) };
+                #[cfg(any(target_os = "macos", target_os = "ios"))]
+                {
+                    sin.sin_len = std::mem::size_of::<sockaddr_in>() as _;
+                }
+                sin.sin_family = AF_INET as _;
+                sin.sin_port = dst.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*dst.ip()).to_be();
+                std::mem::size_of::<sockaddr_in>()
+            }
+            Some(SocketAddr::V6(dst)) => {
+                let sin6 = unsafe { &mut *(addr as *mut sockaddr_storage as *mut sockaddr_in6) };
+                sin6.sin6_family = AF_INET6 as _;
+                sin6.sin6_port = dst.port().to_be();
+                sin6.sin6_addr.s6_addr = dst.ip().octets();
+                std::mem::size_of::<sockaddr_in6>()
+            }
+        }
+    }
+
+    fn sendmsg_ecn(&self, buf: &[u8], dst: Option<SocketAddr>, ecn: u8) -> usize {
+        let mut addr: sockaddr_storage = unsafe { std::mem::zeroed() };
+        let addr_len = UDPSocket::write_sockaddr(&mut addr, dst);
+
+        let (level, kind) = match self.version {
+            4 => (IPPROTO_IP, IP_TOS),
+            _ => (IPPROTO_IPV6, IPV6_TCLASS),
+        };
+
+        let mut iov = iovec {
+            iov_base: buf.as_ptr() as _,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; 4]; // Aligned room for a single int sized control message
+        let mut hdr: msghdr = unsafe { std::mem::zeroed() };
+        if addr_len > 0 {
+            hdr.msg_name = &mut addr as *mut sockaddr_storage as _;
+            hdr.msg_namelen = addr_len as _;
+        }
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr() as _;
+        hdr.msg_controllen = unsafe { CMSG_SPACE(std::mem::size_of::<c_int>() as _) } as _;
+
+        unsafe {
+            let cmsg = CMSG_FIRSTHDR(&hdr);
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = kind;
+            (*cmsg).cmsg_len = CMSG_LEN(std::mem::size_of::<c_int>() as _) as _;
+            std::ptr::write_unaligned(CMSG_DATA(cmsg) as *mut c_int, c_int::from(ecn & 0x3));
+        }
+
+        match unsafe { sendmsg(self.fd, &hdr, 0) } {
+            -1 => 0,
+            n => n as usize,
+        }
+    }
+
+    // Receive a message along with the ECN codepoint of its outer IP header, which is only
+    // reported after set_recv_ecn, and the segment size of coalesced datagrams, which is zero
+    // for a datagram that was not coalesced
+    fn recvmsg_ecn<'a>(&self, buf: &'a mut [u8]) -> Result<Received<'a>, Error> {
+        let mut addr: sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut iov = iovec {
+            iov_base: buf.as_mut_ptr() as _,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; 8];
+        let mut hdr: msghdr = unsafe { std::mem::zeroed() };
+        hdr.msg_name = &mut addr as *mut sockaddr_storage as _;
+        hdr.msg_namelen = std::mem::size_of::<sockaddr_storage>() as _;
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr() as _;
+        hdr.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        let n = unsafe { recvmsg(self.fd, &mut hdr, 0) };
+        if n == -1 {
+            return Err(Error::UDPRead(errno()));
+        }
+
+        let (mut ecn, mut segment_size) = (0, 0);
+        unsafe {
+            let mut cmsg = CMSG_FIRSTHDR(&hdr);
+            while !cmsg.is_null() {
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    // Linux reports the TOS byte as IP_TOS, BSDs as IP_RECVTOS
+                    (IPPROTO_IP, IP_TOS) | (IPPROTO_IP, IP_RECVTOS) => {
+                        ecn = *CMSG_DATA(cmsg) & 0x3;
+                    }
+                    (IPPROTO_IPV6, IPV6_TCLASS) => {
+                        ecn =
+                            (std::ptr::read_unaligned(CMSG_DATA(cmsg) as *const c_int) & 0x3) as u8;
+                    }
+                    #[cfg(target_os = "linux")]
+                    (IPPROTO_UDP, UDP_GRO) => {
+                        segment_size =
+                            std::ptr::read_unaligned(CMSG_DATA(cmsg) as *const c_int) as usize;
+                    }
+                    _ => {}
+                }
+                cmsg = CMSG_NXTHDR(&hdr, cmsg);
+            }
+        }
+
+        let origin = match c_int::from(addr.ss_family) {
+            AF_INET => {
+                let sin = unsafe { &*(&addr as *const sockaddr_storage as *const sockaddr_in) };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    std::net::Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            AF_INET6 => {
+                let sin6 = unsafe { &*(&addr as *const sockaddr_storage as *const sockaddr_in6) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    std::net::Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    0,
+                    0,
+                )))
+            }
+            _ => None,
+        };
+
+        Ok((origin, &mut buf[..n as usize], ecn, segment_size))
+    }
+
     fn write_fd(fd: RawFd, src: &[u8]) -> usize {
         match unsafe { send(fd, &src[0] as *const u8 as _, src.len(), 0) } {
             -1 => 0,
@@ -223,6 +433,43 @@ impl UDPSocket {
 }
 
 /// Socket is closed when it goes out of scope
+impl UDPSocket {
+    /// Probe which optional features the kernel accepts, by setting each option to a harmless
+    /// value on a throwaway socket. As the calls are made with the privileges of the process,
+    /// fwmark is only reported with CAP_NET_ADMIN. Features that are not implemented on the
+    /// platform are never reported.
+    pub fn supported_features() -> SocketFeatures {
+        let sock = match UDPSocket::new() {
+            Ok(sock) => sock,
+            Err(_) => return SocketFeatures::default(),
+        };
+        #[cfg(target_os = "linux")]
+        let features = SocketFeatures {
+            gso: sock.set_int_option(IPPROTO_UDP, UDP_SEGMENT, 0).is_ok(),
+            gro: sock.set_int_option(IPPROTO_UDP, UDP_GRO, 0).is_ok(),
+            fwmark: sock.set_fwmark(0).is_ok(),
+            pacing: sock.set_pacing_rate(u64::MAX).is_ok(),
+            bind_to_device: unsafe {
+                // An empty name removes the binding
+                setsockopt(sock.fd, SOL_SOCKET, SO_BINDTODEVICE, std::ptr::null(), 0) != -1
+            },
+            freebind: sock.set_freebind(false).is_ok(),
+            dont_fragment: sock.set_dont_fragment(true).is_ok(),
+            reuse_port: false,
+        };
+        // The option is not supported by any of these platforms, or is ignored like fwmark
+        #[cfg(not(target_os = "linux"))]
+        let features = SocketFeatures {
+            dont_fragment: sock.set_dont_fragment(true).is_ok(),
+            ..Default::default()
+        };
+        SocketFeatures {
+            reuse_port: sock.set_reuse_port().is_ok(),
+            ..features
+        }
+    }
+}
+
 impl Drop for UDPSocket {
     fn drop(&mut self) {
         unsafe { close(self.fd) };
@@ -255,10 +502,22 @@ impl Sock for UDPSocket {
     /// Bind the socket to a local port
     fn bind(self, port: u16) -> Result<UDPSocket, Error> {
         if self.version == 6 {
-            return self.bind6(port);
+            return self.bind6(Ipv6Addr::UNSPECIFIED, port);
         }
 
-        self.bind4(port)
+        self.bind4(Ipv4Addr::UNSPECIFIED, port)
+    }
+
+    /// Bind the socket to a local address and port
+    fn bind_addr(self, addr: SocketAddr) -> Result<UDPSocket, Error> {
+        match addr {
+            SocketAddr::V4(addr) if self.version == 4 => self.bind4(*addr.ip(), addr.port()),
+            SocketAddr::V6(addr) if self.version == 6 => self.bind6(*addr.ip(), addr.port()),
+            _ => Err(Error::Bind(format!(
+                "{} does not match the IPv{} socket",
+                addr, self.version
+            ))),
+        }
     }
 
     /// Connect a socket to a remote address, must call bind prior to connect
@@ -301,6 +560,23 @@ impl Sock for UDPSocket {
         }
     }
 
+    /// Set the SO_REUSEPORT option, so the kernel load balances flows across the sockets bound to
+    /// the same port
+    fn set_reuse_port(self) -> Result<UDPSocket, Error> {
+        match unsafe {
+            setsockopt(
+                self.fd,
+                SOL_SOCKET,
+                SO_REUSEPORT,
+                &1u32 as *const u32 as *const c_void,
+                std::mem::size_of::<u32>() as u32,
+            )
+        } {
+            -1 => Err(Error::SetSockOpt(errno_str())),
+            _ => Ok(self),
+        }
+    }
+
     #[cfg(target_os = "linux")]
     /// Set the mark on all packets sent by this socket using SO_MARK
     /// Only available on Linux
@@ -324,6 +600,94 @@ impl Sock for UDPSocket {
         Ok(())
     }
 
+    #[cfg(target_os = "linux")]
+    /// Pace the packets sent by this socket using SO_MAX_PACING_RATE
+    /// Only available on Linux, and only effective with the fq qdisc
+    fn set_pacing_rate(&self, bytes_per_sec: u64) -> Result<(), Error> {
+        match unsafe {
+            setsockopt(
+                self.fd,
+                SOL_SOCKET,
+                SO_MAX_PACING_RATE,
+                &bytes_per_sec as *const u64 as *const c_void,
+                std::mem::size_of_val(&bytes_per_sec) as _,
+            )
+        } {
+            -1 => Err(Error::SetSockOpt(errno_str())),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    /// Set the priority of all packets sent by this socket using SO_PRIORITY
+    /// Only available on Linux, priorities above 6 require CAP_NET_ADMIN
+    fn set_priority(&self, prio: u32) -> Result<(), Error> {
+        self.set_int_option(SOL_SOCKET, SO_PRIORITY, prio as c_int)
+    }
+
+    /// Attach a classic BPF program with SO_ATTACH_FILTER, only available on Linux
+    #[cfg(target_os = "linux")]
+    fn attach_bpf_filter(&self, prog: &crate::device::bpf::BpfProgram) -> Result<(), Error> {
+        let fprog = prog.as_fprog();
+        match unsafe {
+            setsockopt(
+                self.fd,
+                SOL_SOCKET,
+                SO_ATTACH_FILTER,
+                &fprog as *const _ as *const c_void,
+                std::mem::size_of_val(&fprog) as _,
+            )
+        } {
+            -1 => Err(Error::SetSockOpt(errno_str())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Set the TTL using IP_TTL, or the hop limit using IPV6_UNICAST_HOPS
+    fn set_ttl(&self, ttl: u32) -> Result<(), Error> {
+        let (level, option) = self.ttl_option();
+        self.set_int_option(level, option, ttl as c_int)
+    }
+
+    fn ttl(&self) -> Result<u32, Error> {
+        let (level, option) = self.ttl_option();
+        Ok(self.get_int_option(level, option)? as u32)
+    }
+
+    /// Set the DF bit using IP_MTU_DISCOVER or IPV6_MTU_DISCOVER, clearing it also stops the
+    /// kernel from doing path MTU discovery
+    #[cfg(target_os = "linux")]
+    fn set_dont_fragment(&self, df: bool) -> Result<(), Error> {
+        let (level, option, value) = match (self.version, df) {
+            (4, true) => (IPPROTO_IP, IP_MTU_DISCOVER, IP_PMTUDISC_DO),
+            (4, false) => (IPPROTO_IP, IP_MTU_DISCOVER, IP_PMTUDISC_DONT),
+            (_, true) => (IPPROTO_IPV6, IPV6_MTU_DISCOVER, IPV6_PMTUDISC_DO),
+            (_, false) => (IPPROTO_IPV6, IPV6_MTU_DISCOVER, IPV6_PMTUDISC_DONT),
+        };
+        self.set_int_option(level, option, value)
+    }
+
+    /// Bind to addresses that are not assigned yet using IP_FREEBIND or IPV6_FREEBIND, must be
+    /// set before bind
+    #[cfg(target_os = "linux")]
+    fn set_freebind(&self, on: bool) -> Result<(), Error> {
+        let (level, option) = match self.version {
+            4 => (IPPROTO_IP, IP_FREEBIND),
+            _ => (IPPROTO_IPV6, IPV6_FREEBIND),
+        };
+        self.set_int_option(level, option, on as c_int)
+    }
+
+    /// Set the DF bit using IP_DONTFRAG or IPV6_DONTFRAG
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    fn set_dont_fragment(&self, df: bool) -> Result<(), Error> {
+        let (level, option) = match self.version {
+            4 => (IPPROTO_IP, IP_DONTFRAG),
+            _ => (IPPROTO_IPV6, IPV6_DONTFRAG),
+        };
+        self.set_int_option(level, option, df as c_int)
+    }
+
     /// Query the local port the socket is bound to
     /// # Panics
     /// If socket is IPv6
@@ -370,8 +734,166 @@ impl Sock for UDPSocket {
         UDPSocket::write_fd(self.fd, src)
     }
 
+    /// Report the ECN codepoint of received packets, using IP_RECVTOS or IPV6_RECVTCLASS
+    fn set_recv_ecn(&self) -> Result<(), Error> {
+        let (level, option) = match self.version {
+            4 => (IPPROTO_IP, IP_RECVTOS),
+            _ => (IPPROTO_IPV6, IPV6_RECVTCLASS),
+        };
+
+        match unsafe {
+            setsockopt(
+                self.fd,
+                level,
+                option,
+                &1 as *const c_int as *const c_void,
+                std::mem::size_of::<c_int>() as _,
+            )
+        } {
+            -1 => Err(Error::SetSockOpt(errno_str())),
+            _ => Ok(()),
+        }
+    }
+
+    fn sendto_ecn(&self, buf: &[u8], dst: SocketAddr, ecn: u8) -> usize {
+        self.sendmsg_ecn(buf, Some(dst), ecn)
+    }
+
+    fn write_ecn(&self, buf: &[u8], ecn: u8) -> usize {
+        self.sendmsg_ecn(buf, None, ecn)
+    }
+
+    fn recvfrom_ecn<'a>(&self, buf: &'a mut [u8]) -> Result<(SocketAddr, &'a mut [u8], u8), Error> {
+        match self.recvmsg_ecn(buf)? {
+            (Some(addr), packet, ecn, _) => Ok((addr, packet, ecn)),
+            (None, _, _, _) => Err(Error::UDPRead(EAFNOSUPPORT)),
+        }
+    }
+
+    fn read_ecn<'a>(&self, buf: &'a mut [u8]) -> Result<(&'a mut [u8], u8), Error> {
+        let (_, packet, ecn, _) = self.recvmsg_ecn(buf)?;
+        Ok((packet, ecn))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_gro(&self) -> Result<(), Error> {
+        self.set_int_option(IPPROTO_UDP, UDP_GRO, 1)
+    }
+
+    /// Always receives with recvmsg, for the segment size in the UDP_GRO control message. The
+    /// ECN codepoint reads as not ECT until set_recv_ecn.
+    #[cfg(target_os = "linux")]
+    fn recv_coalesced<'a>(
+        &self,
+        buf: &'a mut [u8],
+        _with_ecn: bool,
+    ) -> Result<(SocketAddr, &'a mut [u8], usize, u8), Error> {
+        match self.recvmsg_ecn(buf)? {
+            (Some(addr), packet, ecn, segment_size) => {
+                let segment_size = match segment_size {
+                    0 => packet.len(),
+                    n => n,
+                };
+                Ok((addr, packet, segment_size, ecn))
+            }
+            (None, _, _, _) => Err(Error::UDPRead(EAFNOSUPPORT)),
+        }
+    }
+
+    /// Sends the whole batch with sendmmsg, which only needs more than one call when a packet
+    /// fails. Packets are dropped when the socket buffer is full, as with sendto.
+    #[cfg(target_os = "linux")]
+    fn send_batch(&self, batch: &TxBatch) -> (usize, usize) {
+        let mut addrs: [sockaddr_storage; MAX_TX_BATCH] = unsafe { std::mem::zeroed() };
+        let mut iovs: [iovec; MAX_TX_BATCH] = unsafe { std::mem::zeroed() };
+        let mut hdrs: [mmsghdr; MAX_TX_BATCH] = unsafe { std::mem::zeroed() };
+        let mut n = 0;
+        for (((packet, dst), addr), (iov, hdr)) in batch
+            .iter()
+            .zip(addrs.iter_mut())
+            .zip(iovs.iter_mut().zip(hdrs.iter_mut()))
+        {
+            iov.iov_base = packet.as_ptr() as _;
+            iov.iov_len = packet.len();
+            let addr_len = UDPSocket::write_sockaddr(addr, dst);
+            if addr_len > 0 {
+                hdr.msg_hdr.msg_name = addr as *mut sockaddr_storage as _;
+                hdr.msg_hdr.msg_namelen = addr_len as _;
+            }
+            hdr.msg_hdr.msg_iov = iov;
+            hdr.msg_hdr.msg_iovlen = 1;
+            n += 1;
+        }
+
+        let (mut next, mut sent, mut calls) = (0, 0, 0);
+        while next < n {
+            calls += 1;
+            match unsafe { sendmmsg(self.fd, hdrs[next..n].as_mut_ptr(), (n - next) as _, 0) } {
+                -1 => match errno() {
+                    EINTR => {}
+                    EAGAIN | ENOBUFS => break,
+                    _ => next += 1, // Skip the packet that failed, such as one to an unreachable host
+                },
+                k => {
+                    next += k as usize;
+                    sent += k as usize;
+                }
+            }
+        }
+        (sent, calls)
+    }
+
     /// Calls shutdown on a connected socket. This will trigger an EOF in the event queue.
     fn shutdown(&self) {
         unsafe { shutdown(self.fd, SHUT_RDWR) };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl() {
+        let sock4 = UDPSocket::new().unwrap();
+        sock4.set_ttl(7).unwrap();
+        assert_eq!(sock4.ttl().unwrap(), 7);
+
+        let sock6 = UDPSocket::new6().unwrap();
+        sock6.set_ttl(9).unwrap();
+        assert_eq!(sock6.ttl().unwrap(), 9);
+        // The hop limit is a separate option from the TTL of IPv4 packets
+        assert_ne!(sock6.get_int_option(IPPROTO_IP, IP_TTL).ok(), Some(9));
+    }
+
+    #[test]
+    fn test_supported_features() {
+        let features = UDPSocket::supported_features();
+        let sock = || UDPSocket::new().unwrap();
+        assert_eq!(
+            features.dont_fragment,
+            sock().set_dont_fragment(false).is_ok()
+        );
+        assert_eq!(features.reuse_port, sock().set_reuse_port().is_ok());
+
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(features.fwmark, sock().set_fwmark(1).is_ok());
+            assert_eq!(features.pacing, sock().set_pacing_rate(1 << 20).is_ok());
+            assert_eq!(features.freebind, sock().set_freebind(true).is_ok());
+            let segment = sock().set_int_option(IPPROTO_UDP, UDP_SEGMENT, 1280);
+            assert_eq!(features.gso, segment.is_ok());
+            let gro = sock().set_int_option(IPPROTO_UDP, UDP_GRO, 1);
+            assert_eq!(features.gro, gro.is_ok());
+            let lo = sock();
+            let bound = unsafe {
+                setsockopt(lo.fd, SOL_SOCKET, SO_BINDTODEVICE, b"lo".as_ptr() as _, 2) != -1
+            };
+            assert_eq!(features.bind_to_device, bound);
+            // Every kernel the device runs on has these
+            assert!(features.freebind && features.dont_fragment && features.reuse_port);
+        }
+        #[cfg(not(target_os = "linux"))]
+        assert!(!features.gso && !features.gro && !features.fwmark);
+    }
+}
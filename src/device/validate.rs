@@ -0,0 +1,74 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Sanity checks of decapsulated packets, to drop malformed packets before they are written to
+//! the tunnel interface, where the kernel would drop them anyway
+
+use super::offload::{checksum_add, checksum_fold};
+
+const IPV4_MIN_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+
+/// Whether packet is a well formed IPv4 or IPv6 packet: its header fits the packet, its length
+/// fields match the size of the packet and, for IPv4, its header checksum is valid
+pub fn is_well_formed(packet: &[u8]) -> bool {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= IPV4_MIN_HEADER_SIZE => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+            header_len >= IPV4_MIN_HEADER_SIZE
+                && header_len <= total_len
+                && total_len == packet.len()
+                && checksum_fold(checksum_add(0, &packet[..header_len])) == 0xffff
+        }
+        Some(6) if packet.len() >= IPV6_HEADER_SIZE => {
+            let payload_len = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+            IPV6_HEADER_SIZE + payload_len == packet.len()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_packet(len: usize) -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        packet.resize(len, 0);
+        packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        let csum = !checksum_fold(checksum_add(0, &packet[..IPV4_MIN_HEADER_SIZE]));
+        packet[10..12].copy_from_slice(&csum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_is_well_formed() {
+        assert!(is_well_formed(&ipv4_packet(28)));
+        assert!(!is_well_formed(&ipv4_packet(28)[..27]));
+        assert!(!is_well_formed(&[]));
+        assert!(!is_well_formed(&[0x45; 8]));
+
+        // Header length below the minimum, or beyond the total length
+        let mut packet = ipv4_packet(28);
+        packet[0] = 0x44;
+        assert!(!is_well_formed(&packet));
+        let mut packet = ipv4_packet(28);
+        packet[0] = 0x4f;
+        assert!(!is_well_formed(&packet));
+
+        let mut packet = ipv4_packet(28);
+        packet[8] -= 1; // The TTL, which the checksum covers
+        assert!(!is_well_formed(&packet));
+
+        let mut packet = vec![0x60, 0, 0, 0, 0, 8, 17, 64];
+        packet.resize(48, 0);
+        assert!(is_well_formed(&packet));
+        packet[5] = 9;
+        assert!(!is_well_formed(&packet));
+        packet[0] = 0x50;
+        assert!(!is_well_formed(&packet));
+    }
+}
@@ -0,0 +1,676 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A transport that carries WireGuard datagrams to a relay inside WebSocket binary messages, for
+//! networks that only allow HTTP egress. Each message carries exactly one datagram.
+//!
+//! The device side of the transport is one end of a datagram socket pair, so the file descriptor
+//! registered with the event loop stays the same while a background thread keeps the WebSocket
+//! connection alive, answers pings and reconnects to the relay when the connection drops.
+//!
+//! The relay is set with `DeviceConfig::websocket_relay`. All the transports a device opens share
+//! a single connection to it, so peers never get connected sockets of their own.
+
+use super::{errno, DeviceConfig, Error};
+use crate::crypto::x25519::{OsRng, Rng};
+use crate::device::Sock;
+use parking_lot::Mutex;
+use ring::digest;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+const MAX_MESSAGE_SIZE: usize = (1 << 16) - 1; // The size of the largest datagram
+const MAX_HEADER_SIZE: usize = 8192;
+const PING_INTERVAL: Duration = Duration::from_secs(10); // Ping after this long without traffic
+const MAX_MISSED_PINGS: u32 = 2; // Reconnect after this many unanswered pings
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// The relay WebSocketTransport connects to
+#[derive(Debug)]
+pub struct WebSocketRelay {
+    pub addr: SocketAddr,
+    /// Value of the Host header
+    pub host: String,
+    /// The request path, i.e. "/wireguard"
+    pub path: String,
+    connection: Mutex<Weak<Connection>>, // Shared by the transports opened through the relay
+}
+
+// The connection to the relay, closed once the last transport using it is dropped
+#[derive(Debug)]
+struct Connection {
+    local: UnixDatagram, // The device end of the pair, the connection thread holds the other end
+    relay: SocketAddr,   // Reported as the source of all received datagrams
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.local.shutdown(Shutdown::Both);
+    }
+}
+
+impl WebSocketRelay {
+    pub fn new(addr: SocketAddr, host: &str, path: &str) -> WebSocketRelay {
+        WebSocketRelay {
+            addr,
+            host: host.to_owned(),
+            path: path.to_owned(),
+            connection: Mutex::new(Weak::new()),
+        }
+    }
+
+    // Open a transport on the connection to the relay, connecting first if there is none
+    fn open(&self) -> Result<WebSocketTransport, Error> {
+        let mut connection = self.connection.lock();
+        let connection = match connection.upgrade() {
+            Some(connection) => connection,
+            None => {
+                let (local, remote) =
+                    UnixDatagram::pair().map_err(|e| Error::Socket(e.to_string()))?;
+                let (addr, host, path) = (self.addr, self.host.clone(), self.path.clone());
+                thread::spawn(move || run_connection(addr, &host, &path, remote));
+
+                let new = Arc::new(Connection { local, relay: addr });
+                *connection = Arc::downgrade(&new);
+                new
+            }
+        };
+
+        // Every transport has a descriptor of its own, so each can be registered with the event
+        // loop, but they all refer to the same socket
+        let local = connection
+            .local
+            .try_clone()
+            .map_err(|e| Error::Socket(e.to_string()))?;
+
+        Ok(WebSocketTransport {
+            local,
+            connection,
+            port: 0,
+        })
+    }
+}
+
+/// Sends and receives WireGuard datagrams through a WebSocket relay
+#[derive(Debug)]
+pub struct WebSocketTransport {
+    local: UnixDatagram,
+    connection: Arc<Connection>,
+    port: u16,
+}
+
+impl WebSocketTransport {
+    fn relay(config: &DeviceConfig) -> Result<&WebSocketRelay, Error> {
+        config
+            .websocket_relay
+            .as_ref()
+            .ok_or_else(|| Error::Socket("No WebSocket relay set".to_owned()))
+    }
+}
+
+impl AsRawFd for WebSocketTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.local.as_raw_fd()
+    }
+}
+
+impl Sock for WebSocketTransport {
+    // A transport can only be opened through the relay of a device
+    fn new() -> Result<WebSocketTransport, Error> {
+        Err(Error::Socket("No WebSocket relay set".to_owned()))
+    }
+
+    fn new6() -> Result<WebSocketTransport, Error> {
+        Err(Error::Socket("No WebSocket relay set".to_owned()))
+    }
+
+    fn new_with_config(config: &DeviceConfig) -> Result<WebSocketTransport, Error> {
+        WebSocketTransport::relay(config)?.open()
+    }
+
+    fn new6_with_config(config: &DeviceConfig) -> Result<WebSocketTransport, Error> {
+        WebSocketTransport::relay(config)?.open()
+    }
+
+    fn can_connect() -> bool {
+        false
+    }
+
+    // There is nothing to bind, all traffic goes through the relay
+    fn bind(mut self, port: u16) -> Result<WebSocketTransport, Error> {
+        self.port = port;
+        Ok(self)
+    }
+
+    fn connect(self, _dst: &SocketAddr) -> Result<WebSocketTransport, Error> {
+        Ok(self)
+    }
+
+    fn set_non_blocking(self) -> Result<WebSocketTransport, Error> {
+        self.local
+            .set_nonblocking(true)
+            .map_err(|e| Error::FCntl(e.to_string()))?;
+        Ok(self)
+    }
+
+    fn set_reuse(self) -> Result<WebSocketTransport, Error> {
+        Ok(self)
+    }
+
+    fn set_fwmark(&self, _mark: u32) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn port(&self) -> Result<u16, Error> {
+        Ok(self.port)
+    }
+
+    fn sendto(&self, buf: &[u8], _dst: SocketAddr) -> usize {
+        self.write(buf)
+    }
+
+    fn recvfrom<'a>(&self, buf: &'a mut [u8]) -> Result<(SocketAddr, &'a mut [u8]), Error> {
+        let packet = self.read(buf)?;
+        Ok((self.connection.relay, packet))
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        self.local.send(buf).unwrap_or(0)
+    }
+
+    fn read<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        match self.local.recv(buf) {
+            Ok(n) => Ok(&mut buf[..n]),
+            Err(e) => Err(Error::UDPRead(e.raw_os_error().unwrap_or_else(errno))),
+        }
+    }
+
+    // The connection is shared with the other transports of the device, it is closed once the
+    // last of them is dropped
+    fn shutdown(&self) {}
+}
+
+// The value of Sec-WebSocket-Accept the server must respond with for the given key
+fn accept_key(key: &str) -> String {
+    let hash = digest::digest(
+        &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, WEBSOCKET_GUID).as_bytes(),
+    );
+    base64::encode(hash.as_ref())
+}
+
+// Read an HTTP header block, up to and including the empty line
+fn read_http_header<R: Read>(stream: &mut R) -> io::Result<String> {
+    let mut header = vec![];
+    let mut byte = [0u8];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() > MAX_HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Header too long",
+            ));
+        }
+        stream.read_exact(&mut byte)?;
+        header.push(byte[0]);
+    }
+    String::from_utf8(header).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Bad header"))
+}
+
+// Find the value of a header field in an HTTP header block
+fn header_value<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.lines().find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+            _ => None,
+        }
+    })
+}
+
+// Connect to the relay and perform the WebSocket opening handshake
+fn connect_relay(addr: SocketAddr, host: &str, path: &str) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(PING_INTERVAL))?;
+
+    let mut nonce = [0u8; 16];
+    OsRng.fill(&mut nonce);
+    let key = base64::encode(nonce);
+
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        path, host, key
+    )?;
+
+    let response = read_http_header(&mut stream)?;
+    let status_ok = response.starts_with("HTTP/1.1 101 ");
+
+    if !status_ok || header_value(&response, "Sec-WebSocket-Accept") != Some(&accept_key(&key)) {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "WebSocket upgrade refused",
+        ));
+    }
+
+    Ok(stream)
+}
+
+// Write a single frame. Frames sent by the client must be masked, frames sent by the server not.
+fn write_frame<W: Write>(stream: &mut W, opcode: u8, payload: &[u8], mask: bool) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode); // FIN
+
+    let mask_bit = if mask { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => frame.push(mask_bit | len as u8),
+        len if len <= 0xffff => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    if mask {
+        let mut key = [0u8; 4];
+        OsRng.fill(&mut key);
+        frame.extend_from_slice(&key);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+    } else {
+        frame.extend_from_slice(payload);
+    }
+
+    stream.write_all(&frame)
+}
+
+#[derive(Debug)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Accumulates bytes read from a stream, so frames survive read timeouts
+#[derive(Default)]
+struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    // Read until a complete frame is available
+    fn read_frame<R: Read>(&mut self, stream: &mut R) -> io::Result<Frame> {
+        loop {
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(frame);
+            }
+
+            let mut chunk = [0u8; 4096];
+            match stream.read(&mut chunk)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    fn parse_frame(&mut self) -> io::Result<Option<Frame>> {
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let (mut len, mut offset) = (usize::from(buf[1] & 0x7f), 2);
+        if len == 126 {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            len = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+            offset = 4;
+        } else if len == 127 {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let mut len_bytes = [0u8; 8];
+            len_bytes.copy_from_slice(&buf[2..10]);
+            len = u64::from_be_bytes(len_bytes) as usize;
+            offset = 10;
+        }
+
+        if len > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame too long"));
+        }
+
+        let masked = buf[1] & 0x80 != 0;
+        let key_offset = offset;
+        if masked {
+            offset += 4;
+        }
+
+        if buf.len() < offset + len {
+            return Ok(None);
+        }
+
+        let mut payload = buf[offset..offset + len].to_vec();
+        if masked {
+            let key = &buf[key_offset..key_offset + 4];
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= key[i % 4];
+            }
+        }
+
+        let frame = Frame {
+            fin: buf[0] & 0x80 != 0,
+            opcode: buf[0] & 0x0f,
+            payload,
+        };
+
+        self.buf.drain(..offset + len);
+        Ok(Some(frame))
+    }
+}
+
+// Maintains the connection to the relay for the lifetime of the transports using it. Datagrams
+// from the device are forwarded by a separate thread, to the connection that is current at the
+// time.
+fn run_connection(addr: SocketAddr, host: &str, path: &str, pipe: UnixDatagram) {
+    let current: Arc<Mutex<Option<TcpStream>>> = Arc::new(Mutex::new(None));
+    let closed = Arc::new(AtomicBool::new(false));
+
+    let pipe_out = match pipe.try_clone() {
+        Ok(pipe) => pipe,
+        Err(_) => return,
+    };
+    let writer_current = Arc::clone(&current);
+    let writer_closed = Arc::clone(&closed);
+    thread::spawn(move || {
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        // The transports were dropped once the pair is shut down
+        while let Ok(n @ 1..=MAX_MESSAGE_SIZE) = pipe_out.recv(&mut buf) {
+            let mut current = writer_current.lock();
+            if let Some(stream) = current.as_mut() {
+                if write_frame(stream, OP_BINARY, &buf[..n], true).is_err() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    *current = None;
+                }
+            }
+            // Datagrams are dropped while reconnecting, just like UDP would
+        }
+        writer_closed.store(true, Ordering::Relaxed);
+        if let Some(stream) = writer_current.lock().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    });
+
+    while !closed.load(Ordering::Relaxed) {
+        let mut stream = match connect_relay(addr, host, path) {
+            Ok(stream) => stream,
+            Err(_) => {
+                thread::sleep(RECONNECT_DELAY);
+                continue;
+            }
+        };
+
+        match stream.try_clone() {
+            Ok(writer) => *current.lock() = Some(writer),
+            Err(_) => continue,
+        }
+
+        if closed.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let keep = serve_connection(&mut stream, &pipe, &current);
+        *current.lock() = None;
+        let _ = stream.shutdown(Shutdown::Both);
+
+        if !keep {
+            break;
+        }
+    }
+}
+
+// Forward messages from the relay to the device until the connection fails, returns false if
+// the transports were closed
+fn serve_connection(
+    stream: &mut TcpStream,
+    pipe: &UnixDatagram,
+    current: &Mutex<Option<TcpStream>>,
+) -> bool {
+    let mut reader = FrameReader::default();
+    let mut message = vec![];
+    let mut missed_pings = 0;
+
+    loop {
+        let frame = match reader.read_frame(stream) {
+            Ok(frame) => frame,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                // The connection was idle, check it is still alive
+                missed_pings += 1;
+                if missed_pings > MAX_MISSED_PINGS {
+                    return true;
+                }
+                if !send_control(current, OP_PING, &[]) {
+                    return true;
+                }
+                continue;
+            }
+            Err(_) => return true,
+        };
+
+        missed_pings = 0;
+
+        match frame.opcode {
+            OP_BINARY | OP_CONTINUATION => {
+                message.extend_from_slice(&frame.payload);
+                if message.len() > MAX_MESSAGE_SIZE {
+                    return true;
+                }
+                if frame.fin {
+                    if pipe.send(&message).is_err() {
+                        return false;
+                    }
+                    message.clear();
+                }
+            }
+            OP_PING => {
+                if !send_control(current, OP_PONG, &frame.payload) {
+                    return true;
+                }
+            }
+            OP_PONG => {}
+            OP_CLOSE => return true,
+            _ => return true, // Text messages are not part of the protocol
+        }
+    }
+}
+
+// Send a control frame on the current connection, returns false if that failed
+fn send_control(current: &Mutex<Option<TcpStream>>, opcode: u8, payload: &[u8]) -> bool {
+    match current.lock().as_mut() {
+        Some(writer) => write_frame(writer, opcode, payload, true).is_ok(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::x25519::X25519SecretKey;
+    use crate::noise::{Tunn, TunnResult};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    // A minimal relay, that forwards every binary message to all other connected clients.
+    // Every client is pinged right after the upgrade.
+    struct Relay {
+        addr: SocketAddr,
+        clients: Arc<Mutex<Vec<TcpStream>>>,
+        pongs: Arc<Mutex<usize>>,
+    }
+
+    impl Relay {
+        fn start() -> Relay {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let clients = Arc::new(Mutex::new(vec![]));
+            let pongs = Arc::new(Mutex::new(0));
+
+            let (accept_clients, accept_pongs) = (Arc::clone(&clients), Arc::clone(&pongs));
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let (clients, pongs) = (Arc::clone(&accept_clients), Arc::clone(&accept_pongs));
+                    thread::spawn(move || Relay::serve(stream.unwrap(), clients, pongs));
+                }
+            });
+
+            Relay {
+                addr,
+                clients,
+                pongs,
+            }
+        }
+
+        fn serve(
+            mut stream: TcpStream,
+            clients: Arc<Mutex<Vec<TcpStream>>>,
+            pongs: Arc<Mutex<usize>>,
+        ) {
+            let request = read_http_header(&mut stream).unwrap();
+            let key = header_value(&request, "Sec-WebSocket-Key").unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(key)
+            )
+            .unwrap();
+
+            write_frame(&mut stream, OP_PING, b"relay", false).unwrap();
+            let port = stream.peer_addr().unwrap().port();
+            clients.lock().push(stream.try_clone().unwrap());
+
+            let mut reader = FrameReader::default();
+            while let Ok(frame) = reader.read_frame(&mut stream) {
+                match frame.opcode {
+                    OP_PONG => {
+                        assert_eq!(frame.payload, b"relay");
+                        *pongs.lock() += 1;
+                    }
+                    OP_BINARY => {
+                        for client in clients.lock().iter_mut() {
+                            if client.peer_addr().map(|a| a.port()).ok() != Some(port) {
+                                let _ = write_frame(client, OP_BINARY, &frame.payload, false);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Drop all client connections
+        fn disconnect_all(&self) {
+            for client in self.clients.lock().drain(..) {
+                let _ = client.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    // Keep sending the datagram until the other transport receives it, skipping duplicates of
+    // earlier datagrams
+    fn exchange(from: &WebSocketTransport, to: &WebSocketTransport, datagram: &[u8]) -> Vec<u8> {
+        let started = Instant::now();
+        let mut buf = [0u8; 2048];
+        while started.elapsed() < Duration::from_secs(10) {
+            from.write(datagram);
+            while let Ok(received) = to.read(&mut buf) {
+                if received == datagram {
+                    return received.to_vec();
+                }
+            }
+        }
+        panic!("Datagram did not go through the relay");
+    }
+
+    #[test]
+    fn test_websocket_handshake_through_relay() {
+        let relay = Relay::start();
+        // Each relay stands for the relay setting of a device
+        let a_relay = WebSocketRelay::new(relay.addr, "relay.test", "/wireguard");
+        let b_relay = WebSocketRelay::new(relay.addr, "relay.test", "/wireguard");
+
+        let a = a_relay.open().unwrap();
+        let b = b_relay.open().unwrap();
+        // The second socket of a device goes over the connection of the first
+        let a6 = a_relay.open().unwrap();
+        assert_ne!(a.as_raw_fd(), a6.as_raw_fd());
+        for t in &[&a, &b] {
+            t.local
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+        }
+
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let b_public = Arc::new(b_key.public_key());
+        let a_tunn = Tunn::new(a_key, b_public, None, None, 0, None).unwrap();
+        let b_tunn = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+
+        let init = match a_tunn.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let init = exchange(&a, &b, &init);
+        let response = match b_tunn.decapsulate(None, &init, &mut dst) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        let response = exchange(&b, &a, &response);
+        let keepalive = match a_tunn.decapsulate(None, &response, &mut dst) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a keepalive"),
+        };
+
+        // Both clients answered the ping of the relay
+        let started = Instant::now();
+        while *relay.pongs.lock() < 2 && started.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*relay.pongs.lock(), 2);
+        assert_eq!(relay.clients.lock().len(), 2);
+
+        // The session survives the relay dropping the connections
+        relay.disconnect_all();
+        let keepalive = exchange(&a, &b, &keepalive);
+        assert!(matches!(
+            b_tunn.decapsulate(None, &keepalive, &mut dst),
+            TunnResult::Done
+        ));
+
+        // Datagrams sent on either socket of a device share the connection
+        exchange(&a6, &b, b"from the second socket");
+    }
+}
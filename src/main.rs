@@ -9,6 +9,7 @@ pub mod device;
 pub mod ffi;
 pub mod noise;
 
+use crate::crypto::x25519::X25519SecretKey;
 use crate::device::drop_privileges::*;
 use crate::device::*;
 use clap::{value_t, App, Arg};
@@ -33,6 +34,89 @@ fn check_tun_name(_v: String) -> Result<(), String> {
     }
 }
 
+#[cfg(feature = "tcp-api")]
+fn check_api_token(v: String) -> Result<(), String> {
+    if v.len() >= api::MIN_API_TOKEN_LEN {
+        Ok(())
+    } else {
+        Err(format!(
+            "The API token must be at least {} characters long",
+            api::MIN_API_TOKEN_LEN
+        ))
+    }
+}
+
+fn parse_socket_mode(v: &str) -> Result<u32, String> {
+    match u32::from_str_radix(v, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err("The socket mode must be an octal file mode, such as 600".to_owned()),
+    }
+}
+
+fn parse_socket_owner(v: &str) -> Result<(u32, u32), String> {
+    let mut ids = v.splitn(2, ':').map(str::parse::<u32>);
+    match (ids.next(), ids.next()) {
+        (Some(Ok(uid)), Some(Ok(gid))) => Ok((uid, gid)),
+        _ => Err("The socket owner must have the format UID:GID".to_owned()),
+    }
+}
+
+fn parse_id_list(v: &str) -> Result<Vec<u32>, String> {
+    v.split(',')
+        .map(|id| {
+            id.trim()
+                .parse::<u32>()
+                .map_err(|_| "Ids must be a comma separated list of numbers".to_owned())
+        })
+        .collect()
+}
+
+fn parse_padding(v: &str) -> Result<Vec<usize>, String> {
+    v.split(',')
+        .map(|size| match size.trim().parse::<usize>() {
+            Ok(size) if size > 0 && size <= 65535 => Ok(size),
+            _ => Err("Padding sizes must be a comma separated list of sizes in bytes".to_owned()),
+        })
+        .collect()
+}
+
+fn parse_handshake_queue_policy(v: &str) -> Result<noise::HandshakeQueuePolicy, String> {
+    match v {
+        "drop" => Ok(noise::HandshakeQueuePolicy::Drop),
+        "queue" => Ok(noise::HandshakeQueuePolicy::default()),
+        _ => match v.strip_prefix("queue:").map(str::parse::<usize>) {
+            Some(Ok(depth)) if depth > 0 && depth <= noise::MAX_QUEUE_DEPTH => {
+                Ok(noise::HandshakeQueuePolicy::Queue(depth))
+            }
+            Some(_) => Err(format!(
+                "The queue depth must be between 1 and {}",
+                noise::MAX_QUEUE_DEPTH
+            )),
+            None => Err("The handshake queue policy must be queue, queue:DEPTH or drop".to_owned()),
+        },
+    }
+}
+
+fn parse_latency_buckets(v: &str) -> Result<Vec<std::time::Duration>, String> {
+    v.split(',')
+        .map(|ms| match ms.trim().parse::<u64>() {
+            Ok(ms) if ms > 0 => Ok(std::time::Duration::from_millis(ms)),
+            _ => Err("Latency buckets must be a comma separated list of milliseconds".to_owned()),
+        })
+        .collect()
+}
+
+// The seed is a 32 byte key, encoded like private keys in hex or base64
+fn read_cookie_seed(path: &str) -> Result<[u8; 32], String> {
+    let seed = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read the cookie seed: {}", e))?;
+    let seed = seed
+        .trim()
+        .parse::<X25519SecretKey>()
+        .map_err(|_| "The cookie seed must be a 32 byte key in hex or base64".to_owned())?;
+    Ok(noise::make_array(seed.as_bytes()))
+}
+
 fn main() {
     let matches = App::new("boringtun")
         .version(env!("CARGO_PKG_VERSION"))
@@ -54,6 +138,24 @@ fn main() {
                 .env("WG_THREADS")
                 .help("Number of OS threads to use")
                 .default_value("4"),
+            Arg::with_name("event-batch-size")
+                .takes_value(true)
+                .long("event-batch-size")
+                .env("WG_EVENT_BATCH_SIZE")
+                .help("Number of events each thread retrieves per poll, larger favors throughput over latency (1-1024)")
+                .default_value("1"),
+            Arg::with_name("tx-batch-linger")
+                .takes_value(true)
+                .long("tx-batch-linger")
+                .env("WG_TX_BATCH_LINGER")
+                .help("Hold encapsulated packets for up to this many microseconds to send them in batches, 0 to send each at once")
+                .default_value("0"),
+            Arg::with_name("tun-read-buffers")
+                .takes_value(true)
+                .long("tun-read-buffers")
+                .env("WG_TUN_READ_BUFFERS")
+                .help("Number of packets to read ahead from the tunnel interface and send in batches (1-64)")
+                .default_value("1"),
             Arg::with_name("verbosity")
                 .takes_value(true)
                 .long("verbosity")
@@ -76,16 +178,321 @@ fn main() {
             Arg::with_name("disable-connected-udp")
                 .long("disable-connected-udp")
                 .help("Disable connected UDP sockets to each peer"),
+            Arg::with_name("listen-sockets")
+                .takes_value(true)
+                .long("listen-sockets")
+                .env("WG_LISTEN_SOCKETS")
+                .help("Number of sockets sharing the listen port with SO_REUSEPORT, to spread flows across workers (1-64)")
+                .default_value("1"),
+            Arg::with_name("max-handshake-attempts")
+                .takes_value(true)
+                .long("max-handshake-attempts")
+                .env("WG_MAX_HANDSHAKE_ATTEMPTS")
+                .help("Back off handshake retries to a peer after this many unanswered attempts, 0 to never back off")
+                .default_value("0"),
+            Arg::with_name("handshake-backoff-ceiling")
+                .takes_value(true)
+                .long("handshake-backoff-ceiling")
+                .env("WG_HANDSHAKE_BACKOFF_CEILING")
+                .help("The longest interval in seconds between handshake retries of a backed off peer")
+                .default_value("300"),
+            Arg::with_name("log-burst")
+                .takes_value(true)
+                .long("log-burst")
+                .env("WG_LOG_BURST")
+                .help("Log at most this many repeated errors of each kind per log burst interval, 0 to log all of them")
+                .default_value("10"),
+            Arg::with_name("log-burst-interval")
+                .takes_value(true)
+                .long("log-burst-interval")
+                .env("WG_LOG_BURST_INTERVAL")
+                .help("The interval in milliseconds the log burst applies to")
+                .default_value("1000"),
+            Arg::with_name("no-endpoint-buffer")
+                .takes_value(true)
+                .long("no-endpoint-buffer")
+                .env("WG_NO_ENDPOINT_BUFFER")
+                .help("Keep this many packets for a peer without an endpoint until its endpoint is learned, 0 to drop them")
+                .default_value("16"),
+            Arg::with_name("lean-peers")
+                .long("lean-peers")
+                .env("WG_LEAN_PEERS")
+                .help("Keep idle peers small, allocating their timers and queues once they are active"),
+            Arg::with_name("worker-affinity")
+                .takes_value(true)
+                .long("worker-affinity")
+                .env("WG_WORKER_AFFINITY")
+                .validator(|v| affinity::parse_cpu_list(&v).map(|_| ()))
+                .help("Pin the worker threads to these CPUs, such as 0-3,8, the first worker to the first CPU and so on"),
+            Arg::with_name("fast-handshake-retries")
+                .takes_value(true)
+                .long("fast-handshake-retries")
+                .env("WG_FAST_HANDSHAKE_RETRIES")
+                .help("Retry this many first handshake initiations to a peer after the fast retry interval instead of 5 seconds")
+                .default_value("0"),
+            Arg::with_name("fast-handshake-retry-interval")
+                .takes_value(true)
+                .long("fast-handshake-retry-interval")
+                .env("WG_FAST_HANDSHAKE_RETRY_INTERVAL")
+                .help("The interval in milliseconds between the first handshake retries, at most 5000")
+                .default_value("1000"),
+            Arg::with_name("reresolve-interval")
+                .takes_value(true)
+                .long("reresolve-interval")
+                .env("WG_RERESOLVE_INTERVAL")
+                .help("Resolve the names of endpoints given as HOST:PORT again every this many seconds, 0 to never")
+                .default_value("0"),
+            Arg::with_name("liveness-probe-interval")
+                .takes_value(true)
+                .long("liveness-probe-interval")
+                .env("WG_LIVENESS_PROBE_INTERVAL")
+                .help("Send peers with liveness_probe=on an echo request through the tunnel every this many seconds, 0 to never")
+                .default_value("10"),
+            Arg::with_name("failover-attempts")
+                .takes_value(true)
+                .long("failover-attempts")
+                .env("WG_FAILOVER_ATTEMPTS")
+                .help("Fail a peer over to its backup endpoint after this many unanswered handshake initiations")
+                .default_value("3"),
+            Arg::with_name("failover-probe-interval")
+                .takes_value(true)
+                .long("failover-probe-interval")
+                .env("WG_FAILOVER_PROBE_INTERVAL")
+                .help("The interval in seconds between handshake probes of the primary endpoint of a failed over peer")
+                .default_value("30"),
+            Arg::with_name("session-expiry-lead")
+                .takes_value(true)
+                .long("session-expiry-lead")
+                .env("WG_SESSION_EXPIRY_LEAD")
+                .help("How many seconds before a session expires a handshake is started, when prewarming is on")
+                .default_value("10"),
+            Arg::with_name("resumption-window")
+                .takes_value(true)
+                .long("resumption-window")
+                .env("WG_RESUMPTION_WINDOW")
+                .help("How many seconds the session of a removed peer is kept, to be resumed if the peer is added again")
+                .default_value("0"),
+            Arg::with_name("link-mtu")
+                .takes_value(true)
+                .long("link-mtu")
+                .env("WG_LINK_MTU")
+                .help("The MTU of the network peers are reached over, padded packets are kept within it")
+                .default_value("1500"),
+            Arg::with_name("reconnect-backoff-base")
+                .takes_value(true)
+                .long("reconnect-backoff-base")
+                .env("WG_RECONNECT_BACKOFF_BASE")
+                .help("The wait in milliseconds after a failure to connect the socket of a peer, doubled with every further failure")
+                .default_value("100"),
+            Arg::with_name("reconnect-backoff-ceiling")
+                .takes_value(true)
+                .long("reconnect-backoff-ceiling")
+                .env("WG_RECONNECT_BACKOFF_CEILING")
+                .help("The longest wait in seconds between attempts to connect the socket of a peer")
+                .default_value("30"),
+            Arg::with_name("traffic-padding")
+                .takes_value(true)
+                .long("traffic-padding")
+                .env("WG_TRAFFIC_PADDING")
+                .validator(|v| parse_padding(&v).map(|_| ()))
+                .help("Pad inner packets to the smallest of these comma separated sizes that fits, such as 256,512,1280"),
+            Arg::with_name("handshake-queue-policy")
+                .takes_value(true)
+                .long("handshake-queue-policy")
+                .env("WG_HANDSHAKE_QUEUE_POLICY")
+                .validator(|v| parse_handshake_queue_policy(&v).map(|_| ()))
+                .help("Queue packets sent to a peer without a session until the handshake completes, up to a depth with queue:DEPTH, or drop them")
+                .default_value("queue"),
+            Arg::with_name("handshake-latency-buckets")
+                .takes_value(true)
+                .long("handshake-latency-buckets")
+                .env("WG_HANDSHAKE_LATENCY_BUCKETS")
+                .validator(|v| parse_latency_buckets(&v).map(|_| ()))
+                .help("The upper bounds in milliseconds of the buckets of the handshake latency histograms, such as 10,100,1000"),
+            Arg::with_name("handshake-padding")
+                .takes_value(true)
+                .long("handshake-padding")
+                .env("WG_HANDSHAKE_PADDING")
+                .help("Pad handshake messages with random bytes to a random size up to this many bytes, only for peers that ignore the padding, 0 to send them unpadded")
+                .default_value("0"),
+            Arg::with_name("accept-handshake-padding")
+                .long("accept-handshake-padding")
+                .help("Accept handshake messages padded by peers with --handshake-padding, by default only exact sizes are accepted"),
+            Arg::with_name("duplicate-init-window")
+                .takes_value(true)
+                .long("duplicate-init-window")
+                .env("WG_DUPLICATE_INIT_WINDOW")
+                .help("Answer copies of a handshake initiation received within this many milliseconds with the response already sent, 0 to drop them")
+                .default_value("0"),
+            Arg::with_name("cookie-seed-file")
+                .takes_value(true)
+                .long("cookie-seed-file")
+                .env("WG_COOKIE_SEED_FILE")
+                .validator(|v| read_cookie_seed(&v).map(|_| ()))
+                .help("Derive the cookie secret from the key in this file, shared by the nodes of a cluster so they accept each other's cookies"),
+            Arg::with_name("drop-unknown-indices")
+                .long("drop-unknown-indices")
+                .env("WG_DROP_UNKNOWN_INDICES")
+                .help("Drop data messages for no known session before any other work, counting them instead of reporting them"),
+            Arg::with_name("max-peers")
+                .takes_value(true)
+                .long("max-peers")
+                .env("WG_MAX_PEERS")
+                .help("The largest number of peers, 0 for no limit")
+                .default_value("0"),
+            Arg::with_name("wg-compat-log")
+                .long("wg-compat-log")
+                .env("WG_COMPAT_LOG")
+                .help("Also log handshake and keepalive events with the messages of the Linux WireGuard module"),
+            Arg::with_name("ecn-passthrough")
+                .long("ecn-passthrough")
+                .env("WG_ECN_PASSTHROUGH")
+                .help("Propagate ECN codepoints between inner packets and the outer UDP header"),
             #[cfg(target_os = "linux")]
             Arg::with_name("disable-multi-queue")
                 .long("disable-multi-queue")
                 .help("Disable using multiple queues for the tunnel interface"),
+            #[cfg(target_os = "linux")]
+            Arg::with_name("bpf-filter")
+                .long("bpf-filter")
+                .env("WG_BPF_FILTER")
+                .help("Drop datagrams that can not be WireGuard messages in the kernel with a socket filter"),
+            #[cfg(target_os = "linux")]
+            Arg::with_name("udp-gro")
+                .long("udp-gro")
+                .env("WG_UDP_GRO")
+                .help("Let the kernel coalesce datagrams received from the same source into one read"),
+            Arg::with_name("gro-max-segments")
+                .takes_value(true)
+                .long("gro-max-segments")
+                .env("WG_GRO_MAX_SEGMENTS")
+                .help("Number of coalesced datagrams to decapsulate before writing their packets to the tunnel interface (1-64)")
+                .default_value("16"),
+            #[cfg(target_os = "linux")]
+            Arg::with_name("tun-offload")
+                .long("tun-offload")
+                .help("Coalesce received TCP segments into single offloaded writes to the tunnel interface"),
+            Arg::with_name("api-socket-mode")
+                .takes_value(true)
+                .long("api-socket-mode")
+                .env("WG_API_SOCKET_MODE")
+                .validator(|v| parse_socket_mode(&v).map(|_| ()))
+                .help("The octal file mode of the UAPI socket")
+                .default_value("600"),
+            Arg::with_name("api-socket-owner")
+                .takes_value(true)
+                .long("api-socket-owner")
+                .env("WG_API_SOCKET_OWNER")
+                .validator(|v| parse_socket_owner(&v).map(|_| ()))
+                .help("Give the UAPI socket to UID:GID"),
+            Arg::with_name("api-allowed-uids")
+                .takes_value(true)
+                .long("api-allowed-uids")
+                .env("WG_API_ALLOWED_UIDS")
+                .validator(|v| parse_id_list(&v).map(|_| ()))
+                .help("Only accept UAPI clients running as one of these comma separated uids, or with an allowed gid"),
+            Arg::with_name("api-allowed-gids")
+                .takes_value(true)
+                .long("api-allowed-gids")
+                .env("WG_API_ALLOWED_GIDS")
+                .validator(|v| parse_id_list(&v).map(|_| ()))
+                .help("Only accept UAPI clients whose primary group is one of these comma separated gids, or with an allowed uid"),
+            Arg::with_name("api-idle-timeout")
+                .takes_value(true)
+                .long("api-idle-timeout")
+                .env("WG_API_IDLE_TIMEOUT")
+                .help("Close UAPI connections that send nothing for this many milliseconds in the middle of a request")
+                .default_value("5000"),
+            Arg::with_name("api-request-timeout")
+                .takes_value(true)
+                .long("api-request-timeout")
+                .env("WG_API_REQUEST_TIMEOUT")
+                .help("Close UAPI connections that take longer than this many milliseconds to send a request")
+                .default_value("30000"),
+            Arg::with_name("api-max-request-size")
+                .takes_value(true)
+                .long("api-max-request-size")
+                .env("WG_API_MAX_REQUEST_SIZE")
+                .help("The largest UAPI set request in bytes")
+                .default_value("1048576"),
+            #[cfg(target_os = "linux")]
+            Arg::with_name("api-abstract")
+                .takes_value(true)
+                .long("api-abstract")
+                .env("WG_API_ABSTRACT")
+                .help("Serve the UAPI on the given abstract unix socket instead of a file"),
+            #[cfg(feature = "tcp-api")]
+            Arg::with_name("api-tcp-port")
+                .takes_value(true)
+                .long("api-tcp-port")
+                .env("WG_API_TCP_PORT")
+                .requires("api-token")
+                .help("Serve the UAPI on the given loopback TCP port instead of a file"),
+            #[cfg(feature = "tcp-api")]
+            Arg::with_name("api-token")
+                .takes_value(true)
+                .long("api-token")
+                .env("WG_API_TOKEN")
+                .validator(check_api_token)
+                .help("The token clients must present to use the UAPI over TCP"),
         ])
         .get_matches();
 
     let background = !matches.is_present("foreground");
     let tun_name = matches.value_of("INTERFACE_NAME").unwrap();
     let n_threads = value_t!(matches.value_of("threads"), usize).unwrap_or_else(|e| e.exit());
+    let event_batch_size =
+        value_t!(matches.value_of("event-batch-size"), usize).unwrap_or_else(|e| e.exit());
+    let gro_max_segments =
+        value_t!(matches.value_of("gro-max-segments"), usize).unwrap_or_else(|e| e.exit());
+    let tx_batch_linger =
+        value_t!(matches.value_of("tx-batch-linger"), u64).unwrap_or_else(|e| e.exit());
+    let tun_read_buffers =
+        value_t!(matches.value_of("tun-read-buffers"), usize).unwrap_or_else(|e| e.exit());
+    let listen_sockets =
+        value_t!(matches.value_of("listen-sockets"), usize).unwrap_or_else(|e| e.exit());
+    let api_idle_timeout =
+        value_t!(matches.value_of("api-idle-timeout"), u64).unwrap_or_else(|e| e.exit());
+    let api_request_timeout =
+        value_t!(matches.value_of("api-request-timeout"), u64).unwrap_or_else(|e| e.exit());
+    let api_max_request_size =
+        value_t!(matches.value_of("api-max-request-size"), u64).unwrap_or_else(|e| e.exit());
+    let max_handshake_attempts =
+        value_t!(matches.value_of("max-handshake-attempts"), usize).unwrap_or_else(|e| e.exit());
+    let handshake_backoff_ceiling =
+        value_t!(matches.value_of("handshake-backoff-ceiling"), u64).unwrap_or_else(|e| e.exit());
+    let log_burst = value_t!(matches.value_of("log-burst"), u32).unwrap_or_else(|e| e.exit());
+    let log_burst_interval =
+        value_t!(matches.value_of("log-burst-interval"), u64).unwrap_or_else(|e| e.exit());
+    let no_endpoint_buffer =
+        value_t!(matches.value_of("no-endpoint-buffer"), usize).unwrap_or_else(|e| e.exit());
+    let duplicate_init_window =
+        value_t!(matches.value_of("duplicate-init-window"), u64).unwrap_or_else(|e| e.exit());
+    let handshake_padding =
+        value_t!(matches.value_of("handshake-padding"), usize).unwrap_or_else(|e| e.exit());
+    let fast_handshake_retries =
+        value_t!(matches.value_of("fast-handshake-retries"), usize).unwrap_or_else(|e| e.exit());
+    let fast_handshake_retry_interval =
+        value_t!(matches.value_of("fast-handshake-retry-interval"), u64)
+            .unwrap_or_else(|e| e.exit());
+    let max_peers = value_t!(matches.value_of("max-peers"), usize).unwrap_or_else(|e| e.exit());
+    let reresolve_interval =
+        value_t!(matches.value_of("reresolve-interval"), u64).unwrap_or_else(|e| e.exit());
+    let liveness_probe_interval =
+        value_t!(matches.value_of("liveness-probe-interval"), u64).unwrap_or_else(|e| e.exit());
+    let failover_attempts =
+        value_t!(matches.value_of("failover-attempts"), usize).unwrap_or_else(|e| e.exit());
+    let failover_probe_interval =
+        value_t!(matches.value_of("failover-probe-interval"), u64).unwrap_or_else(|e| e.exit());
+    let session_expiry_lead =
+        value_t!(matches.value_of("session-expiry-lead"), u64).unwrap_or_else(|e| e.exit());
+    let resumption_window =
+        value_t!(matches.value_of("resumption-window"), u64).unwrap_or_else(|e| e.exit());
+    let link_mtu = value_t!(matches.value_of("link-mtu"), usize).unwrap_or_else(|e| e.exit());
+    let reconnect_backoff_base =
+        value_t!(matches.value_of("reconnect-backoff-base"), u64).unwrap_or_else(|e| e.exit());
+    let reconnect_backoff_ceiling =
+        value_t!(matches.value_of("reconnect-backoff-ceiling"), u64).unwrap_or_else(|e| e.exit());
     let log_level =
         value_t!(matches.value_of("verbosity"), slog::Level).unwrap_or_else(|e| e.exit());
 
@@ -136,12 +543,116 @@ fn main() {
         logger = Logger::root(drain, o!());
     }
 
+    #[allow(unused_mut)]
+    let mut api_socket = api::ApiSocket::Path;
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(name) = matches.value_of("api-abstract") {
+            api_socket = api::ApiSocket::Abstract(name.to_owned());
+        }
+    }
+
+    #[cfg(feature = "tcp-api")]
+    {
+        if matches.is_present("api-tcp-port") {
+            api_socket = api::ApiSocket::Tcp {
+                port: value_t!(matches.value_of("api-tcp-port"), u16).unwrap_or_else(|e| e.exit()),
+                token: matches.value_of("api-token").unwrap().to_owned(),
+            };
+        }
+    }
+
     let config = DeviceConfig {
         n_threads,
         logger: logger.clone(),
         use_connected_socket: !matches.is_present("disable-connected-udp"),
         #[cfg(target_os = "linux")]
         use_multi_queue: !matches.is_present("disable-multi-queue"),
+        #[cfg(target_os = "linux")]
+        use_tun_offload: matches.is_present("tun-offload"),
+        #[cfg(target_os = "linux")]
+        use_bpf_filter: matches.is_present("bpf-filter"),
+        #[cfg(target_os = "linux")]
+        use_udp_gro: matches.is_present("udp-gro"),
+        gro_max_segments,
+        api_socket,
+        api_socket_mode: parse_socket_mode(matches.value_of("api-socket-mode").unwrap()).unwrap(),
+        api_socket_owner: matches
+            .value_of("api-socket-owner")
+            .map(|v| parse_socket_owner(v).unwrap()),
+        api_allowed_uids: matches
+            .value_of("api-allowed-uids")
+            .map(|v| parse_id_list(v).unwrap())
+            .unwrap_or_default(),
+        api_allowed_gids: matches
+            .value_of("api-allowed-gids")
+            .map(|v| parse_id_list(v).unwrap())
+            .unwrap_or_default(),
+        api_idle_timeout: std::time::Duration::from_millis(api_idle_timeout),
+        api_request_timeout: std::time::Duration::from_millis(api_request_timeout),
+        api_max_request_size,
+        handshake_source_allow: vec![],
+        event_batch_size,
+        tx_batch_linger: std::time::Duration::from_micros(tx_batch_linger),
+        tun_read_buffers,
+        ecn_passthrough: matches.is_present("ecn-passthrough"),
+        wg_compat_log: matches.is_present("wg-compat-log"),
+        drop_unknown_indices: matches.is_present("drop-unknown-indices"),
+        max_peers,
+        listen_sockets,
+        on_decrypt_failure: None,
+        on_session_expiring: None,
+        on_inner_packet: None,
+        session_expiry_lead: std::time::Duration::from_secs(session_expiry_lead),
+        resumption_window: std::time::Duration::from_secs(resumption_window),
+        stats_interval: std::time::Duration::from_secs(10),
+        max_handshake_attempts,
+        handshake_backoff_ceiling: std::time::Duration::from_secs(handshake_backoff_ceiling),
+        fast_handshake_retries,
+        fast_handshake_retry_interval: std::time::Duration::from_millis(
+            fast_handshake_retry_interval,
+        ),
+        failover_attempts,
+        failover_probe_interval: std::time::Duration::from_secs(failover_probe_interval),
+        reconnect_backoff_base: std::time::Duration::from_millis(reconnect_backoff_base),
+        reconnect_backoff_ceiling: std::time::Duration::from_secs(reconnect_backoff_ceiling),
+        handshake_queue_policy: parse_handshake_queue_policy(
+            matches.value_of("handshake-queue-policy").unwrap(),
+        )
+        .unwrap(),
+        traffic_padding: match matches.value_of("traffic-padding") {
+            Some(sizes) => noise::Padding::Buckets(parse_padding(sizes).unwrap()),
+            None => noise::Padding::None,
+        },
+        handshake_padding,
+        accept_handshake_padding: matches.is_present("accept-handshake-padding"),
+        duplicate_init_window: std::time::Duration::from_millis(duplicate_init_window),
+        handshake_latency_buckets: match matches.value_of("handshake-latency-buckets") {
+            Some(buckets) => parse_latency_buckets(buckets).unwrap(),
+            None => noise::histogram::DEFAULT_LATENCY_BUCKETS.to_vec(),
+        },
+        link_mtu,
+        cookie_seed: matches
+            .value_of("cookie-seed-file")
+            .map(|path| read_cookie_seed(path).unwrap()),
+        log_burst,
+        log_burst_interval: std::time::Duration::from_millis(log_burst_interval),
+        no_endpoint_buffer,
+        lean_peers: matches.is_present("lean-peers"),
+        worker_affinity: matches
+            .value_of("worker-affinity")
+            .map(|v| affinity::parse_cpu_list(v).unwrap())
+            .unwrap_or_default(),
+        resolver: resolve::system_resolver(),
+        reresolve_interval: std::time::Duration::from_secs(reresolve_interval),
+        liveness_probe_interval: std::time::Duration::from_secs(liveness_probe_interval),
+        #[cfg(all(feature = "websocket", not(target_arch = "arm")))]
+        websocket_relay: None,
+        #[cfg(feature = "socks5")]
+        socks5_proxy: None,
+        #[cfg(target_os = "linux")]
+        capture_interface: None,
     };
 
     let mut device_handle: DeviceHandle = match DeviceHandle::new(&tun_name, config) {
@@ -0,0 +1,91 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The clocks of a tunnel. Its timers, such as the time to rekey or the keepalive timeout, only
+//! read a monotonic clock that keeps counting while the system is suspended, so neither a step
+//! of the wall clock by NTP nor resuming a VM after a long pause sets them off early or stalls
+//! them. The wall clock is only read where a date is needed: for the TAI64N timestamp of
+//! handshake initiations, and to report the time of the last handshake.
+
+use parking_lot::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A source of time for the timers of a tunnel, see `Tunn::set_clock`
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    /// The time since an arbitrary start, which never goes back and includes the time the
+    /// system was suspended
+    fn monotonic(&self) -> Duration;
+    /// The time since the Unix epoch, which can jump in either direction
+    fn wall(&self) -> Duration;
+}
+
+/// The clocks of the system: CLOCK_BOOTTIME on Linux, and CLOCK_MONOTONIC elsewhere, which counts
+/// the time asleep on macOS
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn monotonic(&self) -> Duration {
+        #[cfg(target_os = "linux")]
+        const CLOCK: libc::clockid_t = libc::CLOCK_BOOTTIME;
+        #[cfg(not(target_os = "linux"))]
+        const CLOCK: libc::clockid_t = libc::CLOCK_MONOTONIC;
+
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(CLOCK, &mut time) };
+        Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    }
+
+    fn wall(&self) -> Duration {
+        // A clock set before the epoch reads as the epoch
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that only moves when told to, for tests of time dependent behavior. Its wall clock
+/// starts at the current time.
+#[derive(Debug)]
+pub struct ManualClock {
+    times: Mutex<(Duration, Duration)>, // Monotonic and wall
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock {
+            times: Mutex::new((Duration::ZERO, SystemClock.wall())),
+        }
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        Default::default()
+    }
+
+    /// Let time pass on both clocks
+    pub fn advance(&self, by: Duration) {
+        let mut times = self.times.lock();
+        times.0 += by;
+        times.1 += by;
+    }
+
+    /// Step the wall clock to a time since the epoch, as NTP might, leaving the monotonic clock
+    pub fn set_wall(&self, wall: Duration) {
+        self.times.lock().1 = wall;
+    }
+}
+
+impl Clock for ManualClock {
+    fn monotonic(&self) -> Duration {
+        self.times.lock().0
+    }
+
+    fn wall(&self) -> Duration {
+        self.times.lock().1
+    }
+}
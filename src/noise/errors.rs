@@ -20,4 +20,6 @@ pub enum WireGuardError {
     LockFailed,
     ConnectionExpired,
     UnderLoad,
+    InvalidExportLength,
+    NonceExhausted,
 }
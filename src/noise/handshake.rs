@@ -4,12 +4,13 @@
 use super::{HandshakeInit, HandshakeResponse, PacketCookieReply};
 use crate::crypto::blake2s::Blake2s;
 use crate::crypto::chacha20poly1305::ChaCha20Poly1305;
-use crate::crypto::x25519::{X25519PublicKey, X25519SecretKey};
+use crate::crypto::x25519::{OsRng, Rng, X25519PublicKey, X25519SecretKey};
+use crate::noise::clock::{Clock, SystemClock};
 use crate::noise::errors::WireGuardError;
 use crate::noise::make_array;
 use crate::noise::session::Session;
 use std::sync::Arc;
-use std::time::{Duration, Instant, SystemTime};
+use std::time::Duration;
 
 // static CONSTRUCTION: &'static [u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
 // static IDENTIFIER: &'static [u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
@@ -68,27 +69,27 @@ struct Tai64N {
 }
 
 #[derive(Debug)]
-// This struct computes a [Tai64N](https://cr.yp.to/libtai/tai64.html) timestamp from current system time
+// This struct computes a [Tai64N](https://cr.yp.to/libtai/tai64.html) timestamp from current system time.
+// Only the wall clock at the start is read, so the timestamps keep growing when it steps back.
 struct TimeStamper {
     duration_at_start: Duration,
-    instant_at_start: Instant,
+    monotonic_at_start: Duration,
 }
 
 impl TimeStamper {
     // Create a new TimeStamper
-    pub fn new() -> TimeStamper {
+    pub fn new(clock: &dyn Clock) -> TimeStamper {
         TimeStamper {
-            duration_at_start: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap(),
-            instant_at_start: Instant::now(),
+            duration_at_start: clock.wall(),
+            monotonic_at_start: clock.monotonic(),
         }
     }
     // Take time reading and generate a 12 byte timestamp
-    pub fn stamp(&self) -> [u8; 12] {
+    pub fn stamp(&self, clock: &dyn Clock) -> [u8; 12] {
         const TAI64_BASE: u64 = (1u64 << 62) + 37;
         let mut ext_stamp = [0u8; 12];
-        let stamp = Instant::now().duration_since(self.instant_at_start) + self.duration_at_start;
+        let stamp =
+            clock.monotonic().saturating_sub(self.monotonic_at_start) + self.duration_at_start;
         ext_stamp[0..8].copy_from_slice(&(stamp.as_secs() + TAI64_BASE).to_be_bytes());
         ext_stamp[8..12].copy_from_slice(&stamp.subsec_nanos().to_be_bytes());
         ext_stamp
@@ -145,31 +146,41 @@ struct HandshakeInitSentState {
     hash: [u8; KEY_LEN],
     chaining_key: [u8; KEY_LEN],
     ephemeral_private: X25519SecretKey,
-    time_sent: Instant,
+    time_sent: Duration, // On the monotonic clock
 }
 
+#[derive(Debug)]
+struct HandshakeInitReceivedState {
+    hash: [u8; KEY_LEN],
+    chaining_key: [u8; KEY_LEN],
+    peer_ephemeral_public: X25519PublicKey,
+    peer_index: u32,
+}
+
+// The state of a handshake in flight is boxed, so a peer without one only holds its keys
 #[derive(Debug)]
 enum HandshakeState {
-    None,                             // No handshake in process
-    InitSent(HandshakeInitSentState), // We initiated the handshake
-    InitReceived {
-        hash: [u8; KEY_LEN],
-        chaining_key: [u8; KEY_LEN],
-        peer_ephemeral_public: X25519PublicKey,
-        peer_index: u32,
-    }, // Handshake initiated by peer
+    None,                                          // No handshake in process
+    InitSent(Box<HandshakeInitSentState>),         // We initiated the handshake
+    InitReceived(Box<HandshakeInitReceivedState>), // Handshake initiated by peer
     Expired, // Handshake was established too long ago (implies no handshake is in progress)
 }
 
 pub struct Handshake {
     params: NoiseParams,
-    next_index: u32,          // Index of the next session
+    alt_params: Option<Box<NoiseParams>>, // The other static key during a key rollover
+    next_index: u32,                      // Index of the next session
     previous: HandshakeState, // Allow to have two outgoing handshakes in flight, because sometimes we may receive a delayed response to a handshake with bad networks
     state: HandshakeState,    // Current handshake state
     cookies: Cookies,
     last_handshake_timestamp: Tai64N, // The timestamp of the last handshake we received
     stamper: TimeStamper,             // TODO: make TimeStamper a singleton
+    clock: Arc<dyn Clock>,
     pub(super) last_rtt: Option<u32>,
+    pub(super) started: Option<Duration>, // When the current handshake was first sent, on the monotonic clock
+    rng: Arc<dyn Rng>,                    // The source of ephemeral keys
+    #[cfg(any(test, feature = "interop-testing"))]
+    injected: Option<(X25519SecretKey, Option<[u8; TIMESTAMP_LEN]>)>, // For the next message only
 }
 
 #[derive(Default)]
@@ -262,6 +273,68 @@ impl NoiseParams {
         self.static_shared = self.static_private.shared_key(&self.peer_static_public)?;
         Ok(())
     }
+
+    // Decrypt a handshake initiation addressed to this static key, returning the resulting
+    // chaining key and hash, the ephemeral key of the initiator and its timestamp
+    fn open_initiation(
+        &self,
+        packet: &HandshakeInit,
+    ) -> Result<([u8; KEY_LEN], [u8; KEY_LEN], X25519PublicKey, Tai64N), WireGuardError> {
+        // initiator.chaining_key = HASH(CONSTRUCTION)
+        let mut chaining_key = INITIAL_CHAIN_KEY;
+        // initiator.hash = HASH(HASH(initiator.chaining_key || IDENTIFIER) || responder.static_public)
+        let mut hash = INITIAL_CHAIN_HASH;
+        hash = HASH!(hash, self.static_public.as_bytes());
+        // msg.unencrypted_ephemeral = DH_PUBKEY(initiator.ephemeral_private)
+        let peer_ephemeral_public = X25519PublicKey::from(packet.unencrypted_ephemeral);
+        // initiator.hash = HASH(initiator.hash || msg.unencrypted_ephemeral)
+        hash = HASH!(hash, peer_ephemeral_public.as_bytes());
+        // temp = HMAC(initiator.chaining_key, msg.unencrypted_ephemeral)
+        // initiator.chaining_key = HMAC(temp, 0x1)
+        chaining_key = HMAC!(
+            HMAC!(chaining_key, peer_ephemeral_public.as_bytes()),
+            [0x01]
+        );
+        // temp = HMAC(initiator.chaining_key, DH(initiator.ephemeral_private, responder.static_public))
+        let ephemeral_shared = self.static_private.shared_key(&peer_ephemeral_public)?;
+        let temp = HMAC!(chaining_key, ephemeral_shared);
+        // initiator.chaining_key = HMAC(temp, 0x1)
+        chaining_key = HMAC!(temp, [0x01]);
+        // key = HMAC(temp, initiator.chaining_key || 0x2)
+        let key = HMAC!(temp, chaining_key, [0x02]);
+
+        let mut peer_static_public_decrypted = [0u8; KEY_LEN];
+        // msg.encrypted_static = AEAD(key, 0, initiator.static_public, initiator.hash)
+        OPEN!(
+            peer_static_public_decrypted,
+            key,
+            0,
+            packet.encrypted_static,
+            hash
+        )?;
+
+        self.peer_static_public
+            .constant_time_is_equal(&X25519PublicKey::from(&peer_static_public_decrypted[..]))?;
+
+        // initiator.hash = HASH(initiator.hash || msg.encrypted_static)
+        hash = HASH!(hash, packet.encrypted_static);
+        // temp = HMAC(initiator.chaining_key, DH(initiator.static_private, responder.static_public))
+        let temp = HMAC!(chaining_key, self.static_shared);
+        // initiator.chaining_key = HMAC(temp, 0x1)
+        chaining_key = HMAC!(temp, [0x01]);
+        // key = HMAC(temp, initiator.chaining_key || 0x2)
+        let key = HMAC!(temp, chaining_key, [0x02]);
+        // msg.encrypted_timestamp = AEAD(key, 0, TAI64N(), initiator.hash)
+        let mut timestamp = [0u8; TIMESTAMP_LEN];
+        OPEN!(timestamp, key, 0, packet.encrypted_timestamp, hash)?;
+
+        let timestamp = Tai64N::parse(&timestamp)?;
+
+        // initiator.hash = HASH(initiator.hash || msg.encrypted_timestamp)
+        hash = HASH!(hash, packet.encrypted_timestamp);
+
+        Ok((chaining_key, hash, peer_ephemeral_public, timestamp))
+    }
 }
 
 impl Handshake {
@@ -281,16 +354,59 @@ impl Handshake {
 
         Ok(Handshake {
             params,
+            alt_params: None,
             next_index: global_idx,
             previous: HandshakeState::None,
             state: HandshakeState::None,
             last_handshake_timestamp: Tai64N::zero(),
-            stamper: TimeStamper::new(),
+            stamper: TimeStamper::new(&SystemClock),
+            clock: Arc::new(SystemClock),
             cookies: Default::default(),
             last_rtt: None,
+            started: None,
+            rng: Arc::new(OsRng),
+            #[cfg(any(test, feature = "interop-testing"))]
+            injected: None,
         })
     }
 
+    pub(crate) fn set_rng(&mut self, rng: Arc<dyn Rng>) {
+        self.rng = rng;
+    }
+
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.stamper = TimeStamper::new(&*clock);
+        self.clock = clock;
+    }
+
+    #[cfg(any(test, feature = "interop-testing"))]
+    pub(crate) fn inject_ephemeral(
+        &mut self,
+        ephemeral: X25519SecretKey,
+        timestamp: Option<[u8; TIMESTAMP_LEN]>,
+    ) {
+        self.injected = Some((ephemeral, timestamp));
+    }
+
+    // The ephemeral key of the next handshake message, and the timestamp of the next initiation
+    fn next_ephemeral(&mut self) -> (X25519SecretKey, [u8; TIMESTAMP_LEN]) {
+        #[cfg(any(test, feature = "interop-testing"))]
+        if let Some((ephemeral, timestamp)) = self.injected.take() {
+            return (
+                ephemeral,
+                timestamp.unwrap_or_else(|| self.stamper.stamp(&*self.clock)),
+            );
+        }
+        (
+            X25519SecretKey::new_from_rng(&*self.rng),
+            self.stamper.stamp(&*self.clock),
+        )
+    }
+
+    pub(crate) fn peer_static_public(&self) -> &Arc<X25519PublicKey> {
+        &self.params.peer_static_public
+    }
+
     pub(crate) fn is_in_progress(&self) -> bool {
         match self.state {
             HandshakeState::None | HandshakeState::Expired => false,
@@ -298,9 +414,10 @@ impl Handshake {
         }
     }
 
-    pub(crate) fn timer(&self) -> Option<Instant> {
+    // When the initiation in flight was sent, on the monotonic clock
+    pub(crate) fn timer(&self) -> Option<Duration> {
         match self.state {
-            HandshakeState::InitSent(HandshakeInitSentState { time_sent, .. }) => Some(time_sent),
+            HandshakeState::InitSent(ref state) => Some(state.time_sent),
             _ => None,
         }
     }
@@ -333,91 +450,87 @@ impl Handshake {
         self.next_index
     }
 
+    // Continue the cyclic session index after the index of a resumed session, so new sessions
+    // do not reuse it. Fails if the index belongs to another peer.
+    pub(crate) fn resume_index(&mut self, index: u32) -> bool {
+        if index & !0xff != self.next_index & !0xff {
+            return false;
+        }
+        self.next_index = index;
+        true
+    }
+
     pub(crate) fn set_static_private(
         &mut self,
         private_key: Arc<X25519SecretKey>,
         public_key: Arc<X25519PublicKey>,
     ) -> Result<(), WireGuardError> {
+        self.alt_params = None;
         self.params.set_static_private(private_key, public_key)
     }
 
+    /// Also accept initiations addressed to a second static key, until the rollover is committed
+    pub(crate) fn set_next_static_private(
+        &mut self,
+        private_key: Arc<X25519SecretKey>,
+        public_key: Arc<X25519PublicKey>,
+    ) -> Result<(), WireGuardError> {
+        self.alt_params = Some(Box::new(NoiseParams::new(
+            private_key,
+            public_key,
+            Arc::clone(&self.params.peer_static_public),
+            self.params.preshared_key,
+        )?));
+        Ok(())
+    }
+
+    /// End a key rollover, keeping only the static key matching `public_key`
+    pub(crate) fn commit_static_private(&mut self, public_key: &X25519PublicKey) {
+        if let Some(alt) = self.alt_params.take() {
+            if alt.static_public.as_bytes() == public_key.as_bytes() {
+                self.params = *alt;
+            }
+        }
+    }
+
     pub(super) fn receive_handshake_initialization<'a>(
         &mut self,
         packet: HandshakeInit,
         dst: &'a mut [u8],
     ) -> Result<(&'a mut [u8], Session), WireGuardError> {
-        // initiator.chaining_key = HASH(CONSTRUCTION)
-        let mut chaining_key = INITIAL_CHAIN_KEY;
-        // initiator.hash = HASH(HASH(initiator.chaining_key || IDENTIFIER) || responder.static_public)
-        let mut hash = INITIAL_CHAIN_HASH;
-        hash = HASH!(hash, self.params.static_public.as_bytes());
         // msg.sender_index = little_endian(initiator.sender_index)
         let peer_index = packet.sender_idx;
-        // msg.unencrypted_ephemeral = DH_PUBKEY(initiator.ephemeral_private)
-        let peer_ephemeral_public = X25519PublicKey::from(packet.unencrypted_ephemeral);
-        // initiator.hash = HASH(initiator.hash || msg.unencrypted_ephemeral)
-        hash = HASH!(hash, peer_ephemeral_public.as_bytes());
-        // temp = HMAC(initiator.chaining_key, msg.unencrypted_ephemeral)
-        // initiator.chaining_key = HMAC(temp, 0x1)
-        chaining_key = HMAC!(
-            HMAC!(chaining_key, peer_ephemeral_public.as_bytes()),
-            [0x01]
-        );
-        // temp = HMAC(initiator.chaining_key, DH(initiator.ephemeral_private, responder.static_public))
-        let ephemeral_shared = self
-            .params
-            .static_private
-            .shared_key(&peer_ephemeral_public)?;
-        let temp = HMAC!(chaining_key, ephemeral_shared);
-        // initiator.chaining_key = HMAC(temp, 0x1)
-        chaining_key = HMAC!(temp, [0x01]);
-        // key = HMAC(temp, initiator.chaining_key || 0x2)
-        let key = HMAC!(temp, chaining_key, [0x02]);
 
-        let mut peer_static_public_decrypted = [0u8; KEY_LEN];
-        // msg.encrypted_static = AEAD(key, 0, initiator.static_public, initiator.hash)
-        OPEN!(
-            peer_static_public_decrypted,
-            key,
-            0,
-            packet.encrypted_static,
-            hash
-        )?;
-
-        self.params
-            .peer_static_public
-            .constant_time_is_equal(&X25519PublicKey::from(&peer_static_public_decrypted[..]))?;
-
-        // initiator.hash = HASH(initiator.hash || msg.encrypted_static)
-        hash = HASH!(hash, packet.encrypted_static);
-        // temp = HMAC(initiator.chaining_key, DH(initiator.static_private, responder.static_public))
-        let temp = HMAC!(chaining_key, self.params.static_shared);
-        // initiator.chaining_key = HMAC(temp, 0x1)
-        chaining_key = HMAC!(temp, [0x01]);
-        // key = HMAC(temp, initiator.chaining_key || 0x2)
-        let key = HMAC!(temp, chaining_key, [0x02]);
-        // msg.encrypted_timestamp = AEAD(key, 0, TAI64N(), initiator.hash)
-        let mut timestamp = [0u8; TIMESTAMP_LEN];
-        OPEN!(timestamp, key, 0, packet.encrypted_timestamp, hash)?;
+        // During a key rollover the initiation may be addressed to either static key
+        let (opened, is_alt) = match self.params.open_initiation(&packet) {
+            Ok(opened) => (opened, false),
+            Err(e) => match self.alt_params.as_ref() {
+                Some(alt) => (alt.open_initiation(&packet)?, true),
+                None => return Err(e),
+            },
+        };
+        let (chaining_key, hash, peer_ephemeral_public, timestamp) = opened;
 
-        let timestamp = Tai64N::parse(&timestamp)?;
         if !timestamp.after(&self.last_handshake_timestamp) {
             // Possibly a replay
             return Err(WireGuardError::WrongTai64nTimestamp);
         }
         self.last_handshake_timestamp = timestamp;
 
-        // initiator.hash = HASH(initiator.hash || msg.encrypted_timestamp)
-        hash = HASH!(hash, packet.encrypted_timestamp);
+        if is_alt {
+            // The peer uses the other key now, so it is also used for our own initiations
+            let alt = self.alt_params.as_mut().unwrap();
+            std::mem::swap(&mut self.params, &mut **alt);
+        }
 
         self.previous = std::mem::replace(
             &mut self.state,
-            HandshakeState::InitReceived {
+            HandshakeState::InitReceived(Box::new(HandshakeInitReceivedState {
                 chaining_key,
                 hash,
                 peer_ephemeral_public,
                 peer_index,
-            },
+            })),
         );
 
         self.format_handshake_response(dst)
@@ -490,8 +603,10 @@ impl Handshake {
         let temp1 = HMAC!(chaining_key, []);
         let temp2 = HMAC!(temp1, [0x01]);
         let temp3 = HMAC!(temp1, temp2, [0x02]);
+        // A third output, used only to export keying material
+        let exporter_secret = HMAC!(temp1, temp3, [0x03]);
 
-        let rtt_time = Instant::now().duration_since(state.time_sent);
+        let rtt_time = self.clock.monotonic().saturating_sub(state.time_sent);
         self.last_rtt = Some(rtt_time.as_millis() as u32);
 
         if is_previous {
@@ -499,7 +614,14 @@ impl Handshake {
         } else {
             self.state = HandshakeState::None;
         }
-        Ok(Session::new(local_index, peer_index, temp3, temp2))
+        Ok(Session::new(
+            local_index,
+            peer_index,
+            temp3,
+            temp2,
+            exporter_secret,
+            self.clock.monotonic(),
+        ))
     }
 
     pub(super) fn receive_cookie_reply(
@@ -584,7 +706,7 @@ impl Handshake {
         let mut hash = INITIAL_CHAIN_HASH;
         hash = HASH!(hash, self.params.peer_static_public.as_bytes());
         // initiator.ephemeral_private = DH_GENERATE()
-        let ephemeral_private = X25519SecretKey::new();
+        let (ephemeral_private, timestamp) = self.next_ephemeral();
         // msg.message_type = 1
         // msg.reserved_zero = { 0, 0, 0 }
         message_type.copy_from_slice(&super::HANDSHAKE_INIT.to_le_bytes());
@@ -621,21 +743,20 @@ impl Handshake {
         // key = HMAC(temp, initiator.chaining_key || 0x2)
         let key = HMAC!(temp, chaining_key, [0x02]);
         // msg.encrypted_timestamp = AEAD(key, 0, TAI64N(), initiator.hash)
-        let timestamp = self.stamper.stamp();
         SEAL!(encrypted_timestamp, key, 0, timestamp, hash);
         // initiator.hash = HASH(initiator.hash || msg.encrypted_timestamp)
         hash = HASH!(hash, encrypted_timestamp);
 
-        let time_now = Instant::now();
+        let time_now = self.clock.monotonic();
         self.previous = std::mem::replace(
             &mut self.state,
-            HandshakeState::InitSent(HandshakeInitSentState {
+            HandshakeState::InitSent(Box::new(HandshakeInitSentState {
                 local_index,
                 chaining_key,
                 hash,
                 ephemeral_private,
                 time_sent: time_now,
-            }),
+            })),
         );
 
         self.append_mac1_and_mac2(local_index, &mut dst[..super::HANDSHAKE_INIT_SZ])
@@ -651,12 +772,12 @@ impl Handshake {
 
         let state = std::mem::replace(&mut self.state, HandshakeState::None);
         let (mut chaining_key, mut hash, peer_ephemeral_public, peer_index) = match state {
-            HandshakeState::InitReceived {
-                chaining_key,
-                hash,
-                peer_ephemeral_public,
-                peer_index,
-            } => (chaining_key, hash, peer_ephemeral_public, peer_index),
+            HandshakeState::InitReceived(state) => (
+                state.chaining_key,
+                state.hash,
+                state.peer_ephemeral_public,
+                state.peer_index,
+            ),
             _ => {
                 panic!("Unexpected attempt to call send_handshake_response");
             }
@@ -669,7 +790,7 @@ impl Handshake {
         let (mut encrypted_nothing, _) = rest.split_at_mut(16);
 
         // responder.ephemeral_private = DH_GENERATE()
-        let ephemeral_private = X25519SecretKey::new();
+        let (ephemeral_private, _) = self.next_ephemeral();
         let local_index = self.inc_index();
         // msg.message_type = 2
         // msg.reserved_zero = { 0, 0, 0 }
@@ -725,9 +846,21 @@ impl Handshake {
         let temp1 = HMAC!(chaining_key, []);
         let temp2 = HMAC!(temp1, [0x01]);
         let temp3 = HMAC!(temp1, temp2, [0x02]);
+        // A third output, used only to export keying material
+        let exporter_secret = HMAC!(temp1, temp3, [0x03]);
 
         let dst = self.append_mac1_and_mac2(local_index, &mut dst[..super::HANDSHAKE_RESP_SZ])?;
 
-        Ok((dst, Session::new(local_index, peer_index, temp2, temp3)))
+        Ok((
+            dst,
+            Session::new(
+                local_index,
+                peer_index,
+                temp2,
+                temp3,
+                exporter_secret,
+                self.clock.monotonic(),
+            ),
+        ))
     }
 }
@@ -0,0 +1,143 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A histogram of durations with fixed buckets, such as the latency of handshakes
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+/// The default bucket bounds of handshake latency histograms: 10 ms to 10 s
+pub const DEFAULT_LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Counts samples in buckets bounded by ascending upper bounds, and a last bucket for the samples
+/// above every bound
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: Cow<'static, [Duration]>, // The default bounds are shared by every histogram
+    counts: Vec<u64>,                 // One more than the bounds, empty until the first sample
+    sum: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new(&DEFAULT_LATENCY_BUCKETS)
+    }
+}
+
+impl Histogram {
+    /// A histogram with the given upper bounds, in any order
+    pub fn new(bounds: &[Duration]) -> Histogram {
+        let mut bounds = bounds.to_vec();
+        bounds.sort();
+        bounds.dedup();
+        Histogram {
+            bounds: if bounds == DEFAULT_LATENCY_BUCKETS {
+                Cow::Borrowed(&DEFAULT_LATENCY_BUCKETS[..])
+            } else {
+                Cow::Owned(bounds)
+            },
+            counts: Vec::new(),
+            sum: Duration::ZERO,
+        }
+    }
+
+    pub fn record(&mut self, sample: Duration) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; self.bounds.len() + 1];
+        }
+        let bucket = self.bounds.partition_point(|&bound| bound < sample);
+        self.counts[bucket] += 1;
+        self.sum += sample;
+    }
+
+    /// The number of samples recorded
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The sum of the samples recorded
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Every upper bound with the number of samples at most that bound, as Prometheus reports
+    /// them, ending with None for all the samples
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        let bounds = self
+            .bounds
+            .iter()
+            .map(|&bound| Some(bound))
+            .chain(Some(None));
+        let counts = (0..=self.bounds.len()).map(|i| self.counts.get(i).copied().unwrap_or(0));
+        let cumulative = counts.scan(0, |total, count| {
+            *total += count;
+            Some(*total)
+        });
+        bounds.zip(cumulative).collect()
+    }
+
+    /// Add the samples of other, if it has the same bounds. Its samples are dropped otherwise,
+    /// as they can not be split over different buckets.
+    pub fn merge(&mut self, other: &Histogram) {
+        if self.bounds == other.bounds && !other.counts.is_empty() {
+            if self.counts.is_empty() {
+                self.counts = vec![0; self.bounds.len() + 1];
+            }
+            for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+                *count += other;
+            }
+            self.sum += other.sum;
+        }
+    }
+}
+
+// Histograms without samples are equal whether their counts are allocated or not
+impl PartialEq for Histogram {
+    fn eq(&self, other: &Histogram) -> bool {
+        self.bounds == other.bounds && self.sum == other.sum && self.buckets() == other.buckets()
+    }
+}
+
+impl Eq for Histogram {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let ms = Duration::from_millis;
+        let mut histogram = Histogram::new(&[ms(100), ms(10), ms(50), ms(10)]);
+        for &sample in &[ms(1), ms(10), ms(11), ms(75), ms(2000)] {
+            histogram.record(sample);
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.sum(), ms(2097));
+        assert_eq!(
+            histogram.buckets(),
+            [
+                (Some(ms(10)), 2),
+                (Some(ms(50)), 3),
+                (Some(ms(100)), 4),
+                (None, 5)
+            ]
+        );
+
+        let mut merged = Histogram::new(&[ms(10), ms(50), ms(100)]);
+        merged.merge(&histogram);
+        assert_eq!(merged, histogram);
+        merged.merge(&Histogram::default());
+        assert_eq!(merged.count(), 5);
+    }
+}
@@ -1,8 +1,11 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+pub mod clock;
 pub mod errors;
 pub mod handshake;
+pub mod histogram;
+pub mod pool;
 pub mod rate_limiter;
 
 mod session;
@@ -10,17 +13,21 @@ mod tests;
 mod timers;
 
 use crate::crypto::x25519::*;
+use crate::noise::clock::Clock;
 use crate::noise::errors::WireGuardError;
 use crate::noise::handshake::Handshake;
-use crate::noise::rate_limiter::RateLimiter;
+use crate::noise::histogram::Histogram;
+use crate::noise::pool::BufferPool;
+use crate::noise::rate_limiter::{RateLimitResult, RateLimiter};
 use crate::noise::timers::{TimerName, Timers};
 
 use std::collections::VecDeque;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Condvar, Mutex, RwLock};
 use slog::{debug, trace, Logger};
 
 const PEER_HANDSHAKE_RATE_LIMIT: u64 = 10; // The default value to use for rate limiting, when no other rate limiter is defined
@@ -39,7 +46,8 @@ const IPV6_IP_SZ: usize = 16;
 
 const IP_LEN_SZ: usize = 2;
 
-const MAX_QUEUE_DEPTH: usize = 256;
+/// The most inner packets a tunnel keeps while it waits for a session
+pub const MAX_QUEUE_DEPTH: usize = 256;
 const N_SESSIONS: usize = 8; // number of sessions in the ring, better keep a PoT
 
 #[derive(Debug)]
@@ -57,21 +65,205 @@ impl<'a> From<WireGuardError> for TunnResult<'a> {
     }
 }
 
+/// All the results of decapsulating a single datagram, as returned by `Tunn::decapsulate_iter`.
+/// Every result borrows the destination buffer, so it has to be handled before the next one is
+/// requested, which is why this does not implement `Iterator`.
+pub struct DecapsulateIter<'a> {
+    tunn: &'a Tunn,
+    src_addr: Option<IpAddr>,
+    datagram: Option<&'a [u8]>, // Taken by the first call
+    dst: &'a mut [u8],
+    done: bool,
+}
+
+impl<'a> DecapsulateIter<'a> {
+    /// The next result, or None once there is nothing left to do. `TunnResult::Done` is never
+    /// returned.
+    pub fn next_result(&mut self) -> Option<TunnResult<'_>> {
+        if self.done {
+            return None;
+        }
+
+        let result = match self.datagram.take() {
+            Some(datagram) => self.tunn.decapsulate(self.src_addr, datagram, self.dst),
+            None => self.tunn.decapsulate(None, &[], self.dst),
+        };
+
+        match result {
+            TunnResult::Done => {
+                self.done = true;
+                None
+            }
+            // Only packets for the network can be followed by packets from the queue
+            TunnResult::WriteToNetwork(packet) => Some(TunnResult::WriteToNetwork(packet)),
+            result => {
+                self.done = true;
+                Some(result)
+            }
+        }
+    }
+}
+
+/// The state of the current session of a tunnel, as returned by `Tunn::session_stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionStats {
+    /// Time since the handshake that established the session, in whole seconds
+    pub age: Duration,
+    /// Estimated time until the session is replaced by a new handshake, either because of its
+    /// age or because of the number of messages sent at the current rate
+    pub time_to_rekey: Duration,
+    /// Inner bytes sent in the session, including padding
+    pub tx_bytes: usize,
+    /// Inner bytes received in the session, including padding
+    pub rx_bytes: usize,
+    /// The index of the session on this side, the receiver index of the messages of the peer
+    pub local_index: u32,
+    /// The index of the session on the side of the peer, the receiver index of our messages
+    pub peer_index: u32,
+    /// When the handshake that established the session completed on this side
+    pub created: SystemTime,
+    /// The state of the replay check of the messages received in the session
+    pub replay_window: ReplayWindow,
+}
+
+/// The anti-replay window of a session: the counters of the data messages received. A message
+/// is dropped as a replay if its counter was received already, or is older than the window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayWindow {
+    /// One past the highest counter received, 0 until a message is received
+    pub next: u64,
+    /// The counters below `next` that were received: bit `i % 64` of word `i / 64` is set if
+    /// counter `next - 1 - i` was
+    pub bitmap: [u64; REPLAY_WINDOW_WORDS],
+}
+
+/// The number of 64 bit words of `ReplayWindow::bitmap`
+pub const REPLAY_WINDOW_WORDS: usize = 16;
+
+impl ReplayWindow {
+    /// The number of counters below `next` the window covers
+    pub fn size(&self) -> u64 {
+        self.bitmap.len() as u64 * 64
+    }
+
+    /// Whether counter was received, None if it is not in the window: at or above `next`, or so
+    /// far below that a message with it is dropped as too old
+    pub fn received(&self, counter: u64) -> Option<bool> {
+        let i = self.next.checked_sub(counter)?.checked_sub(1)?;
+        if i >= self.size() {
+            return None;
+        }
+        Some((self.bitmap[(i / 64) as usize] >> (i % 64)) & 1 == 1)
+    }
+
+    /// The counters in the window that were not received, in ascending order. Messages with
+    /// them are still accepted.
+    pub fn missing(&self) -> Vec<u64> {
+        let first = self.next.saturating_sub(self.size());
+        (first..self.next)
+            .filter(|&counter| self.received(counter) == Some(false))
+            .collect()
+    }
+}
+
+/// How the inner packet of a data message is padded before encryption. The padding is zeros after
+/// the end of the IP packet, so any receiver strips it using the length in the IP header.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Padding {
+    /// Send inner packets as they are
+    #[default]
+    None,
+    /// Pad to the smallest of these sizes that fits, hiding the exact size of inner packets.
+    /// Packets larger than every size are not padded.
+    Buckets(Vec<usize>),
+}
+
+impl Padding {
+    fn padded_len(&self, len: usize) -> usize {
+        match self {
+            // Keepalives must stay empty
+            _ if len == 0 => 0,
+            Padding::None => len,
+            Padding::Buckets(sizes) => sizes
+                .iter()
+                .copied()
+                .filter(|&size| size >= len)
+                .min()
+                .unwrap_or(len),
+        }
+    }
+}
+
+/// What becomes of inner packets sent while there is no session to encrypt them with, such as
+/// the packet that starts the first handshake with a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeQueuePolicy {
+    /// Keep up to this many packets, at most `MAX_QUEUE_DEPTH`, and send them once the
+    /// handshake completes. Packets beyond that are dropped. Queueing spares TCP a retransmit.
+    Queue(usize),
+    /// Drop them, for applications that resend on their own, such as latency sensitive UDP
+    /// applications that would rather lose a packet than get it late
+    Drop,
+}
+
+impl Default for HandshakeQueuePolicy {
+    fn default() -> Self {
+        HandshakeQueuePolicy::Queue(MAX_QUEUE_DEPTH)
+    }
+}
+
+impl HandshakeQueuePolicy {
+    fn depth(&self) -> usize {
+        match *self {
+            HandshakeQueuePolicy::Queue(depth) => depth.min(MAX_QUEUE_DEPTH),
+            HandshakeQueuePolicy::Drop => 0,
+        }
+    }
+}
+
 /// Tunnel represents a point-to-point WireGuard connection
 pub struct Tunn {
     handshake: Mutex<handshake::Handshake>, // The handshake currently in progress
-    sessions: [Arc<RwLock<Option<session::Session>>>; N_SESSIONS], // The N_SESSIONS most recent sessions, index is session id modulo N_SESSIONS
-    current: AtomicUsize, // Index of most recently used session
+    sessions: [RwLock<Option<Box<session::Session>>>; N_SESSIONS], // The N_SESSIONS most recent sessions, index is session id modulo N_SESSIONS, allocated once established
+    current: AtomicUsize,                  // Index of most recently used session
+    session_changed: (Mutex<()>, Condvar), // Notified when a new session becomes current
     packet_queue: Mutex<VecDeque<Vec<u8>>>, // Queue to store blocked packets
+    queue_depth: usize, // The most packets packet_queue holds, see HandshakeQueuePolicy
+    queue_ready: AtomicBool, // A new session became current while packets were queued
+    session_ready: AtomicBool, // A new session became current
+    buffers: Option<Arc<BufferPool>>, // Queued packets are copied to these in lean mode
     timers: timers::Timers, // Keeps tabs on the expiring timers
     tx_bytes: AtomicUsize,
     rx_bytes: AtomicUsize,
-
-    rate_limiter: Arc<RateLimiter>,
+    tx_control_bytes: AtomicUsize, // Handshake, cookie and keepalive messages, as sent on the wire
+    rx_control_bytes: AtomicUsize,
+    initiated_handshakes: AtomicUsize, // Handshakes completed as the initiator
+    responded_handshakes: AtomicUsize, // Handshakes completed as the responder
+    rekeys: AtomicUsize, // Handshakes completed while a session was already established
+    handshake_latency: Mutex<Histogram>, // From the first initiation to the response, as the initiator
+    max_payload: AtomicUsize, // Packets are not padded beyond this, so they fit the path MTU
+
+    rate_limiter: RwLock<Arc<RateLimiter>>, // Only written when a key rollover ends
+    next_key: RwLock<Option<(Arc<X25519PublicKey>, Arc<RateLimiter>)>>, // The next static key during a rollover
+    padding: Padding,
+    handshake_padding: usize, // Handshake messages are padded to a random size up to this
+    accept_handshake_padding: bool, // Padded handshake messages are accepted, not only exact sizes
+    wg_compat_log: Option<String>, // The base64 key of the peer, when events are also logged as WireGuard does
+    duplicate_init_window: Duration, // How long the last handshake response is resent to copies of its initiation
+    last_response: Mutex<Option<Box<CachedResponse>>>, // Only kept with a duplicate_init_window
 
     pub logger: Logger,
 }
 
+// The last handshake response sent, with the initiation it answered
+struct CachedResponse {
+    sender_idx: u32,
+    init: Vec<u8>, // The ephemeral key, static key and timestamp fields of the initiation
+    response: Vec<u8>,
+    local_index: usize,
+    sent: Duration, // On the monotonic clock
+}
+
 type MessageType = u32;
 const HANDSHAKE_INIT: MessageType = 1;
 const HANDSHAKE_RESP: MessageType = 2;
@@ -147,17 +339,36 @@ impl Tunn {
             ),
             sessions: Default::default(),
             current: Default::default(),
+            session_changed: Default::default(),
             tx_bytes: Default::default(),
             rx_bytes: Default::default(),
+            tx_control_bytes: Default::default(),
+            rx_control_bytes: Default::default(),
+            initiated_handshakes: Default::default(),
+            responded_handshakes: Default::default(),
+            rekeys: Default::default(),
+            handshake_latency: Default::default(),
+            max_payload: AtomicUsize::new(usize::MAX),
 
             packet_queue: Mutex::new(VecDeque::new()),
+            queue_depth: MAX_QUEUE_DEPTH,
+            queue_ready: AtomicBool::new(false),
+            session_ready: AtomicBool::new(false),
+            buffers: None,
             timers: Timers::new(persistent_keepalive, rate_limiter.is_none()),
 
             logger: slog::Logger::root(slog::Discard, slog::o!()),
-
-            rate_limiter: rate_limiter.unwrap_or_else(|| {
+            padding: Padding::None,
+            handshake_padding: 0,
+            accept_handshake_padding: false,
+            wg_compat_log: None,
+            duplicate_init_window: Duration::ZERO,
+            last_response: Mutex::new(None),
+
+            next_key: RwLock::new(None),
+            rate_limiter: RwLock::new(rate_limiter.unwrap_or_else(|| {
                 Arc::new(RateLimiter::new(&static_public, PEER_HANDSHAKE_RATE_LIMIT))
-            }),
+            })),
         };
 
         Ok(Box::new(tunn))
@@ -168,6 +379,114 @@ impl Tunn {
         self.logger = logger
     }
 
+    /// Set how inner packets are padded before they are encrypted
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.padding = padding
+    }
+
+    /// Set whether inner packets sent without a session are queued until the handshake
+    /// completes, or dropped. Packets queued already are kept.
+    pub fn set_handshake_queue_policy(&mut self, policy: HandshakeQueuePolicy) {
+        self.queue_depth = policy.depth()
+    }
+
+    /// Pad handshake initiations and responses with random bytes to a random size up to size, so
+    /// they are not told apart by their fixed sizes. Padding never grows a message beyond the
+    /// path MTU. This is a boringtun extension of the wire format: only peers that set
+    /// `set_accept_handshake_padding` complete such handshakes, the Linux kernel and
+    /// wireguard-go drop them. Messages are not padded with a size of 0.
+    pub fn set_handshake_padding(&mut self, size: usize) {
+        self.handshake_padding = size
+    }
+
+    /// Accept handshake initiations and responses longer than their fixed sizes, ignoring the
+    /// bytes past them, as sent by peers with `set_handshake_padding`. Off by default: only
+    /// messages of exactly the size of their type are parsed, as the protocol specifies.
+    pub fn set_accept_handshake_padding(&mut self, accept: bool) {
+        self.accept_handshake_padding = accept
+    }
+
+    /// Also log handshake, keepalive and key expiry events with the messages of the Linux WireGuard
+    /// module, the peer named by its base64 key, so tools that scrape those lines work unchanged.
+    pub fn set_wg_compat_log(&mut self, on: bool) {
+        self.wg_compat_log = match on {
+            true => Some(self.peer_static_public().to_base64()),
+            false => None,
+        }
+    }
+
+    // Log the message built by f from the base64 key of the peer, if WireGuard compatible log
+    // lines are on
+    fn log_compat<F: FnOnce(&str) -> String>(&self, f: F) {
+        if let Some(key) = &self.wg_compat_log {
+            debug!(self.logger, "{}", f(key));
+        }
+    }
+
+    /// Answer a copy of the last handshake initiation received within window of it with the
+    /// response already sent, instead of computing the handshake again. A retransmitted or
+    /// replayed initiation then costs a comparison rather than the key exchange, while without
+    /// this it is dropped, as its timestamp is not newer than the last one, only after that work.
+    /// An initiation with any other contents still goes through the whole handshake and the
+    /// timestamp check. 0, the default, keeps no response.
+    pub fn set_duplicate_init_window(&mut self, window: Duration) {
+        self.duplicate_init_window = window
+    }
+
+    /// Keep an idle tunnel small, for devices with many peers that are rarely active. The tunnel
+    /// allocates its timers on its first handshake or packet, and reads slots shared by every
+    /// lean tunnel until then. The packets it queues are copied to buffers from the shared pool,
+    /// and the queue is freed again once it drains. Handshakes are accepted as usual. Call it
+    /// before the tunnel is used.
+    pub fn set_lean(&mut self, buffers: Arc<BufferPool>) {
+        self.buffers = Some(buffers);
+        self.timers.set_lean();
+    }
+
+    /// True while a lean tunnel has had no handshake or packet, and holds no timers of its own
+    pub fn is_idle(&self) -> bool {
+        self.timers.is_idle()
+    }
+
+    /// The pool queued packets are copied to, in lean mode
+    pub fn buffer_pool(&self) -> Option<&Arc<BufferPool>> {
+        self.buffers.as_ref()
+    }
+
+    /// Set the upper bounds of the buckets of the handshake latency histogram, see
+    /// `handshake_latency`. The samples recorded so far are dropped.
+    pub fn set_handshake_latency_buckets(&mut self, bounds: &[Duration]) {
+        *self.handshake_latency.lock() = Histogram::new(bounds)
+    }
+
+    /// Replace the source of handshake ephemeral keys.
+    /// Only for reproducible tests: any rng other than the default `OsRng` is insecure.
+    pub fn set_rng(&mut self, rng: Arc<dyn Rng>) {
+        self.handshake.lock().set_rng(rng)
+    }
+
+    /// Replace the clock the timers and the handshake timestamps read, before the tunnel is used.
+    /// The timers start again from the current time of the clock. For tests, with a
+    /// `ManualClock` that moves without waiting.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.handshake.lock().set_clock(Arc::clone(&clock));
+        self.timers.set_clock(clock);
+    }
+
+    /// Use ephemeral as the ephemeral key of the next handshake message, initiation or response,
+    /// and timestamp, if any, as the TAI64N timestamp of the next initiation, so the message can
+    /// be compared byte for byte with one captured from another implementation.
+    /// Only for interop tests and tools: a known ephemeral key breaks the secrecy of the session.
+    #[cfg(any(test, feature = "interop-testing"))]
+    pub fn inject_ephemeral(&self, ephemeral: X25519SecretKey, timestamp: Option<[u8; 12]>) {
+        self.handshake.lock().inject_ephemeral(ephemeral, timestamp)
+    }
+
+    /// The static public key of the remote peer
+    pub fn peer_static_public(&self) -> Arc<X25519PublicKey> {
+        Arc::clone(self.handshake.lock().peer_static_public())
+    }
+
     /// Update the private key and clear existing sessions
     pub fn set_static_private(
         &mut self,
@@ -176,9 +495,10 @@ impl Tunn {
         rate_limiter: Option<Arc<RateLimiter>>,
     ) -> Result<(), WireGuardError> {
         self.timers.should_reset_rr = rate_limiter.is_none();
-        self.rate_limiter = rate_limiter.unwrap_or_else(|| {
+        *self.rate_limiter.get_mut() = rate_limiter.unwrap_or_else(|| {
             Arc::new(RateLimiter::new(&static_public, PEER_HANDSHAKE_RATE_LIMIT))
         });
+        *self.next_key.get_mut() = None;
         self.handshake
             .lock()
             .set_static_private(static_private, static_public)?;
@@ -188,6 +508,37 @@ impl Tunn {
         Ok(())
     }
 
+    /// Start a key rollover: handshakes addressed to either the current or the next private key
+    /// are accepted, and existing sessions are kept. A peer that completes a handshake with the
+    /// next key is also initiated with it from then on.
+    pub fn set_next_static_private(
+        &self,
+        static_private: Arc<X25519SecretKey>,
+        static_public: Arc<X25519PublicKey>,
+        rate_limiter: Option<Arc<RateLimiter>>,
+    ) -> Result<(), WireGuardError> {
+        let rate_limiter = rate_limiter.unwrap_or_else(|| {
+            Arc::new(RateLimiter::new(&static_public, PEER_HANDSHAKE_RATE_LIMIT))
+        });
+        self.handshake
+            .lock()
+            .set_next_static_private(static_private, Arc::clone(&static_public))?;
+        *self.next_key.write() = Some((static_public, rate_limiter));
+        Ok(())
+    }
+
+    /// End a key rollover, keeping only the private key matching `static_public`. Sessions
+    /// established with either key are kept until they expire.
+    pub fn commit_static_private(&self, static_public: &X25519PublicKey) {
+        let mut next_key = self.next_key.write();
+        if let Some((next_public, rate_limiter)) = next_key.take() {
+            self.handshake.lock().commit_static_private(static_public);
+            if next_public.as_bytes() == static_public.as_bytes() {
+                *self.rate_limiter.write() = rate_limiter;
+            }
+        }
+    }
+
     /// Encapsulate a single packet from the tunnel interface.
     /// Returns TunnResult.
     /// # Panics
@@ -197,17 +548,32 @@ impl Tunn {
         let current = self.current.load(Ordering::SeqCst);
         if let Some(ref session) = *self.sessions[current % N_SESSIONS].read() {
             // Send the packet using an established session
-            let packet = session.format_packet_data(src, dst);
-            self.timer_tick(TimerName::TimeLastPacketSent);
-            // Exclude Keepalive packets from timer update.
-            if src.len() != 0 {
-                self.timer_tick(TimerName::TimeLastDataPacketSent);
+            let padded_len = self
+                .padding
+                .padded_len(src.len())
+                .min(self.max_payload.load(Ordering::Relaxed))
+                .max(src.len());
+            match session.format_packet_data(src, padded_len, dst) {
+                Ok(n) => {
+                    self.timer_tick(TimerName::TimeLastPacketSent);
+                    // Exclude Keepalive packets from timer update.
+                    if src.len() != 0 {
+                        self.timer_tick(TimerName::TimeLastDataPacketSent);
+                    }
+                    if src.is_empty() {
+                        self.tx_control_bytes.fetch_add(n, Ordering::Relaxed);
+                    }
+                    self.tx_bytes.fetch_add(src.len(), Ordering::Relaxed);
+                    return TunnResult::WriteToNetwork(&mut dst[..n]);
+                }
+                Err(_) => {
+                    // The session may not send any more messages, the packet waits for a new one
+                    debug!(self.logger, "SESSION_EXHAUSTED(REJECT_AFTER_MESSAGES)"; "session" => session.receiving_index);
+                }
             }
-            self.tx_bytes.fetch_add(src.len(), Ordering::Relaxed);
-            return TunnResult::WriteToNetwork(packet);
         }
 
-        // If there is no session, queue the packet for future retry
+        // If there is no session, or it is exhausted, queue the packet for future retry
         self.queue_packet(src);
         // Initiate a new handshake if none is in progress
         self.format_handshake_initiation(dst, false)
@@ -228,24 +594,81 @@ impl Tunn {
             // Indicates a repeated call
             return self.send_queued_packet(dst);
         }
+        let datagram = match self.accept_handshake_padding {
+            true => Tunn::strip_handshake_padding(datagram),
+            false => datagram,
+        };
 
+        // During a key rollover some handshakes are addressed to the next key. The next key is
+        // locked before the current one, as in commit_static_private.
         let mut cookie = [0u8; COOKIE_REPLY_SZ];
-        let packet = match self
-            .rate_limiter
-            .verify_packet(src_addr, datagram, &mut cookie)
-        {
-            Ok(packet) => packet,
-            Err(TunnResult::WriteToNetwork(cookie)) => {
+        let verified = {
+            let next_key = self.next_key.read();
+            let current = self.rate_limiter.read();
+            let rate_limiter = match &*next_key {
+                Some((_, next)) if next.matches_mac1(datagram) => next,
+                _ => &*current,
+            };
+            rate_limiter.verify_packet(src_addr, datagram, &mut cookie)
+        };
+        let packet = match verified {
+            Ok(RateLimitResult::Allowed(packet)) => packet,
+            Ok(RateLimitResult::CookieRequired(cookie)) => {
+                self.tx_control_bytes
+                    .fetch_add(cookie.len(), Ordering::Relaxed);
                 dst[..cookie.len()].copy_from_slice(cookie);
                 return TunnResult::WriteToNetwork(&mut dst[..cookie.len()]);
             }
-            Err(TunnResult::Err(e)) => return TunnResult::Err(e),
-            _ => unreachable!(),
+            Ok(RateLimitResult::RateLimited) => return TunnResult::Err(WireGuardError::UnderLoad),
+            Ok(RateLimitResult::InvalidMac) => return TunnResult::Err(WireGuardError::InvalidMac),
+            Err(e) => return TunnResult::Err(e),
         };
 
         self.handle_verified_packet(packet, dst)
     }
 
+    /// Like `decapsulate`, but also returns the packets that would otherwise require repeated
+    /// calls with an empty datagram, such as data packets queued while the handshake the datagram
+    /// completes was in progress.
+    pub fn decapsulate_iter<'a>(
+        &'a self,
+        src_addr: Option<IpAddr>,
+        datagram: &'a [u8],
+        dst: &'a mut [u8],
+    ) -> DecapsulateIter<'a> {
+        DecapsulateIter {
+            tunn: self,
+            src_addr,
+            datagram: Some(datagram),
+            dst,
+            done: false,
+        }
+    }
+
+    /// Decrypts a data packet received from the network without touching any tunnel state.
+    /// The anti-replay window, current session, endpoint, timers and counters are left as is, so
+    /// the same packet can be decrypted repeatedly and in any order.
+    /// This offers no replay protection and must never be used for a live tunnel, it is intended
+    /// for passive analysis of mirrored traffic.
+    pub fn decapsulate_observe<'a>(&self, datagram: &[u8], dst: &'a mut [u8]) -> TunnResult<'a> {
+        let packet = match Tunn::parse_incoming_packet(datagram) {
+            Ok(Packet::PacketData(packet)) => packet,
+            Ok(_) => return TunnResult::Err(WireGuardError::WrongPacketType),
+            Err(e) => return TunnResult::Err(e),
+        };
+
+        let lock = self.sessions[packet.receiver_idx as usize % N_SESSIONS].read();
+        let session = match lock.as_ref() {
+            Some(session) => session,
+            None => return TunnResult::Err(WireGuardError::NoCurrentSession),
+        };
+
+        match session.observe_packet_data(packet, dst) {
+            Ok(packet) => Tunn::truncate_decapsulated_packet(packet),
+            Err(e) => TunnResult::Err(e),
+        }
+    }
+
     pub(crate) fn handle_verified_packet<'a>(
         &self,
         packet: Packet,
@@ -296,58 +719,197 @@ impl Tunn {
         })
     }
 
+    // Append random padding to the handshake message of len bytes at the start of dst
+    fn pad_handshake<'a>(&self, dst: &'a mut [u8], len: usize) -> &'a mut [u8] {
+        let max_len = self
+            .handshake_padding
+            .min(dst.len())
+            .min(self.max_payload().saturating_add(DATA_OVERHEAD_SZ));
+        if max_len <= len {
+            return &mut dst[..len];
+        }
+
+        let mut random = [0u8; 4];
+        OsRng.fill(&mut random);
+        let padded_len = len + u32::from_le_bytes(random) as usize % (max_len - len + 1);
+        OsRng.fill(&mut dst[len..padded_len]);
+        &mut dst[..padded_len]
+    }
+
+    /// A handshake message without the padding a peer may have appended, see
+    /// `set_handshake_padding`. Other datagrams are returned as they are. Only for receivers that
+    /// opted in to padded handshakes, everything else parses exact sizes.
+    pub fn strip_handshake_padding(src: &[u8]) -> &[u8] {
+        if src.len() < 4 {
+            return src;
+        }
+        match u32::from_le_bytes(make_array(&src[0..4])) {
+            HANDSHAKE_INIT if src.len() > HANDSHAKE_INIT_SZ => &src[..HANDSHAKE_INIT_SZ],
+            HANDSHAKE_RESP if src.len() > HANDSHAKE_RESP_SZ => &src[..HANDSHAKE_RESP_SZ],
+            _ => src,
+        }
+    }
+
+    /// Check if a datagram looks like a handshake initiation, based on its type and size alone
+    pub fn is_handshake_init(src: &[u8]) -> bool {
+        src.len() == HANDSHAKE_INIT_SZ
+            && u32::from_le_bytes(make_array(&src[0..4])) == HANDSHAKE_INIT
+    }
+
+    /// The receiver index of a datagram that looks like a data message, based on its type and
+    /// size alone
+    pub fn data_receiver_index(src: &[u8]) -> Option<u32> {
+        if src.len() < DATA_OVERHEAD_SZ || u32::from_le_bytes(make_array(&src[0..4])) != DATA {
+            return None;
+        }
+        Some(u32::from_le_bytes(make_array(&src[4..8])))
+    }
+
+    /// Is there a session data messages with this receiver index would be decrypted with
+    pub fn has_session(&self, receiver_idx: u32) -> bool {
+        match &*self.sessions[receiver_idx as usize % N_SESSIONS].read() {
+            Some(session) => session.receiving_index == receiver_idx,
+            None => false,
+        }
+    }
+
     fn handle_handshake_init<'a>(
         &self,
         p: HandshakeInit,
         dst: &'a mut [u8],
     ) -> Result<TunnResult<'a>, WireGuardError> {
         debug!(self.logger, "Received handshake_initiation"; "remote_idx" => p.sender_idx);
+        self.log_compat(|key| format!("Receiving handshake initiation from peer {}", key));
+
+        if let Some(len) = self.resend_response(&p, dst) {
+            let packet = &mut dst[..len];
+            debug!(self.logger, "Resending handshake_response to a duplicate handshake_initiation"; "remote_idx" => p.sender_idx);
+            self.rx_control_bytes
+                .fetch_add(HANDSHAKE_INIT_SZ, Ordering::Relaxed);
+            self.tx_control_bytes
+                .fetch_add(packet.len(), Ordering::Relaxed);
+            return Ok(TunnResult::WriteToNetwork(packet));
+        }
 
+        let (sender_idx, init) = (p.sender_idx, Self::init_fields(&p));
         let (packet, session) = {
             let mut handshake = self.handshake.lock();
             handshake.receive_handshake_initialization(p, dst)?
         };
+        let len = packet.len();
+        let packet = self.pad_handshake(dst, len);
 
         // Store new session in ring buffer
+        self.count_rekey();
         let index = session.local_index();
-        *self.sessions[index % N_SESSIONS].write() = Some(session);
+        *self.sessions[index % N_SESSIONS].write() = Some(Box::new(session));
 
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick(TimerName::TimeLastPacketSent);
         self.timer_tick_session_established(false, index); // New session established, we are not the initiator
+        self.responded_handshakes.fetch_add(1, Ordering::Relaxed);
+        self.rx_control_bytes
+            .fetch_add(HANDSHAKE_INIT_SZ, Ordering::Relaxed);
+        self.tx_control_bytes
+            .fetch_add(packet.len(), Ordering::Relaxed);
+
+        if self.duplicate_init_window > Duration::ZERO {
+            *self.last_response.lock() = Some(Box::new(CachedResponse {
+                sender_idx,
+                init,
+                response: packet.to_vec(),
+                local_index: index,
+                sent: self.timers.clock.monotonic(),
+            }));
+        }
 
         debug!(self.logger, "Sending handshake_response"; "local_idx" => index);
+        self.log_compat(|key| format!("Sending handshake response to peer {}", key));
 
         Ok(TunnResult::WriteToNetwork(packet))
     }
 
+    fn init_fields(p: &HandshakeInit) -> Vec<u8> {
+        [
+            p.unencrypted_ephemeral,
+            p.encrypted_static,
+            p.encrypted_timestamp,
+        ]
+        .concat()
+    }
+
+    // Copy the cached response to dst, returning its length, if p is the initiation it answered, received within the
+    // window, and the session of the response is still there. Identical contents mean an
+    // identical timestamp, so no initiation older than the last one accepted is ever answered.
+    fn resend_response(&self, p: &HandshakeInit, dst: &mut [u8]) -> Option<usize> {
+        let cached = self.last_response.lock();
+        let cached = cached.as_ref()?;
+        if cached.sender_idx != p.sender_idx
+            || self.timers.clock.monotonic().saturating_sub(cached.sent)
+                > self.duplicate_init_window
+            || cached.init != Self::init_fields(p)
+            || cached.response.len() > dst.len()
+        {
+            return None;
+        }
+        let session = self.sessions[cached.local_index % N_SESSIONS].read();
+        if session.as_ref()?.local_index() != cached.local_index {
+            return None;
+        }
+
+        dst[..cached.response.len()].copy_from_slice(&cached.response);
+        Some(cached.response.len())
+    }
+
     fn handle_handshake_response<'a>(
         &self,
         p: HandshakeResponse,
         dst: &'a mut [u8],
     ) -> Result<TunnResult<'a>, WireGuardError> {
         debug!(self.logger, "Received handshake_response"; "local_idx" => p.receiver_idx, "remote_idx" => p.sender_idx);
+        self.log_compat(|key| format!("Receiving handshake response from peer {}", key));
 
-        let session = {
+        let (session, started) = {
             let mut handshake = self.handshake.lock();
-            handshake.receive_handshake_response(p)?
+            let session = handshake.receive_handshake_response(p)?;
+            (session, handshake.started.take())
         };
+        if let Some(started) = started {
+            self.handshake_latency
+                .lock()
+                .record(self.timers.clock.monotonic().saturating_sub(started));
+        }
 
-        let keepalive_packet = session.format_packet_data(&[], dst);
+        let n = session.format_packet_data(&[], 0, dst)?;
+        let keepalive_packet = &mut dst[..n];
         // Store new session in ring buffer
+        self.count_rekey();
         let l_idx = session.local_index();
         let index = l_idx % N_SESSIONS;
-        *self.sessions[index].write() = Some(session);
+        *self.sessions[index].write() = Some(Box::new(session));
 
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick_session_established(true, index); // New session established, we are the initiator
         self.set_current_session(l_idx);
+        self.initiated_handshakes.fetch_add(1, Ordering::Relaxed);
+        self.rx_control_bytes
+            .fetch_add(HANDSHAKE_RESP_SZ, Ordering::Relaxed);
+        self.tx_control_bytes
+            .fetch_add(keepalive_packet.len(), Ordering::Relaxed);
 
         debug!(self.logger, "Sending keepalive");
+        self.log_compat(|key| format!("Sending keepalive packet to peer {}", key));
 
         Ok(TunnResult::WriteToNetwork(keepalive_packet)) // Send a keepalive as a response
     }
 
+    // Count a handshake about to store its session as a rekey, if there is a session already
+    fn count_rekey(&self) {
+        if self.sessions.iter().any(|session| session.read().is_some()) {
+            self.rekeys.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
     fn handle_cookie_reply<'a>(
         &self,
         p: PacketCookieReply,
@@ -359,6 +921,8 @@ impl Tunn {
         }
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick(TimerName::TimeCookieReceived);
+        self.rx_control_bytes
+            .fetch_add(COOKIE_REPLY_SZ, Ordering::Relaxed);
 
         debug!(self.logger, "Did set cookie");
 
@@ -373,11 +937,20 @@ impl Tunn {
             return;
         }
         if self.sessions[cur_idx % N_SESSIONS].read().is_none()
-            || self.timers.session_timers[new_idx % N_SESSIONS].time()
-                >= self.timers.session_timers[cur_idx % N_SESSIONS].time()
+            || self.timers.state().session_timers[new_idx % N_SESSIONS].time()
+                >= self.timers.state().session_timers[cur_idx % N_SESSIONS].time()
         {
             self.current.store(new_idx, Ordering::SeqCst);
             debug!(self.logger, "New session"; "session" => new_idx);
+            self.log_compat(|key| format!("Keypair {} created for peer {}", new_idx, key));
+            self.session_ready.store(true, Ordering::Relaxed);
+            if !self.packet_queue.lock().is_empty() {
+                self.queue_ready.store(true, Ordering::Relaxed);
+            }
+
+            // Taking the lock orders the notification after the check of a waiter about to wait
+            let _guard = self.session_changed.0.lock();
+            self.session_changed.1.notify_all();
         }
     }
 
@@ -403,6 +976,12 @@ impl Tunn {
         self.set_current_session(r_idx);
 
         self.timer_tick(TimerName::TimeLastPacketReceived);
+        if decapsulated_packet.is_empty() {
+            // A keepalive
+            self.rx_control_bytes
+                .fetch_add(DATA_OVERHEAD_SZ, Ordering::Relaxed);
+            self.log_compat(|key| format!("Receiving keepalive packet from peer {}", key));
+        }
 
         Ok(self.validate_decapsulated_packet(decapsulated_packet))
     }
@@ -414,11 +993,19 @@ impl Tunn {
         dst: &'a mut [u8],
         force_resend: bool,
     ) -> TunnResult<'a> {
+        if self.timers.responder_only {
+            return TunnResult::Done;
+        }
+
         let mut handshake = self.handshake.lock();
         if handshake.is_in_progress() && !force_resend {
             return TunnResult::Done;
         }
 
+        if !force_resend && self.is_handshake_backed_off() {
+            return TunnResult::Done;
+        }
+
         if handshake.is_expired() {
             self.timers.clear();
         }
@@ -428,11 +1015,18 @@ impl Tunn {
         match handshake.format_handshake_initiation(dst) {
             Ok(packet) => {
                 debug!(self.logger, "Sending handshake_initiation");
+                self.log_compat(|key| format!("Sending handshake initiation to peer {}", key));
+                let len = packet.len();
+                let packet = self.pad_handshake(dst, len);
 
                 if starting_new_handshake {
                     self.timer_tick(TimerName::TimeLastHandshakeStarted);
+                    handshake.started = Some(self.timers.clock.monotonic());
                 }
                 self.timer_tick(TimerName::TimeLastPacketSent);
+                self.timer_tick_handshake_sent();
+                self.tx_control_bytes
+                    .fetch_add(packet.len(), Ordering::Relaxed);
                 TunnResult::WriteToNetwork(packet)
             }
             Err(e) => TunnResult::Err(e),
@@ -460,6 +1054,21 @@ impl Tunn {
     /// Check if an IP packet is v4 or v6, truncate to the length indicated by the length field
     /// Returns the truncated packet and the source IP as TunnResult
     fn validate_decapsulated_packet<'a>(&self, packet: &'a mut [u8]) -> TunnResult<'a> {
+        let result = Tunn::truncate_decapsulated_packet(packet);
+
+        match &result {
+            TunnResult::WriteToTunnelV4(packet, _) | TunnResult::WriteToTunnelV6(packet, _) => {
+                self.timer_tick(TimerName::TimeLastDataPacketReceived);
+                self.rx_bytes.fetch_add(packet.len(), Ordering::Relaxed);
+            }
+            _ => {}
+        }
+
+        result
+    }
+
+    // The stateless part of validate_decapsulated_packet
+    fn truncate_decapsulated_packet<'a>(packet: &'a mut [u8]) -> TunnResult<'a> {
         let (computed_len, src_ip_address) = match packet.len() {
             0 => return TunnResult::Done, // This is keepalive, and not an error
             _ if packet[0] >> 4 == 4 && packet.len() >= IPV4_MIN_HEADER_SIZE => {
@@ -485,9 +1094,6 @@ impl Tunn {
             return TunnResult::Err(WireGuardError::InvalidPacket);
         }
 
-        self.timer_tick(TimerName::TimeLastDataPacketReceived);
-        self.rx_bytes.fetch_add(computed_len, Ordering::Relaxed);
-
         match src_ip_address {
             IpAddr::V4(addr) => TunnResult::WriteToTunnelV4(&mut packet[..computed_len], addr),
             IpAddr::V6(addr) => TunnResult::WriteToTunnelV6(&mut packet[..computed_len], addr),
@@ -502,7 +1108,10 @@ impl Tunn {
                     // On error, return packet to the queue
                     self.requeue_packet(packet);
                 }
-                r => return r,
+                r => {
+                    self.recycle_packet(packet);
+                    return r;
+                }
             }
         }
         TunnResult::Done
@@ -511,16 +1120,19 @@ impl Tunn {
     // Push packet to the back of the queue
     fn queue_packet(&self, packet: &[u8]) {
         let mut q = self.packet_queue.lock();
-        if q.len() < MAX_QUEUE_DEPTH {
+        if q.len() < self.queue_depth {
             // Drop if too many are already in queue
-            q.push_back(packet.to_vec());
+            q.push_back(match &self.buffers {
+                Some(buffers) => buffers.copy(packet),
+                None => packet.to_vec(),
+            });
         }
     }
 
     // Push packet to the front of the queue
     fn requeue_packet(&self, packet: Vec<u8>) {
         let mut q = self.packet_queue.lock();
-        if q.len() < MAX_QUEUE_DEPTH {
+        if q.len() < self.queue_depth {
             // Drop if too many are already in queue
             q.push_front(packet);
         }
@@ -528,7 +1140,18 @@ impl Tunn {
 
     fn dequeue_packet(&self) -> Option<Vec<u8>> {
         let mut q = self.packet_queue.lock();
-        q.pop_front()
+        let packet = q.pop_front();
+        if q.is_empty() && self.buffers.is_some() {
+            *q = VecDeque::new();
+        }
+        packet
+    }
+
+    // Return the buffer of a packet sent or dropped to the pool, in lean mode
+    fn recycle_packet(&self, packet: Vec<u8>) {
+        if let Some(buffers) = &self.buffers {
+            buffers.recycle(packet);
+        }
     }
 
     fn estimate_loss(&self) -> f32 {
@@ -563,6 +1186,22 @@ impl Tunn {
         }
     }
 
+    /// Block until a handshake completed and a session can be used, or the timeout expires.
+    /// Returns true if there is a session.
+    pub fn wait_for_session(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (lock, cvar) = &self.session_changed;
+        let mut guard = lock.lock();
+        loop {
+            if self.time_since_last_handshake().is_some() {
+                return true;
+            }
+            if cvar.wait_until(&mut guard, deadline).timed_out() {
+                return self.time_since_last_handshake().is_some();
+            }
+        }
+    }
+
     /// Return stats from the tunnel:
     /// * Time since last handshake in seconds
     /// * Data bytes sent
@@ -581,9 +1220,123 @@ impl Tunn {
         (time, tx_bytes, rx_bytes, loss, rtt)
     }
 
+    /// Bytes of inner packets sent and received, as in `stats`, without taking any lock
+    pub fn data_stats(&self) -> (usize, usize) {
+        (
+            self.tx_bytes.load(Ordering::Relaxed),
+            self.rx_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Add the traffic counted by another tunnel to the counters of this one, when it takes
+    /// over from that tunnel
+    pub fn add_counters(&self, from: &Tunn) {
+        for (to, from) in &[
+            (&self.tx_bytes, &from.tx_bytes),
+            (&self.rx_bytes, &from.rx_bytes),
+            (&self.tx_control_bytes, &from.tx_control_bytes),
+            (&self.rx_control_bytes, &from.rx_control_bytes),
+            (&self.initiated_handshakes, &from.initiated_handshakes),
+            (&self.responded_handshakes, &from.responded_handshakes),
+            (&self.rekeys, &from.rekeys),
+        ] {
+            to.fetch_add(from.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        let latency = from.handshake_latency.lock().clone();
+        self.handshake_latency.lock().merge(&latency);
+    }
+
+    /// Bytes of handshake, cookie and keepalive messages sent and received, as counted on the
+    /// wire. The data bytes of `stats` only count inner packets, so these are the protocol
+    /// overhead besides the per packet encryption.
+    pub fn control_stats(&self) -> (usize, usize) {
+        (
+            self.tx_control_bytes.load(Ordering::Relaxed),
+            self.rx_control_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The number of handshakes completed as the initiator, when the response to an initiation
+    /// of this side arrived, and as the responder, when this side answered an initiation of the
+    /// peer
+    pub fn handshake_stats(&self) -> (usize, usize) {
+        (
+            self.initiated_handshakes.load(Ordering::Relaxed),
+            self.responded_handshakes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The number of handshakes completed, in either role, that replaced an established session
+    pub fn rekeys(&self) -> usize {
+        self.rekeys.load(Ordering::Relaxed)
+    }
+
+    /// The latencies of the handshakes completed as the initiator, each from the first
+    /// initiation, retries included, to the response that established the session
+    pub fn handshake_latency(&self) -> Histogram {
+        self.handshake_latency.lock().clone()
+    }
+
+    /// Limit padding to max bytes of inner packet, the most that fits a datagram to the endpoint
+    /// without fragmentation. Larger packets are still sent, but never padded.
+    pub fn set_max_payload(&self, max: usize) {
+        self.max_payload.store(max, Ordering::Relaxed);
+    }
+
+    pub fn max_payload(&self) -> usize {
+        self.max_payload.load(Ordering::Relaxed)
+    }
+
+    /// Returns true once after a session became current while packets were queued, which
+    /// happens when the peer initiated the handshake. The queue should then be flushed by
+    /// calling decapsulate with an empty datagram until it returns TunnResult::Done.
+    pub fn wants_queue_flush(&self) -> bool {
+        self.queue_ready.load(Ordering::Relaxed) && self.queue_ready.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns true once after a new session became current, when a handshake completed
+    pub fn take_new_session(&self) -> bool {
+        self.session_ready.load(Ordering::Relaxed)
+            && self.session_ready.swap(false, Ordering::Relaxed)
+    }
+
+    /// A handshake initiation was sent, and no response was received yet
+    pub fn is_handshake_in_progress(&self) -> bool {
+        self.handshake.lock().is_in_progress()
+    }
+
     pub fn is_expired(&self) -> bool {
         self.handshake.lock().is_expired()
     }
+
+    /// Drop every session, and any handshake in progress, so no more packets are sent or accepted
+    /// with their keys. The next packet to the peer starts a new handshake.
+    pub fn expire_sessions(&self) {
+        // A response to an initiation sent before must not establish a session either
+        self.handshake.lock().set_expired();
+        for session in &self.sessions {
+            if let Some(session) = session.write().take() {
+                debug!(self.logger, "SESSION_EXPIRED(FORCED)"; "session" => session.receiving_index);
+            }
+        }
+    }
+
+    /// Derive keying material for use outside the tunnel from the current session, in the
+    /// spirit of RFC 5705. Both peers get the same output for the same label and context, and
+    /// it reveals nothing about the transport keys. Labels and contexts are limited to 255
+    /// bytes, the output to 8160 bytes. Fails if no session is established.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), WireGuardError> {
+        let current = self.current.load(Ordering::SeqCst);
+        match *self.sessions[current % N_SESSIONS].read() {
+            Some(ref session) => session.export_keying_material(label, context, out),
+            None => Err(WireGuardError::NoCurrentSession),
+        }
+    }
 }
 
 #[inline(always)]
@@ -0,0 +1,66 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A pool of packet buffers shared by the queues of many tunnels
+
+use parking_lot::Mutex;
+
+/// Buffers for the packets tunnels queue, recycled once the packets are sent, so tunnels that
+/// queue packets only now and then share a few buffers rather than each keeping its own
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_free: usize, // Buffers returned beyond this many are freed
+}
+
+impl BufferPool {
+    /// A pool keeping up to max_free buffers for reuse
+    pub fn new(max_free: usize) -> BufferPool {
+        BufferPool {
+            free: Mutex::new(Vec::new()),
+            max_free,
+        }
+    }
+
+    /// A copy of packet, in a buffer from the pool if it has one
+    pub fn copy(&self, packet: &[u8]) -> Vec<u8> {
+        let mut buf = self.free.lock().pop().unwrap_or_default();
+        buf.clear();
+        buf.extend_from_slice(packet);
+        buf
+    }
+
+    /// Return a buffer to the pool, once its packet is sent or dropped
+    pub fn recycle(&self, buf: Vec<u8>) {
+        let mut free = self.free.lock();
+        if free.len() < self.max_free {
+            free.push(buf);
+        }
+    }
+
+    /// The number of buffers ready for reuse
+    pub fn free(&self) -> usize {
+        self.free.lock().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new(1);
+        let first = pool.copy(b"first packet");
+        assert_eq!(first, b"first packet");
+        let capacity = first.capacity();
+        pool.recycle(first);
+        pool.recycle(b"dropped, the pool is full".to_vec());
+        assert_eq!(pool.free(), 1);
+
+        let second = pool.copy(b"second");
+        assert_eq!(second, b"second");
+        assert_eq!(second.capacity(), capacity);
+        assert_eq!(pool.free(), 0);
+    }
+}
@@ -1,13 +1,13 @@
 use super::make_array;
 use crate::crypto::blake2s::{constant_time_mac_check, Blake2s};
 use crate::crypto::chacha20poly1305::ChaCha20Poly1305;
-use crate::crypto::x25519::X25519SecretKey;
+use crate::crypto::x25519::{OsRng, Rng};
 use crate::noise::handshake::{LABEL_COOKIE, LABEL_MAC1};
 use crate::noise::*;
 
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use parking_lot::Mutex;
 
@@ -17,8 +17,27 @@ const COOKIE_NONCE_SIZE: usize = 24;
 
 const RESET_PERIOD: u64 = 1; // How often should reset count in seconds
 
+const LABEL_SHARED_SECRET: &[u8] = b"boringtun cookie secret";
+
 type Cookie = [u8; COOKIE_SIZE];
 
+/// The verdict of `RateLimiter::verify_packet` on a datagram
+#[derive(Debug)]
+pub enum RateLimitResult<'a, 'b> {
+    /// The packet may be processed: it is not a handshake message, or one with a valid mac1
+    /// received while not under load, or with a valid mac2 as well
+    Allowed(Packet<'a>),
+    /// A handshake message received under load without a valid mac2, and the cookie reply to
+    /// send back. A wrong mac2 is answered the same as a missing one, as it may come from a
+    /// cookie of a previous secret.
+    CookieRequired(&'b mut [u8]),
+    /// A handshake message received under load from an unknown address, which no cookie can be
+    /// sent to, so it is dropped
+    RateLimited,
+    /// A handshake message with a wrong mac1, which is dropped
+    InvalidMac,
+}
+
 // There are two places where WireGuard requires "randomness" for cookies
 // * The 24 byte nonce in the cookie massage - here the only goal is to avoid nonce reuse
 // * A secret value that changes every two minutes
@@ -31,6 +50,7 @@ pub struct RateLimiter {
     nonce_key: [u8; 32],  // The key we use to derive the nonce
     secret_key: [u8; 16], // The key we use to derive the cookie
     start_time: Instant,
+    shared_clock: bool, // The secret changes with the wall clock rather than the time since start
     nonce_ctr: AtomicU64, // A single 64bit counter should suffice for many years
     mac1_key: [u8; 32],
     cookie_key: [u8; 32],
@@ -41,10 +61,17 @@ pub struct RateLimiter {
 
 impl RateLimiter {
     pub fn new(public_key: &X25519PublicKey, limit: u64) -> Self {
+        RateLimiter::new_with_rng(public_key, limit, &OsRng)
+    }
+
+    /// Create a rate limiter that derives its cookie secrets from the provided rng.
+    /// Only for reproducible tests: any rng other than the default `OsRng` is insecure.
+    pub fn new_with_rng(public_key: &X25519PublicKey, limit: u64, rng: &dyn Rng) -> Self {
         RateLimiter {
-            nonce_key: RateLimiter::rand_bytes(),
-            secret_key: make_array(&RateLimiter::rand_bytes()[..16]),
+            nonce_key: RateLimiter::rand_bytes(rng),
+            secret_key: make_array(&RateLimiter::rand_bytes(rng)[..16]),
             start_time: Instant::now(),
+            shared_clock: false,
             nonce_ctr: AtomicU64::new(0),
             mac1_key: Blake2s::new_hash()
                 .hash(LABEL_MAC1)
@@ -60,8 +87,28 @@ impl RateLimiter {
         }
     }
 
-    fn rand_bytes() -> [u8; 32] {
-        make_array(X25519SecretKey::new().as_bytes()) // Use the randomness of X25519 secret key as a hack
+    /// Create a rate limiter that derives its cookie secret from a seed shared by the nodes of a
+    /// cluster, so a cookie sent by one node is accepted by any other, as long as their clocks
+    /// agree. The secret changes every two minutes of the wall clock, at the same time on every
+    /// node. The seed must be kept as secret as a private key: whoever knows it can compute the
+    /// cookie of any address.
+    pub fn new_with_seed(public_key: &X25519PublicKey, limit: u64, seed: &[u8; 32]) -> Self {
+        RateLimiter {
+            secret_key: make_array(
+                &Blake2s::new_mac(seed)
+                    .hash(LABEL_SHARED_SECRET)
+                    .hash(public_key.as_bytes())
+                    .finalize()[..16],
+            ),
+            shared_clock: true,
+            ..RateLimiter::new(public_key, limit)
+        }
+    }
+
+    fn rand_bytes(rng: &dyn Rng) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        rng.fill(&mut bytes);
+        bytes
     }
 
     /// Reset packet count (ideally should be called with a period of 1 second)
@@ -77,27 +124,24 @@ impl RateLimiter {
 
     // Compute the correct cookie value based on the current secret value and the source IP
     fn current_cookie(&self, addr: IpAddr) -> Cookie {
-        let mut cookie = [0u8; COOKIE_SIZE];
-        let mut addr_bytes = [0u8; 16];
-
-        match addr {
-            IpAddr::V4(a) => addr_bytes[..4].copy_from_slice(&a.octets()[..]),
-            IpAddr::V6(a) => addr_bytes[..].copy_from_slice(&a.octets()[..]),
-        }
-
         // The current cookie for a given IP is the MAC(responder.changing_secret_every_two_minutes, initiator.ip_address)
         // First we derive the secret from the current time, the value of cur_counter would change with time.
-        let cur_counter = Instant::now().duration_since(self.start_time).as_secs() / COOKIE_REFRESH;
+        let elapsed = if self.shared_clock {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+        } else {
+            Instant::now().duration_since(self.start_time)
+        };
+        let cur_counter = elapsed.as_secs() / COOKIE_REFRESH;
 
         // Next we derive the cookie
-        cookie[..].copy_from_slice(
+        make_array(
             &Blake2s::new_mac(&self.secret_key[..])
                 .hash(&cur_counter.to_le_bytes())
-                .hash(&addr_bytes[..])
+                .hash(&addr_bytes(addr)[..])
                 .finalize()[..COOKIE_SIZE],
-        );
-
-        cookie
+        )
     }
 
     fn nonce(&self) -> [u8; COOKIE_NONCE_SIZE] {
@@ -125,73 +169,198 @@ impl RateLimiter {
         mac1: &[u8],
         dst: &'a mut [u8],
     ) -> Result<&'a mut [u8], WireGuardError> {
-        if dst.len() < super::COOKIE_REPLY_SZ {
-            return Err(WireGuardError::DestinationBufferTooSmall);
-        }
-
-        let (message_type, rest) = dst.split_at_mut(4);
-        let (receiver_index, rest) = rest.split_at_mut(4);
-        let (nonce, rest) = rest.split_at_mut(24);
-        let (mut encrypted_cookie, _) = rest.split_at_mut(16 + 16);
-
-        // msg.message_type = 3
-        // msg.reserved_zero = { 0, 0, 0 }
-        message_type.copy_from_slice(&super::COOKIE_REPLY.to_le_bytes());
-        // msg.receiver_index = little_endian(initiator.sender_index)
-        receiver_index.copy_from_slice(&idx.to_le_bytes());
-        nonce.copy_from_slice(&self.nonce()[..]);
-
-        ChaCha20Poly1305::new_aead(&self.cookie_key).xseal(
-            &nonce,
-            mac1,
-            &cookie[..],
-            &mut encrypted_cookie,
-        );
+        seal_cookie_reply(&self.cookie_key, &self.nonce(), idx, &cookie, mac1, dst)
+    }
 
-        Ok(&mut dst[..super::COOKIE_REPLY_SZ])
+    /// Is the datagram a handshake message with a valid mac1 for this rate limiter's key
+    pub(crate) fn matches_mac1(&self, src: &[u8]) -> bool {
+        parse_handshake(src).is_ok() && check_mac1(&self.mac1_key, src).is_ok()
     }
 
-    // Verify the MAC fields on the datagram, and apply rate limiting if needed
+    /// Verify the MAC fields on the datagram, and apply rate limiting if needed. A cookie reply
+    /// is written to dst. Fails for a datagram that is not a WireGuard message.
     pub fn verify_packet<'a, 'b>(
         &self,
         src_addr: Option<IpAddr>,
         src: &'a [u8],
         dst: &'b mut [u8],
-    ) -> Result<Packet<'a>, TunnResult<'b>> {
+    ) -> Result<RateLimitResult<'a, 'b>, WireGuardError> {
         let packet = Tunn::parse_incoming_packet(src)?;
 
         // Verify and rate limit handshake messages only
         if let Packet::HandshakeInit(HandshakeInit { sender_idx, .. })
         | Packet::HandshakeResponse(HandshakeResponse { sender_idx, .. }) = packet
         {
-            let (msg, macs) = src.split_at(src.len() - 32);
-            let (mac1, mac2) = macs.split_at(16);
-
-            let computed_mac1 = Blake2s::new_mac(&self.mac1_key).hash(msg).finalize();
-            constant_time_mac_check(&computed_mac1[..16], mac1).map_err(TunnResult::Err)?;
+            if check_mac1(&self.mac1_key, src).is_err() {
+                return Ok(RateLimitResult::InvalidMac);
+            }
 
             if self.is_under_load() {
                 let addr = match src_addr {
-                    None => return Err(TunnResult::Err(WireGuardError::UnderLoad)),
+                    None => return Ok(RateLimitResult::RateLimited),
                     Some(addr) => addr,
                 };
 
                 // Only given an address can we validate mac2
                 let cookie = self.current_cookie(addr);
-                let computed_mac2 = Blake2s::new_mac(&cookie[..])
-                    .hash(msg)
-                    .hash(mac1)
-                    .finalize();
-
-                if constant_time_mac_check(&computed_mac2[..16], mac2).is_err() {
-                    let cookie_packet = self
-                        .format_cookie_reply(sender_idx, cookie, mac1, dst)
-                        .map_err(TunnResult::Err)?;
-                    return Err(TunnResult::WriteToNetwork(cookie_packet));
+                if check_mac2(&cookie, src).is_err() {
+                    let cookie_packet =
+                        self.format_cookie_reply(sender_idx, cookie, handshake_mac1(src), dst)?;
+                    return Ok(RateLimitResult::CookieRequired(cookie_packet));
                 }
             }
         }
 
-        Ok(packet)
+        Ok(RateLimitResult::Allowed(packet))
+    }
+}
+
+// The standalone functions below let a stateless front-end, such as a proxy, filter handshake
+// messages without constructing a Tunn. They take the static public key of the device the
+// messages are addressed to, and a cookie secret the caller is expected to replace every two
+// minutes.
+
+fn addr_bytes(addr: IpAddr) -> [u8; 16] {
+    let mut addr_bytes = [0u8; 16];
+    match addr {
+        IpAddr::V4(a) => addr_bytes[..4].copy_from_slice(&a.octets()[..]),
+        IpAddr::V6(a) => addr_bytes[..].copy_from_slice(&a.octets()[..]),
+    }
+    addr_bytes
+}
+
+// Returns the sender index of a handshake message, or an error for any other packet
+fn parse_handshake(packet: &[u8]) -> Result<u32, WireGuardError> {
+    match Tunn::parse_incoming_packet(packet)? {
+        Packet::HandshakeInit(HandshakeInit { sender_idx, .. })
+        | Packet::HandshakeResponse(HandshakeResponse { sender_idx, .. }) => Ok(sender_idx),
+        _ => Err(WireGuardError::WrongPacketType),
     }
 }
+
+// The mac1 field of a handshake message of valid length
+fn handshake_mac1(packet: &[u8]) -> &[u8] {
+    &packet[packet.len() - 32..packet.len() - 16]
+}
+
+fn check_mac1(mac1_key: &[u8], packet: &[u8]) -> Result<(), WireGuardError> {
+    let (msg, macs) = packet.split_at(packet.len() - 32);
+    let computed_mac1 = Blake2s::new_mac(mac1_key).hash(msg).finalize();
+    constant_time_mac_check(&computed_mac1[..16], &macs[..16])
+}
+
+fn check_mac2(cookie: &[u8], packet: &[u8]) -> Result<(), WireGuardError> {
+    let (msg, mac2) = packet.split_at(packet.len() - 16);
+    let computed_mac2 = Blake2s::new_mac(cookie).hash(msg).finalize();
+    constant_time_mac_check(&computed_mac2[..16], mac2)
+}
+
+fn seal_cookie_reply<'a>(
+    cookie_key: &[u8; 32],
+    cookie_nonce: &[u8; COOKIE_NONCE_SIZE],
+    idx: u32,
+    cookie: &Cookie,
+    mac1: &[u8],
+    dst: &'a mut [u8],
+) -> Result<&'a mut [u8], WireGuardError> {
+    if dst.len() < super::COOKIE_REPLY_SZ {
+        return Err(WireGuardError::DestinationBufferTooSmall);
+    }
+
+    let (message_type, rest) = dst.split_at_mut(4);
+    let (receiver_index, rest) = rest.split_at_mut(4);
+    let (nonce, rest) = rest.split_at_mut(24);
+    let (mut encrypted_cookie, _) = rest.split_at_mut(16 + 16);
+
+    // msg.message_type = 3
+    // msg.reserved_zero = { 0, 0, 0 }
+    message_type.copy_from_slice(&super::COOKIE_REPLY.to_le_bytes());
+    // msg.receiver_index = little_endian(initiator.sender_index)
+    receiver_index.copy_from_slice(&idx.to_le_bytes());
+    nonce.copy_from_slice(&cookie_nonce[..]);
+
+    ChaCha20Poly1305::new_aead(cookie_key).xseal(&nonce, mac1, &cookie[..], &mut encrypted_cookie);
+
+    Ok(&mut dst[..super::COOKIE_REPLY_SZ])
+}
+
+/// Compute the cookie the peer at `addr` must use for mac2, for the given cookie secret
+pub fn compute_cookie(cookie_secret: &[u8], addr: IpAddr) -> [u8; COOKIE_SIZE] {
+    make_array(
+        &Blake2s::new_mac(cookie_secret)
+            .hash(&addr_bytes(addr)[..])
+            .finalize()[..COOKIE_SIZE],
+    )
+}
+
+/// Compute the mac1 a handshake message addressed to `public_key` should carry
+pub fn compute_mac1(
+    public_key: &X25519PublicKey,
+    packet: &[u8],
+) -> Result<[u8; 16], WireGuardError> {
+    parse_handshake(packet)?;
+    let mac1_key = Blake2s::new_hash()
+        .hash(LABEL_MAC1)
+        .hash(public_key.as_bytes())
+        .finalize();
+    let msg = &packet[..packet.len() - 32];
+    Ok(make_array(
+        &Blake2s::new_mac(&mac1_key).hash(msg).finalize()[..16],
+    ))
+}
+
+/// Compute the mac2 a handshake message from the peer at `addr` should carry
+pub fn compute_mac2(
+    cookie_secret: &[u8],
+    addr: IpAddr,
+    packet: &[u8],
+) -> Result<[u8; 16], WireGuardError> {
+    parse_handshake(packet)?;
+    let cookie = compute_cookie(cookie_secret, addr);
+    let msg = &packet[..packet.len() - 16];
+    Ok(make_array(
+        &Blake2s::new_mac(&cookie).hash(msg).finalize()[..16],
+    ))
+}
+
+/// Verify the mac1 field of a handshake message addressed to `public_key`, in constant time
+pub fn verify_mac1(public_key: &X25519PublicKey, packet: &[u8]) -> Result<(), WireGuardError> {
+    constant_time_mac_check(&compute_mac1(public_key, packet)?, handshake_mac1(packet))
+}
+
+/// Verify the mac2 field of a handshake message from the peer at `addr`, in constant time
+pub fn verify_mac2(
+    cookie_secret: &[u8],
+    addr: IpAddr,
+    packet: &[u8],
+) -> Result<(), WireGuardError> {
+    let mac2 = compute_mac2(cookie_secret, addr, packet)?;
+    constant_time_mac_check(&mac2, &packet[packet.len() - 16..])
+}
+
+/// Write a cookie reply to a handshake message from the peer at `addr`, on behalf of the device
+/// with the static `public_key`. The peer will use the cookie for mac2 of its next attempt.
+pub fn format_cookie_reply<'a>(
+    public_key: &X25519PublicKey,
+    cookie_secret: &[u8],
+    addr: IpAddr,
+    packet: &[u8],
+    dst: &'a mut [u8],
+) -> Result<&'a mut [u8], WireGuardError> {
+    let idx = parse_handshake(packet)?;
+    let cookie_key = Blake2s::new_hash()
+        .hash(LABEL_COOKIE)
+        .hash(public_key.as_bytes())
+        .finalize();
+    let mut nonce = [0u8; COOKIE_NONCE_SIZE];
+    OsRng.fill(&mut nonce);
+    let cookie = compute_cookie(cookie_secret, addr);
+    seal_cookie_reply(
+        &cookie_key,
+        &nonce,
+        idx,
+        &cookie,
+        handshake_mac1(packet),
+        dst,
+    )
+}
@@ -1,14 +1,16 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use super::PacketData;
+use super::{PacketData, ReplayWindow};
+use crate::crypto::blake2s::Blake2s;
 #[cfg(target_arch = "arm")]
 use crate::crypto::chacha20poly1305::*;
 use crate::noise::errors::WireGuardError;
 use parking_lot::Mutex;
 #[cfg(not(target_arch = "arm"))]
 use ring::aead::*;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
 
 pub struct Session {
     pub(crate) receiving_index: u32,
@@ -21,8 +23,12 @@ pub struct Session {
     receiver: ChaCha20Poly1305,
     #[cfg(target_arch = "arm")]
     sender: ChaCha20Poly1305,
-    sending_key_counter: AtomicUsize,
+    sending_key_counter: AtomicU64,
     receiving_key_counter: Mutex<ReceivingKeyCounterValidator>,
+    tx_bytes: AtomicUsize,
+    rx_bytes: AtomicUsize,
+    exporter_secret: [u8; 32], // Derived from the handshake alongside the transport keys
+    created: Duration,         // On the monotonic clock
 }
 
 impl std::fmt::Debug for Session {
@@ -38,9 +44,18 @@ impl std::fmt::Debug for Session {
 const DATA_OFFSET: usize = 16; // Where encrypted data resides in a data packet
 const AEAD_SIZE: usize = 16; // The overhead of the AEAE
 
+// Separates exported keying material from anything else derived from the exporter secret
+const EXPORTER_LABEL: &[u8] = b"boringtun keying material exporter";
+const MAX_EXPORTER_LABEL_SIZE: usize = 255;
+const MAX_EXPORTER_OUTPUT_SIZE: usize = 255 * 32;
+
+// The counter of the last message a session may send, so a nonce is never used twice. The timers
+// replace a session after REKEY_AFTER_MESSAGES, long before.
+pub(super) const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+
 // Receiving buffer constants
 const WORD_SIZE: u64 = 64;
-const N_WORDS: u64 = 16; // Suffice to reorder 64*16 = 1024 packets; can be increased at will
+const N_WORDS: u64 = super::REPLAY_WINDOW_WORDS as u64; // Suffice to reorder 64*16 = 1024 packets
 const N_BITS: u64 = WORD_SIZE * N_WORDS;
 
 #[derive(Debug, Clone, Default)]
@@ -153,12 +168,21 @@ impl ReceivingKeyCounterValidator {
     }
 }
 
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Force zero out of the memory on Drop
+        unsafe { std::ptr::write_volatile(&mut self.exporter_secret, [0u8; 32]) }
+    }
+}
+
 impl Session {
     pub(super) fn new(
         local_index: u32,
         peer_index: u32,
         receiving_key: [u8; 32],
         sending_key: [u8; 32],
+        exporter_secret: [u8; 32],
+        created: Duration,
     ) -> Session {
         Session {
             receiving_index: local_index,
@@ -173,9 +197,50 @@ impl Session {
             receiver: ChaCha20Poly1305::new_aead(&receiving_key[..]),
             #[cfg(target_arch = "arm")]
             sender: ChaCha20Poly1305::new_aead(&sending_key[..]),
-            sending_key_counter: AtomicUsize::new(0),
+            sending_key_counter: AtomicU64::new(0),
             receiving_key_counter: Mutex::new(Default::default()),
+            tx_bytes: Default::default(),
+            rx_bytes: Default::default(),
+            exporter_secret,
+            created,
+        }
+    }
+
+    /// Fill out with keying material derived from this session, for the given label and context.
+    /// This is HKDF-Expand with BLAKE2s over the exporter secret, with the label and context
+    /// each prefixed by their length.
+    pub(super) fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), WireGuardError> {
+        if label.len() > MAX_EXPORTER_LABEL_SIZE
+            || context.len() > MAX_EXPORTER_LABEL_SIZE
+            || out.len() > MAX_EXPORTER_OUTPUT_SIZE
+        {
+            return Err(WireGuardError::InvalidExportLength);
         }
+
+        let mut prev: Option<[u8; 32]> = None;
+        for (i, chunk) in out.chunks_mut(32).enumerate() {
+            let mut hmac = Blake2s::new_hmac(&self.exporter_secret);
+            if let Some(ref prev) = prev {
+                hmac.hash(prev);
+            }
+            let block = hmac
+                .hash(EXPORTER_LABEL)
+                .hash(&[label.len() as u8])
+                .hash(label)
+                .hash(&[context.len() as u8])
+                .hash(context)
+                .hash(&[i as u8 + 1])
+                .finalize();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+            prev = Some(block);
+        }
+
+        Ok(())
     }
 
     pub(super) fn local_index(&self) -> usize {
@@ -205,12 +270,27 @@ impl Session {
     // src - an IP packet from the interface
     // dst - pre-allocated space to hold the encapsulating UDP packet to send over the network
     // returns the size of the formatted packet
-    pub(super) fn format_packet_data<'a>(&self, src: &[u8], dst: &'a mut [u8]) -> &'a mut [u8] {
-        if dst.len() < src.len() + super::DATA_OVERHEAD_SZ {
+    // Encrypt src as a data message, zero padded to padded_len bytes. Fails with NonceExhausted
+    // once REJECT_AFTER_MESSAGES messages were sent, the session must then be replaced.
+    pub(super) fn format_packet_data(
+        &self,
+        src: &[u8],
+        padded_len: usize,
+        dst: &mut [u8],
+    ) -> Result<usize, WireGuardError> {
+        let padded_len = padded_len.max(src.len());
+        if dst.len() < padded_len + super::DATA_OVERHEAD_SZ {
             panic!("The destination buffer is too small");
         }
 
-        let sending_key_counter = self.sending_key_counter.fetch_add(1, Ordering::Relaxed) as u64;
+        let sending_key_counter = self.sending_key_counter.fetch_add(1, Ordering::Relaxed);
+        if sending_key_counter > REJECT_AFTER_MESSAGES {
+            // Keep the counter from wrapping around, however many messages are refused
+            self.sending_key_counter
+                .store(REJECT_AFTER_MESSAGES + 1, Ordering::Relaxed);
+            return Err(WireGuardError::NonceExhausted);
+        }
+        self.tx_bytes.fetch_add(padded_len, Ordering::Relaxed);
 
         let (message_type, rest) = dst.split_at_mut(4);
         let (receiver_index, rest) = rest.split_at_mut(4);
@@ -226,28 +306,46 @@ impl Session {
             let mut nonce = [0u8; 12];
             nonce[4..12].copy_from_slice(&sending_key_counter.to_le_bytes());
             data[..src.len()].copy_from_slice(src);
+            for b in &mut data[src.len()..padded_len] {
+                *b = 0;
+            }
             self.sender
                 .seal_in_place_separate_tag(
                     Nonce::assume_unique_for_key(nonce),
                     Aad::from(&[]),
-                    &mut data[..src.len()],
+                    &mut data[..padded_len],
                 )
                 .map(|tag| {
-                    data[src.len()..src.len() + AEAD_SIZE].copy_from_slice(tag.as_ref());
-                    src.len() + AEAD_SIZE
+                    data[padded_len..padded_len + AEAD_SIZE].copy_from_slice(tag.as_ref());
+                    padded_len + AEAD_SIZE
                 })
                 .unwrap()
         };
 
         #[cfg(target_arch = "arm")]
-        let n = self.sender.seal_wg(
-            sending_key_counter,
-            &[],
-            src,
-            &mut data[..src.len() + AEAD_SIZE],
-        );
+        let n = {
+            let mut padded;
+            let plaintext = if padded_len > src.len() {
+                padded = vec![0u8; padded_len];
+                padded[..src.len()].copy_from_slice(src);
+                &padded[..]
+            } else {
+                src
+            };
+            self.sender.seal_wg(
+                sending_key_counter,
+                &[],
+                plaintext,
+                &mut data[..padded_len + AEAD_SIZE],
+            )
+        };
+
+        Ok(DATA_OFFSET + n)
+    }
 
-        &mut dst[..DATA_OFFSET + n]
+    #[cfg(test)]
+    pub(super) fn set_sending_counter(&self, counter: u64) {
+        self.sending_key_counter.store(counter, Ordering::Relaxed);
     }
 
     // packet - a data packet we received from the network
@@ -270,6 +368,39 @@ impl Session {
         // Don't reuse counters, in case this is a replay attack we want to quickly check the counter without running expensive decryption
         self.receiving_counter_quick_check(packet.counter)?;
 
+        let ret = self.open_packet_data(&packet, dst)?;
+
+        // After decryption is done, check counter again, and mark as received
+        self.receiving_counter_mark(packet.counter)?;
+        self.rx_bytes.fetch_add(ret.len(), Ordering::Relaxed);
+        Ok(ret)
+    }
+
+    // packet - a data packet observed on the network
+    // dst - pre-allocated space to hold the decrypted IP packet
+    // Same as receive_packet_data, but the receiving counter is neither checked nor updated
+    pub(super) fn observe_packet_data<'a>(
+        &self,
+        packet: PacketData,
+        dst: &'a mut [u8],
+    ) -> Result<&'a mut [u8], WireGuardError> {
+        if dst.len() < packet.encrypted_encapsulated_packet.len() {
+            panic!("The destination buffer is too small");
+        }
+        if packet.receiver_idx != self.receiving_index {
+            return Err(WireGuardError::WrongIndex);
+        }
+        self.open_packet_data(&packet, dst)
+    }
+
+    // Decrypt the payload of a data packet into dst
+    fn open_packet_data<'a>(
+        &self,
+        packet: &PacketData,
+        dst: &'a mut [u8],
+    ) -> Result<&'a mut [u8], WireGuardError> {
+        let ct_len = packet.encrypted_encapsulated_packet.len();
+
         #[cfg(not(target_arch = "arm"))]
         let ret = {
             let mut nonce = [0u8; 12];
@@ -292,11 +423,39 @@ impl Session {
             dst,
         )?;
 
-        // After decryption is done, check counter again, and mark as received
-        self.receiving_counter_mark(packet.counter)?;
         Ok(ret)
     }
 
+    // Returns the index packets to us carry as receiver, the index of the peer and the time the
+    // session was derived, on the monotonic clock
+    pub(super) fn identity(&self) -> (u32, u32, Duration) {
+        (self.receiving_index, self.sending_index, self.created)
+    }
+
+    // Returns the number of messages sent, and the inner bytes sent and received
+    pub(super) fn traffic(&self) -> (u64, usize, usize) {
+        (
+            self.sending_key_counter.load(Ordering::Relaxed),
+            self.tx_bytes.load(Ordering::Relaxed),
+            self.rx_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    // Returns the replay window, with the bitmap rotated so bit 0 is the highest counter received
+    pub(super) fn replay_window(&self) -> ReplayWindow {
+        let validator = self.receiving_key_counter.lock();
+        let mut window = ReplayWindow {
+            next: validator.next,
+            bitmap: [0; N_WORDS as usize],
+        };
+        for i in 0..N_BITS.min(validator.next) {
+            if validator.check_bit(validator.next - 1 - i) {
+                window.bitmap[(i / WORD_SIZE) as usize] |= 1 << (i % WORD_SIZE);
+            }
+        }
+        window
+    }
+
     // Returns the estimated downstream packet loss for this session
     pub(super) fn current_packet_cnt(&self) -> (u64, u64) {
         let counter_validator = self.receiving_key_counter.lock();
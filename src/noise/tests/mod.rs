@@ -553,4 +553,1565 @@ mod tests {
             );
         }
     }
+
+    // A deterministic rng for reproducible handshakes, never use outside of tests
+    struct SeededRng {
+        state: std::sync::Mutex<u64>,
+    }
+
+    impl Rng for SeededRng {
+        fn fill(&self, dest: &mut [u8]) {
+            // splitmix64
+            let mut state = self.state.lock().unwrap();
+            for chunk in dest.chunks_mut(8) {
+                *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^= z >> 31;
+                chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+            }
+        }
+    }
+
+    #[test]
+    fn wireguard_handshake_seeded_rng() {
+        let own_key = "b8c8f0c4b4b2c73a09c4d8b2f0d6bd4d02cfa6fbf0ec0c0e5b6e1c2923b5db5a"
+            .parse::<X25519SecretKey>()
+            .unwrap();
+        let peer_key = "409f3eb1d0049ae4d56fbf1d5ee3e3b0f2c439aef8fb6e4b4c1ac595acb5e26e"
+            .parse::<X25519SecretKey>()
+            .unwrap();
+
+        let mut tunn = Tunn::new(
+            Arc::new(own_key),
+            Arc::new(peer_key.public_key()),
+            None,
+            None,
+            1,
+            None,
+        )
+        .unwrap();
+        tunn.set_rng(Arc::new(SeededRng {
+            state: std::sync::Mutex::new(42),
+        }));
+
+        let mut dst = [0u8; 148];
+        let packet = match tunn.format_handshake_initiation(&mut dst, false) {
+            TunnResult::WriteToNetwork(packet) => packet,
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert_eq!(packet.len(), 148);
+
+        // The encrypted timestamp and the macs that cover it depend on the clock, everything
+        // before them must be reproducible: type, sender index, ephemeral and encrypted static
+        let expected: [u8; 88] = [
+            1, 0, 0, 0, 1, 1, 0, 0, 98, 137, 242, 121, 192, 141, 101, 120, 151, 130, 148, 195, 92,
+            185, 177, 24, 69, 153, 247, 92, 255, 44, 220, 44, 139, 227, 5, 80, 208, 70, 112, 116,
+            85, 203, 171, 126, 91, 118, 20, 205, 31, 44, 64, 113, 240, 160, 237, 98, 10, 193, 195,
+            59, 177, 247, 90, 38, 78, 154, 10, 104, 12, 173, 136, 78, 206, 74, 30, 9, 167, 67, 219,
+            109, 129, 81, 30, 216, 242, 73, 252, 87,
+        ];
+        assert_eq!(&packet[..88], &expected[..]);
+    }
+
+    #[test]
+    fn wireguard_handshake_injected_ephemeral() {
+        let own_key = "b8c8f0c4b4b2c73a09c4d8b2f0d6bd4d02cfa6fbf0ec0c0e5b6e1c2923b5db5a"
+            .parse::<X25519SecretKey>()
+            .unwrap();
+        let peer_key = "409f3eb1d0049ae4d56fbf1d5ee3e3b0f2c439aef8fb6e4b4c1ac595acb5e26e"
+            .parse::<X25519SecretKey>()
+            .unwrap();
+        let ephemeral = "e0a3c0d1f5b6a7e8d9c0b1a2f3e4d5c6b7a8f9e0d1c2b3a4f5e6d7c8b9a0f16f"
+            .parse::<X25519SecretKey>()
+            .unwrap();
+        let ephemeral_public = ephemeral.public_key();
+        let own_public = Arc::new(own_key.public_key());
+        let peer_public = Arc::new(peer_key.public_key());
+
+        let tunn = Tunn::new(Arc::new(own_key), peer_public, None, None, 1, None).unwrap();
+        // TAI64N of 2020-01-01 00:00:00
+        let timestamp = [0x40, 0, 0, 0, 0x5e, 0x0b, 0xe1, 0, 0, 0, 0, 0];
+        tunn.inject_ephemeral(ephemeral, Some(timestamp));
+
+        let mut dst = [0u8; 148];
+        let packet = match tunn.format_handshake_initiation(&mut dst, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        // With the ephemeral and the timestamp fixed the whole message is reproducible, the mac2
+        // is zero as no cookie was received
+        let expected: [u8; 148] = [
+            1, 0, 0, 0, 1, 1, 0, 0, 202, 137, 169, 217, 168, 174, 108, 204, 217, 107, 89, 253, 137,
+            216, 200, 81, 65, 180, 27, 157, 130, 208, 88, 2, 254, 38, 241, 179, 165, 84, 166, 84,
+            169, 6, 134, 16, 65, 50, 191, 110, 243, 93, 66, 166, 132, 31, 212, 50, 48, 71, 127, 90,
+            234, 184, 108, 247, 0, 94, 88, 135, 225, 117, 129, 219, 105, 115, 168, 87, 30, 176,
+            136, 55, 80, 9, 161, 64, 150, 150, 63, 180, 184, 86, 109, 194, 212, 238, 227, 212, 32,
+            88, 132, 28, 80, 57, 73, 17, 235, 114, 114, 104, 252, 208, 200, 251, 208, 231, 245, 67,
+            84, 138, 93, 143, 95, 90, 149, 44, 87, 217, 206, 244, 172, 32, 25, 33, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(&packet[..], &expected[..]);
+        assert_eq!(&packet[8..40], ephemeral_public.as_bytes());
+
+        // The injected key is used once, the next initiation has a fresh ephemeral
+        let next = match tunn.format_handshake_initiation(&mut dst, true) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert_ne!(&next[8..40], ephemeral_public.as_bytes());
+
+        let responder = Tunn::new(Arc::new(peer_key), own_public, None, None, 2, None).unwrap();
+        let mut buf = [0u8; 2048];
+        assert!(matches!(
+            responder.decapsulate(None, &packet, &mut buf),
+            TunnResult::WriteToNetwork(_)
+        ));
+    }
+
+    // Establish a session between two tunnels in memory, returns (initiator, responder)
+    fn tunnel_pair() -> (Box<Tunn>, Box<Tunn>) {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let b_public = Arc::new(b_key.public_key());
+
+        let a = Tunn::new(a_key, b_public, None, None, 0, None).unwrap();
+        let b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+        complete_handshake(&a, &b);
+        (a, b)
+    }
+
+    // Complete a handshake initiated by a, with the keepalive that confirms it to b
+    fn complete_handshake(a: &Tunn, b: &Tunn) {
+        let mut buf = [0u8; 2048];
+        let init = match a.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let response = match b.decapsulate(None, &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        let keepalive = match a.decapsulate(None, &response, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a keepalive"),
+        };
+        assert!(matches!(
+            b.decapsulate(None, &keepalive, &mut buf),
+            TunnResult::Done
+        ));
+    }
+
+    #[test]
+    fn wireguard_control_stats() {
+        let (a, b) = tunnel_pair();
+
+        // The initiator sent the initiation and a keepalive, and received the response
+        assert_eq!(a.stats().1, 0);
+        assert_eq!(a.stats().2, 0);
+        assert_eq!(a.control_stats(), (148 + 32, 92));
+        assert_eq!(b.stats().1, 0);
+        assert_eq!(b.stats().2, 0);
+        assert_eq!(b.control_stats(), (92, 148 + 32));
+
+        // Data is only counted as data
+        let mut ip_packet = vec![
+            0x45, 0, 0, 24, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        ip_packet.extend_from_slice(b"test");
+        let mut buf = [0u8; 2048];
+        let packet = match a.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        let mut dst = [0u8; 2048];
+        assert!(matches!(
+            b.decapsulate(None, &packet, &mut dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        assert_eq!(a.stats().1, 24);
+        assert_eq!(b.stats().2, 24);
+        assert_eq!(a.control_stats(), (148 + 32, 92));
+        assert_eq!(b.control_stats(), (92, 148 + 32));
+    }
+
+    #[test]
+    fn wireguard_expire_sessions() {
+        let (a, b) = tunnel_pair();
+        let ip_packet = [
+            0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let mut buf = [0u8; 2048];
+        let from_b = match b.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+
+        a.expire_sessions();
+        assert!(a.session_stats().is_none());
+        // Packets of the expired session are refused
+        assert!(matches!(
+            a.decapsulate(None, &from_b, &mut buf),
+            TunnResult::Err(WireGuardError::NoCurrentSession)
+        ));
+
+        // The next packet to the peer starts a new handshake, and is sent once it completes
+        let init = match a.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert!(Tunn::is_handshake_init(&init));
+        let response = match b.decapsulate(None, &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        assert!(matches!(
+            a.decapsulate(None, &response, &mut buf),
+            TunnResult::WriteToNetwork(_)
+        ));
+        assert!(a.session_stats().is_some());
+    }
+
+    #[test]
+    fn wireguard_handshake_roles() {
+        let (a, b) = tunnel_pair();
+        assert_eq!(a.handshake_stats(), (1, 0));
+        assert_eq!(b.handshake_stats(), (0, 1));
+
+        // The initiator starts every handshake
+        let mut buf = [0u8; 2048];
+        for _ in 0..2 {
+            let init = match a.format_handshake_initiation(&mut buf, true) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("Expected a handshake initiation"),
+            };
+            let response = match b.decapsulate(None, &init, &mut buf) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("Expected a handshake response"),
+            };
+            a.decapsulate(None, &response, &mut buf);
+        }
+        assert_eq!(a.handshake_stats(), (3, 0));
+        assert_eq!(b.handshake_stats(), (0, 3));
+
+        // An initiation that is never answered completes nothing
+        a.format_handshake_initiation(&mut buf, true);
+        assert_eq!(a.handshake_stats(), (3, 0));
+    }
+
+    #[test]
+    fn wireguard_session_identity() {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let b_public = Arc::new(b_key.public_key());
+        let a = Tunn::new(a_key, b_public, None, None, 3, None).unwrap();
+        let b = Tunn::new(b_key, a_public, None, None, 4, None).unwrap();
+        assert!(a.session_stats().is_none());
+
+        let before = std::time::SystemTime::now();
+        let mut buf = [0u8; 2048];
+        let init = match a.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let response = match b.decapsulate(None, &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        let keepalive = match a.decapsulate(None, &response, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a keepalive"),
+        };
+        b.decapsulate(None, &keepalive, &mut buf);
+        let after = std::time::SystemTime::now();
+
+        let init_sender = match Tunn::parse_incoming_packet(&init) {
+            Ok(Packet::HandshakeInit(p)) => p.sender_idx,
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let (response_sender, response_receiver) = match Tunn::parse_incoming_packet(&response) {
+            Ok(Packet::HandshakeResponse(p)) => (p.sender_idx, p.receiver_idx),
+            _ => panic!("Expected a handshake response"),
+        };
+        let keepalive_receiver = match Tunn::parse_incoming_packet(&keepalive) {
+            Ok(Packet::PacketData(p)) => p.receiver_idx,
+            _ => panic!("Expected a data packet"),
+        };
+        assert_eq!(response_receiver, init_sender);
+
+        // Each side reports its own index, which the messages of the other side are sent to
+        let a_stats = a.session_stats().unwrap();
+        let b_stats = b.session_stats().unwrap();
+        assert_eq!(a_stats.local_index, init_sender);
+        assert_eq!(a_stats.peer_index, response_sender);
+        assert_eq!(b_stats.local_index, response_sender);
+        assert_eq!(b_stats.peer_index, init_sender);
+        assert_eq!(keepalive_receiver, b_stats.local_index);
+        assert_eq!(init_sender >> 8, 3);
+        assert_eq!(response_sender >> 8, 4);
+
+        for stats in &[a_stats, b_stats] {
+            assert!(stats.created >= before && stats.created <= after);
+        }
+    }
+
+    #[test]
+    fn wireguard_decapsulate_observe() {
+        let (a, b) = tunnel_pair();
+        let (_, _, rx_bytes, _, _) = b.stats();
+
+        // A minimal IPv4 header from 10.0.0.1, followed by the payload
+        let mut ip_packet = vec![
+            0x45, 0, 0, 24, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        ip_packet.extend_from_slice(b"test");
+
+        let mut buf = [0u8; 2048];
+        let data = match a.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+
+        let mut dst = [0u8; 2048];
+        for _ in 0..2 {
+            match b.decapsulate_observe(&data, &mut dst) {
+                TunnResult::WriteToTunnelV4(packet, addr) => {
+                    assert_eq!(packet, &ip_packet[..]);
+                    assert_eq!(addr, "10.0.0.1".parse::<std::net::Ipv4Addr>().unwrap());
+                }
+                _ => panic!("Expected a decrypted packet"),
+            }
+        }
+
+        // Observing left the counters and the replay window alone
+        assert_eq!(b.stats().2, rx_bytes);
+        assert!(matches!(
+            b.decapsulate(None, &data, &mut dst),
+            TunnResult::WriteToTunnelV4(_, _)
+        ));
+        assert!(matches!(
+            b.decapsulate(None, &data, &mut dst),
+            TunnResult::Err(WireGuardError::InvalidCounter)
+        ));
+    }
+
+    #[test]
+    fn wireguard_standalone_macs() {
+        use crate::noise::rate_limiter::*;
+        use std::net::{IpAddr, Ipv4Addr};
+
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let b_public = b_key.public_key();
+        let a = Tunn::new(a_key, Arc::new(b_key.public_key()), None, None, 0, None).unwrap();
+
+        let mut buf = [0u8; 2048];
+        let mut init = match a.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+
+        // A correct mac1 is accepted, only by the intended recipient
+        assert!(verify_mac1(&b_public, &init).is_ok());
+        assert!(matches!(
+            verify_mac1(&X25519SecretKey::new().public_key(), &init),
+            Err(WireGuardError::InvalidMac)
+        ));
+        assert!(matches!(
+            verify_mac1(&b_public, &init[..100]),
+            Err(WireGuardError::InvalidPacket)
+        ));
+
+        // A corrupted message or mac1 is rejected
+        init[10] ^= 1;
+        assert!(matches!(
+            verify_mac1(&b_public, &init),
+            Err(WireGuardError::InvalidMac)
+        ));
+        init[10] ^= 1;
+        let mac1_off = init.len() - 32;
+        init[mac1_off] ^= 1;
+        assert!(matches!(
+            verify_mac1(&b_public, &init),
+            Err(WireGuardError::InvalidMac)
+        ));
+        init[mac1_off] ^= 1;
+
+        // Without a cookie mac2 is invalid, after a cookie reply the initiator includes it
+        let secret = [7u8; 16];
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        assert!(verify_mac2(&secret, addr, &init).is_err());
+
+        let mut reply = [0u8; 128];
+        let reply = format_cookie_reply(&b_public, &secret, addr, &init, &mut reply).unwrap();
+        assert!(matches!(
+            a.decapsulate(None, reply, &mut buf),
+            TunnResult::Done
+        ));
+
+        let init = match a.format_handshake_initiation(&mut buf, true) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert!(verify_mac1(&b_public, &init).is_ok());
+        assert!(verify_mac2(&secret, addr, &init).is_ok());
+        assert!(verify_mac2(&secret, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), &init).is_err());
+        assert!(verify_mac2(&[8u8; 16], addr, &init).is_err());
+    }
+
+    #[test]
+    fn wireguard_shared_cookie_seed() {
+        use crate::noise::rate_limiter::{RateLimitResult, RateLimiter};
+        use std::net::{IpAddr, Ipv4Addr};
+
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let b_public = b_key.public_key();
+        let a = Tunn::new(a_key, Arc::new(b_key.public_key()), None, None, 0, None).unwrap();
+
+        // Two nodes of a cluster, under load so every handshake needs a cookie
+        let seed = [9u8; 32];
+        let node1 = RateLimiter::new_with_seed(&b_public, 0, &seed);
+        let node2 = RateLimiter::new_with_seed(&b_public, 0, &seed);
+        let addr = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        let init = match a.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let reply = match node1.verify_packet(addr, &init, &mut dst) {
+            Ok(RateLimitResult::CookieRequired(packet)) => packet.to_vec(),
+            _ => panic!("Expected a cookie reply"),
+        };
+        assert!(matches!(
+            a.decapsulate(None, &reply, &mut buf),
+            TunnResult::Done
+        ));
+
+        // The next initiation carries the cookie, which the other node accepts
+        let init = match a.format_handshake_initiation(&mut buf, true) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert!(matches!(
+            node2.verify_packet(addr, &init, &mut dst),
+            Ok(RateLimitResult::Allowed(_))
+        ));
+
+        // Not by a node with another seed, or with a random secret
+        let other = RateLimiter::new_with_seed(&b_public, 0, &[10u8; 32]);
+        assert!(matches!(
+            other.verify_packet(addr, &init, &mut dst),
+            Ok(RateLimitResult::CookieRequired(_))
+        ));
+        let random = RateLimiter::new(&b_public, 0);
+        assert!(matches!(
+            random.verify_packet(addr, &init, &mut dst),
+            Ok(RateLimitResult::CookieRequired(_))
+        ));
+    }
+
+    #[test]
+    fn wireguard_rate_limit_results() {
+        use crate::noise::rate_limiter::{RateLimitResult, RateLimiter};
+        use std::net::{IpAddr, Ipv4Addr};
+
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let b_public = b_key.public_key();
+        let a = Tunn::new(a_key, Arc::new(b_key.public_key()), None, None, 0, None).unwrap();
+        let limiter = RateLimiter::new(&b_public, 1);
+        let addr = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        let init = match a.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+
+        // A clean handshake within the limit passes
+        assert!(matches!(
+            limiter.verify_packet(addr, &init, &mut dst),
+            Ok(RateLimitResult::Allowed(Packet::HandshakeInit(_)))
+        ));
+
+        // A wrong mac1 is rejected before it counts against the limit
+        let mut bad_mac1 = init.clone();
+        bad_mac1[init.len() - 20] ^= 1;
+        assert!(matches!(
+            limiter.verify_packet(addr, &bad_mac1, &mut dst),
+            Ok(RateLimitResult::InvalidMac)
+        ));
+
+        // Beyond the limit handshakes need a cookie, which the address can be sent, and are
+        // dropped without an address. A wrong mac2 is answered with a fresh cookie.
+        assert!(matches!(
+            limiter.verify_packet(addr, &init, &mut dst),
+            Ok(RateLimitResult::CookieRequired(reply)) if reply.len() == COOKIE_REPLY_SZ
+        ));
+        assert!(matches!(
+            limiter.verify_packet(None, &init, &mut dst),
+            Ok(RateLimitResult::RateLimited)
+        ));
+        let mut bad_mac2 = init.clone();
+        bad_mac2[init.len() - 1] ^= 1;
+        assert!(matches!(
+            limiter.verify_packet(addr, &bad_mac2, &mut dst),
+            Ok(RateLimitResult::CookieRequired(_))
+        ));
+
+        // Once the count is reset, the limit applies anew
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        limiter.reset_count();
+        assert!(matches!(
+            limiter.verify_packet(addr, &init, &mut dst),
+            Ok(RateLimitResult::Allowed(_))
+        ));
+
+        // Anything but a WireGuard message is an error
+        assert!(limiter.verify_packet(addr, b"junk", &mut dst).is_err());
+    }
+
+    #[test]
+    fn wireguard_handshake_backoff() {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let mut a = Tunn::new(a_key, Arc::new(b_key.public_key()), None, None, 0, None).unwrap();
+        a.set_handshake_backoff(3, Duration::from_secs(30));
+
+        // Retransmissions to a peer that never answers
+        let mut buf = [0u8; 2048];
+        let mut intervals = vec![];
+        let mut init = vec![];
+        for _ in 0..7 {
+            init = match a.format_handshake_initiation(&mut buf, true) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("Expected a handshake initiation"),
+            };
+            intervals.push(a.handshake_retry_interval().as_secs());
+        }
+        assert_eq!(intervals, [5, 5, 10, 20, 30, 30, 30]);
+        assert_eq!(a.handshake_attempts(), 7);
+
+        // Outgoing data is queued without sending another initiation
+        assert!(matches!(a.encapsulate(b"data", &mut buf), TunnResult::Done));
+
+        // A completed handshake resets the counter
+        let b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+        let response = match b.decapsulate(None, &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        assert!(matches!(
+            a.decapsulate(None, &response, &mut buf),
+            TunnResult::WriteToNetwork(_)
+        ));
+        assert_eq!(a.handshake_attempts(), 0);
+        assert_eq!(a.handshake_retry_interval(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn wireguard_fast_handshake_retry() {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let mut a = Tunn::new(a_key, Arc::new(b_key.public_key()), None, None, 0, None).unwrap();
+        a.set_fast_handshake_retry(2, Duration::from_secs(1));
+
+        let mut buf = [0u8; 2048];
+        assert!(matches!(
+            a.format_handshake_initiation(&mut buf, false),
+            TunnResult::WriteToNetwork(_)
+        ));
+        assert_eq!(a.handshake_retry_interval(), Duration::from_secs(1));
+
+        // The first retransmission is sent after about a second
+        thread::sleep(Duration::from_millis(600));
+        assert!(matches!(a.update_timers(&mut buf), TunnResult::Done));
+        thread::sleep(Duration::from_millis(500));
+        assert!(matches!(
+            a.update_timers(&mut buf),
+            TunnResult::WriteToNetwork(_)
+        ));
+        assert_eq!(a.handshake_attempts(), 2);
+
+        // Later retransmissions fall back to the standard interval
+        let mut intervals = vec![a.handshake_retry_interval().as_secs()];
+        for _ in 0..3 {
+            assert!(matches!(
+                a.format_handshake_initiation(&mut buf, true),
+                TunnResult::WriteToNetwork(_)
+            ));
+            intervals.push(a.handshake_retry_interval().as_secs());
+        }
+        assert_eq!(intervals, [1, 5, 5, 5]);
+
+        // The interval is never longer than the standard one
+        a.set_fast_handshake_retry(10, Duration::from_secs(60));
+        assert_eq!(a.handshake_retry_interval(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn wireguard_export_keying_material() {
+        let (a, b) = tunnel_pair();
+
+        let mut a_out = [0u8; 80];
+        let mut b_out = [0u8; 80];
+        a.export_keying_material(b"control channel", b"v1", &mut a_out)
+            .unwrap();
+        b.export_keying_material(b"control channel", b"v1", &mut b_out)
+            .unwrap();
+        assert_eq!(&a_out[..], &b_out[..]);
+        assert_ne!(a_out, [0u8; 80]);
+
+        // Different labels and contexts give unrelated output
+        b.export_keying_material(b"other channel", b"v1", &mut b_out)
+            .unwrap();
+        assert_ne!(&a_out[..], &b_out[..]);
+        b.export_keying_material(b"control channel", b"v2", &mut b_out)
+            .unwrap();
+        assert_ne!(&a_out[..], &b_out[..]);
+
+        // A shorter output is a prefix of a longer one
+        let mut short = [0u8; 16];
+        a.export_keying_material(b"control channel", b"v1", &mut short)
+            .unwrap();
+        assert_eq!(&short[..], &a_out[..16]);
+
+        assert!(matches!(
+            a.export_keying_material(b"control channel", b"v1", &mut [0u8; 255 * 32 + 1]),
+            Err(WireGuardError::InvalidExportLength)
+        ));
+
+        let a_key = Arc::new(X25519SecretKey::new());
+        let c = Tunn::new(
+            a_key,
+            Arc::new(X25519SecretKey::new().public_key()),
+            None,
+            None,
+            0,
+            None,
+        )
+        .unwrap();
+        assert!(matches!(
+            c.export_keying_material(b"control channel", b"v1", &mut short),
+            Err(WireGuardError::NoCurrentSession)
+        ));
+    }
+
+    #[test]
+    fn wireguard_decapsulate_iter() {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let b_public = Arc::new(b_key.public_key());
+
+        let a = Tunn::new(a_key, b_public, None, None, 0, None).unwrap();
+        let b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+
+        let mut ip_packet = vec![
+            0x45, 0, 0, 24, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        ip_packet.extend_from_slice(b"test");
+
+        // Without a session the packet is queued, and a handshake is started
+        let mut buf = [0u8; 2048];
+        let init = match a.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let response = match b.decapsulate(None, &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+
+        // The response yields the keepalive confirming the session, then the queued packet
+        let mut outputs = vec![];
+        let mut results = a.decapsulate_iter(None, &response, &mut buf);
+        while let Some(result) = results.next_result() {
+            match result {
+                TunnResult::WriteToNetwork(packet) => outputs.push(packet.to_vec()),
+                _ => panic!("Expected packets for the network"),
+            }
+        }
+        assert_eq!(outputs.len(), 2);
+
+        let mut dst = [0u8; 2048];
+        assert!(matches!(
+            b.decapsulate(None, &outputs[0], &mut dst),
+            TunnResult::Done
+        ));
+        match b.decapsulate(None, &outputs[1], &mut dst) {
+            TunnResult::WriteToTunnelV4(packet, _) => assert_eq!(packet, &ip_packet[..]),
+            _ => panic!("Expected the queued packet"),
+        }
+
+        // A single result ends the iteration
+        let data = match a.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        let mut results = b.decapsulate_iter(None, &data, &mut dst);
+        assert!(matches!(
+            results.next_result(),
+            Some(TunnResult::WriteToTunnelV4(_, _))
+        ));
+        assert!(results.next_result().is_none());
+    }
+
+    // Encapsulate packets for b before there is a session, complete the handshake, and return
+    // the packets flushed from the queue as b decrypts them
+    fn send_before_handshake(policy: HandshakeQueuePolicy, packets: &[&[u8]]) -> Vec<Vec<u8>> {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let b_public = Arc::new(b_key.public_key());
+
+        let mut a = Tunn::new(a_key, b_public, None, None, 0, None).unwrap();
+        let b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+        a.set_handshake_queue_policy(policy);
+
+        let mut buf = [0u8; 2048];
+        let mut init = None;
+        for packet in packets {
+            match a.encapsulate(packet, &mut buf) {
+                TunnResult::WriteToNetwork(packet) => init = Some(packet.to_vec()),
+                TunnResult::Done => {}
+                _ => panic!("Expected a handshake initiation or nothing"),
+            }
+        }
+        let response = match b.decapsulate(None, &init.unwrap(), &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+
+        let mut flushed = vec![];
+        let mut dst = [0u8; 2048];
+        let mut results = a.decapsulate_iter(None, &response, &mut buf);
+        while let Some(result) = results.next_result() {
+            match result {
+                TunnResult::WriteToNetwork(packet) => match b.decapsulate(None, packet, &mut dst) {
+                    TunnResult::WriteToTunnelV4(packet, _) => flushed.push(packet.to_vec()),
+                    TunnResult::Done => {} // The keepalive confirming the session
+                    _ => panic!("Expected a data packet"),
+                },
+                _ => panic!("Expected packets for the network"),
+            }
+        }
+        flushed
+    }
+
+    #[test]
+    fn wireguard_handshake_queue_policy() {
+        let packets: Vec<Vec<u8>> = (0..4u8)
+            .map(|i| {
+                let mut packet = vec![
+                    0x45, 0, 0, 21, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+                ];
+                packet.push(i);
+                packet
+            })
+            .collect();
+        let packets: Vec<&[u8]> = packets.iter().map(|p| &p[..]).collect();
+
+        // Queued packets are sent in order once the handshake completes
+        assert_eq!(
+            send_before_handshake(HandshakeQueuePolicy::default(), &packets),
+            packets
+        );
+        // Up to the depth of the queue
+        assert_eq!(
+            send_before_handshake(HandshakeQueuePolicy::Queue(2), &packets),
+            &packets[..2]
+        );
+        // Or not at all
+        assert!(send_before_handshake(HandshakeQueuePolicy::Drop, &packets).is_empty());
+    }
+
+    #[test]
+    fn wireguard_traffic_padding() {
+        let (mut a, b) = tunnel_pair();
+        a.set_padding(Padding::Buckets(vec![256, 512, 1280]));
+
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        for (len, padded_len) in [(24, 256), (300, 512), (1280, 1280), (1300, 1300)] {
+            // An IPv4 header with the total length, followed by a payload
+            let mut ip_packet = vec![
+                0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+            ];
+            ip_packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            ip_packet.resize(len, 0xaa);
+
+            let data = match a.encapsulate(&ip_packet, &mut buf) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("Expected a data packet"),
+            };
+            assert_eq!(data.len(), padded_len + 32);
+
+            match b.decapsulate(None, &data, &mut dst) {
+                TunnResult::WriteToTunnelV4(packet, _) => assert_eq!(packet, &ip_packet[..]),
+                _ => panic!("Expected a decrypted packet"),
+            }
+        }
+
+        // Keepalives are not padded
+        match a.encapsulate(&[], &mut buf) {
+            TunnResult::WriteToNetwork(packet) => assert_eq!(packet.len(), 32),
+            _ => panic!("Expected a keepalive"),
+        }
+    }
+
+    #[test]
+    fn wireguard_padding_max_payload() {
+        let (mut a, _) = tunnel_pair();
+        a.set_padding(Padding::Buckets(vec![1500]));
+        a.set_max_payload(1420);
+
+        let mut buf = [0u8; 2048];
+        for (len, padded_len) in [(100, 1420), (1420, 1420), (1440, 1440)] {
+            let mut ip_packet = vec![
+                0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+            ];
+            ip_packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            ip_packet.resize(len, 0xaa);
+            match a.encapsulate(&ip_packet, &mut buf) {
+                TunnResult::WriteToNetwork(packet) => assert_eq!(packet.len(), padded_len + 32),
+                _ => panic!("Expected a data packet"),
+            }
+        }
+    }
+
+    #[test]
+    fn wireguard_handshake_padding() {
+        let (mut a, mut b) = tunnel_pair();
+        a.set_handshake_padding(400);
+
+        // By default a padded initiation is not even parsed, only exact sizes are
+        let mut buf = [0u8; 2048];
+        let init = loop {
+            match a.format_handshake_initiation(&mut buf, true) {
+                TunnResult::WriteToNetwork(packet) if packet.len() > 148 => break packet.to_vec(),
+                TunnResult::WriteToNetwork(_) => continue,
+                _ => panic!("Expected a handshake initiation"),
+            }
+        };
+        assert!(!Tunn::is_handshake_init(&init));
+        assert!(matches!(
+            b.decapsulate(None, &init, &mut buf),
+            TunnResult::Err(WireGuardError::InvalidPacket)
+        ));
+        assert!(Tunn::is_handshake_init(Tunn::strip_handshake_padding(
+            &init
+        )));
+        a.set_accept_handshake_padding(true);
+        b.set_accept_handshake_padding(true);
+
+        // Stay below the handshake rate limit of the peers
+        let mut sizes = vec![];
+        for _ in 0..4 {
+            // A padded initiation is answered by a peer that does not pad
+            let init = match a.format_handshake_initiation(&mut buf, true) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("Expected a handshake initiation"),
+            };
+            assert!(Tunn::is_handshake_init(Tunn::strip_handshake_padding(
+                &init
+            )));
+            let response = match b.decapsulate(None, &init, &mut buf) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("Expected a handshake response"),
+            };
+            assert_eq!(response.len(), 92);
+            assert!(matches!(
+                a.decapsulate(None, &response, &mut buf),
+                TunnResult::WriteToNetwork(_)
+            ));
+
+            // And a padded response completes its initiation
+            let init = match b.format_handshake_initiation(&mut buf, true) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("Expected a handshake initiation"),
+            };
+            let response = match a.decapsulate(None, &init, &mut buf) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("Expected a handshake response"),
+            };
+            assert!(matches!(
+                b.decapsulate(None, &response, &mut buf),
+                TunnResult::WriteToNetwork(_)
+            ));
+            sizes.push(response.len());
+        }
+        assert!(sizes.iter().all(|size| (92..=400).contains(size)));
+        // Sizes are random, the chance of only unpadded messages is negligible
+        assert!(sizes.iter().any(|&size| size > 92));
+
+        // Padding stays within the path MTU
+        a.set_max_payload(150 - 32);
+        for _ in 0..20 {
+            match a.format_handshake_initiation(&mut buf, true) {
+                TunnResult::WriteToNetwork(packet) => assert!((148..=150).contains(&packet.len())),
+                _ => panic!("Expected a handshake initiation"),
+            }
+        }
+    }
+
+    #[test]
+    fn wireguard_responder_only() {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let mut a = Tunn::new(a_key, Arc::new(b_key.public_key()), None, Some(1), 0, None).unwrap();
+        let b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+        a.set_responder_only(true);
+
+        // Data for the peer is queued, but neither it nor the timers start a handshake
+        let ip_packet = vec![
+            0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let mut buf = [0u8; 2048];
+        assert!(matches!(
+            a.encapsulate(&ip_packet, &mut buf),
+            TunnResult::Done
+        ));
+        assert!(matches!(
+            a.format_handshake_initiation(&mut buf, true),
+            TunnResult::Done
+        ));
+        thread::sleep(Duration::from_millis(1100));
+        assert!(matches!(a.update_timers(&mut buf), TunnResult::Done));
+        assert!(!a.is_handshake_in_progress());
+        assert_eq!(a.control_stats(), (0, 0));
+
+        // Once the peer initiates, the queued packet is sent on the confirmed session
+        let init = match b.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let response = match a.decapsulate(None, &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        assert!(matches!(
+            a.decapsulate(None, &[], &mut buf),
+            TunnResult::Done
+        ));
+        assert!(!a.wants_queue_flush());
+        let keepalive = match b.decapsulate(None, &response, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a keepalive"),
+        };
+        assert!(matches!(
+            a.decapsulate(None, &keepalive, &mut buf),
+            TunnResult::Done
+        ));
+        assert!(a.wants_queue_flush());
+        let data = match a.decapsulate(None, &[], &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected the queued packet"),
+        };
+        let mut dst = [0u8; 2048];
+        match b.decapsulate(None, &data, &mut dst) {
+            TunnResult::WriteToTunnelV4(packet, _) => assert_eq!(packet, &ip_packet[..]),
+            _ => panic!("Expected a decrypted packet"),
+        }
+        assert!(matches!(
+            a.encapsulate(&ip_packet, &mut buf),
+            TunnResult::WriteToNetwork(_)
+        ));
+    }
+
+    #[test]
+    fn wireguard_resume_session() {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let b_public = Arc::new(b_key.public_key());
+        let a = Tunn::new(
+            Arc::clone(&a_key),
+            Arc::clone(&b_public),
+            None,
+            None,
+            3,
+            None,
+        )
+        .unwrap();
+        let b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        let init = match a.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let response = match b.decapsulate(None, &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        let keepalive = match a.decapsulate(None, &response, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a keepalive"),
+        };
+        b.decapsulate(None, &keepalive, &mut buf);
+
+        // A tunnel with another index can not take the session over
+        let new_tunnel = |index| {
+            Tunn::new(
+                Arc::clone(&a_key),
+                Arc::clone(&b_public),
+                None,
+                None,
+                index,
+                None,
+            )
+            .unwrap()
+        };
+        assert!(!new_tunnel(4).resume_session(&a));
+        assert!(!new_tunnel(3).resume_session(&new_tunnel(3)));
+
+        let mut resumed = new_tunnel(3);
+        assert!(resumed.resume_session(&a));
+        assert!(!resumed.is_handshake_in_progress());
+        assert!(resumed.time_since_last_handshake().is_some());
+        assert!(a.time_since_last_handshake().is_none());
+
+        // The session carries on in both directions, without a handshake
+        let ip_packet = vec![
+            0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let data = match resumed.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        assert!(matches!(
+            b.decapsulate(None, &data, &mut dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        let data = match b.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        assert!(matches!(
+            resumed.decapsulate(None, &data, &mut dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        assert_eq!(resumed.control_stats().0, 0);
+    }
+
+    // Advance the counter of the current session of a tunnel
+    fn set_sending_counter(tunnel: &Tunn, counter: u64) {
+        let current = tunnel.current.load(Ordering::Relaxed);
+        tunnel.sessions[current % N_SESSIONS]
+            .read()
+            .as_ref()
+            .unwrap()
+            .set_sending_counter(counter);
+    }
+
+    #[test]
+    fn wireguard_nonce_exhaustion() {
+        let (a, b) = tunnel_pair();
+        let ip_packet = vec![
+            0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+
+        // The last counter is still used, the next message is refused
+        set_sending_counter(&a, session::REJECT_AFTER_MESSAGES);
+        let data = match a.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        assert_eq!(
+            u64::from_le_bytes(make_array(&data[8..16])),
+            session::REJECT_AFTER_MESSAGES
+        );
+        assert!(matches!(
+            b.decapsulate(None, &data, &mut dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        let current = a.current.load(Ordering::Relaxed) % N_SESSIONS;
+        assert!(matches!(
+            a.sessions[current]
+                .read()
+                .as_ref()
+                .unwrap()
+                .format_packet_data(&ip_packet, 0, &mut buf),
+            Err(WireGuardError::NonceExhausted)
+        ));
+
+        // Instead of encrypting the packet is queued, and a handshake started
+        let init = match a.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert_eq!(init[0], 1);
+        let response = match b.decapsulate(None, &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        let keepalive = match a.decapsulate(None, &response, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a keepalive"),
+        };
+        b.decapsulate(None, &keepalive, &mut dst);
+
+        // The queued packet is sent with the new session
+        let data = match a.decapsulate(None, &[], &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected the queued packet"),
+        };
+        assert_eq!(u64::from_le_bytes(make_array(&data[8..16])), 1);
+        match b.decapsulate(None, &data, &mut dst) {
+            TunnResult::WriteToTunnelV4(packet, _) => assert_eq!(packet, &ip_packet[..]),
+            _ => panic!("Expected a decrypted packet"),
+        }
+    }
+
+    #[test]
+    fn wireguard_rekey_after_messages() {
+        let (a, b) = tunnel_pair();
+        let mut buf = [0u8; 2048];
+        assert!(matches!(a.update_timers(&mut buf), TunnResult::Done));
+
+        // Either side starts a handshake once the session sent REKEY_AFTER_MESSAGES messages
+        set_sending_counter(&b, 1 << 60);
+        match b.update_timers(&mut buf) {
+            TunnResult::WriteToNetwork(packet) => assert_eq!(packet[0], 1),
+            _ => panic!("Expected a handshake initiation"),
+        }
+    }
+
+    // Collects the messages logged
+    #[derive(Clone, Default)]
+    struct Capture(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl Drain for Capture {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, _: &OwnedKVList) -> std::result::Result<(), Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn wireguard_compat_log() {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let b_public = b_key.public_key();
+        let mut a = Tunn::new(a_key, Arc::new(b_key.public_key()), None, None, 0, None).unwrap();
+        let capture = Capture::default();
+        a.set_logger(Logger::root(capture.clone(), o!()));
+        a.set_fast_handshake_retry(1, Duration::from_secs(1));
+        a.set_wg_compat_log(true);
+
+        let mut buf = [0u8; 2048];
+        assert!(matches!(
+            a.format_handshake_initiation(&mut buf, false),
+            TunnResult::WriteToNetwork(_)
+        ));
+        thread::sleep(Duration::from_millis(1100));
+        assert!(matches!(
+            a.update_timers(&mut buf),
+            TunnResult::WriteToNetwork(_)
+        ));
+
+        let key = b_public.to_base64();
+        let lines = capture.0.lock().unwrap();
+        assert!(lines.contains(&format!("Sending handshake initiation to peer {}", key)));
+        assert!(lines.contains(&format!(
+            "Handshake for peer {} did not complete after 1 seconds, retrying (try 2)",
+            key
+        )));
+        drop(lines);
+
+        // Off, only the native messages are logged
+        a.set_wg_compat_log(false);
+        capture.0.lock().unwrap().clear();
+        a.format_handshake_initiation(&mut buf, true);
+        assert_eq!(*capture.0.lock().unwrap(), ["Sending handshake_initiation"]);
+    }
+
+    #[test]
+    fn wireguard_handshake_latency() {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let b_public = Arc::new(b_key.public_key());
+        let a = Tunn::new(a_key, b_public, None, None, 0, None).unwrap();
+        let b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+        let (a_sock, b_sock) = connected_sock_pair();
+
+        // A handshake initiated by a, over loopback
+        let handshake = || {
+            let mut buf = [0u8; 2048];
+            let mut recv = [0u8; 2048];
+            match a.format_handshake_initiation(&mut buf, true) {
+                TunnResult::WriteToNetwork(packet) => a_sock.send(packet).unwrap(),
+                _ => panic!("Expected a handshake initiation"),
+            };
+            let n = b_sock.recv(&mut recv).unwrap();
+            match b.decapsulate(None, &recv[..n], &mut buf) {
+                TunnResult::WriteToNetwork(packet) => b_sock.send(packet).unwrap(),
+                _ => panic!("Expected a handshake response"),
+            };
+            let n = a_sock.recv(&mut recv).unwrap();
+            assert!(matches!(
+                a.decapsulate(None, &recv[..n], &mut buf),
+                TunnResult::WriteToNetwork(_)
+            ));
+        };
+
+        handshake();
+        let latency = a.handshake_latency();
+        assert_eq!(latency.count(), 1);
+        assert!(latency.sum() > Duration::ZERO);
+        assert!(latency.sum() < Duration::from_secs(1));
+        assert_eq!(latency.buckets().last(), Some(&(None, 1)));
+        assert_eq!(b.handshake_latency().count(), 0);
+        assert_eq!((a.rekeys(), b.rekeys()), (0, 0));
+
+        // The next handshake replaces the session
+        handshake();
+        assert_eq!(a.handshake_latency().count(), 2);
+        assert_eq!((a.rekeys(), b.rekeys()), (1, 1));
+    }
+
+    #[test]
+    fn wireguard_replay_window() {
+        let (a, b) = tunnel_pair();
+        let window = || a.session_stats().unwrap().replay_window;
+        assert_eq!(window().next, 0);
+        assert_eq!(window().received(0), None);
+
+        let ip_packet = [
+            0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let mut buf = [0u8; 2048];
+        let packets: Vec<Vec<u8>> = (0..4)
+            .map(|_| match b.encapsulate(&ip_packet, &mut buf) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("Expected a data packet"),
+            })
+            .collect();
+
+        // Counter 2 is still on its way when 3 and then 1 arrive
+        for &counter in &[0, 3, 1] {
+            assert!(matches!(
+                a.decapsulate(None, &packets[counter], &mut buf),
+                TunnResult::WriteToTunnelV4(..)
+            ));
+        }
+        let state = window();
+        assert_eq!(state.next, 4);
+        assert_eq!(state.bitmap[0], 0b1101);
+        assert!(state.bitmap[1..].iter().all(|&word| word == 0));
+        assert_eq!(state.received(3), Some(true));
+        assert_eq!(state.received(2), Some(false));
+        assert_eq!(state.received(4), None);
+        assert_eq!(state.missing(), [2]);
+
+        // A replay is dropped, and leaves the window as it was
+        assert!(matches!(
+            a.decapsulate(None, &packets[1], &mut buf),
+            TunnResult::Err(WireGuardError::InvalidCounter)
+        ));
+        assert_eq!(window(), state);
+
+        assert!(matches!(
+            a.decapsulate(None, &packets[2], &mut buf),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        assert!(window().missing().is_empty());
+        assert_eq!(window().size(), 1024);
+        assert_eq!(window().received(0), Some(true));
+    }
+
+    #[test]
+    fn wireguard_duplicate_init() {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let b_public = Arc::new(b_key.public_key());
+        let a = Tunn::new(a_key, b_public, None, None, 0, None).unwrap();
+        let mut b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+        b.set_duplicate_init_window(Duration::from_secs(60));
+
+        let mut buf = [0u8; 2048];
+        let init = |buf: &mut [u8]| match a.format_handshake_initiation(buf, true) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let first = init(&mut buf);
+        let response = match b.decapsulate(None, &first, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+
+        // A copy gets the same response, which a new handshake with a fresh ephemeral key can not
+        // produce, and completes no handshake
+        match b.decapsulate(None, &first, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => assert_eq!(packet, &response[..]),
+            _ => panic!("Expected the cached handshake response"),
+        }
+        assert_eq!(b.handshake_stats(), (0, 1));
+        assert!(matches!(
+            a.decapsulate(None, &response, &mut buf),
+            TunnResult::WriteToNetwork(_)
+        ));
+
+        // Once a newer initiation is answered, the first one is a replay with an old timestamp
+        let second = init(&mut buf);
+        assert!(matches!(
+            b.decapsulate(None, &second, &mut buf),
+            TunnResult::WriteToNetwork(_)
+        ));
+        assert_eq!(b.handshake_stats(), (0, 2));
+        assert!(matches!(
+            b.decapsulate(None, &first, &mut buf),
+            TunnResult::Err(WireGuardError::WrongTai64nTimestamp)
+        ));
+
+        // And without the window, or its session, copies are dropped
+        b.expire_sessions();
+        assert!(matches!(
+            b.decapsulate(None, &second, &mut buf),
+            TunnResult::Err(WireGuardError::WrongTai64nTimestamp)
+        ));
+        b.set_duplicate_init_window(Duration::ZERO);
+        let third = init(&mut buf);
+        assert!(matches!(
+            b.decapsulate(None, &third, &mut buf),
+            TunnResult::WriteToNetwork(_)
+        ));
+        assert!(matches!(
+            b.decapsulate(None, &third, &mut buf),
+            TunnResult::Err(WireGuardError::WrongTai64nTimestamp)
+        ));
+    }
+
+    #[test]
+    fn wireguard_reap_expired_sessions() {
+        use crate::noise::clock::ManualClock;
+
+        let clock = Arc::new(ManualClock::new());
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let mut a = Tunn::new(a_key, Arc::new(b_key.public_key()), None, None, 0, None).unwrap();
+        let mut b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+        a.set_clock(clock.clone());
+        b.set_clock(clock.clone());
+        complete_handshake(&a, &b);
+        assert_eq!(a.reap_expired_sessions(), 0);
+
+        // A second session 100 seconds later, the first one stays until it is past its lifetime
+        let mut buf = [0u8; 2048];
+        clock.advance(Duration::from_secs(100));
+        a.update_timers(&mut buf);
+        b.update_timers(&mut buf);
+        complete_handshake(&a, &b);
+        clock.advance(Duration::from_secs(70));
+        assert_eq!(a.reap_expired_sessions(), 0);
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(a.reap_expired_sessions(), 1);
+        assert_eq!(a.reap_expired_sessions(), 0);
+        let ip_packet = [
+            0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        assert!(matches!(
+            a.encapsulate(&ip_packet, &mut buf),
+            TunnResult::WriteToNetwork(..)
+        ));
+
+        // Once the second session is past its lifetime too nothing is left to send with
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(a.reap_expired_sessions(), 1);
+        assert!(a.session_stats().is_none());
+        assert_eq!(b.reap_expired_sessions(), 2);
+    }
+
+    #[test]
+    fn wireguard_handshake_latency_clock() {
+        use crate::noise::clock::{Clock, ManualClock};
+
+        let clock = Arc::new(ManualClock::new());
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let mut a = Tunn::new(a_key, Arc::new(b_key.public_key()), None, None, 0, None).unwrap();
+        let mut b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+        a.set_clock(clock.clone());
+        b.set_clock(clock.clone());
+
+        // The wall clock steps back a day while the response is on its way, the latency is
+        // still the time that passed
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        let init = match a.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        clock.advance(Duration::from_millis(30));
+        clock.set_wall(clock.wall() - Duration::from_secs(24 * 3600));
+        let response = match b.decapsulate(None, &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        assert!(matches!(
+            a.decapsulate(None, &response, &mut dst),
+            TunnResult::WriteToNetwork(_)
+        ));
+        assert_eq!(a.handshake_latency().sum(), Duration::from_millis(30));
+        assert_eq!(
+            a.session_stats().unwrap().created,
+            std::time::UNIX_EPOCH + clock.wall()
+        );
+    }
+
+    #[test]
+    fn wireguard_clock_jumps() {
+        use crate::noise::clock::{Clock, ManualClock};
+
+        let clock = Arc::new(ManualClock::new());
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let mut a = Tunn::new(a_key, Arc::new(b_key.public_key()), None, None, 0, None).unwrap();
+        let mut b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+        a.set_clock(clock.clone());
+        b.set_clock(clock.clone());
+        complete_handshake(&a, &b);
+
+        // Exchange data every second for a minute, after the wall clock stepped back 30 days
+        let wall = clock.wall();
+        clock.set_wall(wall - Duration::from_secs(30 * 24 * 3600));
+        let ip_packet = [
+            0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        let mut timer_packets = 0;
+        for _ in 0..60 {
+            clock.advance(Duration::from_secs(1));
+            for (from, to) in &[(&a, &b), (&b, &a)] {
+                if let TunnResult::WriteToNetwork(_) = from.update_timers(&mut buf) {
+                    timer_packets += 1;
+                }
+                let packet = match from.encapsulate(&ip_packet, &mut buf) {
+                    TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                    _ => panic!("Expected a data packet"),
+                };
+                assert!(matches!(
+                    to.decapsulate(None, &packet, &mut dst),
+                    TunnResult::WriteToTunnelV4(..)
+                ));
+            }
+        }
+        // Neither a rekey nor a keepalive is due, the session is a minute old by the timers
+        assert_eq!(timer_packets, 0);
+        assert_eq!(a.time_since_handshake(), Some(Duration::from_secs(60)));
+        assert_eq!(a.session_stats().unwrap().age, Duration::from_secs(60));
+        // Only the reported time of the handshake follows the wall clock
+        assert_eq!(
+            a.time_since_last_handshake(),
+            Some(clock.wall() - Duration::from_secs(60))
+        );
+        assert_eq!(
+            a.session_stats().unwrap().created,
+            std::time::UNIX_EPOCH + clock.wall() - Duration::from_secs(60)
+        );
+
+        // Timestamps keep growing, so an initiation after the step is not taken for a replay
+        let init = match a.format_handshake_initiation(&mut buf, true) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert!(matches!(
+            b.decapsulate(None, &init, &mut dst),
+            TunnResult::WriteToNetwork(_)
+        ));
+
+        // A step forward by a year is as harmless, a wall clock near the epoch does not panic
+        clock.set_wall(clock.wall() + Duration::from_secs(365 * 24 * 3600));
+        assert!(matches!(b.update_timers(&mut buf), TunnResult::Done));
+        clock.set_wall(Duration::from_secs(10));
+        assert_eq!(a.time_since_last_handshake(), Some(Duration::ZERO));
+
+        // Ten minutes in suspend count, sessions too old to use are dropped at once
+        clock.advance(Duration::from_secs(600));
+        assert!(matches!(
+            b.update_timers(&mut buf),
+            TunnResult::Err(WireGuardError::ConnectionExpired)
+        ));
+        assert!(b.session_stats().is_none());
+        assert!(!matches!(
+            b.update_timers(&mut buf),
+            TunnResult::WriteToNetwork(_)
+        ));
+    }
+
+    #[test]
+    fn wireguard_lean_tunnel() {
+        let a_key = Arc::new(X25519SecretKey::new());
+        let b_key = Arc::new(X25519SecretKey::new());
+        let a_public = Arc::new(a_key.public_key());
+        let b_public = Arc::new(b_key.public_key());
+
+        let buffers = Arc::new(pool::BufferPool::new(4));
+        let mut a = Tunn::new(a_key, b_public, None, None, 0, None).unwrap();
+        let mut b = Tunn::new(b_key, a_public, None, None, 1, None).unwrap();
+        a.set_lean(Arc::clone(&buffers));
+        b.set_lean(Arc::clone(&buffers));
+
+        // Timer ticks of an idle tunnel allocate nothing
+        let mut buf = [0u8; 2048];
+        assert!(matches!(a.update_timers(&mut buf), TunnResult::Done));
+        assert!(a.is_idle() && b.is_idle());
+
+        // The packet queued during the handshake is copied to a buffer of the pool, returned to
+        // it once sent
+        let mut ip_packet = vec![
+            0x45, 0, 0, 24, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        ip_packet.extend_from_slice(b"test");
+        let init = match a.encapsulate(&ip_packet, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert!(!a.is_idle());
+        let response = match b.decapsulate(None, &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        assert!(!b.is_idle());
+
+        let mut outputs = vec![];
+        let mut results = a.decapsulate_iter(None, &response, &mut buf);
+        while let Some(result) = results.next_result() {
+            if let TunnResult::WriteToNetwork(packet) = result {
+                outputs.push(packet.to_vec());
+            }
+        }
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(buffers.free(), 1);
+
+        let mut dst = [0u8; 2048];
+        b.decapsulate(None, &outputs[0], &mut dst);
+        match b.decapsulate(None, &outputs[1], &mut dst) {
+            TunnResult::WriteToTunnelV4(packet, _) => assert_eq!(packet, &ip_packet[..]),
+            _ => panic!("Expected the queued packet"),
+        }
+    }
 }
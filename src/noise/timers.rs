@@ -2,12 +2,14 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use self::TimerName::*;
+use super::clock::{Clock, SystemClock};
 use super::errors::WireGuardError;
-use crate::noise::{Tunn, TunnResult};
+use crate::noise::{SessionStats, Tunn, TunnResult};
 use slog::debug;
 use std::ops::Index;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, UNIX_EPOCH};
 
 /*
 static MAX_TIMER_HANDSHAKES: u32 = 90 / 5;
@@ -21,8 +23,10 @@ const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
 const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
 const REKEY_ATTEMPT_TIME: Duration = Duration::from_secs(90);
 const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+const REKEY_AFTER_MESSAGES: u64 = 1 << 60;
 const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
 const COOKIE_EXPIRATION_TIME: Duration = Duration::from_secs(120);
+const DEFAULT_HANDSHAKE_BACKOFF_CEILING: Duration = Duration::from_secs(300);
 
 #[derive(Debug)]
 pub enum TimerName {
@@ -35,6 +39,7 @@ pub enum TimerName {
     TimeLastDataPacketSent,     // Time we last send a DATA packet
     TimeCookieReceived,         // Time we last received a cookie
     TimePersistentKeepalive,    // Time we last sent persistent keepalive
+    TimeLastHandshakeSent,      // Time we last sent a handshake initiation
     Top,
 }
 
@@ -46,56 +51,136 @@ pub struct Timer {
     time: AtomicUsize,
 }
 
-#[derive(Debug)]
-pub struct Timers {
+// The timers that change with the traffic of a tunnel
+#[derive(Default, Debug)]
+pub(super) struct TimerState {
     is_initiator: AtomicBool, // Is the owner of the timer the initiator or the responder for the last handshake?
-    time_started: Instant,    // Start time of the tunnel
     timers: [Timer; TimerName::Top as usize],
     pub(super) session_timers: [Timer; super::N_SESSIONS],
     want_keepalive: AtomicBool, // Did we receive data without sending anything back?
     want_handshake: AtomicBool, // Did we send data without hearing back?
+    handshake_attempts: AtomicUsize, // Handshake initiations sent since the last completed handshake
+    expiring_session: AtomicUsize,   // One more than the last session reported as expiring
+}
+
+// The timer slots every lean tunnel reads until it allocates its own, never written
+static IDLE_TIMERS: TimerState = TimerState {
+    is_initiator: AtomicBool::new(false),
+    timers: [const { Timer::zero() }; TimerName::Top as usize],
+    session_timers: [const { Timer::zero() }; super::N_SESSIONS],
+    want_keepalive: AtomicBool::new(false),
+    want_handshake: AtomicBool::new(false),
+    handshake_attempts: AtomicUsize::new(0),
+    expiring_session: AtomicUsize::new(0),
+};
+
+#[derive(Debug)]
+pub struct Timers {
+    pub(super) clock: Arc<dyn Clock>, // Only its monotonic clock is read, the wall clock may jump
+    time_started: Duration,           // Start time of the tunnel, on the monotonic clock
+    state: OnceLock<Box<TimerState>>, // Allocated by the first tick of a lean tunnel
     persistent_keepalive: AtomicUsize,
+    idle_timeout: AtomicUsize, // Seconds without data before the session is torn down, 0 for never
+    max_handshake_attempts: usize, // Attempts before retries back off, 0 to never back off
+    handshake_backoff_ceiling: Duration,
+    fast_handshake_retries: usize, // Initiations retried after fast_handshake_retry_interval
+    fast_handshake_retry_interval: Duration,
+    pub(super) responder_only: bool, // Never initiate handshakes or send persistent keepalives
     pub(super) should_reset_rr: bool, // Should this timer call reset rr function (if not a shared rr instance)
 }
 
 impl Timers {
     pub(super) fn new(persistent_keepalive: Option<u16>, reset_rr: bool) -> Timers {
+        let clock = Arc::new(SystemClock);
         Timers {
-            is_initiator: AtomicBool::new(false),
-            time_started: Instant::now(),
-            timers: Default::default(),
-            session_timers: Default::default(),
-            want_keepalive: Default::default(),
-            want_handshake: Default::default(),
+            time_started: clock.monotonic(),
+            clock,
+            state: OnceLock::from(Box::default()),
             persistent_keepalive: AtomicUsize::new(usize::from(persistent_keepalive.unwrap_or(0))),
+            idle_timeout: Default::default(),
+            max_handshake_attempts: 0,
+            handshake_backoff_ceiling: DEFAULT_HANDSHAKE_BACKOFF_CEILING,
+            fast_handshake_retries: 0,
+            fast_handshake_retry_interval: REKEY_TIMEOUT,
+            responder_only: false,
             should_reset_rr: reset_rr,
         }
     }
 
+    // Drop the timers of a new tunnel, which reads those of IDLE_TIMERS until its first tick
+    pub(super) fn set_lean(&mut self) {
+        self.state = OnceLock::new();
+    }
+
+    // The timers as they are read, those of IDLE_TIMERS while a lean tunnel has none
+    pub(super) fn state(&self) -> &TimerState {
+        self.state.get().map_or(&IDLE_TIMERS, |state| state)
+    }
+
+    // The timers of the tunnel to write, allocated at the current time on first use
+    fn live(&self) -> &TimerState {
+        self.state.get_or_init(|| {
+            let state = Box::<TimerState>::default();
+            state[TimeCurrent].set(self.now());
+            state
+        })
+    }
+
+    // A lean tunnel without timers of its own has had no traffic or handshake yet
+    pub(super) fn is_idle(&self) -> bool {
+        self.state.get().is_none()
+    }
+
     fn is_initiator(&self) -> bool {
-        self.is_initiator.load(Ordering::Relaxed)
+        self.state().is_initiator.load(Ordering::Relaxed)
+    }
+
+    // The time since the start of the tunnel
+    pub(super) fn now(&self) -> Duration {
+        self.clock.monotonic().saturating_sub(self.time_started)
+    }
+
+    // Start the tunnel again on another clock
+    pub(super) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.time_started = clock.monotonic();
+        self.clock = clock;
+        self.clear();
     }
 
     // We don't really clear the timers, but we set them to the current time to
     // so the reference time frame is the same
     pub(super) fn clear(&self) {
-        let now = Instant::now().duration_since(self.time_started);
-        for t in &self.timers[..] {
+        let now = self.now();
+        let state = self.live();
+        for t in &state.timers[..] {
             t.set(now);
         }
-        self.want_handshake.store(false, Ordering::Relaxed);
-        self.want_keepalive.store(false, Ordering::Relaxed);
+        state.want_handshake.store(false, Ordering::Relaxed);
+        state.want_keepalive.store(false, Ordering::Relaxed);
     }
 }
 
-impl Index<TimerName> for Timers {
+impl Index<TimerName> for TimerState {
     type Output = Timer;
     fn index(&self, index: TimerName) -> &Timer {
         &self.timers[index as usize]
     }
 }
 
+impl Index<TimerName> for Timers {
+    type Output = Timer;
+    fn index(&self, index: TimerName) -> &Timer {
+        &self.state()[index]
+    }
+}
+
 impl Timer {
+    const fn zero() -> Timer {
+        Timer {
+            time: AtomicUsize::new(0),
+        }
+    }
+
     pub(super) fn time(&self) -> Duration {
         Duration::from_secs(self.time.load(Ordering::Relaxed) as _)
     }
@@ -107,28 +192,29 @@ impl Timer {
 
 impl Tunn {
     pub(super) fn timer_tick(&self, timer_name: TimerName) {
+        let timers = self.timers.live();
         match timer_name {
             TimeLastPacketReceived => {
-                self.timers.want_keepalive.store(true, Ordering::Relaxed);
-                self.timers.want_handshake.store(false, Ordering::Relaxed);
+                timers.want_keepalive.store(true, Ordering::Relaxed);
+                timers.want_handshake.store(false, Ordering::Relaxed);
             }
             TimeLastPacketSent => {
-                self.timers.want_handshake.store(true, Ordering::Relaxed);
-                self.timers.want_keepalive.store(false, Ordering::Relaxed);
+                timers.want_handshake.store(true, Ordering::Relaxed);
+                timers.want_keepalive.store(false, Ordering::Relaxed);
             }
             _ => {}
         }
 
-        self.timers[timer_name].set(self.timers[TimeCurrent].time());
+        timers[timer_name].set(timers[TimeCurrent].time());
     }
 
     pub(super) fn timer_tick_session_established(&self, is_initiator: bool, session_idx: usize) {
         self.timer_tick(TimeSessionEstablished);
-        self.timers.session_timers[session_idx % crate::noise::N_SESSIONS]
-            .set(self.timers[TimeCurrent].time());
-        self.timers
-            .is_initiator
-            .store(is_initiator, Ordering::Relaxed)
+        let timers = self.timers.live();
+        timers.session_timers[session_idx % crate::noise::N_SESSIONS]
+            .set(timers[TimeCurrent].time());
+        timers.handshake_attempts.store(0, Ordering::Relaxed);
+        timers.is_initiator.store(is_initiator, Ordering::Relaxed)
     }
 
     // We don't really clear the timers, but we set them to the current time to
@@ -140,40 +226,68 @@ impl Tunn {
 
         {
             let mut queued = self.packet_queue.lock();
-            queued.clear();
+            for packet in std::mem::take(&mut *queued) {
+                self.recycle_packet(packet);
+            }
         }
 
         self.timers.clear();
     }
 
-    fn update_session_timers(&self, time_now: Duration) {
-        let timers = &self.timers;
+    // Drop the sessions established more than REJECT_AFTER_TIME ago, returns how many
+    fn update_session_timers(&self, time_now: Duration) -> usize {
+        if self.timers.is_idle() {
+            return 0; // No session was ever established
+        }
+        let timers = self.timers.live();
+        let mut expired = 0;
 
         for (i, t) in timers.session_timers.iter().enumerate() {
-            if time_now - t.time() > REJECT_AFTER_TIME {
+            // A reap may have set the timer to a later time than that of a concurrent tick
+            if time_now.saturating_sub(t.time()) > REJECT_AFTER_TIME {
                 if let Some(session) = self.sessions[i].write().take() {
                     debug!(self.logger, "SESSION_EXPIRED(REJECT_AFTER_TIME)"; "session" => session.receiving_index);
+                    expired += 1;
                 }
                 t.set(time_now);
             }
         }
+        expired
+    }
+
+    /// Drop the sessions that can no longer be used as they are older than REJECT_AFTER_TIME,
+    /// without waiting for the next call to `update_timers`, and return how many were dropped.
+    /// Dropping a session zeroes its exporter secret, and its keys where they are not held by
+    /// ring. Sessions that can still be used are left alone, and each session is only locked
+    /// while it is checked.
+    pub fn reap_expired_sessions(&self) -> usize {
+        self.update_session_timers(self.timers.now())
     }
 
     pub fn update_timers<'a>(&self, dst: &'a mut [u8]) -> TunnResult<'a> {
         let mut handshake_initiation_required = false;
         let mut keepalive_required = false;
 
-        let time = Instant::now();
         let timers = &self.timers;
 
         if timers.should_reset_rr {
-            self.rate_limiter.reset_count();
+            self.rate_limiter.read().reset_count();
+            if let Some((_, rate_limiter)) = &*self.next_key.read() {
+                rate_limiter.reset_count();
+            }
+        }
+
+        // A lean tunnel that never had traffic has nothing to time, unless it is kept alive
+        let persistent_keepalive = timers.persistent_keepalive.load(Ordering::Relaxed);
+        if timers.is_idle() && (persistent_keepalive == 0 || timers.responder_only) {
+            return TunnResult::Done;
         }
 
         // All the times are counted from tunnel initiation, for efficiency our timers are rounded
         // to a second, as there is no real benefit to having highly accurate timers.
-        let now = time.duration_since(timers.time_started);
-        timers[TimeCurrent].set(now);
+        let now = timers.now();
+        let state = timers.live();
+        state[TimeCurrent].set(now);
 
         self.update_session_timers(now);
 
@@ -184,7 +298,7 @@ impl Tunn {
         let aut_packet_sent = timers[TimeLastPacketSent].time();
         let data_packet_received = timers[TimeLastDataPacketReceived].time();
         let data_packet_sent = timers[TimeLastDataPacketSent].time();
-        let persistent_keepalive = timers.persistent_keepalive.load(Ordering::Relaxed);
+        let idle_timeout = timers.idle_timeout.load(Ordering::Relaxed);
 
         {
             let mut handshake = match self.handshake.try_lock() {
@@ -207,6 +321,31 @@ impl Tunn {
             // (REJECT_AFTER_TIME * 3) ms if no new keys have been exchanged.
             if now - session_established >= REJECT_AFTER_TIME * 3 {
                 debug!(self.logger, "CONNECTION_EXPIRED(REJECT_AFTER_TIME * 3)");
+                self.log_compat(|key| {
+                    format!(
+                        "Zeroing out all keys for peer {}, since we haven't received a new one in {} seconds",
+                        key,
+                        (REJECT_AFTER_TIME * 3).as_secs()
+                    )
+                });
+                handshake.set_expired();
+                self.clear_all();
+                return TunnResult::Err(WireGuardError::ConnectionExpired);
+            }
+
+            // A peer that exchanged no data for its idle timeout has its sessions torn down, until
+            // the next packet to it starts a new handshake. Peers with persistent keepalive are
+            // kept up on purpose, and are exempt.
+            if idle_timeout > 0
+                && persistent_keepalive == 0
+                && now
+                    - session_established
+                        .max(data_packet_received)
+                        .max(data_packet_sent)
+                    >= Duration::from_secs(idle_timeout as _)
+                && self.sessions.iter().any(|session| session.read().is_some())
+            {
+                debug!(self.logger, "CONNECTION_IDLE(IDLE_TIMEOUT)");
                 handshake.set_expired();
                 self.clear_all();
                 return TunnResult::Err(WireGuardError::ConnectionExpired);
@@ -220,18 +359,36 @@ impl Tunn {
                     // up to be sent. If a packet is explicitly queued up to be sent, then
                     // this timer is reset.
                     debug!(self.logger, "CONNECTION_EXPIRED(REKEY_ATTEMPT_TIME)");
+                    self.log_compat(|key| {
+                        format!(
+                            "Handshake for peer {} did not complete after {} attempts, giving up",
+                            key,
+                            self.handshake_attempts()
+                        )
+                    });
                     handshake.set_expired();
                     self.clear_all();
                     return TunnResult::Err(WireGuardError::ConnectionExpired);
                 }
 
-                if time_init_sent.elapsed() >= REKEY_TIMEOUT {
-                    // We avoid using `time` here, because it can be earlier than `time_init_sent`.
-                    // Once `checked_duration_since` is stable we can use that.
+                if timers.clock.monotonic().saturating_sub(time_init_sent)
+                    >= self.handshake_retry_interval()
+                {
+                    // We avoid using `now` here, because it is rounded to a second, unlike
+                    // `time_init_sent`.
                     // A handshake initiation is retried after REKEY_TIMEOUT + jitter ms,
                     // if a response has not been received, where jitter is some random
-                    // value between 0 and 333 ms.
+                    // value between 0 and 333 ms. Once the peer is backed off the interval
+                    // grows instead.
                     debug!(self.logger, "HANDSHAKE(REKEY_TIMEOUT)");
+                    self.log_compat(|key| {
+                        format!(
+                            "Handshake for peer {} did not complete after {} seconds, retrying (try {})",
+                            key,
+                            self.handshake_retry_interval().as_secs(),
+                            self.handshake_attempts() + 1
+                        )
+                    });
                     handshake_initiation_required = true;
                 }
             } else {
@@ -261,14 +418,28 @@ impl Tunn {
                     }
                 }
 
+                // After sending REKEY_AFTER_MESSAGES messages with the current session, either
+                // side initiates a new handshake, long before the session runs out of nonces
+                if self.current_session_sent() >= REKEY_AFTER_MESSAGES {
+                    debug!(self.logger, "HANDSHAKE(REKEY_AFTER_MESSAGES)");
+                    handshake_initiation_required = true;
+                }
+
                 // If we have sent a packet to a given peer but have not received a
                 // packet after from that peer for (KEEPALIVE + REKEY_TIMEOUT) ms,
                 // we initiate a new handshake.
                 if data_packet_sent > aut_packet_received
                     && now - aut_packet_received >= KEEPALIVE_TIMEOUT + REKEY_TIMEOUT
-                    && timers.want_handshake.swap(false, Ordering::Relaxed)
+                    && state.want_handshake.swap(false, Ordering::Relaxed)
                 {
                     debug!(self.logger, "HANDSHAKE(KEEPALIVE + REKEY_TIMEOUT)");
+                    self.log_compat(|key| {
+                        format!(
+                            "Retrying handshake with peer {} because we stopped hearing back after {} seconds",
+                            key,
+                            (KEEPALIVE_TIMEOUT + REKEY_TIMEOUT).as_secs()
+                        )
+                    });
                     handshake_initiation_required = true;
                 }
 
@@ -277,7 +448,7 @@ impl Tunn {
                     // to the given peer in KEEPALIVE ms, we send an empty packet.
                     if data_packet_received > aut_packet_sent
                         && now - aut_packet_sent >= KEEPALIVE_TIMEOUT
-                        && timers.want_keepalive.swap(false, Ordering::Relaxed)
+                        && state.want_keepalive.swap(false, Ordering::Relaxed)
                     {
                         debug!(self.logger, "KEEPALIVE(KEEPALIVE_TIMEOUT)");
                         keepalive_required = true;
@@ -285,6 +456,7 @@ impl Tunn {
 
                     // Persistent KEEPALIVE
                     if persistent_keepalive > 0
+                        && !timers.responder_only
                         && (now - timers[TimePersistentKeepalive].time()
                             >= Duration::from_secs(persistent_keepalive as _))
                     {
@@ -301,28 +473,225 @@ impl Tunn {
         }
 
         if keepalive_required {
+            self.log_compat(|key| format!("Sending keepalive packet to peer {}", key));
             return self.encapsulate(&[], dst);
         }
 
         TunnResult::Done
     }
 
+    // The number of messages sent with the current session
+    fn current_session_sent(&self) -> u64 {
+        let current = self.current.load(Ordering::Acquire);
+        match self.sessions[current % super::N_SESSIONS].read().as_ref() {
+            Some(session) => session.traffic().0,
+            None => 0,
+        }
+    }
+
+    /// The time of the last handshake since the Unix epoch, as the UAPI reports it, None without
+    /// a session. It follows the wall clock when that jumps.
     pub fn time_since_last_handshake(&self) -> Option<Duration> {
+        let age = self.time_since_handshake()?;
+        Some(self.timers.clock.wall().saturating_sub(age))
+    }
+
+    /// The time since the last handshake on the monotonic clock, None without a session
+    pub fn time_since_handshake(&self) -> Option<Duration> {
         let current_session = self.current.load(Ordering::Acquire);
         if self.sessions[current_session % super::N_SESSIONS]
             .read()
             .is_some()
         {
-            let time_current = Instant::now().duration_since(self.timers.time_started);
             let time_session_established = self.timers[TimeSessionEstablished].time();
-            let epoch_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-
-            Some(epoch_time - (time_current - time_session_established))
+            Some(self.timers.now().saturating_sub(time_session_established))
         } else {
             None
         }
     }
 
+    /// The age, time to rekey and traffic of the current session, None without a session.
+    /// The peer that initiated the session rekeys it after REKEY_AFTER_TIME, so the estimate
+    /// holds for either side.
+    pub fn session_stats(&self) -> Option<SessionStats> {
+        let current = self.current.load(Ordering::Acquire) % super::N_SESSIONS;
+        let session = self.sessions[current].read();
+        let session = session.as_ref()?;
+        let (sent, tx_bytes, rx_bytes) = session.traffic();
+        let (local_index, peer_index, created) = session.identity();
+        // Date the session by its age, so a step of the wall clock since does not move it
+        let created = UNIX_EPOCH
+            + self
+                .timers
+                .clock
+                .wall()
+                .saturating_sub(self.timers.clock.monotonic().saturating_sub(created));
+
+        let now = self.timers.now();
+        let age = Duration::from_secs(now.as_secs())
+            .saturating_sub(self.timers.state().session_timers[current].time());
+        let mut time_to_rekey = REKEY_AFTER_TIME.saturating_sub(age);
+        if sent > 0 && age > Duration::ZERO {
+            // Extrapolate the rate messages were sent at so far
+            let remaining = u128::from(REKEY_AFTER_MESSAGES.saturating_sub(sent));
+            let millis = remaining * age.as_millis() / u128::from(sent);
+            let by_messages = Duration::from_millis(millis.min(u128::from(u64::MAX)) as u64);
+            time_to_rekey = time_to_rekey.min(by_messages);
+        }
+
+        Some(SessionStats {
+            age,
+            time_to_rekey,
+            tx_bytes,
+            rx_bytes,
+            local_index,
+            peer_index,
+            created,
+            replay_window: session.replay_window(),
+        })
+    }
+
+    /// Returns true once per session, when the current session is less than lead away from
+    /// REJECT_AFTER_TIME, after which it can no longer be used. A handshake started then has
+    /// its new session ready before the old one expires.
+    pub fn is_session_expiring(&self, lead: Duration) -> bool {
+        let current = self.current.load(Ordering::Acquire);
+        if self.sessions[current % super::N_SESSIONS].read().is_none() {
+            return false;
+        }
+
+        let now = self.timers.now();
+        let timers = self.timers.live();
+        let age = now.saturating_sub(timers.session_timers[current % super::N_SESSIONS].time());
+        if age < REJECT_AFTER_TIME.saturating_sub(lead) || age >= REJECT_AFTER_TIME {
+            return false;
+        }
+
+        let reported = current.wrapping_add(1);
+        timers.expiring_session.swap(reported, Ordering::Relaxed) != reported
+    }
+
+    /// Take over the current session of `from`, a tunnel to the same peer created with the same
+    /// index, if it is younger than REJECT_AFTER_TIME. The session keeps its keys, counters and
+    /// age, so it is rekeyed and rejected when it would have been in `from`. Returns false, and
+    /// takes nothing, when there is no such session.
+    pub fn resume_session(&mut self, from: &Tunn) -> bool {
+        let current = from.current.load(Ordering::Acquire);
+        let idx = current % super::N_SESSIONS;
+        let established = from.timers.state().session_timers[idx].time();
+        let now = from.timers.now();
+        if now.saturating_sub(established) >= REJECT_AFTER_TIME {
+            return false;
+        }
+
+        let mut from_session = from.sessions[idx].write();
+        match from_session.as_ref() {
+            Some(session) if self.handshake.lock().resume_index(session.receiving_index) => {}
+            _ => return false,
+        }
+
+        // Timers count from the start of the tunnel, adopt that of the session
+        self.timers.clock = Arc::clone(&from.timers.clock);
+        self.timers.time_started = from.timers.time_started;
+        self.handshake
+            .lock()
+            .set_clock(Arc::clone(&from.timers.clock));
+        self.timers.clear();
+        let timers = self.timers.live();
+        timers[TimeSessionEstablished].set(established);
+        timers.session_timers[idx].set(established);
+        timers
+            .is_initiator
+            .store(from.timers.is_initiator(), Ordering::Relaxed);
+        *self.sessions[idx].write() = from_session.take();
+        self.current.store(current, Ordering::Release);
+        true
+    }
+
+    /// Back off handshake retries after max_attempts consecutive initiations went unanswered.
+    /// From then on the retry interval doubles with every attempt, up to ceiling, until a
+    /// handshake completes. With max_attempts 0 handshakes are always retried after REKEY_TIMEOUT.
+    pub fn set_handshake_backoff(&mut self, max_attempts: usize, ceiling: Duration) {
+        self.timers.max_handshake_attempts = max_attempts;
+        self.timers.handshake_backoff_ceiling = ceiling.max(REKEY_TIMEOUT);
+    }
+
+    /// Retry the first `retries` handshake initiations after each attempt after `interval`
+    /// instead of REKEY_TIMEOUT, so a lost first packet does not stall a connection for long.
+    /// The interval is capped at REKEY_TIMEOUT, with 0 retries all use REKEY_TIMEOUT.
+    pub fn set_fast_handshake_retry(&mut self, retries: usize, interval: Duration) {
+        self.timers.fast_handshake_retries = retries;
+        self.timers.fast_handshake_retry_interval = interval.min(REKEY_TIMEOUT);
+    }
+
+    /// Only respond to handshakes the peer initiates, and send no persistent keepalives. Packets
+    /// for the peer are queued until it initiates a handshake, and sent once the session is
+    /// confirmed.
+    pub fn set_responder_only(&mut self, responder_only: bool) {
+        self.timers.responder_only = responder_only;
+    }
+
+    pub fn is_responder_only(&self) -> bool {
+        self.timers.responder_only
+    }
+
+    /// The number of handshake initiations sent since the last completed handshake
+    pub fn handshake_attempts(&self) -> usize {
+        self.timers
+            .state()
+            .handshake_attempts
+            .load(Ordering::Relaxed)
+    }
+
+    /// The time to wait for a response before the next handshake initiation is sent
+    pub fn handshake_retry_interval(&self) -> Duration {
+        let attempts = self.handshake_attempts();
+        let max_attempts = self.timers.max_handshake_attempts;
+        if attempts > 0 && attempts <= self.timers.fast_handshake_retries {
+            return self.timers.fast_handshake_retry_interval;
+        }
+        if max_attempts == 0 || attempts < max_attempts {
+            return REKEY_TIMEOUT;
+        }
+
+        let backoff = 1u32 << (attempts - max_attempts + 1).min(16);
+        (REKEY_TIMEOUT * backoff).min(self.timers.handshake_backoff_ceiling)
+    }
+
+    /// A backed off peer does not start a new handshake before the retry interval has passed
+    pub fn is_handshake_backed_off(&self) -> bool {
+        let max_attempts = self.timers.max_handshake_attempts;
+        let since_sent = self.timers[TimeCurrent]
+            .time()
+            .checked_sub(self.timers[TimeLastHandshakeSent].time())
+            .unwrap_or_default();
+        max_attempts > 0
+            && self.handshake_attempts() >= max_attempts
+            && since_sent < self.handshake_retry_interval()
+    }
+
+    pub(super) fn timer_tick_handshake_sent(&self) {
+        self.timers
+            .live()
+            .handshake_attempts
+            .fetch_add(1, Ordering::Relaxed);
+        self.timer_tick(TimeLastHandshakeSent);
+    }
+
+    /// Tear the sessions down once no data was sent or received for timeout, None to keep them
+    /// up. Timers have a resolution of a second. Ignored while persistent keepalive is set.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        let secs = timeout.map_or(0, |timeout| timeout.as_secs().max(1) as usize);
+        self.timers.idle_timeout.store(secs, Ordering::Relaxed);
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.timers.idle_timeout.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs as _)),
+        }
+    }
+
     pub fn persistent_keepalive(&self) -> Option<u16> {
         let keepalive = self.timers.persistent_keepalive.load(Ordering::Relaxed);
 